@@ -0,0 +1,38 @@
+#!/bin/bash
+# Builds ShorebirdUpdater.xcframework so iOS host apps can call the
+# shorebird_* C API directly from Swift or Objective-C.
+#
+# Requires Xcode and the iOS rust targets:
+#   rustup target add aarch64-apple-ios aarch64-apple-ios-sim x86_64-apple-ios
+
+set -e
+
+cd "$(dirname "$0")"
+# Cargo puts build output in the workspace target directory.
+TARGET_DIR=../target
+OUT_DIR=$TARGET_DIR/apple
+
+for target in aarch64-apple-ios aarch64-apple-ios-sim x86_64-apple-ios; do
+    cargo build --release --target $target
+done
+
+rm -rf $OUT_DIR
+mkdir -p $OUT_DIR/headers $OUT_DIR/ios-simulator
+
+# updater.h is regenerated by build.rs during the cargo builds above.
+cp include/updater.h include/module.modulemap $OUT_DIR/headers/
+
+# Simulator slices for both architectures must be a single fat library.
+lipo -create \
+    $TARGET_DIR/aarch64-apple-ios-sim/release/libupdater.a \
+    $TARGET_DIR/x86_64-apple-ios/release/libupdater.a \
+    -output $OUT_DIR/ios-simulator/libupdater.a
+
+xcodebuild -create-xcframework \
+    -library $TARGET_DIR/aarch64-apple-ios/release/libupdater.a \
+    -headers $OUT_DIR/headers \
+    -library $OUT_DIR/ios-simulator/libupdater.a \
+    -headers $OUT_DIR/headers \
+    -output $OUT_DIR/ShorebirdUpdater.xcframework
+
+echo "Built $OUT_DIR/ShorebirdUpdater.xcframework"
@@ -14,12 +14,204 @@
 #endif
 
 
+/**
+ * What the next boot will run, see updater::BootDecision.
+ */
+typedef enum ShorebirdBootDecisionReason {
+  /**
+   * The next boot uses a patch.
+   */
+  ShorebirdBootDecisionReason_PatchSelected = 0,
+  /**
+   * No patch is installed for this release.
+   */
+  ShorebirdBootDecisionReason_NoPatchInstalled = 1,
+  /**
+   * The patch failed to launch or was rolled back.
+   */
+  ShorebirdBootDecisionReason_MarkedBad = 2,
+  /**
+   * The patch's artifact is no longer on disk.
+   */
+  ShorebirdBootDecisionReason_ArtifactMissing = 3,
+  /**
+   * The patch's artifact could not be read.
+   */
+  ShorebirdBootDecisionReason_ArtifactUnreadable = 4,
+  /**
+   * The patch's artifact no longer matches its hash.
+   */
+  ShorebirdBootDecisionReason_HashMismatch = 5,
+  /**
+   * The patch is not in shorebird.yaml's patch_allowlist.
+   */
+  ShorebirdBootDecisionReason_NotAllowlisted = 6,
+  /**
+   * The app was updated to a new release.
+   */
+  ShorebirdBootDecisionReason_ReleaseVersionChanged = 7,
+  /**
+   * The patch's artifact changed between selection and boot.
+   */
+  ShorebirdBootDecisionReason_ChangedBeforeBoot = 8,
+  /**
+   * The app was replaced by an older release, the patch is kept for if
+   * the newer release returns.
+   */
+  ShorebirdBootDecisionReason_ReleaseVersionDowngraded = 9,
+} ShorebirdBootDecisionReason;
+
+/**
+ * Why an update was deferred, see updater::DeferReason.
+ */
+typedef enum ShorebirdDeferReason {
+  /**
+   * The update was not deferred.
+   */
+  ShorebirdDeferReason_None = 0,
+  ShorebirdDeferReason_AutoUpdateDisabled = 1,
+  ShorebirdDeferReason_CheckThrottled = 2,
+  ShorebirdDeferReason_PatchesDisabled = 3,
+  ShorebirdDeferReason_IncompatibleBuildNumber = 4,
+  ShorebirdDeferReason_IncompatibleNativeFingerprint = 5,
+  ShorebirdDeferReason_Offline = 6,
+  ShorebirdDeferReason_ServerBackoff = 7,
+  ShorebirdDeferReason_AutoUpdateDisabledByApp = 8,
+  ShorebirdDeferReason_AutoUpdateDisabledByConfig = 9,
+  ShorebirdDeferReason_MeteredNetwork = 10,
+  ShorebirdDeferReason_AppInBackground = 11,
+  ShorebirdDeferReason_UpdaterUpgradeRequired = 12,
+  ShorebirdDeferReason_UpdateInDaemon = 13,
+  /**
+   * The app's patch check response callback vetoed the offered patch,
+   * see shorebird_set_patch_check_response_callback.
+   */
+  ShorebirdDeferReason_VetoedByApp = 14,
+  /**
+   * An app store update is waiting to install, see
+   * shorebird_set_store_update_pending_callback.
+   */
+  ShorebirdDeferReason_StoreUpdatePending = 15,
+  /**
+   * shorebird_init has not been called (or failed), so the updater can't
+   * check for updates.
+   */
+  ShorebirdDeferReason_NotInitialized = 16,
+} ShorebirdDeferReason;
+
+/**
+ * Stable error codes for callers which need to handle failures
+ * programmatically rather than by reading logs.  Values must never be
+ * reused; add new codes at the end.
+ */
+typedef enum ShorebirdErrorCode {
+  /**
+   * No error.
+   */
+  ShorebirdErrorCode_None = 0,
+  /**
+   * An error not covered by a more specific code.
+   */
+  ShorebirdErrorCode_Unknown = 1,
+  ShorebirdErrorCode_InvalidArgument = 2,
+  ShorebirdErrorCode_InvalidState = 3,
+  ShorebirdErrorCode_BadServerResponse = 4,
+  ShorebirdErrorCode_FailedToSaveState = 5,
+  ShorebirdErrorCode_ConfigNotInitialized = 6,
+  ShorebirdErrorCode_UpdateAlreadyInProgress = 7,
+  ShorebirdErrorCode_BadBaseArtifact = 8,
+  ShorebirdErrorCode_HashMismatch = 9,
+  ShorebirdErrorCode_InvalidSignature = 10,
+  /**
+   * The server could not be reached or the connection failed.
+   */
+  ShorebirdErrorCode_Network = 11,
+  /**
+   * Reading or writing a file failed.
+   */
+  ShorebirdErrorCode_Io = 12,
+  /**
+   * The updater is in offline mode, see shorebird_set_offline.
+   */
+  ShorebirdErrorCode_Offline = 13,
+  /**
+   * The server asked the updater to back off (e.g. HTTP 429).
+   */
+  ShorebirdErrorCode_ServerBusy = 14,
+  /**
+   * The updater was built without network support.
+   */
+  ShorebirdErrorCode_NetworkUnsupported = 15,
+  /**
+   * A download was refused (e.g. its signed URL expired) and a fresh URL
+   * could not be obtained.
+   */
+  ShorebirdErrorCode_DownloadUrlExpired = 16,
+  /**
+   * The patch is not in the patch_allowlist bundled with the app.
+   */
+  ShorebirdErrorCode_PatchNotAllowed = 17,
+  /**
+   * A download stopped because the app went into the background, see
+   * shorebird_on_app_background.
+   */
+  ShorebirdErrorCode_DownloadPaused = 18,
+  /**
+   * The patch is encrypted and the app provided no key for it, see
+   * shorebird_set_patch_key_callback.
+   */
+  ShorebirdErrorCode_PatchKeyUnavailable = 19,
+  /**
+   * The patch did not apply to this device's copy of the release, e.g.
+   * because it was built against a different build.
+   */
+  ShorebirdErrorCode_PatchDidNotApply = 20,
+} ShorebirdErrorCode;
+
+/**
+ * Whether an update is running or queued, see updater::UpdateQueueState.
+ */
+typedef enum ShorebirdUpdateQueueState {
+  /**
+   * No update is running.
+   */
+  ShorebirdUpdateQueueState_Idle = 0,
+  /**
+   * An update is running (shorebird_update or the update thread).
+   */
+  ShorebirdUpdateQueueState_Running = 1,
+  /**
+   * The update thread is running and will run once more when it is done,
+   * see queue_follow_up_update in shorebird.yaml.
+   */
+  ShorebirdUpdateQueueState_FollowUpQueued = 2,
+} ShorebirdUpdateQueueState;
+
+/**
+ * Outcome of shorebird_update_with_result, see updater::UpdateStatus.
+ */
+typedef enum ShorebirdUpdateStatus {
+  ShorebirdUpdateStatus_NoUpdate = 0,
+  ShorebirdUpdateStatus_UpdateAvailable = 1,
+  ShorebirdUpdateStatus_UpdateInstalled = 2,
+  ShorebirdUpdateStatus_UpdateDownloaded = 3,
+  ShorebirdUpdateStatus_UpdateDeferred = 4,
+  ShorebirdUpdateStatus_UpdateHadError = 5,
+} ShorebirdUpdateStatus;
+
 /**
  * Struct containing configuration parameters for the updater.
  * Passed to all updater functions.
  * NOTE: If this struct is changed all language bindings must be updated.
  */
 typedef struct AppParameters {
+  /**
+   * sizeof(AppParameters) as compiled by the caller, required.  New fields
+   * are only ever appended, and fields which don't fit in `size` are
+   * treated as unset, so callers built against an older updater.h keep
+   * working.
+   */
+  uintptr_t size;
   /**
    * release_version, required.  Named version of the app, off of which
    * updates are based.  Can be either a version number or a hash.
@@ -36,11 +228,152 @@ typedef struct AppParameters {
    */
   int original_libapp_paths_size;
   /**
-   * Path to cache_dir where the updater will store downloaded artifacts.
+   * Path to cache_dir where the updater will store downloaded artifacts,
+   * required.
    */
   const char *cache_dir;
+  /**
+   * Build number of the running app (e.g. versionCode on Android), optional.
+   * 0 if unknown.  Used to refuse patches targeted at other builds.
+   */
+  uint64_t build_number;
+  /**
+   * app_id to use instead of the one in shorebird.yaml, optional (NULL).
+   * Only honored when shorebird.yaml sets `allow_app_id_override: true`.
+   */
+  const char *app_id_override;
+  /**
+   * Directory to download patches to, optional (NULL).  Defaults to a
+   * directory under cache_dir, which is also used if this one turns out
+   * not to be writable.
+   */
+  const char *download_dir;
+  /**
+   * The device's locale (e.g. "en_US"), optional (NULL).  Only sent to
+   * the server, as language and country, when shorebird.yaml sets
+   * `report_locale: true`.
+   */
+  const char *locale;
+  /**
+   * Whether the app is a debug build, optional (false).  The updater
+   * itself is built in release mode for every flavor, so it can't tell.
+   * Only debug builds honor shorebird.yaml's `pinned_patch_number`.
+   */
+  bool debug_build;
 } AppParameters;
 
+/**
+ * Callbacks for reading files the host can't give the updater a path to,
+ * see shorebird_init_with_file_callbacks.  Files are read from start to end
+ * and every file opened is closed.  `context` is passed back to open.
+ * NOTE: If this struct is changed all language bindings must be updated.
+ */
+typedef struct FileCallbacks {
+  /**
+   * sizeof(FileCallbacks) as compiled by the caller, required.  As with
+   * AppParameters, new fields are only ever appended.
+   */
+  uintptr_t size;
+  void *context;
+  /**
+   * Opens the file called `name` (e.g. "shorebird.yaml"), returning a
+   * handle for read and close, or NULL if it can't be opened.
+   */
+  void *(*open)(void *context, const char *name);
+  /**
+   * Reads up to `count` bytes into `buffer`, returning how many were read
+   * (0 at the end of the file), or -1 on error.
+   */
+  intptr_t (*read)(void *handle, uint8_t *buffer, uintptr_t count);
+  /**
+   * Closes a handle returned by open.
+   */
+  void (*close)(void *handle);
+} FileCallbacks;
+
+/**
+ * Called before each signed request to the update server to get the HMAC
+ * key.  Writes the key to `buffer` (which holds `buffer_len` bytes) and
+ * returns its length, or returns 0 if there is no key.
+ */
+typedef uintptr_t (*ShorebirdSigningKeyCallback)(void *context,
+                                                 uint8_t *buffer,
+                                                 uintptr_t buffer_len);
+
+/**
+ * Called with a patch's encryption key id to get the key which decrypts it.
+ * Writes the 32 byte AES-256 key to `buffer` (which holds `buffer_len`
+ * bytes) and returns its length, or returns 0 if the app has no such key.
+ */
+typedef uintptr_t (*ShorebirdPatchKeyCallback)(void *context,
+                                               const char *key_id,
+                                               uint8_t *buffer,
+                                               uintptr_t buffer_len);
+
+/**
+ * Called with the JSON of a patch check response offering a patch (valid
+ * only during the call), before the update downloads it.  Return false to
+ * veto installing it.
+ */
+typedef bool (*ShorebirdPatchCheckResponseCallback)(void *context,
+                                                    const char *response_json);
+
+/**
+ * Returns whether an app store update (e.g. a Play in-app update) is
+ * downloaded or downloading and waiting to install.
+ */
+typedef bool (*ShorebirdStoreUpdatePendingCallback)(void*);
+
+/**
+ * Callbacks for shorebird_set_delegate.  Any callback may be NULL.  They may
+ * be called on any thread (e.g. the update thread), with `context` passed
+ * back as the first argument.
+ * NOTE: If this struct is changed all language bindings must be updated.
+ */
+typedef struct ShorebirdDelegate {
+  void *context;
+  /**
+   * Called with the patch number after a patch is installed.
+   */
+  void (*on_patch_installed)(void*, uintptr_t);
+  /**
+   * Called with the patch number after a patch is marked bad.
+   */
+  void (*on_patch_invalidated)(void*, uintptr_t);
+  /**
+   * Called when an update fails.
+   */
+  void (*on_update_error)(void*, enum ShorebirdErrorCode);
+  /**
+   * Called with the patch number after a critical patch is installed, the
+   * app should prompt the user to restart.
+   */
+  void (*on_restart_requested)(void*, uintptr_t);
+} ShorebirdDelegate;
+
+/**
+ * Returned by shorebird_update_with_result.  `defer_reason` is only set
+ * when `status` is UpdateDeferred, and `error` only when it is
+ * UpdateHadError.
+ */
+typedef struct UpdateResult {
+  enum ShorebirdUpdateStatus status;
+  enum ShorebirdDeferReason defer_reason;
+  enum ShorebirdErrorCode error;
+} UpdateResult;
+
+/**
+ * Returned by shorebird_last_update_result.
+ */
+typedef struct LastUpdateResult {
+  /**
+   * When the most recent update finished (seconds since epoch), or 0 if
+   * there has been no update for this release.
+   */
+  uint64_t timestamp;
+  struct UpdateResult result;
+} LastUpdateResult;
+
 #ifdef __cplusplus
 extern "C" {
 #endif // __cplusplus
@@ -50,11 +383,30 @@ extern "C" {
  * from the running app.  Second parameter is a YAML string containing
  * configuration compiled into the app.  Returns true on success and false on
  * failure. If false is returned, the updater library will not be usable.
+ * Passing NULL for either parameter (or a required AppParameters field)
+ * returns false rather than crashing.
  */
 SHOREBIRD_EXPORT
 bool shorebird_init(const struct AppParameters *c_params,
                     const char *c_yaml);
 
+/**
+ * Like shorebird_init, but returns why initialization failed.  Returns
+ * ShorebirdErrorCode_None on success.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdErrorCode shorebird_init_with_result(const struct AppParameters *c_params,
+                                                   const char *c_yaml);
+
+/**
+ * Like shorebird_init_with_result, but reads shorebird.yaml through
+ * `file_callbacks` (by the name "shorebird.yaml") instead of taking it as a
+ * string.  The callbacks are only used during this call.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdErrorCode shorebird_init_with_file_callbacks(const struct AppParameters *c_params,
+                                                           const struct FileCallbacks *file_callbacks);
+
 /**
  * The currently running patch number, or 0 if the release has not been
  * patched.
@@ -67,32 +419,309 @@ SHOREBIRD_EXPORT uintptr_t shorebird_current_boot_patch_number(void);
  */
 SHOREBIRD_EXPORT uintptr_t shorebird_next_boot_patch_number(void);
 
+/**
+ * Start validating the next boot patch (that it exists and matches its hash)
+ * on a background thread, so the next call to
+ * shorebird_next_boot_patch_number or shorebird_next_boot_patch_path does
+ * not have to.  Call as early as possible during engine startup.
+ */
+SHOREBIRD_EXPORT void shorebird_prevalidate_next_boot_patch(void);
+
 /**
  * The path to the patch that will boot on the next run of the app, or NULL if
  * there is no next patch.
  */
 SHOREBIRD_EXPORT char *shorebird_next_boot_patch_path(void);
 
+#if (defined(__ANDROID__) || defined(__linux__))
+/**
+ * A file descriptor for a sealed, read-only in-memory copy (memfd) of the
+ * patch that will boot on the next run of the app, so the engine can map it
+ * without re-opening the file on disk.  -1 if there is no next patch, it is
+ * larger than memfd_max_bytes in shorebird.yaml (or that isn't set), or
+ * memfd is unsupported, in which case use shorebird_next_boot_patch_path.
+ * The caller owns the descriptor and must close it.  Android and Linux only.
+ */
+SHOREBIRD_EXPORT int shorebird_next_boot_patch_memfd(void);
+#endif
+
+/**
+ * The metadata the server sent with the patch that will boot on the next run
+ * of the app (e.g. git sha, Dart version), as JSON.  NULL if there is no next
+ * patch or it has no metadata.  The caller must free the result with
+ * shorebird_free_string.
+ */
+SHOREBIRD_EXPORT char *shorebird_next_boot_patch_metadata(void);
+
+#if defined(__ANDROID__)
+/**
+ * An open, read-only file descriptor for the patch that will boot on the next
+ * run of the app, or -1 if there is no next patch.  The caller owns the
+ * descriptor and must close it.  Android only.
+ */
+SHOREBIRD_EXPORT int shorebird_next_boot_patch_fd(void);
+#endif
+
+/**
+ * This device's client_id, which canary patches can be restricted to.
+ * The caller must free the result with shorebird_free_string.  NULL on error.
+ */
+SHOREBIRD_EXPORT char *shorebird_client_id(void);
+
 /**
- * Free a string returned by the updater library.
+ * The patches currently on disk as a JSON array of objects with number, path,
+ * size, hash, metadata, is_current_boot, is_next_boot and is_known_good.
+ * The caller must free the result with shorebird_free_string.  NULL on error.
+ */
+SHOREBIRD_EXPORT char *shorebird_installed_patches_json(void);
+
+/**
+ * Checks the updater's storage and state, as a JSON object with `healthy`,
+ * `checks`, an array of objects with name, ok and detail, and
+ * `download_progress`, null or an object with bytes_downloaded and
+ * total_bytes (null if unknown) for an interrupted download.  Slow, it
+ * re-hashes the booted patch, so only call it from e.g. a debug screen.
+ * The caller must free the result with shorebird_free_string.  NULL on error
+ * (e.g. before shorebird_init).
+ */
+SHOREBIRD_EXPORT char *shorebird_run_health_check(void);
+
+/**
+ * The latest patch install as a JSON object with patch_number, slot_index,
+ * replacing_patch_number, started_at, steps (the steps which finished, e.g.
+ * "artifact_stored"), completed and error, or "null" if no patch has been
+ * installed.  The caller must free the result with shorebird_free_string.
+ * NULL on error.
+ */
+SHOREBIRD_EXPORT char *shorebird_last_install_transaction_json(void);
+
+/**
+ * Non-fatal problems found in shorebird.yaml by shorebird_init (e.g. unknown
+ * keys), one per line, or NULL if there are none.  The caller must free the
+ * result with shorebird_free_string.
+ */
+SHOREBIRD_EXPORT char *shorebird_get_config_warnings(void);
+
+/**
+ * Free a string returned by the updater library.  Passing NULL is a no-op.
  */
 SHOREBIRD_EXPORT void shorebird_free_string(char *c_string);
 
+#if defined(SHOREBIRD_ALLOCATION_TRACKING)
+/**
+ * The number of strings returned by the updater which have not yet been
+ * passed to shorebird_free_string.  Only available when built with the
+ * "allocation_tracking" feature, for detecting leaks in integration tests.
+ */
+SHOREBIRD_EXPORT uintptr_t shorebird_testing_outstanding_allocations(void);
+#endif
+
+/**
+ * For QA: make updates fetch and boot patch `patch_number` instead of the
+ * latest, even if newer patches exist.  If it is already installed it
+ * becomes the next boot patch now.  Pass 0 to remove the pin.  Kept across
+ * launches.  Works in release builds, so only expose it to testers.
+ * Returns false on error (e.g. before shorebird_init).
+ */
+SHOREBIRD_EXPORT bool shorebird_pin_patch(uintptr_t patch_number);
+
+/**
+ * What the next boot will run and, if not a patch, why not (e.g. the patch
+ * was corrupted on disk), for debugging a patch which isn't applying.
+ * `out_patch_number` (which may be NULL) is set to the patch selected or
+ * refused, or 0 if there is none.  Returns NoPatchInstalled on error.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdBootDecisionReason shorebird_boot_decision_reason(uintptr_t *out_patch_number);
+
 /**
  * Check for an update.  Returns true if an update is available.
  */
 SHOREBIRD_EXPORT bool shorebird_check_for_update(void);
 
+/**
+ * Like shorebird_check_for_update, but returns why the check failed.
+ * On success, `out_update_available` (which may be NULL) is set to whether
+ * an update is available.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdErrorCode shorebird_check_for_update_with_result(bool *out_update_available);
+
+/**
+ * Turn offline mode on or off, e.g. to honor a user's data saver setting.
+ * While offline the updater makes no network requests: updates report
+ * ShorebirdDeferReason_Offline and checks fail with
+ * ShorebirdErrorCode_Offline.  May be called at any time, including before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_set_offline(bool offline);
+
+/**
+ * Turn automatic updates (shorebird_start_update_thread) on or off,
+ * overriding shorebird.yaml's auto_update.  Offline mode and the server's
+ * directives still take precedence.  May be called at any time, including
+ * before shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_set_auto_update(bool enabled);
+
+/**
+ * Undo shorebird_set_auto_update, so shorebird.yaml's auto_update (and the
+ * network type) decide again.  May be called at any time, including before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_clear_auto_update(void);
+
+/**
+ * Tell the updater whether the device is on a metered network, in which
+ * case automatic updates are skipped unless turned on with
+ * shorebird_set_auto_update.  May be called at any time, including before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_set_metered_network(bool metered);
+
+/**
+ * Tell the updater the app came to the foreground (e.g. from
+ * onResume/applicationDidBecomeActive).  If it was in the background, an
+ * automatic update check starts on a background thread, as long as
+ * shorebird_auto_update_defer_reason allows, which also resumes any
+ * download paused by shorebird_on_app_background.  Does nothing before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_on_app_foreground(void);
+
+/**
+ * Tell the updater the app went into the background (e.g. from
+ * onPause/applicationDidEnterBackground).  Downloads pause, keeping their
+ * progress, unless shorebird.yaml sets background_downloads.  May be called
+ * at any time, including before shorebird_init.
+ */
+SHOREBIRD_EXPORT void shorebird_on_app_background(void);
+
+/**
+ * Set a header to send with every request the updater makes to base_url,
+ * overriding extra_headers in shorebird.yaml, so secrets (e.g. a gateway
+ * token) need not be built into the app.  Pass NULL for `value` to remove
+ * the header.  Returns false if the name or value is not a valid header.
+ * May be called at any time, including before shorebird_init.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_set_extra_header(const char *name,
+                                const char *value);
+
+/**
+ * Provide the key used to sign requests to the update server (see
+ * request_signing in shorebird.yaml), taking precedence over any key in
+ * shorebird.yaml so it can be rotated.  `context` is passed back to the
+ * callback and must stay valid until the callback is replaced.  Pass NULL
+ * to remove the callback.  May be called at any time, including before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT
+void shorebird_set_request_signing_key_callback(ShorebirdSigningKeyCallback callback,
+                                                void *context);
+
+/**
+ * Provide the keys for patches the server encrypts in transit.  Without a
+ * key, encrypted patches fail to install with
+ * ShorebirdErrorCode_PatchKeyUnavailable.  `context` is passed back to the
+ * callback and must stay valid until the callback is replaced.  Pass NULL
+ * to remove the callback.  May be called at any time, including before
+ * shorebird_init.
+ */
+SHOREBIRD_EXPORT
+void shorebird_set_patch_key_callback(ShorebirdPatchKeyCallback callback,
+                                      void *context);
+
+/**
+ * Why shorebird_start_update_thread would not check for an update right
+ * now, or ShorebirdDeferReason_None if it would.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdDeferReason shorebird_auto_update_defer_reason(void);
+
+/**
+ * Whether shorebird_start_update_thread would check for an update right
+ * now, see shorebird_auto_update_defer_reason for why not.
+ */
+SHOREBIRD_EXPORT bool shorebird_should_auto_update(void);
+
+/**
+ * Lets the app apply its own policy (e.g. no updates during checkout) to
+ * each patch offered, see updater::set_patch_check_response_callback.  A
+ * vetoed update reports ShorebirdDeferReason_VetoedByApp.  The callback
+ * must not itself update.  `context` is passed back to the callback and
+ * must stay valid until the callback is replaced.  Pass NULL to remove the
+ * callback.  Does not require shorebird_init.
+ */
+SHOREBIRD_EXPORT
+void shorebird_set_patch_check_response_callback(ShorebirdPatchCheckResponseCallback callback,
+                                                 void *context);
+
+/**
+ * Registers a callback asked before downloading an offered patch, see
+ * updater::set_store_update_pending_callback.  While it returns true updates
+ * report ShorebirdDeferReason_StoreUpdatePending.  `context` is passed back
+ * to the callback and must stay valid until the callback is replaced.  Pass
+ * NULL to remove the callback.  Does not require shorebird_init.
+ */
+SHOREBIRD_EXPORT
+void shorebird_set_store_update_pending_callback(ShorebirdStoreUpdatePendingCallback callback,
+                                                 void *context);
+
+/**
+ * Registers callbacks so the app doesn't need to poll after background
+ * updates, replacing any previously registered.  The struct is copied.
+ * Passing NULL removes the callbacks.  Does not require shorebird_init.
+ */
+SHOREBIRD_EXPORT
+void shorebird_set_delegate(const struct ShorebirdDelegate *delegate);
+
 /**
  * Synchronously download an update if one is available.
  */
 SHOREBIRD_EXPORT void shorebird_update(void);
 
 /**
- * Start a thread to download an update if one is available.
+ * Synchronously download an update if one is available, and report what
+ * happened (including why, if the updater declined to install it).
+ */
+SHOREBIRD_EXPORT struct UpdateResult shorebird_update_with_result(void);
+
+/**
+ * The outcome of the most recent shorebird_update (or
+ * shorebird_update_with_result or update thread run), without checking for
+ * a new update.  Lets apps show e.g. "last checked 2h ago, up to date".
+ */
+SHOREBIRD_EXPORT struct LastUpdateResult shorebird_last_update_result(void);
+
+/**
+ * Synchronously download and verify an update if one is available, without
+ * installing it.  Returns true if an update is ready for
+ * shorebird_install_downloaded_update.  Critical patches are installed
+ * straight away instead, so this returns false for them.
+ */
+SHOREBIRD_EXPORT bool shorebird_download_update(void);
+
+/**
+ * Install the update downloaded by shorebird_download_update so it is used
+ * on the next run of the app.  Returns true if an update was installed.
+ */
+SHOREBIRD_EXPORT bool shorebird_install_downloaded_update(void);
+
+/**
+ * Start a thread to download an update if one is available.  Returns
+ * immediately without starting another thread if one is already running,
+ * see shorebird_update_queue_state.
  */
 SHOREBIRD_EXPORT void shorebird_start_update_thread(void);
 
+/**
+ * Whether an update is running or queued, e.g. so an app can show that it
+ * is checking.  Does not require shorebird_init.
+ */
+SHOREBIRD_EXPORT
+enum ShorebirdUpdateQueueState shorebird_update_queue_state(void);
+
 /**
  * Tell the updater that we're launching from what it told us was the
  * next patch to boot from. This will copy the next_boot patch to be the
@@ -100,9 +729,20 @@ SHOREBIRD_EXPORT void shorebird_start_update_thread(void);
  *
  * It is required to call this function before calling
  * shorebird_report_launch_success or shorebird_report_launch_failure.
+ * Calling it again for the same patch from the same process (e.g. on hot
+ * restart) does nothing.
  */
 SHOREBIRD_EXPORT void shorebird_report_launch_start(void);
 
+/**
+ * Single-call alternative to shorebird_report_launch_start which also records
+ * that a boot of `patch_number` is pending.  If the app crashes (e.g. abort())
+ * before shorebird_report_launch_success or shorebird_report_launch_failure
+ * is called, the next shorebird_init treats the boot as a failure.
+ * `patch_number` must be shorebird_next_boot_patch_number().
+ */
+SHOREBIRD_EXPORT void shorebird_begin_boot(uintptr_t patch_number);
+
 /**
  * Report that the app failed to launch.  This will cause the updater to
  * attempt to roll back to the previous version if this version has not
@@ -122,6 +762,15 @@ SHOREBIRD_EXPORT void shorebird_report_launch_failure(void);
  */
 SHOREBIRD_EXPORT void shorebird_report_launch_success(void);
 
+/**
+ * Uninstall the most recently installed patch and make the previous good
+ * patch (if any) the one used on next boot.  The uninstalled patch is
+ * marked as bad and will not be downloaded again.  Intended to be triggered
+ * manually (e.g. from a debug menu) to undo a problematic patch.
+ * Returns true if a patch was rolled back.
+ */
+SHOREBIRD_EXPORT bool shorebird_rollback_last_patch(void);
+
 #ifdef __cplusplus
 } // extern "C"
 #endif // __cplusplus
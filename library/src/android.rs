@@ -1,12 +1,13 @@
 use anyhow::Context;
 use std::fs;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 // https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
 #[cfg(test)]
 use std::println as debug; // Workaround to use println! for logs.
 
+use crate::verify::Hash;
 use crate::UpdateError;
 
 /// This function is a hack for Android.  Android passes an array of paths, the
@@ -89,6 +90,8 @@ pub(crate) fn get_relative_lib_path(lib_name: &str) -> PathBuf {
 // And I'm not the right Rust to keep a reference to both with proper lifetimes.
 #[derive(Debug)]
 struct ZipLocation {
+    /// The APK containing the library.
+    path: PathBuf,
     archive: zip::ZipArchive<fs::File>,
     internal_path: String,
 }
@@ -98,6 +101,7 @@ fn check_for_lib_path(zip_path: &Path, lib_path: &str) -> anyhow::Result<ZipLoca
     let apk = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
     if apk.file_names().any(|name| name == lib_path) {
         return Ok(ZipLocation {
+            path: zip_path.to_owned(),
             archive: apk,
             internal_path: lib_path.to_owned(),
         });
@@ -154,8 +158,14 @@ fn find_and_open_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<ZipLocat
 
 /// Given a directory of APKs, find the one that contains the library we want.
 /// This has to be done due to split APKs.
+/// If `expected_cert` is set, the APK containing the library must be signed
+/// with a certificate whose sha256 matches it.
 /// This is public so c_api can use this for testing.
-pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<Cursor<Vec<u8>>> {
+pub(crate) fn open_base_lib(
+    apks_dir: &Path,
+    lib_name: &str,
+    expected_cert: Option<&Hash>,
+) -> anyhow::Result<Cursor<Vec<u8>>> {
     // As far as I can tell, Android provides no apis for reading per-platform
     // assets (e.g. libapp.so) from an APK.  Both Facebook and Chromium
     // seem to have written their own code to do this:
@@ -169,6 +179,17 @@ pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<C
     // portable, but we have a zip library here, and don't on the C++ side.
 
     let mut zip_location = find_and_open_lib(apks_dir, lib_name)?;
+    if let Some(expected_cert) = expected_cert {
+        let certs = apk_signing_cert_digests(&zip_location.path)
+            .with_context(|| format!("Failed to read signature of {:?}", zip_location.path))?;
+        if !certs.contains(expected_cert) {
+            anyhow::bail!(UpdateError::BadBaseArtifact(format!(
+                "{:?} is not signed with expected_apk_cert_sha256, refusing to patch it",
+                zip_location.path
+            )));
+        }
+        debug!("Verified signing certificate of {:?}", zip_location.path);
+    }
     let mut zip_file = zip_location
         .archive
         .by_name(&zip_location.internal_path)
@@ -183,6 +204,113 @@ pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<C
     Ok(Cursor::new(buffer))
 }
 
+/// IDs of the APK Signature Scheme v2, v3 and v3.1 blocks within the APK
+/// Signing Block.
+/// https://source.android.com/docs/security/features/apksigning/v2
+/// cbindgen:ignore
+const APK_SIGNATURE_SCHEME_IDS: [u32; 3] = [0x7109_871a, 0xf053_68c0, 0x1b93_ad61];
+
+/// cbindgen:ignore
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+
+/// The APK Signing Block is small, anything much bigger is not one.
+/// cbindgen:ignore
+const MAX_APK_SIG_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Splits a u32 length-prefixed field off the front of `data`, which is how
+/// every sequence and value in the signature scheme blocks is encoded.
+fn take_length_prefixed<'a>(data: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+    anyhow::ensure!(data.len() >= 4, "Truncated APK signature field");
+    let length = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    anyhow::ensure!(data.len() - 4 >= length, "Truncated APK signature field");
+    let (value, rest) = data[4..].split_at(length);
+    *data = rest;
+    Ok(value)
+}
+
+/// The offset of the zip central directory, read from the End of Central
+/// Directory record at the end of the file.
+fn central_directory_offset(file: &mut fs::File) -> anyhow::Result<u64> {
+    const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+    const EOCD_SIZE: usize = 22;
+    let file_size = file.metadata()?.len();
+    // The record is followed by a comment of up to 65535 bytes.
+    let tail_size = std::cmp::min(file_size, (EOCD_SIZE + 0xffff) as u64);
+    file.seek(SeekFrom::Start(file_size - tail_size))?;
+    let mut tail = vec![0u8; tail_size as usize];
+    file.read_exact(&mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(EOCD_SIZE - 1))
+        .rev()
+        .find(|&i| {
+            let comment_length = u16::from_le_bytes([tail[i + 20], tail[i + 21]]) as usize;
+            tail[i..i + 4] == EOCD_SIGNATURE && i + EOCD_SIZE + comment_length == tail.len()
+        })
+        .context("Not a zip file")?;
+    Ok(u32::from_le_bytes(tail[eocd + 16..eocd + 20].try_into().unwrap()) as u64)
+}
+
+/// The sha256 digests of the signing certificates in the APK Signing Block of
+/// the APK at `path` (v2 and later signature schemes).  The signatures
+/// themselves are not checked here, Android verifies those at install time,
+/// so a modified APK must have been re-signed with a different certificate.
+fn apk_signing_cert_digests(path: &Path) -> anyhow::Result<Vec<Hash>> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = fs::File::open(path)?;
+    let central_directory = central_directory_offset(&mut file)?;
+    // The block ends with its size and a magic number, right before the
+    // central directory.
+    anyhow::ensure!(central_directory >= 32, "APK has no signing block");
+    let mut footer = [0u8; 24];
+    file.seek(SeekFrom::Start(central_directory - 24))?;
+    file.read_exact(&mut footer)?;
+    anyhow::ensure!(
+        &footer[8..] == APK_SIG_BLOCK_MAGIC,
+        "APK is not signed with APK Signature Scheme v2 or later"
+    );
+    // The size excludes the leading copy of the size itself.
+    let block_size = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    anyhow::ensure!(
+        (24..=MAX_APK_SIG_BLOCK_SIZE).contains(&block_size) && block_size + 8 <= central_directory,
+        "Invalid APK signing block size: {}",
+        block_size
+    );
+    let mut pairs = vec![0u8; (block_size - 24) as usize];
+    file.seek(SeekFrom::Start(central_directory - block_size))?;
+    file.read_exact(&mut pairs)?;
+
+    let mut digests = Vec::new();
+    let mut pairs = pairs.as_slice();
+    while !pairs.is_empty() {
+        anyhow::ensure!(pairs.len() >= 12, "Truncated APK signing block");
+        let length = u64::from_le_bytes(pairs[..8].try_into().unwrap());
+        anyhow::ensure!(
+            (4..=pairs.len() as u64 - 8).contains(&length),
+            "Truncated APK signing block"
+        );
+        let id = u32::from_le_bytes(pairs[8..12].try_into().unwrap());
+        let (mut value, rest) = pairs[12..].split_at(length as usize - 4);
+        pairs = rest;
+        if !APK_SIGNATURE_SCHEME_IDS.contains(&id) {
+            continue;
+        }
+        let mut signers = take_length_prefixed(&mut value)?;
+        while !signers.is_empty() {
+            let mut signer = take_length_prefixed(&mut signers)?;
+            let mut signed_data = take_length_prefixed(&mut signer)?;
+            let _digests = take_length_prefixed(&mut signed_data)?;
+            let mut certificates = take_length_prefixed(&mut signed_data)?;
+            // The first certificate is the signer's, any others are its chain.
+            if !certificates.is_empty() {
+                let certificate = take_length_prefixed(&mut certificates)?;
+                digests.push(Sha256::digest(certificate).into());
+            }
+        }
+    }
+    anyhow::ensure!(!digests.is_empty(), "APK signing block has no signers");
+    Ok(digests)
+}
+
 pub fn libapp_path_from_settings(
     original_libapp_paths: &Vec<String>,
 ) -> Result<PathBuf, UpdateError> {
@@ -210,11 +338,102 @@ pub fn libapp_path_from_settings(
     app_data_dir_from_libapp_path(full_libapp_path)
 }
 
+/// The engine maps installed patches executable, which strict SELinux policies
+/// only allow for files with the expected mode and label.  Resets the mode and
+/// copies the SELinux label from the containing directory (roughly what
+/// restorecon does for app data).  Apps are often not permitted to relabel
+/// files, so failing to do so is only logged.
+pub fn fix_installed_file_attributes(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644)).with_context(|| {
+        format!(
+            "Failed to set permissions on {:?}, is the cache dir owned by the app?",
+            path
+        )
+    })?;
+    if let Some(parent) = path.parent() {
+        if let Err(err) = copy_selinux_context(parent, path) {
+            debug!("Not relabelling {:?}: {}", path, err);
+        }
+    }
+    Ok(())
+}
+
+/// Copies the SELinux label of `from` onto `to` if they differ.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn copy_selinux_context(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    // Not a c"" literal, cbindgen can't parse those.
+    #[allow(clippy::manual_c_str_literals)]
+    const SELINUX_XATTR: &CStr = match CStr::from_bytes_with_nul(b"security.selinux\0") {
+        Ok(name) => name,
+        Err(_) => panic!("invalid xattr name"),
+    };
+
+    fn get_context(path: &CString) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; 256];
+        let length = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                SELINUX_XATTR.as_ptr(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if length < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        buffer.truncate(length as usize);
+        Ok(buffer)
+    }
+
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    let expected = get_context(&from)?;
+    if get_context(&to).ok().as_ref() == Some(&expected) {
+        return Ok(());
+    }
+    let result = unsafe {
+        libc::lsetxattr(
+            to.as_ptr(),
+            SELINUX_XATTR.as_ptr(),
+            expected.as_ptr() as *const libc::c_void,
+            expected.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn copy_selinux_context(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
 // These are mostly stub tests to prevent warnings about unused fields.
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use tempdir::TempDir;
 
+    #[test]
+    fn fix_installed_file_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("dlc.vmcode");
+        std::fs::write(&path, "patch").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o200)).unwrap();
+        // Relabelling may not be supported here, but must not fail the call.
+        super::fix_installed_file_attributes(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
     #[test]
     fn find_and_open_lib_test() {
         let tmp_dir = TempDir::new("example").unwrap();
@@ -232,10 +451,85 @@ mod tests {
         );
     }
 
+    /// Writes base.apk containing libapp.so, signed (in form only) with
+    /// `cert` if given.
+    pub(crate) fn write_apk(dir: &std::path::Path, cert: Option<&[u8]>) {
+        use std::io::Write;
+
+        fn length_prefixed(data: &[u8]) -> Vec<u8> {
+            let mut result = (data.len() as u32).to_le_bytes().to_vec();
+            result.extend_from_slice(data);
+            result
+        }
+
+        let path = dir.join("base.apk");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let lib_path = super::get_relative_lib_path("libapp.so");
+        zip.start_file(lib_path.to_str().unwrap(), Default::default())
+            .unwrap();
+        zip.write_all(b"hello world").unwrap();
+        zip.finish().unwrap();
+        let cert = match cert {
+            Some(cert) => cert,
+            None => return,
+        };
+
+        let mut signed_data = length_prefixed(&[]);
+        signed_data.extend(length_prefixed(&length_prefixed(cert)));
+        let mut signer = length_prefixed(&signed_data);
+        signer.extend(length_prefixed(&[])); // signatures
+        signer.extend(length_prefixed(&[])); // public key
+        let value = length_prefixed(&length_prefixed(&signer));
+        let mut pair = ((value.len() + 4) as u64).to_le_bytes().to_vec();
+        pair.extend(0x7109_871a_u32.to_le_bytes());
+        pair.extend(value);
+        let size = ((pair.len() + 24) as u64).to_le_bytes();
+        let mut block = size.to_vec();
+        block.extend(pair);
+        block.extend(size);
+        block.extend(super::APK_SIG_BLOCK_MAGIC);
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let central_directory = super::central_directory_offset(&mut file).unwrap() as usize;
+        let zip = std::fs::read(&path).unwrap();
+        let mut apk = zip[..central_directory].to_vec();
+        apk.extend(&block);
+        apk.extend(&zip[central_directory..]);
+        // No comment, so the offset is at a fixed position from the end.
+        let offset_at = apk.len() - 22 + 16;
+        let new_offset = (central_directory + block.len()) as u32;
+        apk[offset_at..offset_at + 4].copy_from_slice(&new_offset.to_le_bytes());
+        std::fs::write(&path, apk).unwrap();
+    }
+
+    #[test]
+    fn open_base_lib_checks_signing_cert() {
+        use sha2::{Digest, Sha256};
+
+        let cert_hash: crate::verify::Hash = Sha256::digest(b"certificate").into();
+        let other_hash: crate::verify::Hash = Sha256::digest(b"other").into();
+        let tmp_dir = TempDir::new("example").unwrap();
+        write_apk(tmp_dir.path(), Some(b"certificate"));
+        let lib = super::open_base_lib(tmp_dir.path(), "libapp.so", Some(&cert_hash)).unwrap();
+        assert_eq!(lib.into_inner(), b"hello world");
+
+        let error =
+            super::open_base_lib(tmp_dir.path(), "libapp.so", Some(&other_hash)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(crate::UpdateError::BadBaseArtifact(_))
+        ));
+
+        // Unsigned APKs are only rejected when a certificate is expected.
+        write_apk(tmp_dir.path(), None);
+        assert!(super::open_base_lib(tmp_dir.path(), "libapp.so", None).is_ok());
+        assert!(super::open_base_lib(tmp_dir.path(), "libapp.so", Some(&cert_hash)).is_err());
+    }
+
     #[test]
     fn open_base_lib_test() {
         let tmp_dir = TempDir::new("example").unwrap();
-        let error = super::open_base_lib(tmp_dir.path(), "libapp.so").unwrap_err();
+        let error = super::open_base_lib(tmp_dir.path(), "libapp.so", None).unwrap_err();
         assert!(error.to_string().contains("No such file or directory"));
     }
 }
@@ -0,0 +1,199 @@
+// This file decides whether the updater should check for updates on its own
+// (see start_update_thread).  shorebird.yaml, the server's directives and
+// what the app tells us at runtime all have a say, this combines them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::updater::DeferReason;
+
+/// Set by set_auto_update, takes precedence over shorebird.yaml.
+static APP_OVERRIDE: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Set by set_metered_network.
+static METERED_NETWORK: AtomicBool = AtomicBool::new(false);
+
+pub fn set_auto_update_override(enabled: Option<bool>) {
+    *APP_OVERRIDE
+        .lock()
+        .expect("Failed to acquire auto update lock.") = enabled;
+}
+
+pub fn auto_update_override() -> Option<bool> {
+    *APP_OVERRIDE
+        .lock()
+        .expect("Failed to acquire auto update lock.")
+}
+
+pub fn set_metered_network(metered: bool) {
+    METERED_NETWORK.store(metered, Ordering::SeqCst);
+}
+
+pub fn is_metered_network() -> bool {
+    METERED_NETWORK.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+pub fn testing_reset() {
+    set_auto_update_override(None);
+    set_metered_network(false);
+}
+
+/// Everything which has a say in whether an automatic update check may run
+/// now, see defer_reason() for which wins.
+#[derive(Debug, Clone)]
+pub struct AutoUpdatePolicy {
+    /// shorebird.yaml's update_in_daemon: another process does updates.
+    pub update_in_daemon: bool,
+    /// The app put the updater in offline mode.
+    pub offline: bool,
+    /// The server asked us to back off and that time has not yet passed.
+    pub server_backoff: bool,
+    /// The server's directives turned automatic updates off.
+    pub server_disabled: bool,
+    /// The server offered a critical patch which isn't installed yet.
+    pub critical_patch_pending: bool,
+    /// The app turned automatic updates on or off at runtime.
+    pub app_override: Option<bool>,
+    /// shorebird.yaml's auto_update.
+    pub config_enabled: bool,
+    /// The app told us the device is on a metered network.
+    pub metered_network: bool,
+    /// The server's check interval has not passed since the last check.
+    pub throttled: bool,
+}
+
+impl AutoUpdatePolicy {
+    /// Why an automatic update check should not run now, or None if it
+    /// should.  Never in the app if a daemon updates for it.  Offline mode
+    /// and the server always win.  Then an explicit choice by the app at
+    /// runtime beats shorebird.yaml and the network type.  The server's check
+    /// interval applies either way.  A pending critical patch skips the
+    /// network type and check interval.
+    pub fn defer_reason(&self) -> Option<DeferReason> {
+        if self.update_in_daemon {
+            return Some(DeferReason::UpdateInDaemon);
+        }
+        if self.offline {
+            return Some(DeferReason::Offline);
+        }
+        if self.server_backoff {
+            return Some(DeferReason::ServerBackoff);
+        }
+        if self.server_disabled {
+            return Some(DeferReason::AutoUpdateDisabled);
+        }
+        match self.app_override {
+            Some(false) => return Some(DeferReason::AutoUpdateDisabledByApp),
+            Some(true) => {}
+            None if !self.config_enabled => return Some(DeferReason::AutoUpdateDisabledByConfig),
+            None if self.metered_network && !self.critical_patch_pending => {
+                return Some(DeferReason::MeteredNetwork)
+            }
+            None => {}
+        }
+        if self.throttled && !self.critical_patch_pending {
+            return Some(DeferReason::CheckThrottled);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AutoUpdatePolicy;
+    use crate::updater::DeferReason;
+
+    fn allowed() -> AutoUpdatePolicy {
+        AutoUpdatePolicy {
+            update_in_daemon: false,
+            offline: false,
+            server_backoff: false,
+            server_disabled: false,
+            critical_patch_pending: false,
+            app_override: None,
+            config_enabled: true,
+            metered_network: false,
+            throttled: false,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(allowed().defer_reason(), None);
+
+        // The app can't override offline mode or the server.
+        let policy = AutoUpdatePolicy {
+            app_override: Some(true),
+            server_disabled: true,
+            ..allowed()
+        };
+        assert_eq!(policy.defer_reason(), Some(DeferReason::AutoUpdateDisabled));
+        let policy = AutoUpdatePolicy {
+            offline: true,
+            ..policy
+        };
+        assert_eq!(policy.defer_reason(), Some(DeferReason::Offline));
+
+        // Nor the daemon doing the updates.
+        let policy = AutoUpdatePolicy {
+            update_in_daemon: true,
+            ..policy
+        };
+        assert_eq!(policy.defer_reason(), Some(DeferReason::UpdateInDaemon));
+
+        // But it can override shorebird.yaml and metered networks.
+        let policy = AutoUpdatePolicy {
+            config_enabled: false,
+            metered_network: true,
+            ..allowed()
+        };
+        assert_eq!(
+            policy.defer_reason(),
+            Some(DeferReason::AutoUpdateDisabledByConfig)
+        );
+        let policy = AutoUpdatePolicy {
+            config_enabled: true,
+            ..policy
+        };
+        assert_eq!(policy.defer_reason(), Some(DeferReason::MeteredNetwork));
+        let policy = AutoUpdatePolicy {
+            app_override: Some(true),
+            ..policy
+        };
+        assert_eq!(policy.defer_reason(), None);
+        let policy = AutoUpdatePolicy {
+            app_override: Some(false),
+            ..allowed()
+        };
+        assert_eq!(
+            policy.defer_reason(),
+            Some(DeferReason::AutoUpdateDisabledByApp)
+        );
+
+        // Throttling still applies when the app turns updates on.
+        let policy = AutoUpdatePolicy {
+            app_override: Some(true),
+            throttled: true,
+            ..allowed()
+        };
+        assert_eq!(policy.defer_reason(), Some(DeferReason::CheckThrottled));
+
+        // Critical patches skip throttling and metered networks, but nothing
+        // else.
+        let policy = AutoUpdatePolicy {
+            critical_patch_pending: true,
+            metered_network: true,
+            ..policy
+        };
+        assert_eq!(policy.defer_reason(), None);
+        let policy = AutoUpdatePolicy {
+            app_override: Some(false),
+            ..policy
+        };
+        assert_eq!(
+            policy.defer_reason(),
+            Some(DeferReason::AutoUpdateDisabledByApp)
+        );
+    }
+}
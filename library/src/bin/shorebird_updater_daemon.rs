@@ -0,0 +1,12 @@
+// The daemon mode updater process, see src/daemon.rs.  Runs one update and
+// exits, 0 unless the update failed.
+
+fn main() {
+    match updater::run_daemon(std::env::args().skip(1)) {
+        Ok(status) => println!("{}", status),
+        Err(e) => {
+            eprintln!("{:#}\n{}", e, updater::DAEMON_USAGE);
+            std::process::exit(1);
+        }
+    }
+}
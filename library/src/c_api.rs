@@ -5,7 +5,7 @@
 // cbindgen:prefix-with-name could do this for us.
 
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::path::PathBuf;
 
 use crate::updater;
@@ -19,6 +19,12 @@ use std::{println as info, println as error}; // Workaround to use println! for
 /// NOTE: If this struct is changed all language bindings must be updated.
 #[repr(C)]
 pub struct AppParameters {
+    /// sizeof(AppParameters) as compiled by the caller, required.  New fields
+    /// are only ever appended, and fields which don't fit in `size` are
+    /// treated as unset, so callers built against an older updater.h keep
+    /// working.
+    pub size: usize,
+
     /// release_version, required.  Named version of the app, off of which
     /// updates are based.  Can be either a version number or a hash.
     pub release_version: *const libc::c_char,
@@ -31,8 +37,32 @@ pub struct AppParameters {
     /// Length of the original_libapp_paths array.
     pub original_libapp_paths_size: libc::c_int,
 
-    /// Path to cache_dir where the updater will store downloaded artifacts.
+    /// Path to cache_dir where the updater will store downloaded artifacts,
+    /// required.
     pub cache_dir: *const libc::c_char,
+
+    /// Build number of the running app (e.g. versionCode on Android), optional.
+    /// 0 if unknown.  Used to refuse patches targeted at other builds.
+    pub build_number: u64,
+
+    /// app_id to use instead of the one in shorebird.yaml, optional (NULL).
+    /// Only honored when shorebird.yaml sets `allow_app_id_override: true`.
+    pub app_id_override: *const libc::c_char,
+
+    /// Directory to download patches to, optional (NULL).  Defaults to a
+    /// directory under cache_dir, which is also used if this one turns out
+    /// not to be writable.
+    pub download_dir: *const libc::c_char,
+
+    /// The device's locale (e.g. "en_US"), optional (NULL).  Only sent to
+    /// the server, as language and country, when shorebird.yaml sets
+    /// `report_locale: true`.
+    pub locale: *const libc::c_char,
+
+    /// Whether the app is a debug build, optional (false).  The updater
+    /// itself is built in release mode for every flavor, so it can't tell.
+    /// Only debug builds honor shorebird.yaml's `pinned_patch_number`.
+    pub debug_build: bool,
 }
 
 /// Converts a C string to a Rust string, does not free the C string.
@@ -42,9 +72,25 @@ fn to_rust(c_string: *const libc::c_char) -> anyhow::Result<String> {
     Ok(c_str.to_str()?.to_string())
 }
 
+/// Converts an optional (possibly null) C string to a Rust string, does not
+/// free the C string.
+fn to_rust_option(c_string: *const libc::c_char) -> anyhow::Result<Option<String>> {
+    if c_string.is_null() {
+        return Ok(None);
+    }
+    to_rust(c_string).map(Some)
+}
+
+/// Number of strings returned to C which have not been freed yet.
+#[cfg(feature = "allocation_tracking")]
+static OUTSTANDING_ALLOCATIONS: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
 /// Converts a Rust string to a C string, caller must free the C string.
 fn allocate_c_string(rust_string: &str) -> anyhow::Result<*mut c_char> {
     let c_str = CString::new(rust_string)?;
+    #[cfg(feature = "allocation_tracking")]
+    OUTSTANDING_ALLOCATIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
     Ok(c_str.into_raw())
 }
 
@@ -60,25 +106,78 @@ fn to_rust_vector(
     Ok(result)
 }
 
+/// The first version of AppParameters ended with cache_dir, every caller
+/// must provide at least that much.
+/// cbindgen:ignore
+const MIN_APP_PARAMETERS_SIZE: usize =
+    std::mem::offset_of!(AppParameters, cache_dir) + std::mem::size_of::<*const c_char>();
+
+fn size_of_pointee<T>(_: *const T) -> usize {
+    std::mem::size_of::<T>()
+}
+
+/// Reads a field of a caller's struct of type $type which starts with its
+/// size, or None if the caller's (older) struct ends before the field.  Never
+/// reads past `size` bytes.
+macro_rules! sized_field {
+    ($type:ty, $ptr:expr, $size:expr, $field:ident) => {{
+        let field = unsafe { std::ptr::addr_of!((*$ptr).$field) };
+        let end = std::mem::offset_of!($type, $field) + size_of_pointee(field);
+        (end <= $size).then(|| unsafe { field.read() })
+    }};
+}
+
+/// Reads a field of the caller's AppParameters, see sized_field.
+macro_rules! app_parameter {
+    ($c_params:expr, $size:expr, $field:ident) => {
+        sized_field!(AppParameters, $c_params, $size, $field)
+    };
+}
+
 fn app_config_from_c(c_params: *const AppParameters) -> anyhow::Result<updater::AppConfig> {
     anyhow::ensure!(
         !c_params.is_null(),
         "Null parameters passed to app_config_from_c"
     );
-    let c_params_ref = unsafe { &*c_params };
+    let size = unsafe { std::ptr::addr_of!((*c_params).size).read() };
+    anyhow::ensure!(
+        size >= MIN_APP_PARAMETERS_SIZE,
+        "AppParameters.size ({}) is too small, expected at least {}",
+        size,
+        MIN_APP_PARAMETERS_SIZE
+    );
+    // Required fields are all within MIN_APP_PARAMETERS_SIZE.
+    let required = |field: Option<*const c_char>| field.unwrap_or(std::ptr::null());
 
     Ok(updater::AppConfig {
-        cache_dir: to_rust(c_params_ref.cache_dir)?,
-        release_version: to_rust(c_params_ref.release_version)?,
+        cache_dir: to_rust(required(app_parameter!(c_params, size, cache_dir)))?,
+        release_version: to_rust(required(app_parameter!(c_params, size, release_version)))?,
         original_libapp_paths: to_rust_vector(
-            c_params_ref.original_libapp_paths,
-            c_params_ref.original_libapp_paths_size,
+            app_parameter!(c_params, size, original_libapp_paths).unwrap_or(std::ptr::null()),
+            app_parameter!(c_params, size, original_libapp_paths_size).unwrap_or(0),
         )?,
+        build_number: match app_parameter!(c_params, size, build_number) {
+            None | Some(0) => None,
+            build_number => build_number,
+        },
+        app_id_override: match app_parameter!(c_params, size, app_id_override) {
+            Some(app_id_override) => to_rust_option(app_id_override)?,
+            None => None,
+        },
+        download_dir: match app_parameter!(c_params, size, download_dir) {
+            Some(download_dir) => to_rust_option(download_dir)?,
+            None => None,
+        },
+        locale: match app_parameter!(c_params, size, locale) {
+            Some(locale) => to_rust_option(locale)?,
+            None => None,
+        },
+        debug_build: app_parameter!(c_params, size, debug_build).unwrap_or(false),
     })
 }
 
 /// Helper function to log errors instead of panicking or returning a result.
-fn log_on_error<F, R>(f: F, context: &str, error_result: R) -> R
+pub(crate) fn log_on_error<F, R>(f: F, context: &str, error_result: R) -> R
 where
     F: FnOnce() -> Result<R, anyhow::Error>,
 {
@@ -88,10 +187,105 @@ where
     })
 }
 
+/// Stable error codes for callers which need to handle failures
+/// programmatically rather than by reading logs.  Values must never be
+/// reused; add new codes at the end.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShorebirdErrorCode {
+    /// No error.
+    None = 0,
+    /// An error not covered by a more specific code.
+    Unknown = 1,
+    InvalidArgument = 2,
+    InvalidState = 3,
+    BadServerResponse = 4,
+    FailedToSaveState = 5,
+    ConfigNotInitialized = 6,
+    UpdateAlreadyInProgress = 7,
+    BadBaseArtifact = 8,
+    HashMismatch = 9,
+    InvalidSignature = 10,
+    /// The server could not be reached or the connection failed.
+    Network = 11,
+    /// Reading or writing a file failed.
+    Io = 12,
+    /// The updater is in offline mode, see shorebird_set_offline.
+    Offline = 13,
+    /// The server asked the updater to back off (e.g. HTTP 429).
+    ServerBusy = 14,
+    /// The updater was built without network support.
+    NetworkUnsupported = 15,
+    /// A download was refused (e.g. its signed URL expired) and a fresh URL
+    /// could not be obtained.
+    DownloadUrlExpired = 16,
+    /// The patch is not in the patch_allowlist bundled with the app.
+    PatchNotAllowed = 17,
+    /// A download stopped because the app went into the background, see
+    /// shorebird_on_app_background.
+    DownloadPaused = 18,
+    /// The patch is encrypted and the app provided no key for it, see
+    /// shorebird_set_patch_key_callback.
+    PatchKeyUnavailable = 19,
+    /// The patch did not apply to this device's copy of the release, e.g.
+    /// because it was built against a different build.
+    PatchDidNotApply = 20,
+}
+
+impl From<updater::UpdateErrorKind> for ShorebirdErrorCode {
+    fn from(kind: updater::UpdateErrorKind) -> Self {
+        use updater::UpdateErrorKind;
+        match kind {
+            UpdateErrorKind::Unknown => ShorebirdErrorCode::Unknown,
+            UpdateErrorKind::InvalidArgument => ShorebirdErrorCode::InvalidArgument,
+            UpdateErrorKind::InvalidState => ShorebirdErrorCode::InvalidState,
+            UpdateErrorKind::BadServerResponse => ShorebirdErrorCode::BadServerResponse,
+            UpdateErrorKind::FailedToSaveState => ShorebirdErrorCode::FailedToSaveState,
+            UpdateErrorKind::ConfigNotInitialized => ShorebirdErrorCode::ConfigNotInitialized,
+            UpdateErrorKind::UpdateAlreadyInProgress => ShorebirdErrorCode::UpdateAlreadyInProgress,
+            UpdateErrorKind::BadBaseArtifact => ShorebirdErrorCode::BadBaseArtifact,
+            UpdateErrorKind::HashMismatch => ShorebirdErrorCode::HashMismatch,
+            UpdateErrorKind::InvalidSignature => ShorebirdErrorCode::InvalidSignature,
+            UpdateErrorKind::Network => ShorebirdErrorCode::Network,
+            UpdateErrorKind::Io => ShorebirdErrorCode::Io,
+            UpdateErrorKind::Offline => ShorebirdErrorCode::Offline,
+            UpdateErrorKind::ServerBusy => ShorebirdErrorCode::ServerBusy,
+            UpdateErrorKind::NetworkUnsupported => ShorebirdErrorCode::NetworkUnsupported,
+            UpdateErrorKind::DownloadUrlExpired => ShorebirdErrorCode::DownloadUrlExpired,
+            UpdateErrorKind::PatchNotAllowed => ShorebirdErrorCode::PatchNotAllowed,
+            UpdateErrorKind::DownloadPaused => ShorebirdErrorCode::DownloadPaused,
+            UpdateErrorKind::PatchKeyUnavailable => ShorebirdErrorCode::PatchKeyUnavailable,
+            UpdateErrorKind::PatchDidNotApply => ShorebirdErrorCode::PatchDidNotApply,
+        }
+    }
+}
+
+impl From<&anyhow::Error> for ShorebirdErrorCode {
+    fn from(error: &anyhow::Error) -> Self {
+        updater::UpdateErrorKind::from(error).into()
+    }
+}
+
+/// Like log_on_error, but reports failure as a ShorebirdErrorCode.
+fn error_code_on_error<F>(f: F, context: &str) -> ShorebirdErrorCode
+where
+    F: FnOnce() -> anyhow::Result<()>,
+{
+    match f() {
+        Ok(()) => ShorebirdErrorCode::None,
+        Err(e) => {
+            error!("Error {}: {:?}", context, e);
+            ShorebirdErrorCode::from(&e)
+        }
+    }
+}
+
 /// Configures updater.  First parameter is a struct containing configuration
 /// from the running app.  Second parameter is a YAML string containing
 /// configuration compiled into the app.  Returns true on success and false on
 /// failure. If false is returned, the updater library will not be usable.
+/// Passing NULL for either parameter (or a required AppParameters field)
+/// returns false rather than crashing.
 #[no_mangle]
 pub extern "C" fn shorebird_init(
     c_params: *const AppParameters,
@@ -109,6 +303,136 @@ pub extern "C" fn shorebird_init(
     )
 }
 
+/// Like shorebird_init, but returns why initialization failed.  Returns
+/// ShorebirdErrorCode_None on success.
+#[no_mangle]
+pub extern "C" fn shorebird_init_with_result(
+    c_params: *const AppParameters,
+    c_yaml: *const libc::c_char,
+) -> ShorebirdErrorCode {
+    error_code_on_error(
+        || {
+            let config = app_config_from_c(c_params)?;
+            let yaml_string = to_rust(c_yaml)?;
+            updater::init(config, &yaml_string)?;
+            Ok(())
+        },
+        "initializing updater",
+    )
+}
+
+/// Callbacks for reading files the host can't give the updater a path to,
+/// see shorebird_init_with_file_callbacks.  Files are read from start to end
+/// and every file opened is closed.  `context` is passed back to open.
+/// NOTE: If this struct is changed all language bindings must be updated.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FileCallbacks {
+    /// sizeof(FileCallbacks) as compiled by the caller, required.  As with
+    /// AppParameters, new fields are only ever appended.
+    pub size: usize,
+    pub context: *mut c_void,
+    /// Opens the file called `name` (e.g. "shorebird.yaml"), returning a
+    /// handle for read and close, or NULL if it can't be opened.
+    pub open: Option<extern "C" fn(context: *mut c_void, name: *const c_char) -> *mut c_void>,
+    /// Reads up to `count` bytes into `buffer`, returning how many were read
+    /// (0 at the end of the file), or -1 on error.
+    pub read: Option<extern "C" fn(handle: *mut c_void, buffer: *mut u8, count: usize) -> isize>,
+    /// Closes a handle returned by open.
+    pub close: Option<extern "C" fn(handle: *mut c_void)>,
+}
+
+/// A file opened through FileCallbacks, closed when dropped.
+struct ExternalFile {
+    handle: *mut c_void,
+    read: extern "C" fn(*mut c_void, *mut u8, usize) -> isize,
+    close: extern "C" fn(*mut c_void),
+}
+
+impl std::io::Read for ExternalFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = (self.read)(self.handle, buf.as_mut_ptr(), buf.len());
+        match usize::try_from(count) {
+            Ok(count) if count <= buf.len() => Ok(count),
+            _ => Err(std::io::Error::other(format!(
+                "FileCallbacks.read returned {}",
+                count
+            ))),
+        }
+    }
+}
+
+impl Drop for ExternalFile {
+    fn drop(&mut self) {
+        (self.close)(self.handle);
+    }
+}
+
+impl updater::ExternalFileProvider for FileCallbacks {
+    fn open(&self, name: &str) -> anyhow::Result<Box<dyn std::io::Read>> {
+        let (Some(open), Some(read), Some(close)) = (self.open, self.read, self.close) else {
+            anyhow::bail!("FileCallbacks open, read and close are required");
+        };
+        let c_name = CString::new(name)?;
+        let handle = open(self.context, c_name.as_ptr());
+        anyhow::ensure!(!handle.is_null(), "FileCallbacks.open failed for {}", name);
+        Ok(Box::new(ExternalFile {
+            handle,
+            read,
+            close,
+        }))
+    }
+}
+
+/// The first version of FileCallbacks ended with close, every caller must
+/// provide at least that much.
+/// cbindgen:ignore
+const MIN_FILE_CALLBACKS_SIZE: usize =
+    std::mem::offset_of!(FileCallbacks, close) + std::mem::size_of::<*const c_void>();
+
+/// Copies the caller's FileCallbacks, which may be from an older updater.h.
+fn file_callbacks_from_c(c_callbacks: *const FileCallbacks) -> anyhow::Result<FileCallbacks> {
+    anyhow::ensure!(
+        !c_callbacks.is_null(),
+        "Null file_callbacks passed to shorebird_init_with_file_callbacks"
+    );
+    let size = unsafe { std::ptr::addr_of!((*c_callbacks).size).read() };
+    anyhow::ensure!(
+        size >= MIN_FILE_CALLBACKS_SIZE,
+        "FileCallbacks.size ({}) is too small, expected at least {}",
+        size,
+        MIN_FILE_CALLBACKS_SIZE
+    );
+    Ok(FileCallbacks {
+        size,
+        context: sized_field!(FileCallbacks, c_callbacks, size, context)
+            .unwrap_or(std::ptr::null_mut()),
+        open: sized_field!(FileCallbacks, c_callbacks, size, open).flatten(),
+        read: sized_field!(FileCallbacks, c_callbacks, size, read).flatten(),
+        close: sized_field!(FileCallbacks, c_callbacks, size, close).flatten(),
+    })
+}
+
+/// Like shorebird_init_with_result, but reads shorebird.yaml through
+/// `file_callbacks` (by the name "shorebird.yaml") instead of taking it as a
+/// string.  The callbacks are only used during this call.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn shorebird_init_with_file_callbacks(
+    c_params: *const AppParameters,
+    file_callbacks: *const FileCallbacks,
+) -> ShorebirdErrorCode {
+    error_code_on_error(
+        || {
+            let config = app_config_from_c(c_params)?;
+            let file_callbacks = file_callbacks_from_c(file_callbacks)?;
+            updater::init_with_file_provider(config, &file_callbacks)?;
+            Ok(())
+        },
+        "initializing updater",
+    )
+}
+
 /// The currently running patch number, or 0 if the release has not been
 /// patched.
 #[no_mangle]
@@ -135,6 +459,15 @@ pub extern "C" fn shorebird_next_boot_patch_number() -> usize {
     )
 }
 
+/// Start validating the next boot patch (that it exists and matches its hash)
+/// on a background thread, so the next call to
+/// shorebird_next_boot_patch_number or shorebird_next_boot_patch_path does
+/// not have to.  Call as early as possible during engine startup.
+#[no_mangle]
+pub extern "C" fn shorebird_prevalidate_next_boot_patch() {
+    updater::prevalidate_next_boot_patch();
+}
+
 fn path_to_c_string(path: Option<PathBuf>) -> anyhow::Result<*mut c_char> {
     Ok(match path {
         Some(v) => allocate_c_string(v.to_str().unwrap())?,
@@ -156,23 +489,512 @@ pub extern "C" fn shorebird_next_boot_patch_path() -> *mut c_char {
     )
 }
 
-/// Free a string returned by the updater library.
+/// A file descriptor for a sealed, read-only in-memory copy (memfd) of the
+/// patch that will boot on the next run of the app, so the engine can map it
+/// without re-opening the file on disk.  -1 if there is no next patch, it is
+/// larger than memfd_max_bytes in shorebird.yaml (or that isn't set), or
+/// memfd is unsupported, in which case use shorebird_next_boot_patch_path.
+/// The caller owns the descriptor and must close it.  Android and Linux only.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[no_mangle]
+pub extern "C" fn shorebird_next_boot_patch_memfd() -> libc::c_int {
+    use std::os::unix::io::IntoRawFd;
+    log_on_error(
+        || Ok(updater::next_boot_patch_memfd()?.map_or(-1, |file| file.into_raw_fd())),
+        "fetching next_boot_patch_memfd",
+        -1,
+    )
+}
+
+/// The metadata the server sent with the patch that will boot on the next run
+/// of the app (e.g. git sha, Dart version), as JSON.  NULL if there is no next
+/// patch or it has no metadata.  The caller must free the result with
+/// shorebird_free_string.
+#[no_mangle]
+pub extern "C" fn shorebird_next_boot_patch_metadata() -> *mut c_char {
+    log_on_error(
+        || match updater::next_boot_patch_metadata()? {
+            Some(metadata) => allocate_c_string(&metadata),
+            None => Ok(std::ptr::null_mut()),
+        },
+        "fetching next_boot_patch_metadata",
+        std::ptr::null_mut(),
+    )
+}
+
+/// An open, read-only file descriptor for the patch that will boot on the next
+/// run of the app, or -1 if there is no next patch.  The caller owns the
+/// descriptor and must close it.  Android only.
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "C" fn shorebird_next_boot_patch_fd() -> libc::c_int {
+    use std::os::unix::io::IntoRawFd;
+    log_on_error(
+        || Ok(updater::next_boot_patch_file()?.map_or(-1, |file| file.into_raw_fd())),
+        "fetching next_boot_patch_fd",
+        -1,
+    )
+}
+
+/// This device's client_id, which canary patches can be restricted to.
+/// The caller must free the result with shorebird_free_string.  NULL on error.
+#[no_mangle]
+pub extern "C" fn shorebird_client_id() -> *mut c_char {
+    log_on_error(
+        || allocate_c_string(&updater::client_id()?),
+        "fetching client_id",
+        std::ptr::null_mut(),
+    )
+}
+
+/// The patches currently on disk as a JSON array of objects with number, path,
+/// size, hash, metadata, is_current_boot, is_next_boot and is_known_good.
+/// The caller must free the result with shorebird_free_string.  NULL on error.
+#[no_mangle]
+pub extern "C" fn shorebird_installed_patches_json() -> *mut c_char {
+    log_on_error(
+        || allocate_c_string(&serde_json::to_string(&updater::installed_patches()?)?),
+        "fetching installed patches",
+        std::ptr::null_mut(),
+    )
+}
+
+/// Checks the updater's storage and state, as a JSON object with `healthy`,
+/// `checks`, an array of objects with name, ok and detail, and
+/// `download_progress`, null or an object with bytes_downloaded and
+/// total_bytes (null if unknown) for an interrupted download.  Slow, it
+/// re-hashes the booted patch, so only call it from e.g. a debug screen.
+/// The caller must free the result with shorebird_free_string.  NULL on error
+/// (e.g. before shorebird_init).
+#[no_mangle]
+pub extern "C" fn shorebird_run_health_check() -> *mut c_char {
+    log_on_error(
+        || allocate_c_string(&serde_json::to_string(&updater::health_check()?)?),
+        "running health check",
+        std::ptr::null_mut(),
+    )
+}
+
+/// The latest patch install as a JSON object with patch_number, slot_index,
+/// replacing_patch_number, started_at, steps (the steps which finished, e.g.
+/// "artifact_stored"), completed and error, or "null" if no patch has been
+/// installed.  The caller must free the result with shorebird_free_string.
+/// NULL on error.
+#[no_mangle]
+pub extern "C" fn shorebird_last_install_transaction_json() -> *mut c_char {
+    log_on_error(
+        || {
+            allocate_c_string(&serde_json::to_string(
+                &updater::last_install_transaction()?
+            )?)
+        },
+        "fetching last install transaction",
+        std::ptr::null_mut(),
+    )
+}
+
+/// Non-fatal problems found in shorebird.yaml by shorebird_init (e.g. unknown
+/// keys), one per line, or NULL if there are none.  The caller must free the
+/// result with shorebird_free_string.
+#[no_mangle]
+pub extern "C" fn shorebird_get_config_warnings() -> *mut c_char {
+    log_on_error(
+        || match updater::config_warnings()? {
+            warnings if warnings.is_empty() => Ok(std::ptr::null_mut()),
+            warnings => allocate_c_string(&warnings.join("\n")),
+        },
+        "fetching config warnings",
+        std::ptr::null_mut(),
+    )
+}
+
+/// Free a string returned by the updater library.  Passing NULL is a no-op.
 #[no_mangle]
 pub extern "C" fn shorebird_free_string(c_string: *mut c_char) {
     if c_string.is_null() {
         return;
     }
+    #[cfg(feature = "allocation_tracking")]
+    OUTSTANDING_ALLOCATIONS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
     unsafe {
         drop(CString::from_raw(c_string));
     }
 }
 
+/// The number of strings returned by the updater which have not yet been
+/// passed to shorebird_free_string.  Only available when built with the
+/// "allocation_tracking" feature, for detecting leaks in integration tests.
+#[cfg(feature = "allocation_tracking")]
+#[no_mangle]
+pub extern "C" fn shorebird_testing_outstanding_allocations() -> usize {
+    OUTSTANDING_ALLOCATIONS.load(std::sync::atomic::Ordering::SeqCst)
+}
+
+/// For QA: make updates fetch and boot patch `patch_number` instead of the
+/// latest, even if newer patches exist.  If it is already installed it
+/// becomes the next boot patch now.  Pass 0 to remove the pin.  Kept across
+/// launches.  Works in release builds, so only expose it to testers.
+/// Returns false on error (e.g. before shorebird_init).
+#[no_mangle]
+pub extern "C" fn shorebird_pin_patch(patch_number: usize) -> bool {
+    log_on_error(
+        || {
+            updater::pin_patch((patch_number != 0).then_some(patch_number))?;
+            Ok(true)
+        },
+        "pinning patch",
+        false,
+    )
+}
+
+/// What the next boot will run and, if not a patch, why not (e.g. the patch
+/// was corrupted on disk), for debugging a patch which isn't applying.
+/// `out_patch_number` (which may be NULL) is set to the patch selected or
+/// refused, or 0 if there is none.  Returns NoPatchInstalled on error.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn shorebird_boot_decision_reason(
+    out_patch_number: *mut usize,
+) -> ShorebirdBootDecisionReason {
+    let decision = log_on_error(
+        updater::boot_decision,
+        "deciding boot patch",
+        updater::BootDecision::NoPatch,
+    );
+    if !out_patch_number.is_null() {
+        let patch_number = match decision {
+            updater::BootDecision::Patch(number) => number,
+            updater::BootDecision::NoPatch => 0,
+            updater::BootDecision::Rejected(rejected) => rejected.patch_number,
+        };
+        unsafe { *out_patch_number = patch_number };
+    }
+    decision.into()
+}
+
 /// Check for an update.  Returns true if an update is available.
 #[no_mangle]
 pub extern "C" fn shorebird_check_for_update() -> bool {
     log_on_error(updater::check_for_update, "checking for update", false)
 }
 
+/// Like shorebird_check_for_update, but returns why the check failed.
+/// On success, `out_update_available` (which may be NULL) is set to whether
+/// an update is available.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn shorebird_check_for_update_with_result(
+    out_update_available: *mut bool,
+) -> ShorebirdErrorCode {
+    error_code_on_error(
+        || {
+            let update_available = updater::check_for_update()?;
+            if !out_update_available.is_null() {
+                unsafe { *out_update_available = update_available };
+            }
+            Ok(())
+        },
+        "checking for update",
+    )
+}
+
+/// Turn offline mode on or off, e.g. to honor a user's data saver setting.
+/// While offline the updater makes no network requests: updates report
+/// ShorebirdDeferReason_Offline and checks fail with
+/// ShorebirdErrorCode_Offline.  May be called at any time, including before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_offline(offline: bool) {
+    updater::set_offline(offline);
+}
+
+/// Turn automatic updates (shorebird_start_update_thread) on or off,
+/// overriding shorebird.yaml's auto_update.  Offline mode and the server's
+/// directives still take precedence.  May be called at any time, including
+/// before shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_auto_update(enabled: bool) {
+    updater::set_auto_update(Some(enabled));
+}
+
+/// Undo shorebird_set_auto_update, so shorebird.yaml's auto_update (and the
+/// network type) decide again.  May be called at any time, including before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_clear_auto_update() {
+    updater::set_auto_update(None);
+}
+
+/// Tell the updater whether the device is on a metered network, in which
+/// case automatic updates are skipped unless turned on with
+/// shorebird_set_auto_update.  May be called at any time, including before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_metered_network(metered: bool) {
+    updater::set_metered_network(metered);
+}
+
+/// Tell the updater the app came to the foreground (e.g. from
+/// onResume/applicationDidBecomeActive).  If it was in the background, an
+/// automatic update check starts on a background thread, as long as
+/// shorebird_auto_update_defer_reason allows, which also resumes any
+/// download paused by shorebird_on_app_background.  Does nothing before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_on_app_foreground() {
+    updater::on_app_foreground();
+}
+
+/// Tell the updater the app went into the background (e.g. from
+/// onPause/applicationDidEnterBackground).  Downloads pause, keeping their
+/// progress, unless shorebird.yaml sets background_downloads.  May be called
+/// at any time, including before shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_on_app_background() {
+    updater::on_app_background();
+}
+
+/// Set a header to send with every request the updater makes to base_url,
+/// overriding extra_headers in shorebird.yaml, so secrets (e.g. a gateway
+/// token) need not be built into the app.  Pass NULL for `value` to remove
+/// the header.  Returns false if the name or value is not a valid header.
+/// May be called at any time, including before shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_extra_header(
+    name: *const libc::c_char,
+    value: *const libc::c_char,
+) -> bool {
+    log_on_error(
+        || {
+            let name = to_rust(name)?;
+            let value = to_rust_option(value)?;
+            updater::set_extra_header(&name, value.as_deref())?;
+            Ok(true)
+        },
+        "setting extra header",
+        false,
+    )
+}
+
+/// Called before each signed request to the update server to get the HMAC
+/// key.  Writes the key to `buffer` (which holds `buffer_len` bytes) and
+/// returns its length, or returns 0 if there is no key.
+pub type ShorebirdSigningKeyCallback =
+    Option<extern "C" fn(context: *mut c_void, buffer: *mut u8, buffer_len: usize) -> usize>;
+
+/// The largest request signing key shorebird_set_request_signing_key_callback
+/// accepts, HMAC-SHA256 hashes longer keys down to 32 bytes anyway.
+/// cbindgen:ignore
+const MAX_SIGNING_KEY_LEN: usize = 256;
+
+/// Provide the key used to sign requests to the update server (see
+/// request_signing in shorebird.yaml), taking precedence over any key in
+/// shorebird.yaml so it can be rotated.  `context` is passed back to the
+/// callback and must stay valid until the callback is replaced.  Pass NULL
+/// to remove the callback.  May be called at any time, including before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_request_signing_key_callback(
+    callback: ShorebirdSigningKeyCallback,
+    context: *mut c_void,
+) {
+    // Raw pointers aren't Send, the host promises context is usable from
+    // any thread.
+    let context = context as usize;
+    let provider = callback.map(|callback| -> updater::KeyProvider {
+        std::sync::Arc::new(move || {
+            let mut buffer = [0u8; MAX_SIGNING_KEY_LEN];
+            let len = callback(context as *mut c_void, buffer.as_mut_ptr(), buffer.len());
+            if len > buffer.len() {
+                error!("Request signing key is longer than {} bytes.", buffer.len());
+                return None;
+            }
+            (len > 0).then(|| buffer[..len].to_vec())
+        })
+    });
+    updater::set_request_signing_key_provider(provider);
+}
+
+/// Called with a patch's encryption key id to get the key which decrypts it.
+/// Writes the 32 byte AES-256 key to `buffer` (which holds `buffer_len`
+/// bytes) and returns its length, or returns 0 if the app has no such key.
+pub type ShorebirdPatchKeyCallback = Option<
+    extern "C" fn(
+        context: *mut c_void,
+        key_id: *const c_char,
+        buffer: *mut u8,
+        buffer_len: usize,
+    ) -> usize,
+>;
+
+/// Provide the keys for patches the server encrypts in transit.  Without a
+/// key, encrypted patches fail to install with
+/// ShorebirdErrorCode_PatchKeyUnavailable.  `context` is passed back to the
+/// callback and must stay valid until the callback is replaced.  Pass NULL
+/// to remove the callback.  May be called at any time, including before
+/// shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_patch_key_callback(
+    callback: ShorebirdPatchKeyCallback,
+    context: *mut c_void,
+) {
+    // Raw pointers aren't Send, the host promises context is usable from
+    // any thread.
+    let context = context as usize;
+    let provider = callback.map(|callback| -> updater::PatchKeyProvider {
+        std::sync::Arc::new(move |key_id| {
+            let key_id = CString::new(key_id).ok()?;
+            // Room for a key of the wrong length, so it can be reported.
+            let mut buffer = [0u8; 2 * updater::PATCH_KEY_LEN];
+            let len = callback(
+                context as *mut c_void,
+                key_id.as_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            );
+            if len > buffer.len() {
+                error!("Patch key is longer than {} bytes.", buffer.len());
+                return None;
+            }
+            (len > 0).then(|| buffer[..len].to_vec())
+        })
+    });
+    updater::set_patch_key_provider(provider);
+}
+
+/// Why shorebird_start_update_thread would not check for an update right
+/// now, or ShorebirdDeferReason_None if it would.
+#[no_mangle]
+pub extern "C" fn shorebird_auto_update_defer_reason() -> ShorebirdDeferReason {
+    // Only fails if shorebird_init hasn't succeeded.
+    log_on_error(
+        || Ok(updater::auto_update_deferral()?.into()),
+        "checking auto update policy",
+        ShorebirdDeferReason::NotInitialized,
+    )
+}
+
+/// Whether shorebird_start_update_thread would check for an update right
+/// now, see shorebird_auto_update_defer_reason for why not.
+#[no_mangle]
+pub extern "C" fn shorebird_should_auto_update() -> bool {
+    shorebird_auto_update_defer_reason() == ShorebirdDeferReason::None
+}
+
+/// Callbacks for shorebird_set_delegate.  Any callback may be NULL.  They may
+/// be called on any thread (e.g. the update thread), with `context` passed
+/// back as the first argument.
+/// NOTE: If this struct is changed all language bindings must be updated.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShorebirdDelegate {
+    pub context: *mut c_void,
+    /// Called with the patch number after a patch is installed.
+    pub on_patch_installed: Option<extern "C" fn(*mut c_void, usize)>,
+    /// Called with the patch number after a patch is marked bad.
+    pub on_patch_invalidated: Option<extern "C" fn(*mut c_void, usize)>,
+    /// Called when an update fails.
+    pub on_update_error: Option<extern "C" fn(*mut c_void, ShorebirdErrorCode)>,
+    /// Called with the patch number after a critical patch is installed, the
+    /// app should prompt the user to restart.
+    pub on_restart_requested: Option<extern "C" fn(*mut c_void, usize)>,
+}
+
+// The caller promises `context` may be used from any thread.
+unsafe impl Send for ShorebirdDelegate {}
+unsafe impl Sync for ShorebirdDelegate {}
+
+impl updater::UpdaterDelegate for ShorebirdDelegate {
+    fn on_patch_installed(&self, patch_number: usize) {
+        if let Some(callback) = self.on_patch_installed {
+            callback(self.context, patch_number);
+        }
+    }
+
+    fn on_patch_invalidated(&self, patch_number: usize) {
+        if let Some(callback) = self.on_patch_invalidated {
+            callback(self.context, patch_number);
+        }
+    }
+
+    fn on_update_error(&self, error: &anyhow::Error) {
+        if let Some(callback) = self.on_update_error {
+            callback(self.context, ShorebirdErrorCode::from(error));
+        }
+    }
+
+    fn on_restart_requested(&self, patch_number: usize) {
+        if let Some(callback) = self.on_restart_requested {
+            callback(self.context, patch_number);
+        }
+    }
+}
+
+/// Called with the JSON of a patch check response offering a patch (valid
+/// only during the call), before the update downloads it.  Return false to
+/// veto installing it.
+pub type ShorebirdPatchCheckResponseCallback =
+    Option<extern "C" fn(context: *mut c_void, response_json: *const c_char) -> bool>;
+
+/// Lets the app apply its own policy (e.g. no updates during checkout) to
+/// each patch offered, see updater::set_patch_check_response_callback.  A
+/// vetoed update reports ShorebirdDeferReason_VetoedByApp.  The callback
+/// must not itself update.  `context` is passed back to the callback and
+/// must stay valid until the callback is replaced.  Pass NULL to remove the
+/// callback.  Does not require shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_patch_check_response_callback(
+    callback: ShorebirdPatchCheckResponseCallback,
+    context: *mut c_void,
+) {
+    // Raw pointers aren't Send, the host promises context is usable from
+    // any thread.
+    let context = context as usize;
+    let callback = callback.map(|callback| -> updater::PatchCheckResponseCallback {
+        std::sync::Arc::new(move |response_json| match CString::new(response_json) {
+            Ok(response_json) => callback(context as *mut c_void, response_json.as_ptr()),
+            // JSON escapes control characters, so this can't happen.
+            Err(_) => true,
+        })
+    });
+    updater::set_patch_check_response_callback(callback);
+}
+
+/// Returns whether an app store update (e.g. a Play in-app update) is
+/// downloaded or downloading and waiting to install.
+pub type ShorebirdStoreUpdatePendingCallback = Option<extern "C" fn(*mut c_void) -> bool>;
+
+/// Registers a callback asked before downloading an offered patch, see
+/// updater::set_store_update_pending_callback.  While it returns true updates
+/// report ShorebirdDeferReason_StoreUpdatePending.  `context` is passed back
+/// to the callback and must stay valid until the callback is replaced.  Pass
+/// NULL to remove the callback.  Does not require shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_set_store_update_pending_callback(
+    callback: ShorebirdStoreUpdatePendingCallback,
+    context: *mut c_void,
+) {
+    // Raw pointers aren't Send, the host promises context is usable from
+    // any thread.
+    let context = context as usize;
+    let callback = callback.map(|callback| -> updater::StoreUpdatePendingCallback {
+        std::sync::Arc::new(move || callback(context as *mut c_void))
+    });
+    updater::set_store_update_pending_callback(callback);
+}
+
+/// Registers callbacks so the app doesn't need to poll after background
+/// updates, replacing any previously registered.  The struct is copied.
+/// Passing NULL removes the callbacks.  Does not require shorebird_init.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn shorebird_set_delegate(delegate: *const ShorebirdDelegate) {
+    let delegate = unsafe { delegate.as_ref() }.copied();
+    updater::set_delegate(
+        delegate.map(|d| std::sync::Arc::new(d) as std::sync::Arc<dyn updater::UpdaterDelegate>),
+    );
+}
+
 /// Synchronously download an update if one is available.
 #[no_mangle]
 pub extern "C" fn shorebird_update() {
@@ -183,23 +1005,309 @@ pub extern "C" fn shorebird_update() {
     );
 }
 
-/// Start a thread to download an update if one is available.
+/// Outcome of shorebird_update_with_result, see updater::UpdateStatus.
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum ShorebirdUpdateStatus {
+    NoUpdate = 0,
+    UpdateAvailable = 1,
+    UpdateInstalled = 2,
+    UpdateDownloaded = 3,
+    UpdateDeferred = 4,
+    UpdateHadError = 5,
+}
+
+/// What the next boot will run, see updater::BootDecision.
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum ShorebirdBootDecisionReason {
+    /// The next boot uses a patch.
+    PatchSelected = 0,
+    /// No patch is installed for this release.
+    NoPatchInstalled = 1,
+    /// The patch failed to launch or was rolled back.
+    MarkedBad = 2,
+    /// The patch's artifact is no longer on disk.
+    ArtifactMissing = 3,
+    /// The patch's artifact could not be read.
+    ArtifactUnreadable = 4,
+    /// The patch's artifact no longer matches its hash.
+    HashMismatch = 5,
+    /// The patch is not in shorebird.yaml's patch_allowlist.
+    NotAllowlisted = 6,
+    /// The app was updated to a new release.
+    ReleaseVersionChanged = 7,
+    /// The patch's artifact changed between selection and boot.
+    ChangedBeforeBoot = 8,
+    /// The app was replaced by an older release, the patch is kept for if
+    /// the newer release returns.
+    ReleaseVersionDowngraded = 9,
+}
+
+impl From<updater::BootDecision> for ShorebirdBootDecisionReason {
+    fn from(decision: updater::BootDecision) -> Self {
+        use updater::{BootDecision, PatchRejection};
+        match decision {
+            BootDecision::Patch(_) => Self::PatchSelected,
+            BootDecision::NoPatch => Self::NoPatchInstalled,
+            BootDecision::Rejected(rejected) => match rejected.reason {
+                PatchRejection::MarkedBad => Self::MarkedBad,
+                PatchRejection::ArtifactMissing => Self::ArtifactMissing,
+                PatchRejection::ArtifactUnreadable => Self::ArtifactUnreadable,
+                PatchRejection::HashMismatch => Self::HashMismatch,
+                PatchRejection::NotAllowlisted => Self::NotAllowlisted,
+                PatchRejection::ReleaseVersionChanged => Self::ReleaseVersionChanged,
+                PatchRejection::ChangedBeforeBoot => Self::ChangedBeforeBoot,
+                PatchRejection::ReleaseVersionDowngraded => Self::ReleaseVersionDowngraded,
+            },
+        }
+    }
+}
+
+/// Why an update was deferred, see updater::DeferReason.
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum ShorebirdDeferReason {
+    /// The update was not deferred.
+    None = 0,
+    AutoUpdateDisabled = 1,
+    CheckThrottled = 2,
+    PatchesDisabled = 3,
+    IncompatibleBuildNumber = 4,
+    IncompatibleNativeFingerprint = 5,
+    Offline = 6,
+    ServerBackoff = 7,
+    AutoUpdateDisabledByApp = 8,
+    AutoUpdateDisabledByConfig = 9,
+    MeteredNetwork = 10,
+    AppInBackground = 11,
+    UpdaterUpgradeRequired = 12,
+    UpdateInDaemon = 13,
+    /// The app's patch check response callback vetoed the offered patch,
+    /// see shorebird_set_patch_check_response_callback.
+    VetoedByApp = 14,
+    /// An app store update is waiting to install, see
+    /// shorebird_set_store_update_pending_callback.
+    StoreUpdatePending = 15,
+    /// shorebird_init has not been called (or failed), so the updater can't
+    /// check for updates.
+    NotInitialized = 16,
+}
+
+impl From<Option<updater::DeferReason>> for ShorebirdDeferReason {
+    fn from(reason: Option<updater::DeferReason>) -> Self {
+        use updater::DeferReason;
+        match reason {
+            None => ShorebirdDeferReason::None,
+            Some(DeferReason::AutoUpdateDisabled) => ShorebirdDeferReason::AutoUpdateDisabled,
+            Some(DeferReason::CheckThrottled) => ShorebirdDeferReason::CheckThrottled,
+            Some(DeferReason::PatchesDisabled) => ShorebirdDeferReason::PatchesDisabled,
+            Some(DeferReason::IncompatibleBuildNumber) => {
+                ShorebirdDeferReason::IncompatibleBuildNumber
+            }
+            Some(DeferReason::IncompatibleNativeFingerprint) => {
+                ShorebirdDeferReason::IncompatibleNativeFingerprint
+            }
+            Some(DeferReason::Offline) => ShorebirdDeferReason::Offline,
+            Some(DeferReason::ServerBackoff) => ShorebirdDeferReason::ServerBackoff,
+            Some(DeferReason::AutoUpdateDisabledByApp) => {
+                ShorebirdDeferReason::AutoUpdateDisabledByApp
+            }
+            Some(DeferReason::AutoUpdateDisabledByConfig) => {
+                ShorebirdDeferReason::AutoUpdateDisabledByConfig
+            }
+            Some(DeferReason::MeteredNetwork) => ShorebirdDeferReason::MeteredNetwork,
+            Some(DeferReason::AppInBackground) => ShorebirdDeferReason::AppInBackground,
+            Some(DeferReason::UpdaterUpgradeRequired) => {
+                ShorebirdDeferReason::UpdaterUpgradeRequired
+            }
+            Some(DeferReason::UpdateInDaemon) => ShorebirdDeferReason::UpdateInDaemon,
+            Some(DeferReason::VetoedByApp) => ShorebirdDeferReason::VetoedByApp,
+            Some(DeferReason::StoreUpdatePending) => ShorebirdDeferReason::StoreUpdatePending,
+        }
+    }
+}
+
+/// Returned by shorebird_update_with_result.  `defer_reason` is only set
+/// when `status` is UpdateDeferred, and `error` only when it is
+/// UpdateHadError.
+#[repr(C)]
+pub struct UpdateResult {
+    pub status: ShorebirdUpdateStatus,
+    pub defer_reason: ShorebirdDeferReason,
+    pub error: ShorebirdErrorCode,
+}
+
+impl From<updater::UpdateStatus> for UpdateResult {
+    fn from(status: updater::UpdateStatus) -> Self {
+        use updater::UpdateStatus;
+        let (status, defer_reason) = match status {
+            UpdateStatus::NoUpdate => (ShorebirdUpdateStatus::NoUpdate, None),
+            UpdateStatus::UpdateAvailable => (ShorebirdUpdateStatus::UpdateAvailable, None),
+            UpdateStatus::UpdateInstalled => (ShorebirdUpdateStatus::UpdateInstalled, None),
+            UpdateStatus::UpdateDownloaded => (ShorebirdUpdateStatus::UpdateDownloaded, None),
+            UpdateStatus::UpdateDeferred(reason) => {
+                (ShorebirdUpdateStatus::UpdateDeferred, Some(reason))
+            }
+            UpdateStatus::UpdateHadError => (ShorebirdUpdateStatus::UpdateHadError, None),
+        };
+        UpdateResult {
+            status,
+            defer_reason: defer_reason.into(),
+            error: ShorebirdErrorCode::None,
+        }
+    }
+}
+
+/// Synchronously download an update if one is available, and report what
+/// happened (including why, if the updater declined to install it).
+#[no_mangle]
+pub extern "C" fn shorebird_update_with_result() -> UpdateResult {
+    match updater::update() {
+        Ok(status) => status.into(),
+        Err(e) => {
+            error!("Error downloading update: {:?}", e);
+            UpdateResult {
+                error: ShorebirdErrorCode::from(&e),
+                ..updater::UpdateStatus::UpdateHadError.into()
+            }
+        }
+    }
+}
+
+/// Returned by shorebird_last_update_result.
+#[repr(C)]
+pub struct LastUpdateResult {
+    /// When the most recent update finished (seconds since epoch), or 0 if
+    /// there has been no update for this release.
+    pub timestamp: u64,
+    pub result: UpdateResult,
+}
+
+/// The outcome of the most recent shorebird_update (or
+/// shorebird_update_with_result or update thread run), without checking for
+/// a new update.  Lets apps show e.g. "last checked 2h ago, up to date".
+#[no_mangle]
+pub extern "C" fn shorebird_last_update_result() -> LastUpdateResult {
+    log_on_error(
+        || {
+            Ok(match updater::last_update_attempt()? {
+                Some(attempt) => LastUpdateResult {
+                    timestamp: attempt.timestamp,
+                    result: UpdateResult {
+                        error: attempt.error.map_or(ShorebirdErrorCode::None, Into::into),
+                        ..attempt.status.into()
+                    },
+                },
+                None => LastUpdateResult {
+                    timestamp: 0,
+                    result: updater::UpdateStatus::NoUpdate.into(),
+                },
+            })
+        },
+        "fetching last update result",
+        LastUpdateResult {
+            timestamp: 0,
+            result: updater::UpdateStatus::UpdateHadError.into(),
+        },
+    )
+}
+
+/// Synchronously download and verify an update if one is available, without
+/// installing it.  Returns true if an update is ready for
+/// shorebird_install_downloaded_update.  Critical patches are installed
+/// straight away instead, so this returns false for them.
+#[no_mangle]
+pub extern "C" fn shorebird_download_update() -> bool {
+    log_on_error(
+        || {
+            Ok(matches!(
+                updater::download_update()?,
+                updater::UpdateStatus::UpdateDownloaded
+            ))
+        },
+        "downloading update without installing",
+        false,
+    )
+}
+
+/// Install the update downloaded by shorebird_download_update so it is used
+/// on the next run of the app.  Returns true if an update was installed.
+#[no_mangle]
+pub extern "C" fn shorebird_install_downloaded_update() -> bool {
+    log_on_error(
+        || {
+            Ok(matches!(
+                updater::install_downloaded_update()?,
+                updater::UpdateStatus::UpdateInstalled
+            ))
+        },
+        "installing downloaded update",
+        false,
+    )
+}
+
+/// Start a thread to download an update if one is available.  Returns
+/// immediately without starting another thread if one is already running,
+/// see shorebird_update_queue_state.
 #[no_mangle]
 pub extern "C" fn shorebird_start_update_thread() {
     updater::start_update_thread();
 }
 
+/// Whether an update is running or queued, see updater::UpdateQueueState.
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum ShorebirdUpdateQueueState {
+    /// No update is running.
+    Idle = 0,
+    /// An update is running (shorebird_update or the update thread).
+    Running = 1,
+    /// The update thread is running and will run once more when it is done,
+    /// see queue_follow_up_update in shorebird.yaml.
+    FollowUpQueued = 2,
+}
+
+impl From<updater::UpdateQueueState> for ShorebirdUpdateQueueState {
+    fn from(state: updater::UpdateQueueState) -> Self {
+        match state {
+            updater::UpdateQueueState::Idle => ShorebirdUpdateQueueState::Idle,
+            updater::UpdateQueueState::Running => ShorebirdUpdateQueueState::Running,
+            updater::UpdateQueueState::FollowUpQueued => ShorebirdUpdateQueueState::FollowUpQueued,
+        }
+    }
+}
+
+/// Whether an update is running or queued, e.g. so an app can show that it
+/// is checking.  Does not require shorebird_init.
+#[no_mangle]
+pub extern "C" fn shorebird_update_queue_state() -> ShorebirdUpdateQueueState {
+    updater::update_queue_state().into()
+}
+
 /// Tell the updater that we're launching from what it told us was the
 /// next patch to boot from. This will copy the next_boot patch to be the
 /// current_boot patch.
 ///
 /// It is required to call this function before calling
 /// shorebird_report_launch_success or shorebird_report_launch_failure.
+/// Calling it again for the same patch from the same process (e.g. on hot
+/// restart) does nothing.
 #[no_mangle]
 pub extern "C" fn shorebird_report_launch_start() {
     log_on_error(updater::report_launch_start, "reporting launch start", ());
 }
 
+/// Single-call alternative to shorebird_report_launch_start which also records
+/// that a boot of `patch_number` is pending.  If the app crashes (e.g. abort())
+/// before shorebird_report_launch_success or shorebird_report_launch_failure
+/// is called, the next shorebird_init treats the boot as a failure.
+/// `patch_number` must be shorebird_next_boot_patch_number().
+#[no_mangle]
+pub extern "C" fn shorebird_begin_boot(patch_number: usize) {
+    log_on_error(|| updater::begin_boot(patch_number), "beginning boot", ());
+}
+
 /// Report that the app failed to launch.  This will cause the updater to
 /// attempt to roll back to the previous version if this version has not
 /// been launched successfully before.
@@ -229,15 +1337,32 @@ pub extern "C" fn shorebird_report_launch_success() {
     );
 }
 
+/// Uninstall the most recently installed patch and make the previous good
+/// patch (if any) the one used on next boot.  The uninstalled patch is
+/// marked as bad and will not be downloaded again.  Intended to be triggered
+/// manually (e.g. from a debug menu) to undo a problematic patch.
+/// Returns true if a patch was rolled back.
+#[no_mangle]
+pub extern "C" fn shorebird_rollback_last_patch() -> bool {
+    log_on_error(
+        || updater::rollback_last_patch().map(|_| true),
+        "rolling back last patch",
+        false,
+    )
+}
+
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
     use crate::{
-        network::PatchCheckResponse, testing_set_network_hooks, updater::testing_reset_config,
+        network::{DownloadFileResponse, PatchCheckResponse},
+        testing_set_network_hooks,
+        updater::testing_reset_config,
     };
     use serial_test::serial;
     use tempdir::TempDir;
 
+    use std::sync::atomic::Ordering;
     use std::{ffi::CString, ptr::null_mut};
 
     fn c_string(string: &str) -> *mut libc::c_char {
@@ -283,10 +1408,16 @@ mod test {
         let app_paths = c_array(app_paths_vec);
 
         super::AppParameters {
+            size: std::mem::size_of::<super::AppParameters>(),
             cache_dir: c_string(&cache_dir),
             release_version: c_string("1.0.0"),
             original_libapp_paths: app_paths as *const *const libc::c_char,
             original_libapp_paths_size: app_paths_size,
+            build_number: 0,
+            app_id_override: std::ptr::null(),
+            download_dir: std::ptr::null(),
+            locale: std::ptr::null(),
+            debug_build: false,
         }
     }
 
@@ -307,20 +1438,116 @@ mod test {
         assert_eq!(shorebird_init(std::ptr::null(), std::ptr::null()), false);
     }
 
+    #[serial]
+    #[test]
+    fn init_with_file_callbacks() {
+        use std::io::Read;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn open(context: *mut c_void, name: *const c_char) -> *mut c_void {
+            if super::to_rust(name).unwrap() != "shorebird.yaml" {
+                return std::ptr::null_mut();
+            }
+            OPEN_FILES.fetch_add(1, Ordering::SeqCst);
+            let yaml = unsafe { &*(context as *const &[u8]) };
+            Box::into_raw(Box::new(std::io::Cursor::new(*yaml))) as *mut c_void
+        }
+        extern "C" fn read(handle: *mut c_void, buffer: *mut u8, count: usize) -> isize {
+            let file = unsafe { &mut *(handle as *mut std::io::Cursor<&[u8]>) };
+            let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, count) };
+            file.read(buffer).unwrap() as isize
+        }
+        extern "C" fn close(handle: *mut c_void) {
+            drop(unsafe { Box::from_raw(handle as *mut std::io::Cursor<&[u8]>) });
+            OPEN_FILES.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let mut yaml: &[u8] = b"app_id: from-callbacks";
+        let mut callbacks = super::FileCallbacks {
+            size: std::mem::size_of::<super::FileCallbacks>(),
+            context: &mut yaml as *mut &[u8] as *mut c_void,
+            open: Some(open),
+            read: Some(read),
+            close: None,
+        };
+        assert_eq!(
+            super::shorebird_init_with_file_callbacks(&c_params, &callbacks),
+            ShorebirdErrorCode::InvalidArgument
+        );
+        callbacks.close = Some(close);
+        assert_eq!(
+            super::shorebird_init_with_file_callbacks(&c_params, std::ptr::null()),
+            ShorebirdErrorCode::Unknown
+        );
+        // Callers must say how much of the struct they provide.
+        callbacks.size = 0;
+        assert_eq!(
+            super::shorebird_init_with_file_callbacks(&c_params, &callbacks),
+            ShorebirdErrorCode::Unknown
+        );
+        callbacks.size = std::mem::size_of::<super::FileCallbacks>();
+        assert_eq!(
+            super::shorebird_init_with_file_callbacks(&c_params, &callbacks),
+            ShorebirdErrorCode::None
+        );
+        free_parameters(c_params);
+        assert_eq!(OPEN_FILES.load(Ordering::SeqCst), 0);
+        let app_id = crate::config::with_config(|config| Ok(config.app_id.clone()));
+        assert_eq!(app_id.unwrap(), "from-callbacks");
+    }
+
     #[serial]
     #[test]
     fn init_with_null_app_parameters() {
         testing_reset_config();
         // Should log but not crash.
         let c_params = AppParameters {
+            size: std::mem::size_of::<AppParameters>(),
             cache_dir: std::ptr::null(),
             release_version: std::ptr::null(),
             original_libapp_paths: std::ptr::null(),
             original_libapp_paths_size: 0,
+            build_number: 0,
+            app_id_override: std::ptr::null(),
+            download_dir: std::ptr::null(),
+            locale: std::ptr::null(),
+            debug_build: false,
         };
         assert_eq!(shorebird_init(&c_params, std::ptr::null()), false);
     }
 
+    #[serial]
+    #[test]
+    fn init_with_older_app_parameters() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        c_params.build_number = 12;
+
+        // Fields past `size` are ignored, as an older caller wouldn't have
+        // them.
+        c_params.size = std::mem::offset_of!(AppParameters, build_number);
+        let config = super::app_config_from_c(&c_params).unwrap();
+        assert_eq!(config.build_number, None);
+        assert_eq!(config.release_version, "1.0.0");
+
+        c_params.size = std::mem::size_of::<AppParameters>();
+        let config = super::app_config_from_c(&c_params).unwrap();
+        assert_eq!(config.build_number, Some(12));
+
+        // Too small to hold the required fields.
+        c_params.size = super::MIN_APP_PARAMETERS_SIZE - 1;
+        assert!(super::app_config_from_c(&c_params).is_err());
+        let c_yaml = c_string("app_id: foo");
+        assert!(!shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+    }
+
     #[serial]
     #[test]
     fn init_with_bad_yaml() {
@@ -333,6 +1560,241 @@ mod test {
         free_parameters(c_params);
     }
 
+    #[serial]
+    #[test]
+    fn config_warnings() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo\nauto_updates: false\nchanel: beta");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let c_warnings = super::shorebird_get_config_warnings();
+        let warnings = super::to_rust(c_warnings).unwrap();
+        assert_eq!(
+            warnings,
+            "Unknown key in shorebird.yaml: auto_updates\nUnknown key in shorebird.yaml: chanel"
+        );
+        super::shorebird_free_string(c_warnings);
+
+        testing_reset_config();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+        assert!(super::shorebird_get_config_warnings().is_null());
+    }
+
+    #[serial]
+    #[test]
+    fn boot_decision_reason() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let mut patch_number = 7;
+        assert_eq!(
+            super::shorebird_boot_decision_reason(&mut patch_number),
+            super::ShorebirdBootDecisionReason::NoPatchInstalled
+        );
+        assert_eq!(patch_number, 0);
+        assert_eq!(
+            super::shorebird_boot_decision_reason(null_mut()),
+            super::ShorebirdBootDecisionReason::NoPatchInstalled
+        );
+        testing_reset_config();
+    }
+
+    #[serial]
+    #[test]
+    fn extra_headers() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo\nextra_headers:\n  \"X Bad\": a");
+        assert!(!shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        let c_yaml = c_string("app_id: foo\nextra_headers:\n  X-Org-Token: a");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let name = c_string("X-Org-Token");
+        let value = c_string("b");
+        let bad_value = c_string("b\nX-Injected: c");
+        assert!(super::shorebird_set_extra_header(name, value));
+        assert!(!super::shorebird_set_extra_header(name, bad_value));
+        assert!(!super::shorebird_set_extra_header(null_mut(), value));
+        assert_eq!(
+            crate::network::extra_headers().get("x-org-token"),
+            Some(&"b".to_string())
+        );
+        assert!(super::shorebird_set_extra_header(name, null_mut()));
+        assert!(crate::network::extra_headers().is_empty());
+        free_c_string(name);
+        free_c_string(value);
+        free_c_string(bad_value);
+        crate::network::testing_reset_extra_headers();
+        testing_reset_config();
+    }
+
+    #[serial]
+    #[test]
+    fn request_signing_key_callback() {
+        extern "C" fn provide_key(context: *mut c_void, buffer: *mut u8, len: usize) -> usize {
+            let key = unsafe { &*(context as *const [u8; 7]) };
+            assert!(len >= key.len());
+            unsafe { std::ptr::copy_nonoverlapping(key.as_ptr(), buffer, key.len()) };
+            key.len()
+        }
+        static KEY: [u8; 7] = *b"rotated";
+
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        // The key must be base64.
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo\nrequest_signing:\n  key: \"not base64!\"");
+        assert!(!shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+
+        let c_yaml = c_string("app_id: foo\nrequest_signing:\n  scheme: headers");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let url = "https://api.shorebird.dev/api/v1/patches/check";
+        // No key in shorebird.yaml and no callback.
+        assert!(crate::request_signing::signature_headers("POST", url, b"{}").is_err());
+
+        super::shorebird_set_request_signing_key_callback(
+            Some(provide_key),
+            &KEY as *const [u8; 7] as *mut c_void,
+        );
+        let headers = crate::request_signing::signature_headers("POST", url, b"{}").unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].0, "x-shorebird-request-signature");
+        super::shorebird_set_request_signing_key_callback(None, std::ptr::null_mut());
+        assert!(crate::request_signing::signature_headers("POST", url, b"{}").is_err());
+        testing_reset_config();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[serial]
+    #[test]
+    fn patch_key_callback() {
+        extern "C" fn provide_key(
+            _context: *mut c_void,
+            key_id: *const c_char,
+            buffer: *mut u8,
+            len: usize,
+        ) -> usize {
+            if unsafe { CStr::from_ptr(key_id) }.to_bytes() != b"k1" {
+                return 0;
+            }
+            assert!(len >= 32);
+            unsafe { std::ptr::write_bytes(buffer, 7, 32) };
+            32
+        }
+
+        super::shorebird_set_patch_key_callback(Some(provide_key), std::ptr::null_mut());
+        assert!(crate::encryption::key_for("k1").is_ok());
+        let error = crate::encryption::key_for("k2").unwrap_err();
+        assert_eq!(
+            ShorebirdErrorCode::from(&error),
+            ShorebirdErrorCode::PatchKeyUnavailable
+        );
+        super::shorebird_set_patch_key_callback(None, std::ptr::null_mut());
+        assert!(crate::encryption::key_for("k1").is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn installed_patches_json() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let c_json = super::shorebird_installed_patches_json();
+        assert_eq!(super::to_rust(c_json).unwrap(), "[]");
+        super::shorebird_free_string(c_json);
+    }
+
+    #[serial]
+    #[test]
+    fn health_check_json() {
+        testing_reset_config();
+        assert!(super::shorebird_run_health_check().is_null());
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let c_json = super::shorebird_run_health_check();
+        let report: serde_json::Value =
+            serde_json::from_str(&super::to_rust(c_json).unwrap()).unwrap();
+        super::shorebird_free_string(c_json);
+        assert_eq!(report["healthy"], true);
+        assert_eq!(report["checks"].as_array().unwrap().len(), 5);
+        assert!(report["download_progress"].is_null());
+
+        // Progress is reported without the (possibly signed) URL.
+        crate::config::with_config(|config| {
+            let mut state = crate::cache::UpdaterState::load_or_new_on_error(
+                &config.cache_dir,
+                &config.release_version,
+                &config.clock,
+                false,
+            );
+            state.set_partial_download(Some(crate::cache::PartialDownload {
+                url: "https://example.com/patch?token=secret".to_string(),
+                bytes_downloaded: 6,
+                etag: None,
+                total_bytes: Some(11),
+            }));
+            state.save()
+        })
+        .unwrap();
+        let c_json = super::shorebird_run_health_check();
+        let json = super::to_rust(c_json).unwrap();
+        super::shorebird_free_string(c_json);
+        assert!(!json.contains("secret"), "{}", json);
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            report["download_progress"],
+            serde_json::json!({"bytes_downloaded": 6, "total_bytes": 11})
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn last_install_transaction_json() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        let c_json = super::shorebird_last_install_transaction_json();
+        assert_eq!(super::to_rust(c_json).unwrap(), "null");
+        super::shorebird_free_string(c_json);
+    }
+
     #[serial]
     #[test]
     fn empty_state_no_update() {
@@ -354,6 +1816,9 @@ mod test {
         shorebird_report_launch_start();
         shorebird_report_launch_success();
         shorebird_report_launch_failure();
+
+        // There is nothing to roll back.
+        assert!(!shorebird_rollback_last_patch());
     }
 
     fn write_fake_zip(zip_path: &str, libapp_contents: &[u8]) {
@@ -368,19 +1833,25 @@ mod test {
         zip.finish().unwrap();
     }
 
-    #[serial]
-    #[test]
-    fn patch_success() {
-        testing_reset_config();
-        let tmp_dir = TempDir::new("example").unwrap();
+    /// Whether the patch offered by init_with_patch_available is critical.
+    static PATCH_IS_CRITICAL: std::sync::atomic::AtomicBool =
+        std::sync::atomic::AtomicBool::new(false);
 
-        // Generated by `string_patch "hello world" "hello tests"`
+    /// Called by testing_reset_config, so a failed test can't leave the
+    /// offered patch critical for the next.
+    pub(crate) fn testing_reset() {
+        PATCH_IS_CRITICAL.store(false, Ordering::SeqCst);
+    }
+
+    /// Initializes the updater with a server which offers patch 1, which
+    /// turns "hello world" into "hello tests".
+    fn init_with_patch_available(tmp_dir: &TempDir) {
+        testing_reset_config();
         let base = "hello world";
-        let expected_new: &str = "hello tests";
         let apk_path = tmp_dir.path().join("base.apk");
         write_fake_zip(apk_path.to_str().unwrap(), base.as_bytes());
         let fake_libapp_path = tmp_dir.path().join("lib/arch/ignored.so");
-        let c_params = parameters(&tmp_dir, fake_libapp_path.to_str().unwrap());
+        let c_params = parameters(tmp_dir, fake_libapp_path.to_str().unwrap());
         // app_id is required or shorebird_init will fail.
         let c_yaml = c_string("app_id: foo");
         assert_eq!(shorebird_init(&c_params, c_yaml), true);
@@ -398,27 +1869,208 @@ mod test {
                         number: 1,
                         hash: hash.to_owned(),
                         download_url: "ignored".to_owned(),
+                        priority: match PATCH_IS_CRITICAL.load(Ordering::SeqCst) {
+                            true => crate::PatchPriority::Critical,
+                            false => crate::PatchPriority::Normal,
+                        },
+                        metadata: Some(serde_json::json!({"git_sha": "abc123"})),
+                        ..Default::default()
                     }),
-                })
+                    ..Default::default()
+                }
+                .into())
             },
-            |_url| {
+            |_url, _resume_from| {
                 // Generated by `string_patch "hello world" "hello tests"`
                 let patch_bytes: Vec<u8> = vec![
                     40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
                     0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
                 ];
-                Ok(patch_bytes)
+                Ok(DownloadFileResponse {
+                    is_partial: false,
+                    etag: None,
+                    total_bytes: None,
+                    body: Box::new(std::io::Cursor::new(patch_bytes)),
+                })
             },
         );
+    }
+
+    #[serial]
+    #[test]
+    fn patch_success() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        // Generated by `string_patch "hello world" "hello tests"`
+        let expected_new: &str = "hello tests";
         shorebird_update();
 
         let version = shorebird_next_boot_patch_number();
         assert_eq!(version, 1);
 
         // Read path contents into memory and check against expected.
-        let path = to_rust(shorebird_next_boot_patch_path()).unwrap();
+        let c_path = shorebird_next_boot_patch_path();
+        let path = to_rust(c_path).unwrap();
+        shorebird_free_string(c_path);
         let new = std::fs::read_to_string(path).unwrap();
         assert_eq!(new, expected_new);
+
+        let c_metadata = super::shorebird_next_boot_patch_metadata();
+        assert_eq!(to_rust(c_metadata).unwrap(), r#"{"git_sha":"abc123"}"#);
+        shorebird_free_string(c_metadata);
+    }
+
+    #[serial]
+    #[test]
+    fn delegate_callbacks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static INSTALLED: AtomicUsize = AtomicUsize::new(0);
+        static INVALIDATED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn record(context: *mut c_void, patch_number: usize) {
+            let counter = unsafe { &*(context as *const AtomicUsize) };
+            counter.store(patch_number, Ordering::SeqCst);
+        }
+        extern "C" fn record_invalidated(_context: *mut c_void, patch_number: usize) {
+            INVALIDATED.store(patch_number, Ordering::SeqCst);
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        let delegate = super::ShorebirdDelegate {
+            context: &INSTALLED as *const AtomicUsize as *mut c_void,
+            on_patch_installed: Some(record),
+            on_patch_invalidated: Some(record_invalidated),
+            on_update_error: None,
+            on_restart_requested: None,
+        };
+        super::shorebird_set_delegate(&delegate);
+        shorebird_update();
+        assert_eq!(INSTALLED.load(Ordering::SeqCst), 1);
+        assert_eq!(INVALIDATED.load(Ordering::SeqCst), 0);
+
+        shorebird_report_launch_start();
+        shorebird_report_launch_failure();
+        assert_eq!(INVALIDATED.load(Ordering::SeqCst), 1);
+
+        super::shorebird_set_delegate(std::ptr::null());
+    }
+
+    #[serial]
+    #[test]
+    fn delegate_can_call_back_into_updater() {
+        use std::sync::atomic::AtomicBool;
+        static CALLBACK_OK: AtomicBool = AtomicBool::new(false);
+        extern "C" fn install_again(_context: *mut c_void, _patch_number: usize) {
+            let result = crate::install_downloaded_update();
+            CALLBACK_OK.store(result.is_ok(), Ordering::SeqCst);
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        let delegate = super::ShorebirdDelegate {
+            context: std::ptr::null_mut(),
+            on_patch_installed: Some(install_again),
+            on_patch_invalidated: None,
+            on_update_error: None,
+            on_restart_requested: None,
+        };
+        super::shorebird_set_delegate(&delegate);
+        shorebird_update();
+        super::shorebird_set_delegate(std::ptr::null());
+        // Not UpdateAlreadyInProgress, the update lock was released first.
+        assert!(CALLBACK_OK.load(Ordering::SeqCst));
+    }
+
+    #[serial]
+    #[test]
+    fn critical_patches_install_immediately() {
+        use std::sync::atomic::AtomicUsize;
+        static RESTART_REQUESTED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn record_restart(_context: *mut c_void, patch_number: usize) {
+            RESTART_REQUESTED.store(patch_number, Ordering::SeqCst);
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        PATCH_IS_CRITICAL.store(true, Ordering::SeqCst);
+        let delegate = super::ShorebirdDelegate {
+            context: std::ptr::null_mut(),
+            on_patch_installed: None,
+            on_patch_invalidated: None,
+            on_update_error: None,
+            on_restart_requested: Some(record_restart),
+        };
+        super::shorebird_set_delegate(&delegate);
+
+        // Installed without waiting for shorebird_install_downloaded_update.
+        assert!(!super::shorebird_download_update());
+        assert_eq!(shorebird_next_boot_patch_number(), 1);
+        assert_eq!(RESTART_REQUESTED.load(Ordering::SeqCst), 1);
+
+        PATCH_IS_CRITICAL.store(false, Ordering::SeqCst);
+        super::shorebird_set_delegate(std::ptr::null());
+    }
+
+    #[serial]
+    #[test]
+    fn update_with_result() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        let result = super::shorebird_update_with_result();
+        assert_eq!(result.status, super::ShorebirdUpdateStatus::UpdateInstalled);
+        assert_eq!(result.defer_reason, super::ShorebirdDeferReason::None);
+        assert_eq!(result.error, super::ShorebirdErrorCode::None);
+
+        let last = super::shorebird_last_update_result();
+        assert_ne!(last.timestamp, 0);
+        assert_eq!(
+            last.result.status,
+            super::ShorebirdUpdateStatus::UpdateInstalled
+        );
+        assert_eq!(last.result.error, super::ShorebirdErrorCode::None);
+
+        let result: super::UpdateResult =
+            crate::UpdateStatus::UpdateDeferred(crate::DeferReason::IncompatibleNativeFingerprint)
+                .into();
+        assert_eq!(result.status, super::ShorebirdUpdateStatus::UpdateDeferred);
+        assert_eq!(
+            result.defer_reason,
+            super::ShorebirdDeferReason::IncompatibleNativeFingerprint
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn download_then_install() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_patch_available(&tmp_dir);
+        // Nothing has been downloaded yet.
+        assert!(!shorebird_install_downloaded_update());
+
+        assert!(shorebird_download_update());
+        // Downloading does not change the next boot patch.
+        assert_eq!(shorebird_next_boot_patch_number(), 0);
+
+        assert!(shorebird_install_downloaded_update());
+        assert_eq!(shorebird_next_boot_patch_number(), 1);
+        // Only installs once.
+        assert!(!shorebird_install_downloaded_update());
+    }
+
+    #[cfg(feature = "allocation_tracking")]
+    #[serial]
+    #[test]
+    fn outstanding_allocations() {
+        let before = super::shorebird_testing_outstanding_allocations();
+        let c_string = super::allocate_c_string("hello").unwrap();
+        assert_eq!(
+            super::shorebird_testing_outstanding_allocations(),
+            before + 1
+        );
+        // Freeing NULL must not be counted.
+        shorebird_free_string(null_mut());
+        shorebird_free_string(c_string);
+        assert_eq!(super::shorebird_testing_outstanding_allocations(), before);
     }
 
     #[serial]
@@ -427,6 +2079,53 @@ mod test {
         testing_reset_config();
         assert_eq!(shorebird_next_boot_patch_number(), 0);
         assert_eq!(shorebird_next_boot_patch_path(), null_mut());
+        assert_eq!(super::shorebird_next_boot_patch_metadata(), null_mut());
+    }
+
+    #[serial]
+    #[test]
+    fn error_codes() {
+        use super::ShorebirdErrorCode;
+
+        testing_reset_config();
+        let mut update_available = true;
+        assert_eq!(
+            super::shorebird_check_for_update_with_result(&mut update_available),
+            ShorebirdErrorCode::ConfigNotInitialized
+        );
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("bad yaml");
+        assert_eq!(
+            super::shorebird_init_with_result(&c_params, c_yaml),
+            ShorebirdErrorCode::InvalidArgument
+        );
+        free_c_string(c_yaml);
+        let c_yaml = c_string("app_id: foo");
+        assert_eq!(
+            super::shorebird_init_with_result(&c_params, c_yaml),
+            ShorebirdErrorCode::None
+        );
+        assert_eq!(
+            super::shorebird_init_with_result(&c_params, c_yaml),
+            ShorebirdErrorCode::InvalidState
+        );
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        // The most specific recognized cause wins, regardless of context.
+        let error = anyhow::Error::from(crate::UpdateError::HashMismatch).context("Downloading");
+        assert_eq!(
+            ShorebirdErrorCode::from(&error),
+            ShorebirdErrorCode::HashMismatch
+        );
+        let error = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(ShorebirdErrorCode::from(&error), ShorebirdErrorCode::Io);
+        assert_eq!(
+            ShorebirdErrorCode::from(&anyhow::anyhow!("Something else")),
+            ShorebirdErrorCode::Unknown
+        );
     }
 
     #[serial]
@@ -485,17 +2184,19 @@ mod test {
                 // Hang until we have the lock.
                 let _lock = CALLBACK_MUTEX.lock().unwrap();
                 Ok(PatchCheckResponse {
-                    patch_available: false,
                     patch: Some(crate::Patch {
                         number: 1,
                         hash: "ignored".to_owned(),
                         download_url: "ignored".to_owned(),
+                        ..Default::default()
                     }),
-                })
+                    ..Default::default()
+                }
+                .into())
             },
-            |_url| {
+            |_url, _resume_from| {
                 // Never called.
-                Ok(Vec::new())
+                anyhow::bail!("unexpected download");
             },
         );
         {
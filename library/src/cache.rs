@@ -5,18 +5,27 @@
 // consistent and use patch number everywhere.
 // PatchInfo can probably go away.
 
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
-use crate::updater::UpdateError;
+use crate::file_errors::IoResultExt;
+
+use crate::decode_benchmark::DecodeBenchmark;
+use crate::install_transaction::{InstallStep, InstallTransaction};
+use crate::names::ReleaseVersion;
+use crate::network::ClientDirectives;
+use crate::time::Clock;
+use crate::updater::{UpdateError, UpdateErrorKind, UpdateStatus};
 
 // https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
 #[cfg(test)]
-use std::{println as info, println as warn}; // Workaround to use println! for logs.
+use std::{println as error, println as info, println as warn}; // Workaround to use println! for logs.
 
 /// The public interace for talking about patches to the Cache.
 #[derive(PartialEq, Debug)]
@@ -25,11 +34,442 @@ pub struct PatchInfo {
     pub number: usize,
 }
 
+impl PatchInfo {
+    /// See artifact_name().
+    pub fn artifact_name(&self, release_version: &str) -> String {
+        artifact_name(release_version, self.number)
+    }
+}
+
+/// The name of the files for patch_number of release_version on this ABI,
+/// e.g. "1.0.0+1_patch3_aarch64", so downloads sharing a directory (see
+/// AppConfig::download_dir) can't collide and are easy to tell apart.
+/// Installed artifacts are named by their hash instead, see Slot::hash.
+pub fn artifact_name(release_version: &str, patch_number: usize) -> String {
+    let release_version: String = release_version
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '+' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}_patch{}_{}",
+        release_version,
+        patch_number,
+        crate::config::current_arch()
+    )
+}
+
+/// A patch which is on disk, as reported by UpdaterState::installed_patches().
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct InstalledPatch {
+    pub number: usize,
+    pub path: PathBuf,
+    /// Size of the artifact on disk in bytes.
+    pub size: u64,
+    /// Hex-encoded sha256 of the artifact, if known.
+    pub hash: Option<String>,
+    /// The metadata the server sent with the patch, if any.
+    pub metadata: Option<String>,
+    pub is_current_boot: bool,
+    pub is_next_boot: bool,
+    /// Whether this patch has booted successfully on this device.
+    pub is_known_good: bool,
+}
+
 /// The private interface onto slots/patches within the cache.
 #[derive(Deserialize, Serialize, Default, Clone, Debug)]
 struct Slot {
     /// Patch number for the patch in this slot.
     patch_number: usize,
+    /// Hex-encoded sha256 of the artifact as installed (i.e. after any
+    /// padding), which is also its name in the objects directory, so patches
+    /// with identical contents share one file.  None for slots installed by
+    /// older versions of the updater, whose artifact is in the slot's
+    /// directory instead.
+    #[serde(default)]
+    hash: Option<String>,
+    /// When the artifact last matched `hash`, see check_artifact().
+    #[serde(default)]
+    validation: Option<ArtifactValidation>,
+    /// The storage the artifact was written to, see InstallJournal.
+    #[serde(default)]
+    install_journal: Option<InstallJournal>,
+}
+
+/// What the artifact and its storage looked like right after install.  If
+/// the artifact later fails its hash check this is logged with the failure,
+/// so devices with faulty flash can be told apart from e.g. a full disk.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct InstallJournal {
+    /// When the artifact was installed (seconds since epoch).
+    installed_at: u64,
+    size: u64,
+    /// Bytes free on the filesystem after install, if known.
+    free_bytes: Option<u64>,
+    /// e.g. "ext4" or "f2fs", if known.
+    filesystem: Option<String>,
+}
+
+/// What the artifact on disk looked like when it last matched its hash.
+/// While size and mtime are unchanged we trust the file without re-hashing
+/// (which is slow for large patches), up to REVALIDATE_AFTER_SECONDS.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct ArtifactValidation {
+    size: u64,
+    modified_nanos: u64,
+    /// When the hash was last checked (seconds since epoch).
+    verified_at: u64,
+}
+
+/// The next boot patch's artifact as next_boot_patch() handed it out, so
+/// report_launch_start() can tell whether it was swapped (e.g. by a storage
+/// cleaner) before the engine booted it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootArtifactSnapshot {
+    pub patch_number: usize,
+    path: PathBuf,
+    size: u64,
+    modified_nanos: u64,
+}
+
+impl BootArtifactSnapshot {
+    pub fn take(patch: &PatchInfo) -> anyhow::Result<Self> {
+        let (size, modified_nanos) = size_and_modified(&patch.path)?;
+        Ok(Self {
+            patch_number: patch.number,
+            path: patch.path.clone(),
+            size,
+            modified_nanos,
+        })
+    }
+
+    /// Whether the artifact changed since the snapshot was taken.  A new size
+    /// or mtime is forgiven if the contents still match the hash the
+    /// artifact is named by (see is_object_name).
+    pub fn changed(&self) -> bool {
+        match size_and_modified(&self.path) {
+            Ok((size, modified_nanos))
+                if size == self.size && modified_nanos == self.modified_nanos =>
+            {
+                return false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Failed to check boot artifact: {:#}", e);
+                return true;
+            }
+        }
+        // Artifacts installed by older updaters aren't named by hash, so
+        // have nothing to forgive by.
+        let expected = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| is_object_name(name));
+        match (expected, hash_file(&self.path)) {
+            (Some(expected), Ok(actual)) if actual == expected => false,
+            (_, Ok(actual)) => {
+                error!(
+                    "Boot artifact {:?} changed, expected: {:?}, got: {}",
+                    self.path, expected, actual
+                );
+                true
+            }
+            (_, Err(e)) => {
+                warn!("Failed to hash boot artifact: {:#}", e);
+                true
+            }
+        }
+    }
+}
+
+/// The state is a few KB, see UpdaterState::load.
+/// cbindgen:ignore
+const MAX_STATE_FILE_BYTES: u64 = 1024 * 1024;
+
+/// The state nests a handful of levels deep, see json_nesting_depth.
+/// cbindgen:ignore
+const MAX_STATE_JSON_DEPTH: usize = 16;
+
+/// The deepest nesting of arrays and objects in json, without parsing it.
+/// Checked before parsing so hostile input can't make the parser recurse
+/// deeply.  Malformed json is left for the parser to reject.
+fn json_nesting_depth(json: &[u8]) -> usize {
+    let mut depth: usize = 0;
+    let mut max_depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max_depth
+}
+
+/// How long an unchanged artifact is trusted before it is re-hashed anyway.
+/// cbindgen:ignore
+const REVALIDATE_AFTER_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// Where a newer release's state, slots and objects are kept while an older
+/// release runs, see handle_release_change().
+/// cbindgen:ignore
+const NEWER_RELEASE_DIR: &str = "newer_release";
+
+/// Bumped every time any UpdaterState is saved, so results computed from a
+/// loaded state can tell whether they are stale, see state_generation().
+static STATE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Changes whenever an UpdaterState is saved by this process.
+pub fn state_generation() -> u64 {
+    STATE_GENERATION.load(Ordering::SeqCst)
+}
+
+/// Each state file as this process last read or wrote it (empty if there
+/// was none), see state_changed_elsewhere().
+#[cfg(any(feature = "daemon", test))]
+fn last_seen_states() -> &'static std::sync::Mutex<BTreeMap<PathBuf, Vec<u8>>> {
+    use once_cell::sync::OnceCell;
+    static INSTANCE: OnceCell<std::sync::Mutex<BTreeMap<PathBuf, Vec<u8>>>> = OnceCell::new();
+    INSTANCE.get_or_init(Default::default)
+}
+
+#[cfg(any(feature = "daemon", test))]
+fn remember_state(path: &Path, contents: &[u8]) {
+    last_seen_states()
+        .lock()
+        .expect("State record lock poisoned")
+        .insert(path.to_owned(), contents.to_vec());
+}
+
+#[cfg(not(any(feature = "daemon", test)))]
+fn remember_state(_: &Path, _: &[u8]) {}
+
+/// Whether another process saved the state in cache_dir since this one last
+/// read or wrote it.  Only daemon mode shares the cache between processes,
+/// see daemon.rs.
+#[cfg(any(feature = "daemon", test))]
+pub fn state_changed_elsewhere(cache_dir: &Path) -> bool {
+    let path = cache_dir.join("state.json");
+    let on_disk = std::fs::read(&path).unwrap_or_default();
+    let seen = last_seen_states()
+        .lock()
+        .expect("State record lock poisoned")
+        .get(&path)
+        .cloned()
+        .unwrap_or_default();
+    on_disk != seen
+}
+
+/// A random u64.  RandomState is randomly keyed per instance, which is all
+/// the randomness we need without another dependency.
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// A new random client_id: 128 bits, hex-encoded.
+fn new_client_id() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+/// Where the client_id is kept.  Not a state file, so unlike state.json it
+/// survives the release version changing.
+const CLIENT_ID_FILE_NAME: &str = "client_id";
+
+fn is_valid_client_id(client_id: &str) -> bool {
+    client_id.len() == 32 && client_id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// This device's client_id from cache_dir, or if there isn't a valid one
+/// `legacy` (from a state.json written before the id had its own file) or a
+/// new one, which is saved.  If saving fails the id only lasts until the
+/// next load.
+fn device_client_id(cache_dir: &Path, legacy: Option<&str>) -> String {
+    let path = cache_dir.join(CLIENT_ID_FILE_NAME);
+    if let Ok(saved) = std::fs::read_to_string(&path) {
+        if is_valid_client_id(&saved) {
+            return saved;
+        }
+    }
+    let client_id = match legacy {
+        Some(legacy) if is_valid_client_id(legacy) => legacy.to_owned(),
+        _ => new_client_id(),
+    };
+    // Write to a temporary file and rename so the id is never partial.
+    let tmp_path = cache_dir.join("client_id.tmp");
+    let result = std::fs::create_dir_all(cache_dir)
+        .and_then(|()| std::fs::write(&tmp_path, &client_id))
+        .and_then(|()| std::fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+        warn!("Failed to save client_id to {:?}: {}", path, e);
+    }
+    client_id
+}
+
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(crate::time::SystemClock)
+}
+
+/// Returns the size and mtime of the file at path.
+fn size_and_modified(path: &Path) -> anyhow::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path).with_file_context("read metadata of", path)?;
+    let modified = metadata
+        .modified()
+        .with_file_context("read mtime of", path)?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64);
+    Ok((metadata.len(), modified))
+}
+
+/// Whether name could be an object in the objects directory, i.e. a
+/// hex-encoded sha256.  Anything else (e.g. from a corrupt state.json) must
+/// not be used as a path.
+pub fn is_object_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The sha256 of the file at path, as hex.
+pub fn hash_file(path: &Path) -> anyhow::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut file = File::open(path).with_file_context("open", path)?;
+    let mut hasher = Sha256::new();
+    // sha2 is built without std, so has no io::Write to copy into.
+    let mut buffer = [0u8; 8192];
+    loop {
+        match std::io::Read::read(&mut file, &mut buffer).with_file_context("read", path)? {
+            0 => break,
+            count => hasher.update(&buffer[..count]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl InstallJournal {
+    fn now(path: &Path, size: u64, now: u64) -> Self {
+        Self {
+            installed_at: now,
+            size,
+            free_bytes: crate::storage::free_space(path),
+            filesystem: crate::storage::filesystem_type(path),
+        }
+    }
+
+    /// Describes the install, for logging alongside a corrupt artifact whose
+    /// size is now `size`.
+    fn describe(&self, size: u64) -> String {
+        let unknown = || "unknown".to_string();
+        format!(
+            "installed at {} with size {} (now {}), {} bytes free on {} filesystem",
+            self.installed_at,
+            self.size,
+            size,
+            self.free_bytes
+                .map_or_else(unknown, |free| free.to_string()),
+            self.filesystem.clone().unwrap_or_else(unknown)
+        )
+    }
+}
+
+impl ArtifactValidation {
+    /// Records that the artifact at path matches its hash as of now.
+    fn now(path: &Path, now: u64) -> anyhow::Result<Self> {
+        let (size, modified_nanos) = size_and_modified(path)?;
+        Ok(Self {
+            size,
+            modified_nanos,
+            verified_at: now,
+        })
+    }
+}
+
+/// A download which was interrupted before completion, recorded so the
+/// next update() can resume it with a ranged request.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+pub struct PartialDownload {
+    /// The URL being downloaded.  A different URL starts a fresh download.
+    pub url: String,
+    /// Number of bytes already written to disk.
+    pub bytes_downloaded: u64,
+    /// The ETag the server reported, used so we only resume if the file on
+    /// the server has not changed.
+    pub etag: Option<String>,
+    /// The size of the whole file, if the server said.  Only used to report
+    /// progress, see health::run_health_check().
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+}
+
+/// The outcome of the most recent update(), so apps can show when the
+/// updater last ran and what happened without triggering a new check.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct UpdateAttempt {
+    /// When update() finished (seconds since epoch).
+    pub timestamp: u64,
+    pub status: UpdateStatus,
+    /// Why update() failed, only set when status is UpdateHadError.
+    pub error: Option<UpdateErrorKind>,
+}
+
+/// Why the updater refused to boot a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchRejection {
+    /// The patch failed to launch or was rolled back.
+    MarkedBad,
+    /// The patch's artifact is no longer on disk.
+    ArtifactMissing,
+    /// The patch's artifact could not be read.
+    ArtifactUnreadable,
+    /// The patch's artifact no longer matches its hash (e.g. corrupted
+    /// storage).
+    HashMismatch,
+    /// The patch is not in shorebird.yaml's patch_allowlist.
+    NotAllowlisted,
+    /// The app was updated to a new release, which doesn't use patches for
+    /// the old one.
+    ReleaseVersionChanged,
+    /// The patch's artifact changed between being selected for boot and the
+    /// launch starting, see BootArtifactSnapshot.
+    ChangedBeforeBoot,
+    /// The app was replaced by an older release (e.g. a sideloaded older
+    /// APK).  The patch is kept for if the newer release returns, see
+    /// handle_release_change().
+    ReleaseVersionDowngraded,
+}
+
+/// The most recent patch the updater refused to boot, and why.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RejectedPatch {
+    pub patch_number: usize,
+    pub reason: PatchRejection,
+}
+
+/// How long a patch took to boot, from report_launch_start() (or
+/// begin_boot()) to report_launch_success().
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BootTiming {
+    pub patch_number: usize,
+    pub duration_ms: u64,
 }
 
 // This struct is public, as callers can have a handle to it, but modifying
@@ -41,7 +481,7 @@ pub struct UpdaterState {
     /// The release version this cache corresponds to.
     /// If this does not match the release version we're booting from we will
     /// clear the cache.
-    release_version: String,
+    release_version: ReleaseVersion,
     /// List of patches that failed to boot.  We will never attempt these again.
     failed_patches: Vec<usize>,
     /// List of patches that successfully booted. We will never rollback past
@@ -53,12 +493,72 @@ pub struct UpdaterState {
     next_boot_slot_index: Option<usize>,
     /// List of slots.
     slots: Vec<Slot>,
+    /// Download in progress, if any.
+    #[serde(default)]
+    partial_download: Option<PartialDownload>,
+    /// Directives from the most recent patch check.
+    #[serde(default)]
+    client_directives: ClientDirectives,
+    /// When the most recent patch check completed (seconds since epoch).
+    #[serde(default)]
+    last_patch_check_timestamp: Option<u64>,
+    /// The server asked us not to check for patches before this time
+    /// (seconds since epoch), e.g. with Retry-After.
+    #[serde(default)]
+    check_not_before: Option<u64>,
+    /// Patch which has been downloaded and verified but not yet installed.
+    #[serde(default)]
+    staged_patch_number: Option<usize>,
+    /// See client_id().  Kept in its own file, so only read from older
+    /// states, whose id is moved there.
+    #[serde(default, skip_serializing)]
+    client_id: String,
+    /// The outcome of the most recent update(), if any.
+    #[serde(default)]
+    last_update_attempt: Option<UpdateAttempt>,
+    /// Opaque metadata (e.g. git sha) the server sent with each patch, by
+    /// patch number.  Only kept for patches still on disk.
+    #[serde(default)]
+    patch_metadata: BTreeMap<usize, String>,
+    /// Hash of each patch as sent by the server (and checked on download),
+    /// by patch number, for checking against patch_allowlist at boot.  Only
+    /// kept for patches still on disk.
+    #[serde(default)]
+    patch_hashes: BTreeMap<usize, String>,
+    /// A critical patch (see PatchPriority) the server offered in the most
+    /// recent patch check which has not been installed yet.
+    #[serde(default)]
+    critical_patch_number: Option<usize>,
+    /// The most recent successful boot's timing, until it is sent with a
+    /// patch check.
+    #[serde(default)]
+    last_boot_timing: Option<BootTiming>,
+    /// The most recent patch refused at boot, until another is installed.
+    #[serde(default)]
+    last_rejected_patch: Option<RejectedPatch>,
+    /// The patch QA pinned this device to with pin_patch(), if any.
+    #[serde(default)]
+    pinned_patch_number: Option<usize>,
+    /// Whether the decode benchmark has run, it only runs once.
+    #[serde(default)]
+    decode_benchmark_ran: bool,
+    /// The decode benchmark's result, until it is sent with a patch check.
+    #[serde(default)]
+    decode_benchmark: Option<DecodeBenchmark>,
+    /// Where timestamps (e.g. ArtifactValidation::verified_at) come from, see
+    /// UpdateConfig::clock.
+    #[serde(skip, default = "default_clock")]
+    clock: Arc<dyn Clock>,
+    /// Whether save() syncs the state to disk, see UpdateConfig::durable_writes.
+    #[serde(skip)]
+    durable_writes: bool,
     // Add file path or FD so modifying functions can save it to disk?
 }
 
 impl UpdaterState {
-    fn new(cache_dir: PathBuf, release_version: String) -> Self {
+    fn new(cache_dir: PathBuf, release_version: ReleaseVersion) -> Self {
         Self {
+            client_id: device_client_id(&cache_dir, None),
             cache_dir,
             release_version,
             current_boot_slot_index: None,
@@ -66,6 +566,22 @@ impl UpdaterState {
             failed_patches: Vec::new(),
             successful_patches: Vec::new(),
             slots: Vec::new(),
+            partial_download: None,
+            client_directives: ClientDirectives::default(),
+            last_patch_check_timestamp: None,
+            check_not_before: None,
+            staged_patch_number: None,
+            last_update_attempt: None,
+            patch_metadata: BTreeMap::new(),
+            patch_hashes: BTreeMap::new(),
+            critical_patch_number: None,
+            last_boot_timing: None,
+            last_rejected_patch: None,
+            pinned_patch_number: None,
+            decode_benchmark_ran: false,
+            decode_benchmark: None,
+            clock: default_clock(),
+            durable_writes: false,
         }
     }
 }
@@ -90,6 +606,26 @@ impl UpdaterState {
         }
         info!("Marking patch {} as bad", patch_number);
         self.failed_patches.push(patch_number);
+        self.reject_patch(patch_number, PatchRejection::MarkedBad);
+    }
+
+    /// Records that patch_number won't be booted, for boot_decision().
+    pub fn reject_patch(&mut self, patch_number: usize, reason: PatchRejection) {
+        self.last_rejected_patch = Some(RejectedPatch {
+            patch_number,
+            reason,
+        });
+        // Otherwise a server still offering it would keep bypassing the
+        // usual auto update gating.
+        if self.critical_patch_number == Some(patch_number) {
+            self.critical_patch_number = None;
+        }
+    }
+
+    /// The most recent patch refused at boot since a patch was last
+    /// installed, if any.
+    pub fn last_rejected_patch(&self) -> Option<RejectedPatch> {
+        self.last_rejected_patch
     }
 
     pub fn mark_patch_as_good(&mut self, patch_number: usize) {
@@ -104,51 +640,139 @@ impl UpdaterState {
         self.successful_patches.push(patch_number);
     }
 
-    fn load(cache_dir: &Path) -> anyhow::Result<Self> {
+    /// Loads the state as saved, without the checks (or clearing) done by
+    /// load_or_new_on_error().
+    pub fn load(cache_dir: &Path) -> anyhow::Result<Self> {
+        use std::io::Read;
+
         // Load UpdaterState from disk
         let path = cache_dir.join("state.json");
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+        let file = File::open(&path).with_file_context("open", &path)?;
+        // This runs during boot, so refuse to spend long on a file which
+        // can't be one we wrote (e.g. one filled with garbage).
+        let mut contents = Vec::new();
+        BufReader::new(file)
+            .take(MAX_STATE_FILE_BYTES + 1)
+            .read_to_end(&mut contents)
+            .with_file_context("read", &path)?;
+        anyhow::ensure!(
+            contents.len() as u64 <= MAX_STATE_FILE_BYTES,
+            "State file is larger than {} bytes",
+            MAX_STATE_FILE_BYTES
+        );
+        anyhow::ensure!(
+            json_nesting_depth(&contents) <= MAX_STATE_JSON_DEPTH,
+            "State file is nested deeper than {} levels",
+            MAX_STATE_JSON_DEPTH
+        );
         // TODO: Now that we depend on serde_yaml for shorebird.yaml
         // we could use yaml here instead of json.
-        let state = serde_json::from_reader(reader)?;
+        let mut state: Self = serde_json::from_slice(&contents)?;
+        remember_state(&path, &contents);
+        // The state may have been moved (e.g. by migration), so trust the
+        // directory we loaded it from over the one it recorded.
+        state.cache_dir = cache_dir.to_owned();
+        state.client_id = device_client_id(cache_dir, Some(&state.client_id));
         Ok(state)
     }
 
-    pub fn load_or_new_on_error(cache_dir: &Path, release_version: &str) -> Self {
+    /// `clock` is used for timestamps in the state, including when checking
+    /// installed patches while loading.  If `durable_writes`, save() syncs.
+    pub fn load_or_new_on_error(
+        cache_dir: &Path,
+        release_version: &ReleaseVersion,
+        clock: &Arc<dyn Clock>,
+        durable_writes: bool,
+    ) -> Self {
+        let new = || Self {
+            clock: clock.clone(),
+            durable_writes,
+            ..Self::new(cache_dir.to_owned(), release_version.to_owned())
+        };
         let load_result = Self::load(cache_dir);
         match load_result {
             Ok(mut loaded) => {
-                if loaded.release_version != release_version {
+                loaded.clock = clock.clone();
+                loaded.durable_writes = durable_writes;
+                if &loaded.release_version != release_version {
                     info!(
                         "release_version changed {} -> {}, clearing updater state",
                         loaded.release_version, release_version
                     );
-                    return Self::new(cache_dir.to_owned(), release_version.to_owned());
+                    let mut state = new();
+                    if let Some(patch) = loaded.next_boot_patch() {
+                        state.reject_patch(patch.number, PatchRejection::ReleaseVersionChanged);
+                    }
+                    return state;
                 }
                 let validate_result = loaded.validate();
                 if let Err(e) = validate_result {
                     info!("Error while validating state: {:#}, clearing state.", e);
-                    return Self::new(cache_dir.to_owned(), release_version.to_owned());
+                    return new();
                 }
                 loaded
             }
             Err(e) => {
-                // FIXME: Should match on errorKind and display a warning if it's
-                // not a file not found error.
-                info!("No cached state, making empty: {:#}", e);
-                Self::new(cache_dir.to_owned(), release_version.to_owned())
+                let path = cache_dir.join("state.json");
+                // Either missing or about to be moved aside.
+                remember_state(&path, &[]);
+                if path.exists() {
+                    // Keep the unreadable state for diagnosis, and so it
+                    // isn't read (slowly, if it is huge) on every load.
+                    let backup_path = cache_dir.join("state.json.corrupt");
+                    warn!(
+                        "Unreadable state, moving it to {:?} and making empty: {:#}",
+                        backup_path, e
+                    );
+                    if let Err(e) = std::fs::rename(&path, &backup_path) {
+                        error!("Failed to move unreadable state: {:#}", e);
+                    }
+                } else {
+                    info!("No cached state, making empty: {:#}", e);
+                }
+                new()
             }
         }
     }
 
     pub fn save(&self) -> anyhow::Result<()> {
         // Save UpdaterState to disk
-        std::fs::create_dir_all(&self.cache_dir).context("create_dir_all")?;
+        std::fs::create_dir_all(&self.cache_dir)
+            .with_file_context("create directory", &self.cache_dir)?;
+        // Written next to state.json and then renamed over it, so a failed
+        // or interrupted save leaves the previous state intact rather than a
+        // truncated file.  The rename is what makes e.g. an install (the
+        // next_boot_slot_index pointer moving to the new slot) take effect.
         let path = Path::new(&self.cache_dir).join("state.json");
-        let file = File::create(path).context("File::create for state.json")?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, self)?;
+        let temp_path = Path::new(&self.cache_dir).join("state.json.tmp");
+        // In daemon mode another process may have saved since this state
+        // was loaded, and overwriting would lose what it saved (e.g. the
+        // app marking a patch bad).
+        #[cfg(feature = "daemon")]
+        let _state_lock = crate::daemon::StateLock::acquire(&self.cache_dir)?;
+        #[cfg(feature = "daemon")]
+        anyhow::ensure!(
+            !state_changed_elsewhere(&self.cache_dir),
+            "State was saved by another process since it was loaded, not overwriting it"
+        );
+        let contents = serde_json::to_vec_pretty(self)?;
+        STATE_GENERATION.fetch_add(1, Ordering::SeqCst);
+        // Without syncing, a power cut after the rename can leave an empty
+        // state.json (or the old one) behind.
+        if self.durable_writes {
+            use std::io::Write;
+            let mut file = File::create(&temp_path).with_file_context("create", &temp_path)?;
+            file.write_all(&contents)
+                .and_then(|()| file.sync_all())
+                .with_file_context("write", &temp_path)?;
+        } else {
+            std::fs::write(&temp_path, &contents).with_file_context("write", &temp_path)?;
+        }
+        std::fs::rename(&temp_path, &path).with_file_context("move state to", &path)?;
+        if self.durable_writes {
+            crate::updater::sync_dir(&self.cache_dir)?;
+        }
+        remember_state(&path, &contents);
         Ok(())
     }
 
@@ -187,6 +811,38 @@ impl UpdaterState {
         None
     }
 
+    /// Every patch whose artifact is still on disk, ordered by patch number.
+    /// Slots whose artifact has gone missing are left out.
+    pub fn installed_patches(&self) -> Vec<InstalledPatch> {
+        let mut patches: Vec<InstalledPatch> = (0..self.slots.len())
+            .filter_map(|index| {
+                let slot = &self.slots[index];
+                let path = self.patch_path_for_index(index);
+                let size = std::fs::metadata(&path).ok()?.len();
+                Some(InstalledPatch {
+                    number: slot.patch_number,
+                    size,
+                    hash: slot.hash.clone(),
+                    metadata: self.patch_metadata(slot.patch_number).map(str::to_owned),
+                    is_current_boot: self.current_boot_slot_index == Some(index),
+                    is_next_boot: self.next_boot_slot_index == Some(index),
+                    is_known_good: self.is_known_good_patch(slot.patch_number),
+                    path,
+                })
+            })
+            .collect();
+        patches.sort_by_key(|patch| patch.number);
+        patches
+    }
+
+    /// The numbers of installed_patches(), ascending.
+    pub fn installed_patch_numbers(&self) -> Vec<usize> {
+        self.installed_patches()
+            .into_iter()
+            .map(|patch| patch.number)
+            .collect()
+    }
+
     fn validate(&mut self) -> anyhow::Result<()> {
         // iterate through all slots:
         // Make sure they're still valid.
@@ -195,10 +851,28 @@ impl UpdaterState {
         let mut needs_save = false;
         // Iterate backwards so we can remove slots.
         for i in (0..slot_count).rev() {
+            if let Err(e) = self.move_slot_to_objects(i) {
+                warn!(
+                    "Failed to move slot {} to the objects directory: {:#}",
+                    i, e
+                );
+            }
             let slot = &self.slots[i];
-            if !self.validate_slot(slot) {
-                warn!("Slot {} is invalid, clearing.", i);
+            let problem = self
+                .slot_problem(slot)
+                .or_else(|| self.check_artifact(i, &mut needs_save).err());
+            if let Some(reason) = problem {
+                warn!("Slot {} is invalid ({:?}), clearing.", i, reason);
+                let patch_number = self.slots[i].patch_number;
+                // Nothing was installed in an empty slot.
+                if patch_number != 0 {
+                    self.reject_patch(patch_number, reason);
+                }
                 self.clear_slot(i)?;
+                // Boot the release rather than a cleared slot.
+                if self.next_boot_slot_index == Some(i) {
+                    self.set_next_boot_patch_slot(None);
+                }
                 needs_save = true;
             }
         }
@@ -208,11 +882,191 @@ impl UpdaterState {
         Ok(())
     }
 
+    /// Deletes slot directories on disk which no slot in the state refers to
+    /// (e.g. left behind by an interrupted install or an older layout).
+    /// Slots whose artifacts are missing are already dropped by validate().
+    /// Returns the number of directories removed.
+    pub fn remove_orphaned_slot_dirs(&self) -> anyhow::Result<usize> {
+        let entries = match std::fs::read_dir(&self.cache_dir) {
+            Ok(entries) => entries,
+            // Nothing on disk (e.g. removed externally), so nothing orphaned.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_file_context("read directory", &self.cache_dir),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let index = match name.to_string_lossy().strip_prefix("slot_") {
+                Some(index) => index.parse::<usize>().ok(),
+                None => continue,
+            };
+            // Slots with a hash keep their artifact in the objects directory.
+            let in_use = index.is_some_and(|i| {
+                i < self.slots.len()
+                    && self.slots[i].patch_number != 0
+                    && !self.uses_objects(&self.slots[i])
+            });
+            if !in_use && entry.path().is_dir() {
+                warn!("Removing orphaned slot directory {:?}", entry.path());
+                std::fs::remove_dir_all(entry.path()).with_file_context("remove", &entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes objects which no slot refers to (e.g. after the release
+    /// version changed, or an interrupted install).  Returns the number of
+    /// objects removed.
+    pub fn remove_unreferenced_objects(&self) -> anyhow::Result<usize> {
+        let objects_dir = self.objects_dir();
+        let entries = match std::fs::read_dir(&objects_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_file_context("read directory", &objects_dir),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !self.is_object_referenced(&name.to_string_lossy()) {
+                warn!("Removing unreferenced object {:?}", entry.path());
+                std::fs::remove_file(entry.path()).with_file_context("remove", &entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn is_object_referenced(&self, name: &str) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.patch_number != 0 && slot.hash.as_deref() == Some(name))
+    }
+
+    /// Moves the artifact of a slot written before the objects directory
+    /// existed into it, if it has a hash to name it by.
+    fn move_slot_to_objects(&mut self, index: usize) -> anyhow::Result<()> {
+        let slot = &self.slots[index];
+        let legacy_path = self.slot_dir_for_index(index).join("dlc.vmcode");
+        if !self.uses_objects(slot) || !legacy_path.exists() {
+            return Ok(());
+        }
+        let object_path = self.patch_path_for_index(index);
+        info!("Moving {:?} to {:?}", legacy_path, object_path);
+        if object_path.exists() {
+            std::fs::remove_file(&legacy_path).with_file_context("remove", &legacy_path)?;
+        } else {
+            let objects_dir = self.objects_dir();
+            std::fs::create_dir_all(&objects_dir)
+                .with_file_context("create directory", &objects_dir)?;
+            std::fs::rename(&legacy_path, &object_path)
+                .with_file_context("move patch to", &object_path)?;
+        }
+        let slot_dir = self.slot_dir_for_index(index);
+        std::fs::remove_dir_all(&slot_dir).with_file_context("remove", &slot_dir)
+    }
+
+    /// Checks the artifact in slot `index` still matches the hash recorded at
+    /// install, re-hashing only if it changed on disk or was last checked
+    /// more than REVALIDATE_AFTER_SECONDS ago.  Sets `needs_save` if it
+    /// records a new check.
+    fn check_artifact(
+        &mut self,
+        index: usize,
+        needs_save: &mut bool,
+    ) -> Result<(), PatchRejection> {
+        let expected = match &self.slots[index].hash {
+            Some(hash) => hash.clone(),
+            None => return Ok(()),
+        };
+        let path = self.patch_path_for_index(index);
+        let (size, modified_nanos) = match size_and_modified(&path) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Failed to check patch artifact: {:#}", e);
+                return Err(PatchRejection::ArtifactUnreadable);
+            }
+        };
+        let now = self.clock.unix_timestamp();
+        if let Some(validation) = &self.slots[index].validation {
+            let unchanged = validation.size == size && validation.modified_nanos == modified_nanos;
+            // A clock which went backwards forces a re-hash, which is fine.
+            let recent = now >= validation.verified_at
+                && now - validation.verified_at < REVALIDATE_AFTER_SECONDS;
+            if unchanged && recent {
+                return Ok(());
+            }
+        }
+        info!("Re-hashing patch artifact {:?}", path);
+        match hash_file(&path) {
+            Ok(hash) if hash == expected => {
+                self.slots[index].validation = Some(ArtifactValidation {
+                    size,
+                    modified_nanos,
+                    verified_at: now,
+                });
+                *needs_save = true;
+                Ok(())
+            }
+            Ok(hash) => {
+                error!(
+                    "Hash mismatch for patch artifact {:?}, expected: {}, got: {}",
+                    path, expected, hash
+                );
+                if let Some(journal) = &self.slots[index].install_journal {
+                    error!(
+                        "Patch {} was {}.",
+                        self.slots[index].patch_number,
+                        journal.describe(size)
+                    );
+                }
+                Err(PatchRejection::HashMismatch)
+            }
+            Err(e) => {
+                warn!("Failed to hash patch artifact: {:#}", e);
+                Err(PatchRejection::ArtifactUnreadable)
+            }
+        }
+    }
+
+    /// Re-hashes the artifact of patch_number, regardless of when it was
+    /// last checked.  Returns whether it was hashed: patches installed
+    /// without a recorded hash pass if their artifact exists.
+    pub fn verify_patch_artifact(&self, patch_number: usize) -> anyhow::Result<bool> {
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.patch_number == patch_number)
+            .ok_or_else(|| anyhow::anyhow!("Patch {} is not installed", patch_number))?;
+        let path = self.patch_path_for_index(index);
+        let Some(expected) = &self.slots[index].hash else {
+            // Nothing to compare against, so don't spend time hashing.
+            anyhow::ensure!(path.exists(), "{} is missing", path.display());
+            return Ok(false);
+        };
+        let actual = hash_file(&path)?;
+        anyhow::ensure!(
+            &actual == expected,
+            "Hash mismatch for {}, expected: {}, got: {}",
+            path.display(),
+            expected,
+            actual
+        );
+        Ok(true)
+    }
+
     fn validate_slot(&self, slot: &Slot) -> bool {
+        self.slot_problem(slot).is_none()
+    }
+
+    /// Why slot can't be booted, if it can't.
+    fn slot_problem(&self, slot: &Slot) -> Option<PatchRejection> {
         // Check if the patch is known bad.
         if self.is_known_bad_patch(slot.patch_number) {
             info!("Slot {:?} is known bad.", slot);
-            return false;
+            return Some(PatchRejection::MarkedBad);
         }
         let index = self
             .slots
@@ -221,17 +1075,10 @@ impl UpdaterState {
         let patch_path = self.patch_path_for_index(index.unwrap());
         if !patch_path.exists() {
             info!("Slot {:?} {} does not exist.", slot, patch_path.display());
-            return false;
+            return Some(PatchRejection::ArtifactMissing);
         }
-        // TODO: This should also check if the hash matches?
-        // let hash = compute_hash(&PathBuf::from(&slot.path));
-        // if let Ok(hash) = hash {
-        //     if hash == slot.hash {
-        //         return true;
-        //     }
-        //     error!("Hash mismatch for slot: {:?}", slot);
-        // }
-        true
+        // Hashes are checked separately (and cached) by check_artifact().
+        None
     }
 
     fn latest_bootable_slot(&self) -> Option<usize> {
@@ -249,25 +1096,48 @@ impl UpdaterState {
         None
     }
 
+    /// Makes the highest bootable patch for which `allowed` returns true the
+    /// next boot patch, or the base release if there is none.  Not saved.
+    pub fn activate_latest_bootable_patch_where(&mut self, allowed: impl Fn(usize) -> bool) {
+        let slot_index = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| allowed(slot.patch_number) && self.validate_slot(slot))
+            .max_by_key(|(_, slot)| slot.patch_number)
+            .map(|(index, _)| index);
+        self.set_next_boot_patch_slot(slot_index);
+    }
+
     pub fn activate_latest_bootable_patch(&mut self) -> Result<(), UpdateError> {
         self.set_next_boot_patch_slot(self.latest_bootable_slot());
         self.save().map_err(|_| UpdateError::FailedToSaveState)
     }
 
-    fn available_slot(&self) -> usize {
-        // Assume we only use two slots and pick the one that's not current.
-        if self.slots.is_empty() {
-            return 0;
+    /// Picks the slot to install the next patch into, using at most
+    /// `retain_patch_count` slots.  Never picks the currently booted slot.
+    /// Slots holding patches which have booted successfully are kept as
+    /// fallbacks for as long as there is room.
+    fn available_slot(&self, retain_patch_count: usize) -> usize {
+        // Always keep at least the booted slot and one to install into.
+        let retain_patch_count = std::cmp::max(retain_patch_count, 2);
+        let replaceable = |index: &usize| self.current_boot_slot_index != Some(*index);
+        // This does not check next_boot_slot_index, we're assuming that
+        // whoever is calling this is OK with replacing the next boot patch.
+        let not_fallback = (0..self.slots.len())
+            .filter(replaceable)
+            .find(|i| !self.is_known_good_patch(self.slots[*i].patch_number));
+        if let Some(slot_index) = not_fallback {
+            return slot_index;
         }
-        if let Some(slot_index) = self.current_boot_slot_index {
-            // This does not check next_boot_slot_index, we're assuming that
-            // whoever is calling this is OK with replacing the next boot
-            // patch.
-            if slot_index == 0 {
-                return 1;
-            }
+        if self.slots.len() < retain_patch_count {
+            return self.slots.len();
         }
-        return 0;
+        // Out of room, replace the oldest fallback.
+        (0..self.slots.len())
+            .filter(replaceable)
+            .min_by_key(|i| self.slots[*i].patch_number)
+            .unwrap_or(0)
     }
 
     fn clear_slot(&mut self, index: usize) -> anyhow::Result<()> {
@@ -276,10 +1146,23 @@ impl UpdaterState {
             // Ignore slots past the end for now?
             return Ok(());
         }
-        self.slots[index] = Slot::default();
+        let slot = std::mem::take(&mut self.slots[index]);
+        self.remove_slot_files(index, &slot)
+    }
+
+    /// Deletes what `slot`, which used to be in slot `index`, kept on disk.
+    fn remove_slot_files(&self, index: usize, slot: &Slot) -> anyhow::Result<()> {
         let slot_dir_string = self.slot_dir_for_index(index);
         if slot_dir_string.exists() {
-            std::fs::remove_dir_all(&slot_dir_string)?;
+            std::fs::remove_dir_all(&slot_dir_string)
+                .with_file_context("remove", &slot_dir_string)?;
+        }
+        // Other patches may have the same contents.
+        if let Some(hash) = slot.hash.as_deref().filter(|_| self.uses_objects(slot)) {
+            let object_path = self.objects_dir().join(hash);
+            if !self.is_object_referenced(hash) && object_path.exists() {
+                std::fs::remove_file(&object_path).with_file_context("remove", &object_path)?;
+            }
         }
         Ok(())
     }
@@ -296,22 +1179,39 @@ impl UpdaterState {
     }
 
     fn patch_path_for_index(&self, index: usize) -> PathBuf {
-        self.slot_dir_for_index(index).join("dlc.vmcode")
+        match self.slots.get(index) {
+            Some(slot) if self.uses_objects(slot) => self
+                .objects_dir()
+                .join(slot.hash.as_deref().unwrap_or_default()),
+            _ => self.slot_dir_for_index(index).join("dlc.vmcode"),
+        }
+    }
+
+    /// Whether slot's artifact is stored in the objects directory, rather
+    /// than in the slot's own directory like older versions did.
+    fn uses_objects(&self, slot: &Slot) -> bool {
+        slot.hash.as_deref().is_some_and(is_object_name)
+    }
+
+    /// Artifacts, named by their hash, see Slot::hash.
+    fn objects_dir(&self) -> PathBuf {
+        Path::new(&self.cache_dir).join("objects")
     }
 
     fn slot_dir_for_index(&self, index: usize) -> PathBuf {
         Path::new(&self.cache_dir).join(format!("slot_{}", index))
     }
 
-    pub fn install_patch(&mut self, patch: PatchInfo) -> anyhow::Result<()> {
-        let slot_index = self.available_slot();
-        let slot_dir_string = self.slot_dir_for_index(slot_index);
-        let slot_dir = PathBuf::from(&slot_dir_string);
-
-        // Clear the slot.
-        self.clear_slot(slot_index)?; // Invalidate the slot.
-        self.save()?;
-        std::fs::create_dir_all(&slot_dir)?;
+    /// Moves the patch into a slot and makes it the next boot patch.
+    /// Up to `retain_patch_count` patches are kept on disk as fallbacks.
+    /// The patch being replaced is only deleted once the state pointing at
+    /// the new one is saved, so a failed install leaves it bootable.
+    pub fn install_patch(
+        &mut self,
+        patch: PatchInfo,
+        retain_patch_count: usize,
+    ) -> anyhow::Result<()> {
+        let slot_index = self.available_slot(retain_patch_count);
 
         if self.is_known_bad_patch(patch.number) {
             return Err(UpdateError::InvalidArgument(
@@ -321,18 +1221,64 @@ impl UpdaterState {
             .into());
         }
 
-        // Move the artifact into the slot.
-        let artifact_path = slot_dir.join("dlc.vmcode");
-        std::fs::rename(&patch.path, &artifact_path)?;
+        let mut transaction = InstallTransaction::begin(
+            &self.cache_dir,
+            patch.number,
+            slot_index,
+            self.slots.get(slot_index).map(|slot| slot.patch_number),
+            self.clock.unix_timestamp(),
+        );
+        let result = self.install_patch_to_slot(patch, slot_index, &mut transaction);
+        match &result {
+            Ok(()) => transaction.complete(&self.cache_dir),
+            Err(e) => transaction.fail(&self.cache_dir, e),
+        }
+        result
+    }
+
+    /// The steps of install_patch(), each recorded in transaction as it
+    /// finishes.
+    fn install_patch_to_slot(
+        &mut self,
+        patch: PatchInfo,
+        slot_index: usize,
+        transaction: &mut InstallTransaction,
+    ) -> anyhow::Result<()> {
+        // Move the artifact into the objects directory, unless another patch
+        // with the same contents is already there.  The hash is recorded so
+        // later loads can detect corruption.
+        let hash = hash_file(&patch.path)?;
+        let objects_dir = self.objects_dir();
+        std::fs::create_dir_all(&objects_dir)
+            .with_file_context("create directory", &objects_dir)?;
+        let artifact_path = objects_dir.join(&hash);
+        let already_stored = artifact_path.exists()
+            && hash_file(&artifact_path).is_ok_and(|existing| existing == hash);
+        if already_stored {
+            info!("Patch {} is identical to an installed patch.", patch.number);
+            std::fs::remove_file(&patch.path).with_file_context("remove", &patch.path)?;
+        } else {
+            std::fs::rename(&patch.path, &artifact_path)
+                .with_file_context("move patch to", &artifact_path)?;
+        }
+        let now = self.clock.unix_timestamp();
+        let validation = ArtifactValidation::now(&artifact_path, now)?;
+        let install_journal = InstallJournal::now(&artifact_path, validation.size, now);
+        transaction.record_step(&self.cache_dir, InstallStep::ArtifactStored);
 
         // Update the state to include the new slot.
+        let replaced = self.slots.get(slot_index).cloned();
         self.set_slot(
             slot_index,
             Slot {
                 patch_number: patch.number,
+                hash: Some(hash),
+                validation: Some(validation),
+                install_journal: Some(install_journal),
             },
         );
         self.set_next_boot_patch_slot(Some(slot_index));
+        self.last_rejected_patch = None;
 
         if let Some(latest) = self.latest_patch_number() {
             if patch.number < latest {
@@ -343,6 +1289,12 @@ impl UpdaterState {
             }
         }
         self.save()?;
+        transaction.record_step(&self.cache_dir, InstallStep::StateSaved);
+        // Only now is the replaced patch's artifact unused.
+        if let Some(replaced) = replaced {
+            self.remove_slot_files(slot_index, &replaced)?;
+        }
+        transaction.record_step(&self.cache_dir, InstallStep::ReplacedRemoved);
 
         let path = self.patch_path_for_index(slot_index);
         if !path.exists() {
@@ -369,6 +1321,212 @@ impl UpdaterState {
         Ok(())
     }
 
+    /// Uninstalls the patch which would be used for next boot (normally the
+    /// most recently installed patch) and makes the latest remaining bootable
+    /// patch the next boot patch.  The uninstalled patch is marked as bad so
+    /// that it will not be downloaded again.
+    /// Returns the patch number which was rolled back.
+    pub fn rollback_next_boot_patch(&mut self) -> Result<usize, UpdateError> {
+        let patch = self.next_boot_patch().ok_or(UpdateError::InvalidState(
+            "No patch to roll back.".to_owned(),
+        ))?;
+        // An explicit rollback overrides any previous successful launch.
+        self.successful_patches.retain(|v| v != &patch.number);
+        self.mark_patch_as_bad(patch.number);
+        // Boot from the slot holding the highest remaining bootable patch.
+        let slot_index = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| self.validate_slot(slot))
+            .max_by_key(|(_, slot)| slot.patch_number)
+            .map(|(index, _)| index);
+        self.set_next_boot_patch_slot(slot_index);
+        self.save().map_err(|_| UpdateError::FailedToSaveState)?;
+        info!("Rolled back patch {}", patch.number);
+        Ok(patch.number)
+    }
+
+    /// Directives from the most recent patch check.
+    pub fn client_directives(&self) -> &ClientDirectives {
+        &self.client_directives
+    }
+
+    /// When the most recent patch check completed, if ever.
+    pub fn last_patch_check_timestamp(&self) -> Option<u64> {
+        self.last_patch_check_timestamp
+    }
+
+    /// Records a completed patch check, replacing any previous directives.
+    pub fn record_patch_check(&mut self, directives: ClientDirectives, unix_timestamp: u64) {
+        self.client_directives = directives;
+        self.last_patch_check_timestamp = Some(unix_timestamp);
+        self.check_not_before = None;
+        // These were sent with the check.
+        self.last_boot_timing = None;
+        self.decode_benchmark = None;
+    }
+
+    /// The critical patch waiting to be installed, if any.
+    pub fn critical_patch_number(&self) -> Option<usize> {
+        self.critical_patch_number
+    }
+
+    pub fn set_critical_patch_number(&mut self, patch_number: Option<usize>) {
+        self.critical_patch_number = patch_number;
+    }
+
+    /// The patch pinned at runtime, see updater::pin_patch.
+    pub fn pinned_patch_number(&self) -> Option<usize> {
+        self.pinned_patch_number
+    }
+
+    pub fn set_pinned_patch_number(&mut self, patch_number: Option<usize>) {
+        self.pinned_patch_number = patch_number;
+    }
+
+    /// Makes installed patch_number the next boot patch.  Returns false (and
+    /// changes nothing) if it isn't installed or can't be booted.
+    pub fn set_next_boot_patch_number(&mut self, patch_number: usize) -> bool {
+        let index = self.slots.iter().position(|slot| {
+            slot.patch_number == patch_number && patch_number != 0 && self.validate_slot(slot)
+        });
+        if index.is_some() {
+            self.set_next_boot_patch_slot(index);
+        }
+        index.is_some()
+    }
+
+    /// When the server said we may next check for patches, if it asked us
+    /// to back off.
+    pub fn check_not_before(&self) -> Option<u64> {
+        self.check_not_before
+    }
+
+    /// Records that the server asked us not to check before `unix_timestamp`.
+    pub fn set_check_not_before(&mut self, unix_timestamp: u64) {
+        self.check_not_before = Some(unix_timestamp);
+    }
+
+    /// The outcome of the most recent update(), if update() has been called.
+    pub fn last_update_attempt(&self) -> Option<&UpdateAttempt> {
+        self.last_update_attempt.as_ref()
+    }
+
+    pub fn set_last_update_attempt(&mut self, attempt: UpdateAttempt) {
+        self.last_update_attempt = Some(attempt);
+    }
+
+    /// The timing of the most recent successful boot, if it has not yet been
+    /// reported to the server.
+    pub fn last_boot_timing(&self) -> Option<BootTiming> {
+        self.last_boot_timing
+    }
+
+    pub fn set_last_boot_timing(&mut self, timing: BootTiming) {
+        self.last_boot_timing = Some(timing);
+    }
+
+    pub fn decode_benchmark_ran(&self) -> bool {
+        self.decode_benchmark_ran
+    }
+
+    /// The decode benchmark's result waiting to be sent, if any.
+    pub fn decode_benchmark(&self) -> Option<&DecodeBenchmark> {
+        self.decode_benchmark.as_ref()
+    }
+
+    /// Records that the decode benchmark ran, and its result if it worked.
+    pub fn set_decode_benchmark(&mut self, benchmark: Option<DecodeBenchmark>) {
+        self.decode_benchmark_ran = true;
+        self.decode_benchmark = benchmark;
+    }
+
+    /// This device's seed for assigning it to patch experiments, taken from
+    /// (the random) client_id so it is as stable.
+    pub fn experiment_seed(&self) -> u64 {
+        u64::from_str_radix(&self.client_id[..16], 16).unwrap_or_default()
+    }
+
+    /// Random id for this install of the app, sent with patch checks so
+    /// canary patches can be targeted at specific devices.  Unlike the rest
+    /// of the state it is kept when the release version changes, so devices
+    /// stay in canary allowlists across releases.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// The patch waiting to be installed, see updater::download_update().
+    pub fn staged_patch_number(&self) -> Option<usize> {
+        self.staged_patch_number
+    }
+
+    /// Records the patch waiting to be installed, or clears it if None.
+    pub fn set_staged_patch_number(&mut self, patch_number: Option<usize>) {
+        self.staged_patch_number = patch_number;
+    }
+
+    /// The metadata the server sent with patch_number, if any.
+    pub fn patch_metadata(&self, patch_number: usize) -> Option<&str> {
+        self.patch_metadata.get(&patch_number).map(String::as_str)
+    }
+
+    /// Records (or clears) the metadata for patch_number, forgetting metadata
+    /// for patches which are no longer installed or staged.
+    pub fn set_patch_metadata(&mut self, patch_number: usize, metadata: Option<String>) {
+        let installed = self.installed_or_staged_patch_numbers();
+        self.patch_metadata
+            .retain(|number, _| installed.contains(number));
+        match metadata {
+            Some(metadata) => self.patch_metadata.insert(patch_number, metadata),
+            None => self.patch_metadata.remove(&patch_number),
+        };
+    }
+
+    /// The hash the server sent with patch_number, if recorded.
+    pub fn patch_hash(&self, patch_number: usize) -> Option<&str> {
+        self.patch_hashes.get(&patch_number).map(String::as_str)
+    }
+
+    /// Records the hash for patch_number, forgetting hashes for patches
+    /// which are no longer installed or staged.
+    pub fn set_patch_hash(&mut self, patch_number: usize, hash: String) {
+        let installed = self.installed_or_staged_patch_numbers();
+        self.patch_hashes
+            .retain(|number, _| installed.contains(number));
+        self.patch_hashes.insert(patch_number, hash);
+    }
+
+    /// Patches which are in a slot or staged, whose per-patch data is kept.
+    fn installed_or_staged_patch_numbers(&self) -> Vec<usize> {
+        let slots = self.slots.iter().map(|slot| slot.patch_number);
+        slots.chain(self.staged_patch_number).collect()
+    }
+
+    /// The download which was interrupted before completion, if any.
+    pub fn partial_download(&self) -> Option<&PartialDownload> {
+        self.partial_download.as_ref()
+    }
+
+    /// Records progress of an in-flight download, or clears it if None.
+    pub fn set_partial_download(&mut self, partial_download: Option<PartialDownload>) {
+        self.partial_download = partial_download;
+    }
+
+    /// Deletes every installed patch so the next boot uses the release as
+    /// shipped in the store.  Patches are not marked bad, so they may be
+    /// installed again if the server offers them later.
+    pub fn uninstall_all_patches(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.slots.len() {
+            self.clear_slot(index)?;
+        }
+        self.slots.clear();
+        self.current_boot_slot_index = None;
+        self.next_boot_slot_index = None;
+        self.last_rejected_patch = None;
+        self.save()
+    }
+
     /// Switches the next boot slot to the given slot or clears it if None.
     pub fn set_next_boot_patch_slot(&mut self, maybe_index: Option<usize>) {
         self.next_boot_slot_index = maybe_index;
@@ -396,23 +1554,248 @@ impl UpdaterState {
     }
 }
 
+/// Written by begin_boot() and removed once the launch is reported as a
+/// success or failure.  If it still exists at init, that boot crashed.
+#[derive(Deserialize, Serialize)]
+struct BootPendingMarker {
+    patch_number: usize,
+}
+
+/// cbindgen:ignore
+const BOOT_PENDING_FILE_NAME: &str = "boot_pending.json";
+
+fn boot_pending_marker_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(BOOT_PENDING_FILE_NAME)
+}
+
+/// Records that the app is booting the given patch.
+pub fn write_boot_pending_marker(cache_dir: &Path, patch_number: usize) -> anyhow::Result<()> {
+    std::fs::create_dir_all(cache_dir).with_file_context("create directory", cache_dir)?;
+    // Write to a temporary file and rename so the marker is never partial.
+    let tmp_path = cache_dir.join("boot_pending.json.tmp");
+    let contents = serde_json::to_vec(&BootPendingMarker { patch_number })?;
+    std::fs::write(&tmp_path, contents).with_file_context("write", &tmp_path)?;
+    let path = boot_pending_marker_path(cache_dir);
+    std::fs::rename(&tmp_path, &path).with_file_context("create", &path)?;
+    Ok(())
+}
+
+/// Removes the boot pending marker, returning the patch number it recorded
+/// if there was one.
+pub fn take_boot_pending_marker(cache_dir: &Path) -> anyhow::Result<Option<usize>> {
+    let path = boot_pending_marker_path(cache_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read(&path).with_file_context("read", &path)?;
+    std::fs::remove_file(&path).with_file_context("remove", &path)?;
+    let marker: BootPendingMarker = serde_json::from_slice(&contents)?;
+    Ok(Some(marker.patch_number))
+}
+
+/// Removes the boot pending marker (if any), as the launch finished.
+pub fn clear_boot_pending_marker(cache_dir: &Path) -> anyhow::Result<()> {
+    let path = boot_pending_marker_path(cache_dir);
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_file_context("remove", &path)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether `entry` is part of a release's state, see move_state_files().
+fn is_state_file(entry: &std::fs::DirEntry) -> bool {
+    let name = entry.file_name();
+    let is_slot_dir = name.to_string_lossy().starts_with("slot_") && entry.path().is_dir();
+    is_slot_dir
+        || name == "objects"
+        || name == "state.json"
+        || name == crate::install_transaction::TRANSACTION_FILE_NAME
+        || name == BOOT_PENDING_FILE_NAME
+}
+
+/// Removes the state, slots and objects in `dir`.
+fn remove_state_files(dir: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_file_context("read directory", dir)? {
+        let entry = entry?;
+        if !is_state_file(&entry) {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            std::fs::remove_dir_all(&path).with_file_context("remove", &path)?;
+        } else {
+            std::fs::remove_file(&path).with_file_context("remove", &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the state, slots and objects in `from` into `to`.  state.json moves
+/// last, so an interrupted move is retried.
+fn move_state_files(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to).with_file_context("create directory", to)?;
+    for entry in std::fs::read_dir(from).with_file_context("read directory", from)? {
+        let entry = entry?;
+        if is_state_file(&entry) && entry.file_name() != "state.json" {
+            std::fs::rename(entry.path(), to.join(entry.file_name()))
+                .with_file_context("move", &entry.path())?;
+        }
+    }
+    let state_path = from.join("state.json");
+    std::fs::rename(&state_path, to.join("state.json")).with_file_context("move", &state_path)
+}
+
+/// Moves state, slots and objects written by versions of the updater which did not
+/// namespace the cache by channel from `legacy_dir` into `cache_dir`.
+/// Does nothing if `cache_dir` already has state or there is nothing to move.
+pub fn migrate_legacy_layout(legacy_dir: &Path, cache_dir: &Path) -> anyhow::Result<()> {
+    let legacy_state_path = legacy_dir.join("state.json");
+    if !legacy_state_path.exists() || cache_dir.join("state.json").exists() {
+        return Ok(());
+    }
+    info!(
+        "Migrating updater state from {:?} to {:?}",
+        legacy_dir, cache_dir
+    );
+    move_state_files(legacy_dir, cache_dir)
+}
+
+/// What handle_release_change() did.
+#[derive(Debug, PartialEq)]
+pub enum ReleaseChange {
+    /// Nothing: the release is unchanged, or is an upgrade (whose old
+    /// patches load_or_new_on_error() drops).
+    None,
+    /// The release went backwards from `from`, whose state was set aside.
+    Downgraded { from: ReleaseVersion },
+    /// The release is the one set aside by an earlier downgrade, and its
+    /// state was restored.
+    Restored,
+}
+
+/// Handles a release change before anything loads the state, which would
+/// clear it.  If the release went backwards (e.g. a sideloaded older APK),
+/// the newer release's state, slots and objects are moved into
+/// NEWER_RELEASE_DIR rather than thrown away, and moved back if that release
+/// returns.  Only the newest release is kept there.
+pub fn handle_release_change(
+    cache_dir: &Path,
+    release_version: &ReleaseVersion,
+) -> anyhow::Result<ReleaseChange> {
+    use std::cmp::Ordering;
+
+    // In daemon mode the other process must not save while the state moves.
+    #[cfg(feature = "daemon")]
+    let _state_lock = crate::daemon::StateLock::acquire(cache_dir)?;
+    let archive_dir = cache_dir.join(NEWER_RELEASE_DIR);
+    let saved = UpdaterState::load(cache_dir).ok();
+    if saved
+        .as_ref()
+        .is_some_and(|saved| &saved.release_version == release_version)
+    {
+        return Ok(ReleaseChange::None);
+    }
+    let archived_release = archive_dir
+        .join("state.json")
+        .exists()
+        .then(|| UpdaterState::load(&archive_dir).ok())
+        .flatten()
+        .map(|archived| archived.release_version);
+    if archived_release.as_ref() == Some(release_version) {
+        info!(
+            "Restoring the state of release {} set aside when an older release ran.",
+            release_version
+        );
+        remove_state_files(cache_dir)?;
+        move_state_files(&archive_dir, cache_dir)?;
+        std::fs::remove_dir_all(&archive_dir).with_file_context("remove", &archive_dir)?;
+        return Ok(ReleaseChange::Restored);
+    }
+
+    let Some(saved) = saved else {
+        return Ok(ReleaseChange::None);
+    };
+    if release_version.numeric_cmp(&saved.release_version) != Some(Ordering::Less) {
+        // An upgrade past the release set aside means it won't be back.
+        let superseded = archived_release.as_ref().is_some_and(|archived| {
+            release_version.numeric_cmp(archived) == Some(Ordering::Greater)
+        });
+        if superseded {
+            std::fs::remove_dir_all(&archive_dir).with_file_context("remove", &archive_dir)?;
+        }
+        return Ok(ReleaseChange::None);
+    }
+    // Only the newest release is kept, which may be the one already set
+    // aside (e.g. 1.0.2 -> 1.0.1 -> 1.0.0 keeps 1.0.2's patches).
+    let keep_archived = archived_release.as_ref().is_some_and(|archived| {
+        archived.numeric_cmp(&saved.release_version) == Some(Ordering::Greater)
+    });
+    if keep_archived {
+        warn!(
+            "release_version went backwards {} -> {}, removing its patches as a newer release's are set aside.",
+            saved.release_version, release_version
+        );
+        remove_state_files(cache_dir)?;
+    } else {
+        warn!(
+            "release_version went backwards {} -> {}, setting aside the newer release's patches.",
+            saved.release_version, release_version
+        );
+        if archive_dir.exists() {
+            std::fs::remove_dir_all(&archive_dir).with_file_context("remove", &archive_dir)?;
+        }
+        move_state_files(cache_dir, &archive_dir)?;
+    }
+    // Gone because this process moved or removed it, not because another
+    // saved.
+    remember_state(&cache_dir.join("state.json"), &[]);
+    let mut state = UpdaterState::new(cache_dir.to_owned(), release_version.to_owned());
+    if let Some(patch) = saved.next_boot_patch() {
+        state.reject_patch(patch.number, PatchRejection::ReleaseVersionDowngraded);
+    }
+    state.save()?;
+    Ok(ReleaseChange::Downgraded {
+        from: saved.release_version,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use tempdir::TempDir;
 
-    use crate::cache::{PatchInfo, UpdaterState};
+    use crate::cache::{InstallJournal, PatchInfo, PatchRejection, RejectedPatch, UpdaterState};
+    use crate::install_transaction::{InstallStep, InstallTransaction};
 
     fn test_state(tmp_dir: &TempDir) -> UpdaterState {
         let cache_dir = tmp_dir.path();
-        UpdaterState::new(cache_dir.to_owned(), "1.0.0+1".to_string())
+        UpdaterState::new(cache_dir.to_owned(), "1.0.0+1".parse().unwrap())
     }
 
     fn fake_patch(tmp_dir: &TempDir, number: usize) -> super::PatchInfo {
         let path = tmp_dir.path().join(format!("patch_{}", number));
-        std::fs::write(&path, "fake patch").unwrap();
+        std::fs::write(&path, format!("fake patch {}", number)).unwrap();
         PatchInfo { number, path }
     }
 
+    #[test]
+    fn artifact_names_are_distinct() {
+        let arch = crate::config::current_arch();
+        assert_eq!(
+            super::artifact_name("1.0.0+1", 3),
+            format!("1.0.0+1_patch3_{}", arch)
+        );
+        assert_eq!(
+            super::artifact_name("1.0/beta 2", 3),
+            format!("1.0_beta_2_patch3_{}", arch)
+        );
+        assert_ne!(
+            super::artifact_name("1.0.0+1", 3),
+            super::artifact_name("1.0.0+2", 3)
+        );
+    }
+
     #[test]
     fn next_boot_patch_does_not_crash() {
         let tmp_dir = TempDir::new("example").unwrap();
@@ -425,30 +1808,283 @@ mod tests {
         assert_eq!(state.next_boot_patch(), None);
     }
 
+    #[test]
+    fn patch_metadata_is_kept_for_installed_patches() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.set_patch_metadata(1, Some("one".to_string()));
+        state.set_patch_hash(1, "#1".to_string());
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        state.set_patch_metadata(2, Some("two".to_string()));
+        state.set_patch_hash(2, "#2".to_string());
+        assert_eq!(state.patch_metadata(1), Some("one"));
+        assert_eq!(state.patch_metadata(2), Some("two"));
+
+        // Patch 2 was never installed, so its metadata is dropped.
+        state.set_patch_metadata(3, None);
+        assert_eq!(state.patch_metadata(1), Some("one"));
+        assert_eq!(state.patch_metadata(2), None);
+        assert_eq!(state.patch_metadata(3), None);
+        state.set_patch_hash(3, "#3".to_string());
+        assert_eq!(state.patch_hash(1), Some("#1"));
+        assert_eq!(state.patch_hash(2), None);
+        assert_eq!(state.patch_hash(3), Some("#3"));
+    }
+
+    #[test]
+    fn installed_patches() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        assert!(state.installed_patches().is_empty());
+
+        state.install_patch(fake_patch(&tmp_dir, 2), 3).unwrap();
+        state.set_patch_metadata(2, Some("two".to_string()));
+        state.activate_current_patch().unwrap();
+        state.mark_patch_as_good(2);
+        state.install_patch(fake_patch(&tmp_dir, 1), 3).unwrap();
+
+        let patches = state.installed_patches();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].number, 1);
+        assert!(patches[0].is_next_boot);
+        assert!(!patches[0].is_current_boot);
+        assert_eq!(patches[0].metadata, None);
+        assert_eq!(patches[1].number, 2);
+        assert!(patches[1].is_current_boot);
+        assert!(patches[1].is_known_good);
+        assert_eq!(patches[1].metadata.as_deref(), Some("two"));
+        assert_eq!(
+            patches[1].size,
+            std::fs::metadata(&patches[1].path).unwrap().len()
+        );
+        assert!(patches[1].hash.is_some());
+
+        // Patches whose artifact has been removed are not reported.
+        std::fs::remove_file(&patches[0].path).unwrap();
+        let patches = state.installed_patches();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].number, 2);
+    }
+
+    #[test]
+    fn corrupt_artifacts_are_detected() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let clock = std::sync::Arc::new(crate::time::FakeClock::new(1000));
+        let mut state = test_state(&tmp_dir);
+        state.clock = clock.clone();
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        let path = state.next_boot_patch().unwrap().path;
+        let validation = state.slots[0].validation.clone().unwrap();
+        assert!(state.slots[0].hash.is_some());
+        assert_eq!(validation.verified_at, 1000);
+
+        // The install is journaled so corruption can be diagnosed later.
+        let journal = state.slots[0].install_journal.clone().unwrap();
+        assert_eq!(journal.size, validation.size);
+        assert_eq!(journal.installed_at, 1000);
+        #[cfg(target_os = "linux")]
+        assert!(journal.free_bytes.is_some() && journal.filesystem.is_some());
+        let description = InstallJournal {
+            installed_at: 1,
+            size: 10,
+            free_bytes: None,
+            filesystem: Some("f2fs".to_string()),
+        }
+        .describe(12);
+        assert_eq!(
+            description,
+            "installed at 1 with size 10 (now 12), unknown bytes free on f2fs filesystem"
+        );
+
+        // Unchanged artifacts are not re-hashed.
+        state.validate().unwrap();
+        assert_eq!(state.slots[0].validation, Some(validation.clone()));
+
+        // Stale checks are redone.
+        clock.advance(super::REVALIDATE_AFTER_SECONDS - 1);
+        state.validate().unwrap();
+        assert_eq!(state.slots[0].validation, Some(validation.clone()));
+        clock.advance(1);
+        state.validate().unwrap();
+        let now = 1000 + super::REVALIDATE_AFTER_SECONDS;
+        assert_eq!(state.slots[0].validation.as_ref().unwrap().verified_at, now);
+
+        // Same size, different contents (and so mtime).
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        std::fs::write(&path, "fake patcH 1").unwrap();
+        state.validate().unwrap();
+        assert_eq!(state.slots[0].patch_number, 0);
+        assert!(!path.exists());
+        // The release boots instead, and the reason is kept.
+        assert_eq!(state.next_boot_patch(), None);
+        assert_eq!(
+            state.last_rejected_patch(),
+            Some(RejectedPatch {
+                patch_number: 1,
+                reason: PatchRejection::HashMismatch
+            })
+        );
+    }
+
     #[test]
     fn release_version_changed() {
         let tmp_dir = TempDir::new("example").unwrap();
         let mut state = test_state(&tmp_dir);
         state.next_boot_slot_index = Some(1);
         state.save().unwrap();
-        let loaded = UpdaterState::load_or_new_on_error(&state.cache_dir, &state.release_version);
+        let loaded = UpdaterState::load_or_new_on_error(
+            &state.cache_dir,
+            &state.release_version,
+            &super::default_clock(),
+            false,
+        );
         assert_eq!(loaded.next_boot_slot_index, Some(1));
 
-        let loaded_after_version_change =
-            UpdaterState::load_or_new_on_error(&state.cache_dir, "1.0.0+2");
+        let loaded_after_version_change = UpdaterState::load_or_new_on_error(
+            &state.cache_dir,
+            &"1.0.0+2".parse().unwrap(),
+            &super::default_clock(),
+            false,
+        );
         assert_eq!(loaded_after_version_change.next_boot_slot_index, None);
     }
 
+    #[test]
+    fn unreadable_state_is_set_aside() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.next_boot_slot_index = Some(1);
+        state.save().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let backup_path = tmp_dir.path().join("state.json.corrupt");
+
+        // Too large, even though it parses.
+        let mut huge = std::fs::read_to_string(&state_path).unwrap();
+        huge.push_str(&" ".repeat(super::MAX_STATE_FILE_BYTES as usize));
+        std::fs::write(&state_path, &huge).unwrap();
+        assert!(UpdaterState::load(tmp_dir.path()).is_err());
+        let loaded = UpdaterState::load_or_new_on_error(
+            tmp_dir.path(),
+            &state.release_version,
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(loaded.next_boot_slot_index, None);
+        assert!(!state_path.exists());
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), huge);
+
+        // Too deep.
+        let deep = format!("{}{}", "[".repeat(100), "]".repeat(100));
+        std::fs::write(&state_path, &deep).unwrap();
+        let error = UpdaterState::load(tmp_dir.path()).err().unwrap();
+        assert!(error.to_string().contains("nested"), "{}", error);
+        UpdaterState::load_or_new_on_error(
+            tmp_dir.path(),
+            &state.release_version,
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), deep);
+    }
+
+    #[test]
+    fn state_changed_elsewhere() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path();
+        let state = test_state(&tmp_dir);
+        // Nothing seen and nothing there.
+        assert!(!super::state_changed_elsewhere(cache_dir));
+        state.save().unwrap();
+        assert!(!super::state_changed_elsewhere(cache_dir));
+
+        // Another process saving a different state.
+        let mut other = test_state(&tmp_dir);
+        other.next_boot_slot_index = Some(0);
+        let contents = serde_json::to_vec_pretty(&other).unwrap();
+        std::fs::write(cache_dir.join("state.json"), &contents).unwrap();
+        assert!(super::state_changed_elsewhere(cache_dir));
+        // Which isn't saved over.
+        #[cfg(feature = "daemon")]
+        {
+            assert!(state.save().is_err());
+            assert_eq!(
+                std::fs::read(cache_dir.join("state.json")).unwrap(),
+                contents
+            );
+        }
+
+        // Loading it catches up.
+        UpdaterState::load_or_new_on_error(
+            cache_dir,
+            &state.release_version,
+            &super::default_clock(),
+            false,
+        );
+        assert!(!super::state_changed_elsewhere(cache_dir));
+        state.save().unwrap();
+        assert!(!super::state_changed_elsewhere(cache_dir));
+    }
+
+    #[test]
+    fn json_nesting_depth() {
+        assert_eq!(super::json_nesting_depth(b"1"), 0);
+        assert_eq!(super::json_nesting_depth(br#"{"a": [1, {"b": []}]}"#), 4);
+        // Brackets in strings don't count.
+        assert_eq!(super::json_nesting_depth(br#"["[[", "\"{{"]"#), 1);
+    }
+
+    #[test]
+    fn client_id_is_stable() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let state = test_state(&tmp_dir);
+        assert_eq!(state.client_id().len(), 32);
+        assert_eq!(state.client_id(), test_state(&tmp_dir).client_id());
+        let other_dir = TempDir::new("example").unwrap();
+        assert_ne!(state.client_id(), test_state(&other_dir).client_id());
+        state.save().unwrap();
+        let loaded = UpdaterState::load_or_new_on_error(
+            &state.cache_dir,
+            &state.release_version,
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(loaded.client_id(), state.client_id());
+        assert_eq!(loaded.experiment_seed(), state.experiment_seed());
+
+        // Kept when the release changes, unlike the rest of the state.
+        let next_release = UpdaterState::load_or_new_on_error(
+            &state.cache_dir,
+            &"1.0.1+1".parse().unwrap(),
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(next_release.client_id(), state.client_id());
+    }
+
+    #[test]
+    fn client_id_moves_out_of_older_states() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let legacy_id = "0123456789abcdef0123456789abcdef";
+        let mut json: serde_json::Value = serde_json::to_value(test_state(&tmp_dir)).unwrap();
+        json["client_id"] = legacy_id.into();
+        std::fs::remove_file(tmp_dir.path().join("client_id")).unwrap();
+        std::fs::write(tmp_dir.path().join("state.json"), json.to_string()).unwrap();
+        let loaded = UpdaterState::load(tmp_dir.path()).unwrap();
+        assert_eq!(loaded.client_id(), legacy_id);
+        assert_eq!(test_state(&tmp_dir).client_id(), legacy_id);
+        assert_eq!(loaded.experiment_seed(), 0x0123456789abcdef);
+    }
+
     #[test]
     fn latest_downloaded_patch() {
         let tmp_dir = TempDir::new("example").unwrap();
         let mut state = test_state(&tmp_dir);
         assert_eq!(state.latest_patch_number(), None);
-        state.install_patch(fake_patch(&tmp_dir, 1)).unwrap();
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
         assert_eq!(state.latest_patch_number(), Some(1));
-        state.install_patch(fake_patch(&tmp_dir, 2)).unwrap();
+        state.install_patch(fake_patch(&tmp_dir, 2), 2).unwrap();
         assert_eq!(state.latest_patch_number(), Some(2));
-        state.install_patch(fake_patch(&tmp_dir, 1)).unwrap();
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
         // This probably should be Some(2) assuming we didn't write
         // over the top of patch 2 when re-installing patch 1.
         // I expect if we support rollbacks we might be more explicit
@@ -456,12 +2092,329 @@ mod tests {
         assert_eq!(state.latest_patch_number(), Some(1));
     }
 
+    #[test]
+    fn rollback_next_boot_patch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        assert!(state.rollback_next_boot_patch().is_err());
+
+        // Boot from patch 1 so that installing 2 uses the other slot.
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        state.activate_current_patch().unwrap();
+        state.mark_patch_as_good(1);
+        state.install_patch(fake_patch(&tmp_dir, 2), 2).unwrap();
+        assert_eq!(state.next_boot_patch().unwrap().number, 2);
+
+        // Rolling back restores the previous patch and never retries 2.
+        assert_eq!(state.rollback_next_boot_patch().unwrap(), 2);
+        assert_eq!(state.next_boot_patch().unwrap().number, 1);
+        assert!(state.is_known_bad_patch(2));
+
+        // Known good patches can still be rolled back explicitly.
+        assert_eq!(state.rollback_next_boot_patch().unwrap(), 1);
+        assert_eq!(state.next_boot_patch(), None);
+        assert!(!state.is_known_good_patch(1));
+    }
+
+    #[test]
+    fn release_downgrade_keeps_newer_patches() {
+        use super::{handle_release_change, ReleaseChange};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path();
+        let newer = "1.0.1+1".parse().unwrap();
+        let older = "1.0.0+1".parse().unwrap();
+        let mut state = UpdaterState::new(cache_dir.to_owned(), "1.0.1+1".parse().unwrap());
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+
+        assert_eq!(
+            handle_release_change(cache_dir, &older).unwrap(),
+            ReleaseChange::Downgraded {
+                from: "1.0.1+1".parse().unwrap()
+            }
+        );
+        let downgraded =
+            UpdaterState::load_or_new_on_error(cache_dir, &older, &super::default_clock(), false);
+        assert_eq!(downgraded.next_boot_patch(), None);
+        assert_eq!(
+            downgraded.last_rejected_patch(),
+            Some(RejectedPatch {
+                patch_number: 1,
+                reason: PatchRejection::ReleaseVersionDowngraded,
+            })
+        );
+        // Running the older release again changes nothing.
+        assert_eq!(
+            handle_release_change(cache_dir, &older).unwrap(),
+            ReleaseChange::None
+        );
+
+        assert_eq!(
+            handle_release_change(cache_dir, &newer).unwrap(),
+            ReleaseChange::Restored
+        );
+        let restored =
+            UpdaterState::load_or_new_on_error(cache_dir, &newer, &super::default_clock(), false);
+        let patch = restored.next_boot_patch().unwrap();
+        assert_eq!(patch.number, 1);
+        assert!(patch.path.exists());
+        assert!(!cache_dir.join(super::NEWER_RELEASE_DIR).exists());
+
+        // Upgrades still start over.
+        let upgraded = "1.0.2+1".parse().unwrap();
+        assert_eq!(
+            handle_release_change(cache_dir, &upgraded).unwrap(),
+            ReleaseChange::None
+        );
+        let upgraded = UpdaterState::load_or_new_on_error(
+            cache_dir,
+            &upgraded,
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(upgraded.next_boot_patch(), None);
+    }
+
+    #[test]
+    fn rejecting_critical_patch_clears_it() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.set_critical_patch_number(Some(1));
+        state.reject_patch(2, PatchRejection::NotAllowlisted);
+        assert_eq!(state.critical_patch_number(), Some(1));
+        state.mark_patch_as_bad(1);
+        assert_eq!(state.critical_patch_number(), None);
+    }
+
+    #[test]
+    fn release_downgrades_keep_newest_release() {
+        use super::{handle_release_change, ReleaseChange};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path();
+        let newest: crate::ReleaseVersion = "1.0.2+1".parse().unwrap();
+        let middle: crate::ReleaseVersion = "1.0.1+1".parse().unwrap();
+        let oldest = "1.0.0+1".parse().unwrap();
+        let mut state = UpdaterState::new(cache_dir.to_owned(), newest.clone());
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        super::write_boot_pending_marker(cache_dir, 1).unwrap();
+
+        handle_release_change(cache_dir, &middle).unwrap();
+        // The newer release's boot isn't this release's to recover.
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), None);
+        assert!(!cache_dir.join("install_transaction.json").exists());
+        let mut state =
+            UpdaterState::load_or_new_on_error(cache_dir, &middle, &super::default_clock(), false);
+        state.install_patch(fake_patch(&tmp_dir, 2), 2).unwrap();
+
+        assert_eq!(
+            handle_release_change(cache_dir, &oldest).unwrap(),
+            ReleaseChange::Downgraded { from: middle }
+        );
+        assert_eq!(
+            handle_release_change(cache_dir, &newest).unwrap(),
+            ReleaseChange::Restored
+        );
+        let restored =
+            UpdaterState::load_or_new_on_error(cache_dir, &newest, &super::default_clock(), false);
+        assert_eq!(restored.next_boot_patch().unwrap().number, 1);
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), Some(1));
+        assert!(cache_dir.join("install_transaction.json").exists());
+    }
+
+    #[test]
+    fn migrate_legacy_layout() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut legacy_state = test_state(&tmp_dir);
+        legacy_state
+            .install_patch(fake_patch(&tmp_dir, 1), 2)
+            .unwrap();
+
+        let channel_dir = tmp_dir.path().join("channels/stable");
+        super::migrate_legacy_layout(tmp_dir.path(), &channel_dir).unwrap();
+        assert!(!tmp_dir.path().join("state.json").exists());
+        assert!(!tmp_dir.path().join("slot_0").exists());
+
+        let migrated = UpdaterState::load_or_new_on_error(
+            &channel_dir,
+            &"1.0.0+1".parse().unwrap(),
+            &super::default_clock(),
+            false,
+        );
+        let patch = migrated.next_boot_patch().unwrap();
+        assert_eq!(patch.number, 1);
+        assert!(patch.path.starts_with(&channel_dir));
+        assert!(patch.path.exists());
+
+        // Running again with nothing to migrate is a no-op.
+        super::migrate_legacy_layout(tmp_dir.path(), &channel_dir).unwrap();
+        assert!(channel_dir.join("state.json").exists());
+    }
+
+    #[test]
+    fn boot_pending_marker() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path();
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), None);
+        super::clear_boot_pending_marker(cache_dir).unwrap();
+
+        super::write_boot_pending_marker(cache_dir, 3).unwrap();
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), Some(3));
+        // Taking the marker removes it.
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), None);
+
+        super::write_boot_pending_marker(cache_dir, 4).unwrap();
+        super::clear_boot_pending_marker(cache_dir).unwrap();
+        assert_eq!(super::take_boot_pending_marker(cache_dir).unwrap(), None);
+    }
+
+    #[test]
+    fn remove_orphaned_slot_dirs() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        // Nothing on disk yet.
+        assert_eq!(state.remove_orphaned_slot_dirs().unwrap(), 0);
+
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        std::fs::create_dir_all(tmp_dir.path().join("slot_1")).unwrap();
+        std::fs::create_dir_all(tmp_dir.path().join("slot_bogus")).unwrap();
+        assert_eq!(state.remove_orphaned_slot_dirs().unwrap(), 2);
+        assert!(!tmp_dir.path().join("slot_1").exists());
+        assert!(!tmp_dir.path().join("slot_bogus").exists());
+        assert!(state.next_boot_patch().unwrap().path.exists());
+    }
+
+    #[test]
+    fn failed_saves_keep_previous_state() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        assert!(!tmp_dir.path().join("state.json.tmp").exists());
+
+        // The temporary file can't be written, so neither can the state.
+        std::fs::create_dir(tmp_dir.path().join("state.json.tmp")).unwrap();
+        assert!(state.install_patch(fake_patch(&tmp_dir, 2), 2).is_err());
+        let loaded = UpdaterState::load_or_new_on_error(
+            tmp_dir.path(),
+            &"1.0.0+1".parse().unwrap(),
+            &super::default_clock(),
+            false,
+        );
+        assert_eq!(loaded.next_boot_patch().unwrap().number, 1);
+
+        // The install transaction says where the install stopped.
+        let transaction = InstallTransaction::load(tmp_dir.path()).unwrap();
+        assert_eq!(transaction.patch_number, 2);
+        assert_eq!(transaction.steps, vec![InstallStep::ArtifactStored]);
+        assert_eq!(transaction.interrupted_step(), Some("save state"));
+        assert!(transaction.error.is_some());
+    }
+
+    #[test]
+    fn identical_patches_share_an_object() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        let same_patch = |number: usize| {
+            let path = tmp_dir.path().join(format!("patch_{}", number));
+            std::fs::write(&path, "republished").unwrap();
+            PatchInfo { number, path }
+        };
+        state.install_patch(same_patch(1), 2).unwrap();
+        state.activate_current_patch().unwrap();
+        state.mark_patch_as_good(1);
+        let first = state.current_boot_patch().unwrap().path;
+        state.install_patch(same_patch(2), 2).unwrap();
+        assert_eq!(state.next_boot_patch().unwrap().path, first);
+        let objects_dir = tmp_dir.path().join("objects");
+        assert_eq!(std::fs::read_dir(&objects_dir).unwrap().count(), 1);
+        assert_eq!(
+            first.file_name().unwrap(),
+            state.slots[0].hash.as_deref().unwrap()
+        );
+
+        // Still used by patch 1.
+        state.rollback_next_boot_patch().unwrap();
+        assert!(first.exists());
+
+        // Objects nothing refers to are collected.
+        std::fs::write(objects_dir.join("0".repeat(64)), "unused").unwrap();
+        assert_eq!(state.remove_unreferenced_objects().unwrap(), 1);
+        assert!(first.exists());
+    }
+
+    #[test]
+    fn slots_move_to_objects() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        let object_path = state.next_boot_patch().unwrap().path;
+        // Where older versions of the updater kept the artifact.
+        let slot_dir = tmp_dir.path().join("slot_0");
+        std::fs::create_dir_all(&slot_dir).unwrap();
+        std::fs::rename(&object_path, slot_dir.join("dlc.vmcode")).unwrap();
+
+        state.validate().unwrap();
+        assert_eq!(state.next_boot_patch().unwrap().path, object_path);
+        assert!(object_path.exists());
+        assert!(!slot_dir.exists());
+
+        // Slots without a hash stay where they are.
+        state.slots[0].hash = None;
+        assert_eq!(
+            state.next_boot_patch().unwrap().path,
+            slot_dir.join("dlc.vmcode")
+        );
+    }
+
+    #[test]
+    fn uninstall_all_patches() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        state.install_patch(fake_patch(&tmp_dir, 1), 2).unwrap();
+        state.activate_current_patch().unwrap();
+        state.install_patch(fake_patch(&tmp_dir, 2), 2).unwrap();
+        state.uninstall_all_patches().unwrap();
+        assert!(state.current_boot_patch().is_none());
+        assert!(state.next_boot_patch().is_none());
+        assert!(!tmp_dir.path().join("slot_0").exists());
+        assert!(!tmp_dir.path().join("slot_1").exists());
+        // Not marked bad, so they can be offered again.
+        assert!(!state.is_known_bad_patch(2));
+        assert_eq!(state.latest_patch_number(), None);
+    }
+
     #[test]
     fn do_not_install_known_bad_patch() {
         let tmp_dir = TempDir::new("example").unwrap();
         let mut state = test_state(&tmp_dir);
         let bad_patch = fake_patch(&tmp_dir, 1);
         state.mark_patch_as_bad(bad_patch.number);
-        assert!(state.install_patch(bad_patch).is_err());
+        assert!(state.install_patch(bad_patch, 2).is_err());
+    }
+
+    #[test]
+    fn retains_good_patches() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = test_state(&tmp_dir);
+        // Install and successfully boot patches 1 through 4, keeping 3.
+        for number in 1..=4 {
+            state
+                .install_patch(fake_patch(&tmp_dir, number), 3)
+                .unwrap();
+            state.activate_current_patch().unwrap();
+            state.mark_patch_as_good(number);
+        }
+        let mut retained: Vec<usize> = state.slots.iter().map(|s| s.patch_number).collect();
+        retained.sort();
+        assert_eq!(retained, vec![2, 3, 4]);
+        assert_eq!(state.current_boot_patch().unwrap().number, 4);
+
+        // An unbooted patch replaces the previous unbooted patch rather than
+        // a fallback.
+        state.install_patch(fake_patch(&tmp_dir, 5), 4).unwrap();
+        state.install_patch(fake_patch(&tmp_dir, 6), 4).unwrap();
+        let mut retained: Vec<usize> = state.slots.iter().map(|s| s.patch_number).collect();
+        retained.sort();
+        assert_eq!(retained, vec![2, 3, 4, 6]);
     }
 }
@@ -1,17 +1,25 @@
 // This file handles the global config for the updater library.
-use crate::network::NetworkHooks;
+use crate::cache::migrate_legacy_layout;
+use crate::names::{Channel, ReleaseVersion};
+use crate::network::{validate_extra_headers, NetworkHooks};
+use crate::request_signing::RequestSigning;
+use crate::storage::probe_writable;
+use crate::thread_priority::ThreadPriority;
+use crate::time::Clock;
+use crate::verify::{decode_hash, is_valid_public_key, verify_signature, Hash, PublicKey};
 
 use crate::updater::AppConfig;
-use crate::yaml::YamlConfig;
+use crate::yaml::{PatchAllowlist, RequestSigningConfig, YamlConfig};
 use crate::UpdateError;
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 
 use once_cell::sync::OnceCell;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 // https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
 #[cfg(test)]
-use std::println as info; // Workaround to use println! for logs.
+use std::{println as info, println as warn}; // Workaround to use println! for logs.
 
 // cbindgen looks for const, ignore these so it doesn't warn about them.
 
@@ -19,18 +27,49 @@ use std::println as info; // Workaround to use println! for logs.
 const DEFAULT_BASE_URL: &'static str = "https://api.shorebird.dev";
 /// cbindgen:ignore
 const DEFAULT_CHANNEL: &'static str = "stable";
+/// cbindgen:ignore
+const DEFAULT_RETAIN_PATCH_COUNT: usize = 2;
 
 fn global_config() -> &'static Mutex<Option<UpdateConfig>> {
     static INSTANCE: OnceCell<Mutex<Option<UpdateConfig>>> = OnceCell::new();
     INSTANCE.get_or_init(|| Mutex::new(None))
 }
 
+/// Unit tests can call this to control the time seen by the updater.
+#[cfg(test)]
+pub fn testing_set_clock(clock: Arc<dyn Clock>) {
+    with_config_mut(|maybe_config| match maybe_config {
+        Some(config) => config.clock = clock,
+        None => panic!("testing_set_clock called before config was initialized"),
+    });
+}
+
+/// Moves the time seen by the updater forward by `seconds` and stops it
+/// moving on its own, so tests can step past check throttling and backoff.
+/// Needs init() first.
+#[cfg(any(test, feature = "testing"))]
+pub fn testing_advance_clock(seconds: u64) {
+    with_config_mut(|maybe_config| match maybe_config {
+        Some(config) => {
+            let now = config.clock.unix_timestamp();
+            config.clock = Arc::new(crate::time::FakeClock::new(now + seconds));
+        }
+        None => panic!("testing_advance_clock called before config was initialized"),
+    });
+}
+
 /// Unit tests should call this to reset the config between tests.
 #[cfg(test)]
 pub fn testing_reset_config() {
     with_config_mut(|config| {
         *config = None;
     });
+    crate::request_signing::set_config(None);
+    crate::network::set_config_headers("", Default::default());
+    crate::updater::testing_reset_launch_start();
+    crate::lifecycle::testing_reset();
+    crate::auto_update::testing_reset();
+    crate::c_api::test::testing_reset();
 }
 
 pub fn check_initialized_and_call<F, R>(
@@ -73,12 +112,64 @@ where
 pub struct UpdateConfig {
     pub cache_dir: PathBuf,
     pub download_dir: PathBuf,
-    pub channel: String,
+    pub channel: Channel,
+    /// Channels to check when `channel` has no patch, see YamlConfig.
+    pub channel_fallbacks: Vec<Channel>,
     pub app_id: String,
-    pub release_version: String,
+    pub release_version: ReleaseVersion,
+    pub build_number: Option<u64>,
+    // Only Android reads the base release from disk, see prepare_for_install().
+    #[cfg_attr(not(target_os = "android"), allow(dead_code))]
     pub libapp_path: PathBuf,
     pub base_url: String,
+    pub native_fingerprint: Option<String>,
+    /// See YamlConfig::expected_apk_cert_sha256.
+    #[cfg_attr(not(target_os = "android"), allow(dead_code))]
+    pub expected_apk_cert_sha256: Option<Hash>,
+    pub retain_patch_count: usize,
+    pub patch_public_key: Option<PublicKey>,
+    pub require_signed_responses: bool,
+    /// Hashes of the patches which may be installed or booted, see
+    /// YamlConfig::patch_allowlist.  None allows any patch.
+    pub patch_allowlist: Option<BTreeSet<Hash>>,
+    pub patch_alignment: Option<u64>,
+    pub prefault_patches: bool,
+    /// Whether to fsync patch artifacts as they are written.
+    pub durable_writes: bool,
+    /// Whether to fall back to full artifacts, see YamlConfig.
+    pub allow_full_downloads: bool,
+    /// Whether to run the decode benchmark once, see YamlConfig.
+    pub decode_benchmark: bool,
+    /// Largest patch to copy into a memfd, see YamlConfig.  None is off.
+    pub memfd_max_bytes: Option<u64>,
+    /// Whether automatic updates are on, see YamlConfig::auto_update.
+    pub auto_update: bool,
+    /// Maximum random delay before the update thread checks, see YamlConfig.
+    pub auto_update_jitter_seconds: u64,
+    /// Whether start_update_thread queues a follow-up run, see YamlConfig.
+    pub queue_follow_up_update: bool,
+    /// Whether another process updates for the app, see YamlConfig.
+    pub update_in_daemon: bool,
+    /// Whether to check the booted artifact at launch start, see YamlConfig.
+    pub verify_boot_artifact: bool,
+    /// Whether patch checks include the DeviceEnvironment, see YamlConfig.
+    pub report_device_environment: bool,
+    /// Whether patch checks list installed patch numbers, see YamlConfig.
+    pub report_installed_patches: bool,
+    /// Language and country to send with patch checks, only if
+    /// YamlConfig::report_locale is set, see coarse_locale().
+    pub locale: Option<String>,
+    /// Priority of the update and decompression threads, see YamlConfig.
+    pub update_thread_priority: ThreadPriority,
+    /// The patch to request and boot instead of the latest, see YamlConfig.
+    pub pinned_patch_number: Option<usize>,
+    /// Whether to log patch check bodies, see YamlConfig::log_network_bodies.
+    pub log_network_bodies: bool,
     pub network_hooks: NetworkHooks,
+    /// Where to get the current time, see time.rs.
+    pub clock: Arc<dyn Clock>,
+    /// Non-fatal problems found in shorebird.yaml, see YamlConfig::warnings.
+    pub config_warnings: Vec<String>,
 }
 
 pub fn set_config(
@@ -86,39 +177,321 @@ pub fn set_config(
     libapp_path: PathBuf,
     yaml: YamlConfig,
     network_hooks: NetworkHooks,
+    clock: Arc<dyn Clock>,
 ) -> anyhow::Result<()> {
     with_config_mut(|config| {
         anyhow::ensure!(config.is_none(), "shorebird_init has already been called.");
 
-        let mut cache_path = std::path::PathBuf::from(&app_config.cache_dir);
+        let config_warnings = yaml.warnings();
+        anyhow::ensure!(
+            config_warnings.is_empty() || !yaml.strict_config.unwrap_or(false),
+            UpdateError::InvalidArgument("yaml".to_string(), config_warnings.join(", "))
+        );
+        for warning in &config_warnings {
+            warn!("{}", warning);
+        }
+
+        let mut app_id = yaml.app_id.to_string();
+        let mut cache_dir = std::path::PathBuf::from(&app_config.cache_dir);
+        if let Some(app_id_override) = app_config.app_id_override {
+            if yaml.allow_app_id_override.unwrap_or(false) {
+                anyhow::ensure!(
+                    is_valid_path_component(&app_id_override),
+                    UpdateError::InvalidArgument("app_id_override".to_string(), app_id_override)
+                );
+                info!("Overriding app_id {} with {}", app_id, app_id_override);
+                // Keep state for each overridden app_id separate on disk.
+                cache_dir = cache_dir.join("apps").join(&app_id_override);
+                app_id = app_id_override;
+            } else {
+                warn!("Ignoring app_id_override, not allowed by shorebird.yaml.");
+            }
+        }
+
+        let patch_public_key = match &yaml.patch_public_key {
+            Some(encoded) => Some(decode_public_key(encoded).ok_or_else(|| {
+                UpdateError::InvalidArgument("patch_public_key".to_string(), encoded.clone())
+            })?),
+            None => None,
+        };
+        let expected_apk_cert_sha256 = match &yaml.expected_apk_cert_sha256 {
+            Some(encoded) => Some(decode_hash(&encoded.replace(':', "")).ok_or_else(|| {
+                UpdateError::InvalidArgument(
+                    "expected_apk_cert_sha256".to_string(),
+                    encoded.clone(),
+                )
+            })?),
+            None => None,
+        };
+        let base_url = yaml
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .to_owned();
+        let base_url = parse_base_url(&base_url)
+            .map_err(|reason| {
+                UpdateError::InvalidArgument(
+                    "base_url".to_string(),
+                    format!("{} ({})", base_url, reason),
+                )
+            })?
+            .to_string();
+        let require_signed_responses = yaml.require_signed_responses.unwrap_or(false);
+        anyhow::ensure!(
+            patch_public_key.is_some() || !require_signed_responses,
+            UpdateError::InvalidArgument(
+                "require_signed_responses".to_string(),
+                "requires patch_public_key".to_string()
+            )
+        );
+        let patch_allowlist = match &yaml.patch_allowlist {
+            Some(allowlist) => Some(decode_patch_allowlist(
+                allowlist,
+                patch_public_key.as_ref(),
+            )?),
+            None => None,
+        };
+        let extra_headers = match &yaml.extra_headers {
+            Some(headers) => validate_extra_headers(headers)?,
+            None => Default::default(),
+        };
+        let pinned_patch_number = match yaml.pinned_patch_number {
+            Some(number) if !app_config.debug_build => {
+                warn!(
+                    "Ignoring pinned_patch_number {}, only debug builds of the app honor it.",
+                    number
+                );
+                None
+            }
+            pinned => pinned,
+        };
+        let request_signing = match &yaml.request_signing {
+            Some(signing) => Some(decode_request_signing(signing, &base_url, &clock)?),
+            None => None,
+        };
+
+        let release_version: ReleaseVersion = app_config.release_version.parse()?;
+        let channel: Channel = yaml.channel.as_deref().unwrap_or(DEFAULT_CHANNEL).parse()?;
+        let channel_fallbacks = yaml
+            .channel_fallbacks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|fallback| {
+                fallback.parse().map_err(|_| {
+                    UpdateError::InvalidArgument("channel_fallbacks".to_string(), fallback)
+                })
+            })
+            .collect::<Result<Vec<Channel>, _>>()?;
+        // Keep state for each channel separate on disk so that switching
+        // channels never mixes patches between them.
+        let legacy_cache_dir = cache_dir;
+        let cache_dir = legacy_cache_dir.join("channels").join(channel.as_str());
+        if let Err(err) = migrate_legacy_layout(&legacy_cache_dir, &cache_dir) {
+            warn!("Failed to migrate updater state: {:#}", err);
+        }
+
+        let mut cache_path = cache_dir.clone();
         cache_path.push("downloads");
-        let download_dir = cache_path;
+        let download_dir = match &app_config.download_dir {
+            // Mirror cache_dir's app_id/channel layout so downloads are kept
+            // apart the same way.
+            Some(preferred) => {
+                let relative = cache_dir
+                    .strip_prefix(&app_config.cache_dir)
+                    .unwrap_or(Path::new(""));
+                choose_download_dir(
+                    Path::new(preferred).join(relative).join("downloads"),
+                    cache_path,
+                )
+            }
+            None => cache_path,
+        };
+
+        let locale = match (yaml.report_locale.unwrap_or(false), &app_config.locale) {
+            (true, Some(locale)) => {
+                let coarse = coarse_locale(locale);
+                if coarse.is_none() {
+                    warn!("Not reporting unrecognized locale {:?}.", locale);
+                }
+                coarse
+            }
+            _ => None,
+        };
 
         let new_config = UpdateConfig {
-            cache_dir: std::path::PathBuf::from(app_config.cache_dir),
+            cache_dir,
             download_dir: download_dir,
-            channel: yaml
-                .channel
-                .as_deref()
-                .unwrap_or(DEFAULT_CHANNEL)
-                .to_owned(),
-            app_id: yaml.app_id.to_string(),
-            release_version: app_config.release_version.to_string(),
+            channel,
+            channel_fallbacks,
+            app_id,
+            release_version,
+            build_number: app_config.build_number,
             libapp_path,
-            base_url: yaml
-                .base_url
-                .as_deref()
-                .unwrap_or(DEFAULT_BASE_URL)
-                .to_owned(),
+            base_url,
+            native_fingerprint: yaml.native_fingerprint,
+            expected_apk_cert_sha256,
+            patch_public_key,
+            require_signed_responses,
+            patch_allowlist,
+            // An alignment of 0 or 1 would not change anything.
+            patch_alignment: yaml.patch_alignment.filter(|alignment| *alignment > 1),
+            prefault_patches: yaml.prefault_patches.unwrap_or(false),
+            durable_writes: yaml.durable_writes.unwrap_or(true),
+            allow_full_downloads: yaml.allow_full_downloads.unwrap_or(false),
+            decode_benchmark: yaml.decode_benchmark.unwrap_or(false),
+            memfd_max_bytes: yaml.memfd_max_bytes,
+            auto_update: yaml.auto_update.unwrap_or(true),
+            auto_update_jitter_seconds: yaml.auto_update_jitter_seconds.unwrap_or(0),
+            queue_follow_up_update: yaml.queue_follow_up_update.unwrap_or(false),
+            update_in_daemon: yaml.update_in_daemon.unwrap_or(false),
+            verify_boot_artifact: yaml.verify_boot_artifact.unwrap_or(false),
+            report_device_environment: yaml.report_device_environment.unwrap_or(false),
+            report_installed_patches: yaml.report_installed_patches.unwrap_or(false),
+            locale,
+            update_thread_priority: yaml.update_thread_priority.unwrap_or_default(),
+            pinned_patch_number,
+            log_network_bodies: yaml.log_network_bodies.unwrap_or(false),
+            retain_patch_count: yaml
+                .retain_patch_count
+                .unwrap_or(DEFAULT_RETAIN_PATCH_COUNT),
             network_hooks,
+            clock,
+            config_warnings,
         };
         info!("Updater configured with: {:?}", config);
+        crate::network::set_config_headers(&new_config.base_url, extra_headers);
         *config = Some(new_config);
+        crate::request_signing::set_config(request_signing);
+        crate::lifecycle::set_config(yaml.background_downloads.unwrap_or(false));
 
         Ok(())
     })
 }
 
+/// `preferred` if it is writable, otherwise `fallback`.
+fn choose_download_dir(preferred: PathBuf, fallback: PathBuf) -> PathBuf {
+    match probe_writable(&preferred) {
+        Ok(()) => preferred,
+        Err(err) => {
+            warn!("Using default download directory: {:#}", err);
+            fallback
+        }
+    }
+}
+
+/// Decodes a base64-encoded ed25519 public key, returns None if it is not
+/// one.
+fn decode_public_key(encoded: &str) -> Option<PublicKey> {
+    use base64::Engine;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+    let public_key = PublicKey::try_from(bytes.as_slice()).ok()?;
+    is_valid_public_key(&public_key).then_some(public_key)
+}
+
+/// Checks the signature on a patch_allowlist and decodes its hashes.
+fn decode_patch_allowlist(
+    allowlist: &PatchAllowlist,
+    public_key: Option<&PublicKey>,
+) -> Result<BTreeSet<Hash>, UpdateError> {
+    use base64::Engine;
+    let invalid = |reason: &str| {
+        UpdateError::InvalidArgument("patch_allowlist".to_string(), reason.to_string())
+    };
+    let public_key = public_key.ok_or_else(|| invalid("requires patch_public_key"))?;
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(&allowlist.signature)
+        .map_err(|_| invalid("signature is not base64"))?;
+    let message = allowlist.hashes.join("\n");
+    if !verify_signature(public_key, message.as_bytes(), &signature) {
+        return Err(invalid("invalid signature"));
+    }
+    allowlist
+        .hashes
+        .iter()
+        .map(|hash| decode_hash(hash).ok_or_else(|| invalid(hash)))
+        .collect()
+}
+
+/// Decodes the request_signing section, checking the key is base64.
+fn decode_request_signing(
+    signing: &RequestSigningConfig,
+    base_url: &str,
+    clock: &Arc<dyn Clock>,
+) -> Result<RequestSigning, UpdateError> {
+    use base64::Engine;
+    let key = match &signing.key {
+        Some(encoded) => Some(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|_| {
+                    UpdateError::InvalidArgument(
+                        "request_signing".to_string(),
+                        "key is not base64".to_string(),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+    Ok(RequestSigning {
+        base_url: base_url.to_owned(),
+        key,
+        scheme: signing.scheme.unwrap_or_default(),
+        clock: clock.clone(),
+    })
+}
+
+/// base_url must be an absolute http(s) URL, since request paths are joined
+/// onto it.  Parses base_url, or says what is wrong with it.  The path is given a
+/// trailing slash, so request paths join onto a prefix (e.g. a tenant's path
+/// behind a reverse proxy) rather than replacing its last segment.
+pub fn parse_base_url(base_url: &str) -> Result<url::Url, &'static str> {
+    let mut url = url::Url::parse(base_url).map_err(|_| "not a URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("not http(s)");
+    }
+    if !url.has_host() {
+        return Err("no host");
+    }
+    // Both would be dropped from every request URL.
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err("has a query or fragment");
+    }
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Overridden app_ids are used as directory names (as channels are, see
+/// Channel), so must be non-empty and must not contain path separators or be relative path components.
+fn is_valid_path_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// Reduces a locale (e.g. "zh_Hant_TW.UTF-8") to its language and country
+/// ("zh-TW"), dropping anything finer, or None if it doesn't start with a
+/// language.  Countries may be a region code (e.g. "419", Latin America).
+fn coarse_locale(locale: &str) -> Option<String> {
+    let locale = locale.split(['.', '@']).next()?;
+    let mut subtags = locale.split(['-', '_']);
+    let language = subtags.next()?;
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let language = language.to_ascii_lowercase();
+    let is_country = |subtag: &&str| {
+        (subtag.len() == 2 && subtag.chars().all(|c| c.is_ascii_alphabetic()))
+            || (subtag.len() == 3 && subtag.chars().all(|c| c.is_ascii_digit()))
+    };
+    Some(match subtags.find(is_country) {
+        Some(country) => format!("{}-{}", language, country.to_ascii_uppercase()),
+        None => language,
+    })
+}
+
 // Arch/Platform names need to be kept in sync with the shorebird cli.
 pub fn current_arch() -> &'static str {
     #[cfg(target_arch = "x86")]
@@ -145,3 +518,45 @@ pub fn current_platform() -> &'static str {
     static PLATFORM: &str = "ios";
     return PLATFORM;
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn coarse_locale() {
+        use super::coarse_locale;
+
+        assert_eq!(coarse_locale("en_US").as_deref(), Some("en-US"));
+        assert_eq!(coarse_locale("en-us").as_deref(), Some("en-US"));
+        assert_eq!(coarse_locale("zh_Hant_TW.UTF-8").as_deref(), Some("zh-TW"));
+        assert_eq!(coarse_locale("es-419").as_deref(), Some("es-419"));
+        assert_eq!(coarse_locale("de-DE-1996").as_deref(), Some("de-DE"));
+        assert_eq!(coarse_locale("sr@latin").as_deref(), Some("sr"));
+        assert_eq!(coarse_locale("FIL").as_deref(), Some("fil"));
+        for invalid in ["", "C", "english", "e1-US", "-US"] {
+            assert_eq!(coarse_locale(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_base_url() {
+        use super::parse_base_url;
+
+        let parsed = |base_url| parse_base_url(base_url).unwrap().to_string();
+        assert_eq!(
+            parsed("https://api.shorebird.dev"),
+            "https://api.shorebird.dev/"
+        );
+        assert_eq!(
+            parsed("https://example.com/tenant"),
+            "https://example.com/tenant/"
+        );
+        assert_eq!(
+            parsed("http://localhost:8080/a/b/"),
+            "http://localhost:8080/a/b/"
+        );
+        assert_eq!(parse_base_url("api.shorebird.dev"), Err("not a URL"));
+        assert_eq!(parse_base_url("ftp://example.com"), Err("not http(s)"));
+        assert!(parse_base_url("https://example.com/?tenant=1").is_err());
+        assert!(parse_base_url("https://example.com/#a").is_err());
+    }
+}
@@ -0,0 +1,266 @@
+// This file lets Rust hosts configure the updater with typed setters rather
+// than assembling an AppConfig and a shorebird.yaml string by hand.  The
+// builder still produces both (see UpdaterConfig), so init() stays the one
+// way in and the yaml remains the source of truth for FFI callers.
+
+use std::path::{Path, PathBuf};
+
+use crate::names::{Channel, ReleaseVersion};
+use crate::updater::{AppConfig, UpdateError};
+use crate::yaml::YamlConfig;
+
+/// What UpdaterConfigBuilder::build() produces: the arguments to init().
+pub struct UpdaterConfig {
+    pub app_config: AppConfig,
+    /// The equivalent of shorebird.yaml.
+    pub yaml: String,
+}
+
+/// Builds the configuration for init_with().  app_id, cache_dir,
+/// release_version and at least one libapp_path are required, everything
+/// else defaults as it would if left out of shorebird.yaml.
+pub struct UpdaterConfigBuilder {
+    cache_dir: Option<PathBuf>,
+    release_version: Option<String>,
+    libapp_paths: Vec<PathBuf>,
+    build_number: Option<u64>,
+    app_id_override: Option<String>,
+    download_dir: Option<PathBuf>,
+    locale: Option<String>,
+    debug_build: bool,
+    yaml: serde_yaml::Mapping,
+}
+
+fn path_to_string(argument: &str, path: &Path) -> Result<String, UpdateError> {
+    path.to_str().map(str::to_owned).ok_or_else(|| {
+        UpdateError::InvalidArgument(argument.to_string(), path.display().to_string())
+    })
+}
+
+impl UpdaterConfigBuilder {
+    pub fn new(app_id: &str) -> Self {
+        Self {
+            cache_dir: None,
+            release_version: None,
+            libapp_paths: Vec::new(),
+            build_number: None,
+            app_id_override: None,
+            download_dir: None,
+            locale: None,
+            debug_build: false,
+            yaml: serde_yaml::Mapping::new(),
+        }
+        .yaml_value("app_id", app_id)
+    }
+
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Checked by build(), see ReleaseVersion.
+    pub fn release_version(mut self, release_version: &str) -> Self {
+        self.release_version = Some(release_version.to_string());
+        self
+    }
+
+    /// Adds a path to the app's original libapp, see
+    /// AppConfig::original_libapp_paths.
+    pub fn libapp_path(mut self, libapp_path: impl Into<PathBuf>) -> Self {
+        self.libapp_paths.push(libapp_path.into());
+        self
+    }
+
+    pub fn build_number(mut self, build_number: u64) -> Self {
+        self.build_number = Some(build_number);
+        self
+    }
+
+    /// Only honored with allow_app_id_override(true).
+    pub fn app_id_override(mut self, app_id: &str) -> Self {
+        self.app_id_override = Some(app_id.to_string());
+        self
+    }
+
+    pub fn download_dir(mut self, download_dir: impl Into<PathBuf>) -> Self {
+        self.download_dir = Some(download_dir.into());
+        self
+    }
+
+    /// Only sent with report_locale(true), see AppConfig::locale.
+    pub fn locale(mut self, locale: &str) -> Self {
+        self.locale = Some(locale.to_string());
+        self
+    }
+
+    /// See AppConfig::debug_build.
+    pub fn debug_build(mut self, debug_build: bool) -> Self {
+        self.debug_build = debug_build;
+        self
+    }
+
+    pub fn channel(self, channel: Channel) -> Self {
+        self.yaml_value("channel", channel.as_str())
+    }
+
+    pub fn channel_fallbacks(self, channels: &[Channel]) -> Self {
+        let channels: Vec<&str> = channels.iter().map(Channel::as_str).collect();
+        self.yaml_value("channel_fallbacks", channels)
+    }
+
+    /// Checked by build(), must be an absolute http(s) URL.
+    pub fn base_url(self, base_url: &str) -> Self {
+        self.yaml_value("base_url", base_url)
+    }
+
+    pub fn allow_app_id_override(self, allow: bool) -> Self {
+        self.yaml_value("allow_app_id_override", allow)
+    }
+
+    pub fn auto_update(self, enabled: bool) -> Self {
+        self.yaml_value("auto_update", enabled)
+    }
+
+    pub fn patch_public_key(self, key: &str) -> Self {
+        self.yaml_value("patch_public_key", key)
+    }
+
+    pub fn report_locale(self, enabled: bool) -> Self {
+        self.yaml_value("report_locale", enabled)
+    }
+
+    /// Sets any other shorebird.yaml key, see YamlConfig.  Unknown keys and
+    /// values of the wrong type fail build().
+    pub fn yaml_value(mut self, key: &str, value: impl Into<serde_yaml::Value>) -> Self {
+        self.yaml.insert(key.into(), value.into());
+        self
+    }
+
+    /// Checks everything init() would, apart from what needs the cache
+    /// directory or libapp to exist.
+    pub fn build(self) -> Result<UpdaterConfig, UpdateError> {
+        let invalid_yaml = |message: String| UpdateError::InvalidArgument("yaml".into(), message);
+        let yaml = serde_yaml::to_string(&self.yaml).map_err(|e| invalid_yaml(e.to_string()))?;
+        let parsed = YamlConfig::from_yaml(&yaml).map_err(|e| invalid_yaml(e.to_string()))?;
+        let warnings = parsed.warnings();
+        if !warnings.is_empty() {
+            return Err(invalid_yaml(warnings.join(", ")));
+        }
+        if let Some(base_url) = &parsed.base_url {
+            if let Err(reason) = crate::config::parse_base_url(base_url) {
+                return Err(UpdateError::InvalidArgument(
+                    "base_url".into(),
+                    format!("{} ({})", base_url, reason),
+                ));
+            }
+        }
+
+        let cache_dir = self
+            .cache_dir
+            .ok_or_else(|| UpdateError::InvalidArgument("cache_dir".into(), "unset".into()))?;
+        let release_version = self.release_version.unwrap_or_default();
+        release_version.parse::<ReleaseVersion>()?;
+        if self.libapp_paths.is_empty() {
+            return Err(UpdateError::InvalidArgument(
+                "libapp_path".into(),
+                "unset".into(),
+            ));
+        }
+        let original_libapp_paths = self
+            .libapp_paths
+            .iter()
+            .map(|path| path_to_string("libapp_path", path))
+            .collect::<Result<_, _>>()?;
+        let download_dir = match &self.download_dir {
+            Some(dir) => Some(path_to_string("download_dir", dir)?),
+            None => None,
+        };
+
+        Ok(UpdaterConfig {
+            app_config: AppConfig {
+                cache_dir: path_to_string("cache_dir", &cache_dir)?,
+                release_version,
+                original_libapp_paths,
+                build_number: self.build_number,
+                app_id_override: self.app_id_override,
+                download_dir,
+                locale: self.locale,
+                debug_build: self.debug_build,
+            },
+            yaml,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpdaterConfigBuilder;
+    use crate::yaml::YamlConfig;
+    use crate::UpdateError;
+
+    fn builder() -> UpdaterConfigBuilder {
+        UpdaterConfigBuilder::new("1234")
+            .cache_dir("/cache")
+            .release_version("1.0.0+1")
+            .libapp_path("/app/libapp.so")
+    }
+
+    #[test]
+    fn builds_app_config_and_yaml() {
+        let config = builder()
+            .channel("beta".parse().unwrap())
+            .channel_fallbacks(&["stable".parse().unwrap()])
+            .base_url("https://example.com")
+            .auto_update(false)
+            .yaml_value("retain_patch_count", 3)
+            .build_number(7)
+            .build()
+            .unwrap();
+        assert_eq!(config.app_config.cache_dir, "/cache");
+        assert_eq!(config.app_config.release_version, "1.0.0+1");
+        assert_eq!(config.app_config.original_libapp_paths, ["/app/libapp.so"]);
+        assert_eq!(config.app_config.build_number, Some(7));
+
+        let yaml = YamlConfig::from_yaml(&config.yaml).unwrap();
+        assert_eq!(yaml.app_id, "1234");
+        assert_eq!(yaml.channel.as_deref(), Some("beta"));
+        assert_eq!(yaml.channel_fallbacks, Some(vec!["stable".to_string()]));
+        assert_eq!(yaml.base_url.as_deref(), Some("https://example.com"));
+        assert_eq!(yaml.auto_update, Some(false));
+        assert_eq!(yaml.retain_patch_count, Some(3));
+    }
+
+    #[test]
+    fn build_validates() {
+        let invalid_argument = |builder: UpdaterConfigBuilder| match builder.build() {
+            Err(UpdateError::InvalidArgument(argument, _)) => argument,
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        };
+        assert_eq!(invalid_argument(builder().base_url("ftp://x")), "base_url");
+        assert_eq!(
+            invalid_argument(builder().release_version(" ")),
+            "release_version"
+        );
+        assert_eq!(
+            invalid_argument(builder().yaml_value("auto_updates", false)),
+            "yaml"
+        );
+        assert_eq!(
+            invalid_argument(builder().yaml_value("auto_update", "sometimes")),
+            "yaml"
+        );
+        assert_eq!(
+            invalid_argument(UpdaterConfigBuilder::new("1234").libapp_path("/a")),
+            "cache_dir"
+        );
+        assert_eq!(
+            invalid_argument(
+                UpdaterConfigBuilder::new("1234")
+                    .cache_dir("/c")
+                    .release_version("1")
+            ),
+            "libapp_path"
+        );
+    }
+}
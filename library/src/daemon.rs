@@ -0,0 +1,248 @@
+// This file lets a separate process do the app's updates (daemon mode, the
+// "daemon" feature), so e.g. a download started while a desktop app was
+// open can finish after the app exits.  The host spawns the
+// shorebird_updater_daemon binary with the parameters it passes to init, and
+// sets update_in_daemon in shorebird.yaml so the app's own update thread
+// leaves checking to the daemon.  The app still reads state (e.g. for the
+// next boot patch) and reports launches as usual.
+//
+// Both processes share the cache directory.  Updates hold CacheLock
+// throughout, so only one process updates at a time.  Every state write
+// holds StateLock, and a state which another process saved since this one
+// read it is never saved over (see state_changed_elsewhere()), so e.g. an
+// install is abandoned if the app marked a patch bad while it downloaded.
+// The next run of the daemon tries again.  Writes which only load, modify
+// and save (e.g. reporting a launch) hold StateLock throughout, so they
+// never find the state changed.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::file_errors::IoResultExt;
+use crate::updater::{AppConfig, UpdateError, UpdateStatus};
+
+/// cbindgen:ignore
+const LOCK_FILE_NAME: &str = "update.lock";
+
+/// cbindgen:ignore
+const STATE_LOCK_FILE_NAME: &str = "state.lock";
+
+/// cbindgen:ignore
+pub const DAEMON_USAGE: &str = "Usage: shorebird_updater_daemon --cache-dir <dir> \
+--release-version <version> --libapp-path <path> [--libapp-path <path>...] \
+[--build-number <number>] [--locale <locale>] --yaml <shorebird.yaml>";
+
+/// Held by whichever process is updating the cache directory.  Released on
+/// drop, or by the OS if the process dies.
+pub struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    /// Fails with UpdateError::UpdateAlreadyInProgress if another process
+    /// (or another CacheLock in this one) holds the lock.
+    pub fn acquire(cache_dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(cache_dir).with_file_context("create directory", cache_dir)?;
+        let path = cache_dir.join(LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_file_context("open", &path)?;
+        match lock(&file, false) {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                anyhow::bail!(UpdateError::UpdateAlreadyInProgress)
+            }
+            Err(e) => Err(e).with_file_context("lock", &path),
+        }
+    }
+}
+
+thread_local! {
+    /// How many StateLocks this thread holds, see StateLock::acquire().
+    static STATE_LOCK_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Held while writing the state in cache_dir, so another process can't
+/// save it in between.  Unlike CacheLock this waits for the other process,
+/// which only holds it briefly.
+pub struct StateLock {
+    _file: Option<File>,
+}
+
+impl StateLock {
+    /// Waits for the lock.  A thread which already holds it (e.g. saving
+    /// the state it locked to load) gets it again straight away.
+    pub fn acquire(cache_dir: &Path) -> anyhow::Result<Self> {
+        if STATE_LOCK_DEPTH.get() > 0 {
+            STATE_LOCK_DEPTH.set(STATE_LOCK_DEPTH.get() + 1);
+            return Ok(Self { _file: None });
+        }
+        std::fs::create_dir_all(cache_dir).with_file_context("create directory", cache_dir)?;
+        let path = cache_dir.join(STATE_LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_file_context("open", &path)?;
+        lock(&file, true).with_file_context("lock", &path)?;
+        STATE_LOCK_DEPTH.set(1);
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        STATE_LOCK_DEPTH.set(STATE_LOCK_DEPTH.get() - 1);
+    }
+}
+
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    // flock locks belong to the open file, so are released when it closes.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(());
+    }
+    Err(std::io::Error::last_os_error())
+}
+
+#[cfg(not(unix))]
+fn lock(_: &File, _: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "daemon mode is only supported on unix",
+    ))
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<(AppConfig, PathBuf)> {
+    let mut app_config = AppConfig {
+        cache_dir: String::new(),
+        release_version: String::new(),
+        original_libapp_paths: Vec::new(),
+        build_number: None,
+        app_id_override: None,
+        download_dir: None,
+        locale: None,
+        debug_build: false,
+    };
+    let mut yaml_path = None;
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            anyhow::bail!("Missing value for {}", flag);
+        };
+        match flag.as_str() {
+            "--cache-dir" => app_config.cache_dir = value,
+            "--release-version" => app_config.release_version = value,
+            "--libapp-path" => app_config.original_libapp_paths.push(value),
+            "--build-number" => app_config.build_number = Some(value.parse()?),
+            "--locale" => app_config.locale = Some(value),
+            "--yaml" => yaml_path = Some(PathBuf::from(value)),
+            _ => anyhow::bail!("Unknown argument {}", flag),
+        }
+    }
+    anyhow::ensure!(!app_config.cache_dir.is_empty(), "Missing --cache-dir");
+    anyhow::ensure!(
+        !app_config.original_libapp_paths.is_empty(),
+        "Missing --libapp-path"
+    );
+    let yaml_path = yaml_path.ok_or_else(|| anyhow::anyhow!("Missing --yaml"))?;
+    Ok((app_config, yaml_path))
+}
+
+/// Runs one update for the app described by args (see DAEMON_USAGE), as the
+/// shorebird_updater_daemon binary does.
+pub fn run_daemon(args: impl IntoIterator<Item = String>) -> anyhow::Result<UpdateStatus> {
+    let (app_config, yaml_path) = parse_args(args)?;
+    let yaml = std::fs::read_to_string(&yaml_path).with_file_context("read", &yaml_path)?;
+    crate::updater::init(app_config, &yaml)?;
+    crate::updater::update()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, CacheLock, StateLock};
+    use crate::UpdateError;
+    use tempdir::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_args() {
+        let (app_config, yaml_path) = parse_args(args(&[
+            "--cache-dir",
+            "/cache",
+            "--release-version",
+            "1.0.0+1",
+            "--libapp-path",
+            "/app/libapp.so",
+            "--build-number",
+            "7",
+            "--yaml",
+            "/app/shorebird.yaml",
+        ]))
+        .unwrap();
+        assert_eq!(app_config.cache_dir, "/cache");
+        assert_eq!(app_config.release_version, "1.0.0+1");
+        assert_eq!(app_config.original_libapp_paths, vec!["/app/libapp.so"]);
+        assert_eq!(app_config.build_number, Some(7));
+        assert_eq!(yaml_path.to_str(), Some("/app/shorebird.yaml"));
+
+        for invalid in [
+            args(&["--cache-dir"]),
+            args(&["--cache-dir", "/cache", "--yaml", "y", "--verbose", "1"]),
+            args(&["--libapp-path", "/app/libapp.so", "--yaml", "y"]),
+            args(&["--cache-dir", "/cache", "--libapp-path", "/app/libapp.so"]),
+        ] {
+            assert!(parse_args(invalid.clone()).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_lock_is_exclusive() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let lock = CacheLock::acquire(tmp_dir.path()).unwrap();
+        let error = CacheLock::acquire(tmp_dir.path()).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<UpdateError>(),
+            Some(&UpdateError::UpdateAlreadyInProgress)
+        );
+        drop(lock);
+        assert!(CacheLock::acquire(tmp_dir.path()).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn state_lock_waits_for_other_holders() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path().to_owned();
+        let outer = StateLock::acquire(&cache_dir).unwrap();
+        // The holding thread can take it again, e.g. to save.
+        drop(StateLock::acquire(&cache_dir).unwrap());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let _lock = StateLock::acquire(&cache_dir).unwrap();
+            sender.send(()).unwrap();
+        });
+        let timeout = std::time::Duration::from_millis(100);
+        assert!(receiver.recv_timeout(timeout).is_err());
+        drop(outer);
+        receiver.recv().unwrap();
+        waiter.join().unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+// This file measures how fast the device decompresses each patch format the
+// updater supports, so the server can (in the future) pick the best format
+// for each class of device.  It only runs once per install, and only if the
+// app opts in (see YamlConfig::decode_benchmark).
+//
+// zstd is the only format the updater decodes today.  Formats are reported
+// by name so others (e.g. brotli) can be added without a server change.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(test)]
+use std::println as info; // Workaround to use println! for logs.
+
+/// Decompressed size of the sample each format decodes.
+/// cbindgen:ignore
+const SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Each format decodes its sample repeatedly for about this long.
+/// cbindgen:ignore
+const TIME_PER_FORMAT: Duration = Duration::from_millis(50);
+
+/// The result of run(), sent once with the next patch check.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DecodeBenchmark {
+    /// Decompressed bytes per second, by format (e.g. "zstd").
+    pub bytes_per_second: BTreeMap<String, u64>,
+}
+
+/// Sample data which compresses roughly like code: repetitive, but not
+/// trivially so.  Deterministic, so every device decodes the same bytes.
+fn sample() -> Vec<u8> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    let mut seed: u32 = 0x5eed;
+    while sample.len() < SAMPLE_SIZE {
+        // A small linear congruential generator is plenty here.
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let word = (seed >> 16) % 64;
+        sample.extend_from_slice(&word.to_le_bytes()[..2]);
+        sample.extend_from_slice(b"\x00\x01\x02\x03");
+    }
+    sample.truncate(SAMPLE_SIZE);
+    sample
+}
+
+/// Decodes compressed repeatedly for about TIME_PER_FORMAT (at least once)
+/// and returns the decompressed bytes per second.
+fn measure(
+    compressed: &[u8],
+    decode: impl Fn(&[u8], &mut Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    let mut output = Vec::with_capacity(SAMPLE_SIZE);
+    let mut decoded = 0u64;
+    let start = Instant::now();
+    loop {
+        output.clear();
+        decode(compressed, &mut output)?;
+        anyhow::ensure!(output.len() == SAMPLE_SIZE, "Decoded the wrong size.");
+        decoded += output.len() as u64;
+        if start.elapsed() >= TIME_PER_FORMAT {
+            break;
+        }
+    }
+    let nanos = start.elapsed().as_nanos().max(1);
+    Ok((u128::from(decoded) * 1_000_000_000 / nanos) as u64)
+}
+
+/// Measures the decode speed of each supported format.  Takes a little over
+/// TIME_PER_FORMAT per format, so should be run off the UI thread.
+pub fn run() -> anyhow::Result<DecodeBenchmark> {
+    use comde::com::Compressor;
+    use comde::de::Decompressor;
+    use comde::zstd::{ZstdCompressor, ZstdDecompressor};
+
+    let sample = sample();
+    let mut bytes_per_second = BTreeMap::new();
+
+    let mut zstd = std::io::Cursor::new(Vec::new());
+    ZstdCompressor::new().compress(&mut zstd, &mut &sample[..])?;
+    let zstd = zstd.into_inner();
+    let speed = measure(&zstd, |compressed, output| {
+        ZstdDecompressor::new().copy(compressed, output)?;
+        Ok(())
+    })?;
+    bytes_per_second.insert("zstd".to_string(), speed);
+
+    info!("Decode benchmark: {:?}", bytes_per_second);
+    Ok(DecodeBenchmark { bytes_per_second })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sample_is_deterministic() {
+        let sample = super::sample();
+        assert_eq!(sample.len(), super::SAMPLE_SIZE);
+        assert_eq!(sample, super::sample());
+    }
+
+    #[test]
+    fn run() {
+        let benchmark = super::run().unwrap();
+        assert!(benchmark.bytes_per_second["zstd"] > 0);
+    }
+}
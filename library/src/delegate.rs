@@ -0,0 +1,107 @@
+// This file deals with notifying the embedder of things the updater did, so
+// it doesn't need to poll the state after a background update, and with
+// letting it veto installing what a patch check offers (including because an
+// app store update is waiting to install, which would replace the release
+// anyway).
+
+use std::sync::{Arc, RwLock};
+
+/// Implemented by hosts which want to hear about updater events, see
+/// updater::set_delegate.  Callbacks may come from any thread (e.g. the
+/// update thread) and are made without holding any updater locks, so it is
+/// safe to call back into the updater (e.g. update()) from them.
+pub trait UpdaterDelegate: Send + Sync + 'static {
+    /// A patch was installed and will be used on the next boot.
+    fn on_patch_installed(&self, _patch_number: usize) {}
+    /// A patch was marked bad (e.g. it failed to launch, was rolled back or
+    /// isn't in patch_allowlist) and will not be booted again.  Every path
+    /// which marks a patch bad calls this.  Slots dropped when the state is
+    /// loaded because their artifact is missing or corrupt are not marked
+    /// bad (the patch may be downloaded again), so aren't reported here, see
+    /// boot_decision().
+    fn on_patch_invalidated(&self, _patch_number: usize) {}
+    /// An update failed.
+    fn on_update_error(&self, _error: &anyhow::Error) {}
+    /// A critical patch was installed, the app should prompt the user to
+    /// restart so it takes effect.  Follows on_patch_installed.
+    fn on_restart_requested(&self, _patch_number: usize) {}
+    /// The patch being booted changed on disk after next_boot_patch()
+    /// selected it (see YamlConfig::verify_boot_artifact), so it was marked
+    /// bad.  Follows on_patch_invalidated.
+    fn on_boot_artifact_changed(&self, _patch_number: usize) {}
+    /// The app was replaced by an older release (e.g. a sideloaded older
+    /// APK).  The newer release's patches are kept for if it returns rather
+    /// than deleted.
+    fn on_release_downgraded(&self, _from: &str, _to: &str) {}
+}
+
+static DELEGATE: RwLock<Option<Arc<dyn UpdaterDelegate>>> = RwLock::new(None);
+
+/// Replaces the delegate, or removes it if None.
+pub fn set_delegate(delegate: Option<Arc<dyn UpdaterDelegate>>) {
+    *DELEGATE.write().expect("Failed to acquire delegate lock.") = delegate;
+}
+
+/// Called with the JSON of a patch check response offering a patch, before
+/// the update downloads it.  Returns false to veto installing it.
+pub type PatchCheckResponseCallback = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+static RESPONSE_CALLBACK: RwLock<Option<PatchCheckResponseCallback>> = RwLock::new(None);
+
+/// Replaces the patch check response callback, or removes it if None.
+pub fn set_response_callback(callback: Option<PatchCheckResponseCallback>) {
+    *RESPONSE_CALLBACK
+        .write()
+        .expect("Failed to acquire delegate lock.") = callback;
+}
+
+/// Whether the response callback (if any) allows installing what `response`
+/// offers.  `response` is only serialized if there is a callback.
+pub fn response_allowed(response: &crate::network::PatchCheckResponse) -> anyhow::Result<bool> {
+    let callback = RESPONSE_CALLBACK
+        .read()
+        .expect("Failed to acquire delegate lock.")
+        .clone();
+    match callback {
+        Some(callback) => Ok(callback(&serde_json::to_string(response)?)),
+        None => Ok(true),
+    }
+}
+
+/// Returns whether an app store update (e.g. a Play in-app update) is
+/// downloaded or downloading and waiting to install.
+pub type StoreUpdatePendingCallback = Arc<dyn Fn() -> bool + Send + Sync>;
+
+static STORE_UPDATE_CALLBACK: RwLock<Option<StoreUpdatePendingCallback>> = RwLock::new(None);
+
+/// Replaces the store update pending callback, or removes it if None.
+pub fn set_store_update_pending_callback(callback: Option<StoreUpdatePendingCallback>) {
+    *STORE_UPDATE_CALLBACK
+        .write()
+        .expect("Failed to acquire delegate lock.") = callback;
+}
+
+/// Asks the host whether a store update is pending, false if it hasn't said
+/// how to tell.
+pub fn is_store_update_pending() -> bool {
+    let callback = STORE_UPDATE_CALLBACK
+        .read()
+        .expect("Failed to acquire delegate lock.")
+        .clone();
+    callback.is_some_and(|callback| callback())
+}
+
+/// Calls f with the delegate, if there is one.  The lock is released first
+/// so the delegate may replace itself.
+pub fn notify_delegate<F>(f: F)
+where
+    F: FnOnce(&dyn UpdaterDelegate),
+{
+    let delegate = DELEGATE
+        .read()
+        .expect("Failed to acquire delegate lock.")
+        .clone();
+    if let Some(delegate) = delegate {
+        f(delegate.as_ref());
+    }
+}
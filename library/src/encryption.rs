@@ -0,0 +1,317 @@
+// This file decrypts patches which are encrypted in transit, so their bytes
+// are unreadable to anyone (e.g. a CDN operator) without the app's key.  The
+// server marks such patches with Patch::encryption_key_id and the app
+// supplies the key for that id (see set_key_provider).
+//
+// The downloaded file (i.e. the compressed patch) is encrypted with
+// AES-256-GCM in fixed size chunks, so each chunk can be authenticated as it
+// streams into the inflate pipeline rather than only once the whole file has
+// been read:
+//
+//   7 bytes  random nonce prefix
+//   chunks   CHUNK_SIZE bytes of plaintext each (the last may be shorter,
+//            or empty) followed by its tag, sealed with the nonce
+//            prefix || chunk index (u32, big endian) || 1 if last else 0
+//
+// Marking the last chunk means a truncated file fails to decrypt rather than
+// looking like a shorter patch.
+//
+// Decrypting needs the "encryption" feature.  Without it the key provider
+// can still be set, but key_for() fails, so encrypted patches don't download.
+
+#[cfg(feature = "encryption")]
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "encryption")]
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+use crate::updater::UpdateError;
+
+/// cbindgen:ignore
+#[cfg(feature = "encryption")]
+const NONCE_PREFIX_LEN: usize = 7;
+/// Plaintext bytes per chunk, the last chunk may have fewer.
+/// cbindgen:ignore
+#[cfg(feature = "encryption")]
+const CHUNK_SIZE: usize = 64 * 1024;
+/// cbindgen:ignore
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+/// AES-256 keys are 32 bytes.
+/// cbindgen:ignore
+pub const PATCH_KEY_LEN: usize = 32;
+
+/// Returns the key for the given key id, or None if the host has no such key.
+pub type PatchKeyProvider = Arc<dyn Fn(&str) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Set by set_key_provider.
+static KEY_PROVIDER: RwLock<Option<PatchKeyProvider>> = RwLock::new(None);
+
+/// Replaces the host's key provider, or removes it if None.
+pub fn set_key_provider(provider: Option<PatchKeyProvider>) {
+    *KEY_PROVIDER
+        .write()
+        .expect("Failed to acquire patch key lock.") = provider;
+}
+
+/// A key for decrypting patches, see DecryptingReader.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub struct PatchKey([u8; PATCH_KEY_LEN]);
+
+impl PatchKey {
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+}
+
+impl std::fmt::Debug for PatchKey {
+    // Keys do not belong in logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("PatchKey(..)")
+    }
+}
+
+/// Asks the host's key provider for the key with id `key_id`.
+pub fn key_for(key_id: &str) -> anyhow::Result<PatchKey> {
+    anyhow::ensure!(
+        cfg!(feature = "encryption"),
+        "Patch is encrypted with key {}, but the updater was built without the \"encryption\" feature.",
+        key_id
+    );
+    let provider = KEY_PROVIDER
+        .read()
+        .expect("Failed to acquire patch key lock.")
+        .clone();
+    let Some(provider) = provider else {
+        anyhow::bail!(UpdateError::PatchKeyUnavailable(key_id.to_string()));
+    };
+    match provider(key_id) {
+        Some(bytes) => PatchKey::new(&bytes).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Patch key {} is {} bytes, expected {}.",
+                key_id,
+                bytes.len(),
+                PATCH_KEY_LEN
+            )
+        }),
+        None => anyhow::bail!(UpdateError::PatchKeyUnavailable(key_id.to_string())),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(feature = "encryption")]
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Reads until `buffer` is full or `reader` ends, returning the bytes read.
+#[cfg(feature = "encryption")]
+fn read_up_to(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Decrypts an encrypted patch (see the top of this file) as it is read.
+/// Fails with ErrorKind::InvalidData if any chunk doesn't authenticate, so
+/// nothing tampered with (or truncated) is ever returned.
+#[cfg(feature = "encryption")]
+pub struct DecryptingReader<R> {
+    inner: R,
+    key: LessSafeKey,
+    nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    index: u32,
+    /// Holds a whole sealed chunk, plus one byte to tell whether it is last.
+    buffer: Vec<u8>,
+    /// The decrypted chunk being returned is buffer[position..end].
+    position: usize,
+    end: usize,
+    /// The first byte of the next chunk, read while checking for the end.
+    carry: Option<u8>,
+    finished: bool,
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(inner: R, key: &PatchKey) -> Self {
+        let key = UnboundKey::new(&AES_256_GCM, &key.0).expect("AES-256 keys are 32 bytes");
+        Self {
+            inner,
+            key: LessSafeKey::new(key),
+            nonce_prefix: None,
+            index: 0,
+            buffer: vec![0u8; CHUNK_SIZE + TAG_LEN + 1],
+            position: 0,
+            end: 0,
+            carry: None,
+            finished: false,
+        }
+    }
+
+    fn decrypt_next_chunk(&mut self) -> std::io::Result<()> {
+        let prefix = match self.nonce_prefix {
+            Some(prefix) => prefix,
+            None => {
+                let mut prefix = [0u8; NONCE_PREFIX_LEN];
+                if read_up_to(&mut self.inner, &mut prefix)? != NONCE_PREFIX_LEN {
+                    return Err(invalid_data("Encrypted patch is truncated.".to_string()));
+                }
+                *self.nonce_prefix.insert(prefix)
+            }
+        };
+        let mut len = 0;
+        if let Some(byte) = self.carry.take() {
+            self.buffer[0] = byte;
+            len = 1;
+        }
+        len += read_up_to(&mut self.inner, &mut self.buffer[len..])?;
+        // A full buffer means there is at least one more chunk.
+        let last = len < self.buffer.len();
+        if !last {
+            len -= 1;
+            self.carry = Some(self.buffer[len]);
+        }
+        if len < TAG_LEN {
+            return Err(invalid_data("Encrypted patch is truncated.".to_string()));
+        }
+        let index = self.index;
+        let nonce = nonce(&prefix, index, last);
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut self.buffer[..len])
+            .map_err(|_| {
+                invalid_data(format!(
+                    "Encrypted patch chunk {} failed to decrypt.",
+                    index
+                ))
+            })?;
+        self.position = 0;
+        self.end = plaintext.len();
+        self.finished = last;
+        self.index = index
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("Encrypted patch has too many chunks.".to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.end {
+            if self.finished {
+                return Ok(0);
+            }
+            self.decrypt_next_chunk()?;
+        }
+        let count = buf.len().min(self.end - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+pub mod testing {
+    use ring::aead::{Aad, LessSafeKey, UnboundKey, AES_256_GCM};
+
+    /// Encrypts `plaintext` the way the patch tool does.
+    pub fn encrypt(key: &[u8; super::PATCH_KEY_LEN], plaintext: &[u8]) -> Vec<u8> {
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+        let prefix = [7u8; super::NONCE_PREFIX_LEN];
+        let mut output = prefix.to_vec();
+        let mut chunks: Vec<&[u8]> = plaintext.chunks(super::CHUNK_SIZE).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let count = chunks.len();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let mut sealed = chunk.to_vec();
+            let nonce = super::nonce(&prefix, index as u32, index + 1 == count);
+            key.seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+                .unwrap();
+            output.extend(sealed);
+        }
+        output
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::testing::encrypt;
+    use super::{key_for, set_key_provider, DecryptingReader, PatchKey, CHUNK_SIZE};
+    use crate::UpdateError;
+    use serial_test::serial;
+    use std::io::Read;
+
+    const KEY: [u8; 32] = [42u8; 32];
+
+    fn decrypt(encrypted: &[u8], key: &[u8; 32]) -> std::io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        DecryptingReader::new(encrypted, &PatchKey::new(key).unwrap()).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn decrypts_chunks() {
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 100] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&KEY, &plaintext);
+            assert_eq!(decrypt(&encrypted, &KEY).unwrap(), plaintext, "{}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let plaintext = vec![1u8; CHUNK_SIZE * 2 + 100];
+        let encrypted = encrypt(&KEY, &plaintext);
+        assert!(decrypt(&encrypted, &[1u8; 32]).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered[CHUNK_SIZE + 100] ^= 1;
+        assert!(decrypt(&tampered, &KEY).is_err());
+
+        // Dropping whole chunks must not look like a shorter patch.
+        let sealed_chunk = CHUNK_SIZE + super::TAG_LEN;
+        let truncated = &encrypted[..super::NONCE_PREFIX_LEN + sealed_chunk * 2];
+        let error = decrypt(truncated, &KEY).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(decrypt(&encrypted[..3], &KEY).is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn keys_come_from_the_provider() {
+        set_key_provider(None);
+        assert_eq!(
+            key_for("k1").unwrap_err().downcast_ref::<UpdateError>(),
+            Some(&UpdateError::PatchKeyUnavailable("k1".to_string()))
+        );
+        set_key_provider(Some(std::sync::Arc::new(|key_id| match key_id {
+            "k1" => Some(KEY.to_vec()),
+            "short" => Some(vec![1, 2, 3]),
+            _ => None,
+        })));
+        assert!(key_for("k1").is_ok());
+        assert!(key_for("short").is_err());
+        assert!(key_for("k2").is_err());
+        set_key_provider(None);
+    }
+}
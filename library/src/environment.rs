@@ -0,0 +1,112 @@
+// This file deals with detecting emulators and rooted/jailbroken devices, so
+// QA and emulator noise can be filtered out of reliability metrics.  These
+// are heuristics: they are cheap and easily fooled, and are only reported if
+// the app opts in (see YamlConfig::report_device_environment).
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+/// What we could tell about the device.  None means unknown (e.g. a platform
+/// we have no heuristics for).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct DeviceEnvironment {
+    pub is_emulator: Option<bool>,
+    pub is_rooted: Option<bool>,
+}
+
+/// Paths which only exist on rooted Android devices.
+#[cfg(any(target_os = "android", test))]
+const ANDROID_SU_PATHS: &[&str] = &[
+    "/system/bin/su",
+    "/system/xbin/su",
+    "/sbin/su",
+    "/system/sd/xbin/su",
+    "/data/local/bin/su",
+    "/data/local/xbin/su",
+];
+
+/// Paths which only exist on jailbroken iOS devices.
+#[cfg(any(target_os = "ios", test))]
+const IOS_JAILBREAK_PATHS: &[&str] = &[
+    "/Applications/Cydia.app",
+    "/Library/MobileSubstrate/MobileSubstrate.dylib",
+    "/bin/bash",
+    "/usr/sbin/sshd",
+    "/etc/apt",
+    "/private/var/lib/apt/",
+    "/var/jb",
+];
+
+#[cfg(any(target_os = "android", target_os = "ios", test))]
+fn any_path_exists(paths: &[&str]) -> bool {
+    paths.iter().any(|path| std::path::Path::new(path).exists())
+}
+
+/// Reads an Android system property, or None if it is unset.
+#[cfg(target_os = "android")]
+fn system_property(name: &str) -> Option<String> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // PROP_VALUE_MAX is 92.
+    let mut value = [0 as libc::c_char; 92];
+    let length = unsafe { libc::__system_property_get(name.as_ptr(), value.as_mut_ptr()) };
+    if length <= 0 {
+        return None;
+    }
+    let value = unsafe { std::ffi::CStr::from_ptr(value.as_ptr()) };
+    Some(value.to_string_lossy().into_owned())
+}
+
+#[cfg(target_os = "android")]
+fn detect() -> DeviceEnvironment {
+    let is_emulator = system_property("ro.kernel.qemu").as_deref() == Some("1")
+        || system_property("ro.hardware")
+            .is_some_and(|hardware| hardware.contains("goldfish") || hardware.contains("ranchu"));
+    DeviceEnvironment {
+        is_emulator: Some(is_emulator),
+        is_rooted: Some(any_path_exists(ANDROID_SU_PATHS)),
+    }
+}
+
+#[cfg(target_os = "ios")]
+fn detect() -> DeviceEnvironment {
+    // The simulator runs on the host, so the jailbreak paths are meaningless.
+    let is_emulator = std::env::var_os("SIMULATOR_DEVICE_NAME").is_some();
+    DeviceEnvironment {
+        is_emulator: Some(is_emulator),
+        is_rooted: (!is_emulator).then(|| any_path_exists(IOS_JAILBREAK_PATHS)),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn detect() -> DeviceEnvironment {
+    DeviceEnvironment::default()
+}
+
+/// The environment of this device, detected once per process.
+pub fn device_environment() -> DeviceEnvironment {
+    static ENVIRONMENT: OnceCell<DeviceEnvironment> = OnceCell::new();
+    *ENVIRONMENT.get_or_init(detect)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn heuristic_paths_are_absolute() {
+        for path in super::ANDROID_SU_PATHS
+            .iter()
+            .chain(super::IOS_JAILBREAK_PATHS)
+        {
+            assert!(path.starts_with('/'), "{}", path);
+        }
+        assert!(!super::any_path_exists(&["/does/not/exist"]));
+        assert!(super::any_path_exists(&["/does/not/exist", "/"]));
+    }
+
+    #[test]
+    fn unknown_on_other_platforms() {
+        assert_eq!(
+            super::device_environment(),
+            super::DeviceEnvironment::default()
+        );
+    }
+}
@@ -0,0 +1,66 @@
+// This file's job is to turn io::Errors from filesystem operations into
+// errors which say which file was involved and, where we can tell, what the
+// user or developer can do about it (e.g. storage full, permissions).
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// A hint for resolving common filesystem errors, if we have one.
+fn hint_for(error: &std::io::Error) -> Option<&'static str> {
+    match error.kind() {
+        ErrorKind::StorageFull => Some("The device is out of storage space."),
+        ErrorKind::PermissionDenied => {
+            Some("Permission denied, is the cache directory owned by the app?")
+        }
+        ErrorKind::ReadOnlyFilesystem => Some("The filesystem is read-only."),
+        _ => None,
+    }
+}
+
+pub trait IoResultExt<T> {
+    /// Adds the operation and path (and a hint, if any) to an io::Error.
+    /// `action` reads as "Failed to {action} {path}", e.g. "create".
+    fn with_file_context(self, action: &str, path: &Path) -> anyhow::Result<T>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn with_file_context(self, action: &str, path: &Path) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            let message = match hint_for(&error) {
+                Some(hint) => format!("Failed to {} {}: {}", action, path.display(), hint),
+                None => format!("Failed to {} {}", action, path.display()),
+            };
+            anyhow::Error::new(error).context(message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IoResultExt;
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+
+    #[test]
+    fn with_file_context_adds_path_and_hint() {
+        let result: std::io::Result<()> = Err(Error::from(ErrorKind::StorageFull));
+        let error = result
+            .with_file_context("write", Path::new("/cache/state.json"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to write /cache/state.json: The device is out of storage space."
+        );
+        // The original error is kept as the source.
+        assert_eq!(
+            error.downcast_ref::<Error>().unwrap().kind(),
+            ErrorKind::StorageFull
+        );
+
+        let result: std::io::Result<()> = Err(Error::from(ErrorKind::NotFound));
+        let error = result
+            .with_file_context("open", Path::new("/cache/state.json"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Failed to open /cache/state.json");
+    }
+}
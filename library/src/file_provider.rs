@@ -0,0 +1,66 @@
+// This file lets the host give the updater files it can't open by path, e.g.
+// assets inside the app bundle which the engine can only read through its
+// own asset APIs (see shorebird_init_with_file_callbacks).
+
+use std::io::Read;
+
+/// The name shorebird.yaml is requested by, see init_with_file_provider().
+/// cbindgen:ignore
+pub const SHOREBIRD_YAML_NAME: &str = "shorebird.yaml";
+
+/// shorebird.yaml is a handful of lines, anything this big is not it.
+/// cbindgen:ignore
+const MAX_YAML_BYTES: u64 = 1024 * 1024;
+
+/// Opens files by name on behalf of the updater.
+pub trait ExternalFileProvider {
+    fn open(&self, name: &str) -> anyhow::Result<Box<dyn Read>>;
+}
+
+/// Reads shorebird.yaml through the provider.
+pub fn read_shorebird_yaml(provider: &dyn ExternalFileProvider) -> anyhow::Result<String> {
+    use anyhow::Context;
+
+    let file = provider
+        .open(SHOREBIRD_YAML_NAME)
+        .with_context(|| format!("Failed to open {}", SHOREBIRD_YAML_NAME))?;
+    let mut yaml = String::new();
+    file.take(MAX_YAML_BYTES + 1)
+        .read_to_string(&mut yaml)
+        .with_context(|| format!("Failed to read {}", SHOREBIRD_YAML_NAME))?;
+    anyhow::ensure!(
+        yaml.len() as u64 <= MAX_YAML_BYTES,
+        "{} is larger than {} bytes",
+        SHOREBIRD_YAML_NAME,
+        MAX_YAML_BYTES
+    );
+    Ok(yaml)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    struct FakeProvider(Vec<u8>);
+
+    impl super::ExternalFileProvider for FakeProvider {
+        fn open(&self, name: &str) -> anyhow::Result<Box<dyn Read>> {
+            anyhow::ensure!(name == super::SHOREBIRD_YAML_NAME, "No such file");
+            Ok(Box::new(std::io::Cursor::new(self.0.clone())))
+        }
+    }
+
+    #[test]
+    fn read_shorebird_yaml() {
+        let provider = FakeProvider(b"app_id: 1234".to_vec());
+        assert_eq!(
+            super::read_shorebird_yaml(&provider).unwrap(),
+            "app_id: 1234"
+        );
+
+        let too_big = FakeProvider(vec![b'#'; super::MAX_YAML_BYTES as usize + 1]);
+        assert!(super::read_shorebird_yaml(&too_big).is_err());
+        let not_utf8 = FakeProvider(vec![0xff, 0xfe]);
+        assert!(super::read_shorebird_yaml(&not_utf8).is_err());
+    }
+}
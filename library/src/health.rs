@@ -0,0 +1,143 @@
+// This file checks that the updater's storage and state are usable, so
+// support can ask users to run it (e.g. from a debug screen) before filing
+// a ticket instead of guessing from logs.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::cache::UpdaterState;
+use crate::config::UpdateConfig;
+use crate::install_transaction::InstallTransaction;
+use crate::storage::probe_writable;
+
+/// The outcome of one health check.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub name: String,
+    pub ok: bool,
+    /// What was found, or why the check failed.
+    pub detail: Option<String>,
+}
+
+/// How far an interrupted download has got, as last saved (when it started
+/// or stopped).  Leaves out the URL, which may be signed.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DownloadProgress {
+    pub bytes_downloaded: u64,
+    /// The size of the whole download, if the server said.
+    pub total_bytes: Option<u64>,
+}
+
+/// The outcome of health_check(), serialized as JSON for the C API.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    /// Whether every check passed.
+    pub healthy: bool,
+    pub checks: Vec<HealthCheck>,
+    /// The download waiting to be resumed, if any.
+    pub download_progress: Option<DownloadProgress>,
+}
+
+impl HealthCheck {
+    fn from_result(name: &str, result: anyhow::Result<Option<String>>) -> Self {
+        match result {
+            Ok(detail) => Self {
+                name: name.to_string(),
+                ok: true,
+                detail,
+            },
+            Err(e) => Self {
+                name: name.to_string(),
+                ok: false,
+                detail: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+/// Files can be created in dir.  A missing dir is fine (it is created when
+/// needed), and is left missing: checking health shouldn't change state.
+fn check_writable(dir: &Path) -> anyhow::Result<Option<String>> {
+    if !dir.exists() {
+        return Ok(Some("not created yet".to_string()));
+    }
+    probe_writable(dir)?;
+    Ok(None)
+}
+
+/// The state file parses, or there isn't one yet.
+fn check_state_file(config: &UpdateConfig) -> anyhow::Result<Option<String>> {
+    if !config.cache_dir.join("state.json").exists() {
+        return Ok(Some("no state file yet".to_string()));
+    }
+    UpdaterState::load(&config.cache_dir)?;
+    Ok(None)
+}
+
+/// The artifact of the booted patch still matches its hash.  Uses the state
+/// as saved, since loading it normally would quietly drop a corrupt patch.
+fn check_current_patch(config: &UpdateConfig) -> anyhow::Result<Option<String>> {
+    let state = match UpdaterState::load(&config.cache_dir) {
+        Ok(state) => state,
+        Err(_) => return Ok(Some("no readable state".to_string())),
+    };
+    match state.current_boot_patch() {
+        Some(patch) => Ok(Some(match state.verify_patch_artifact(patch.number)? {
+            true => format!("patch {}", patch.number),
+            false => format!("patch {} (no hash recorded)", patch.number),
+        })),
+        None => Ok(Some("no patch booted".to_string())),
+    }
+}
+
+/// The latest install finished, see InstallTransaction.
+fn check_last_install(config: &UpdateConfig) -> anyhow::Result<Option<String>> {
+    let transaction = match InstallTransaction::load(&config.cache_dir) {
+        Some(transaction) => transaction,
+        None => return Ok(Some("no install recorded".to_string())),
+    };
+    if let Some(step) = transaction.interrupted_step() {
+        anyhow::bail!(
+            "install of patch {} stopped at: {}{}",
+            transaction.patch_number,
+            step,
+            transaction
+                .error
+                .map(|error| format!(" ({})", error))
+                .unwrap_or_default()
+        );
+    }
+    Ok(Some(format!("patch {}", transaction.patch_number)))
+}
+
+/// The saved state's partial download, if any.
+fn download_progress(config: &UpdateConfig) -> Option<DownloadProgress> {
+    let state = UpdaterState::load(&config.cache_dir).ok()?;
+    let partial = state.partial_download()?;
+    Some(DownloadProgress {
+        bytes_downloaded: partial.bytes_downloaded,
+        total_bytes: partial.total_bytes,
+    })
+}
+
+/// Runs every check, a failing check does not stop the others.  Doesn't
+/// need the config lock, so callers should pass a copy of the config rather
+/// than block other API calls while the patch is hashed.
+pub fn run_health_check(config: &UpdateConfig) -> HealthReport {
+    let checks = vec![
+        HealthCheck::from_result("cache_dir_writable", check_writable(&config.cache_dir)),
+        HealthCheck::from_result(
+            "download_dir_writable",
+            check_writable(&config.download_dir),
+        ),
+        HealthCheck::from_result("state_file_parses", check_state_file(config)),
+        HealthCheck::from_result("current_patch_valid", check_current_patch(config)),
+        HealthCheck::from_result("last_install_completed", check_last_install(config)),
+    ];
+    HealthReport {
+        healthy: checks.iter().all(|check| check.ok),
+        checks,
+        download_progress: download_progress(config),
+    }
+}
@@ -0,0 +1,178 @@
+// This file records each patch install as a small transaction: what the
+// install set out to do, then each step as it finishes.  Only the latest
+// install is kept.  If the process dies (or a step fails) part way through,
+// the record says exactly where, which is hard to tell from the resulting
+// state alone.  It is only for diagnostics (see last_install_transaction()),
+// nothing reads it to decide what to do.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_errors::IoResultExt;
+
+#[cfg(test)]
+use std::println as warn; // Workaround to use println! for logs.
+
+/// cbindgen:ignore
+pub(crate) const TRANSACTION_FILE_NAME: &str = "install_transaction.json";
+
+/// The steps of UpdaterState::install_patch(), in order.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStep {
+    /// The artifact was moved into the objects directory (or found to be
+    /// there already).
+    ArtifactStored,
+    /// The state pointing next boot at the new slot was saved.
+    StateSaved,
+    /// The files of the patch previously in the slot were removed.
+    ReplacedRemoved,
+}
+
+/// The latest patch install, as recorded while it ran.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct InstallTransaction {
+    pub patch_number: usize,
+    pub slot_index: usize,
+    /// The patch which was in the slot before, if any.
+    pub replacing_patch_number: Option<usize>,
+    /// When the install started (seconds since epoch).
+    pub started_at: u64,
+    /// The steps which finished, in order.
+    pub steps: Vec<InstallStep>,
+    /// Whether every step finished.
+    pub completed: bool,
+    /// Why the install stopped, if a step failed rather than the process
+    /// dying.
+    pub error: Option<String>,
+}
+
+fn transaction_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(TRANSACTION_FILE_NAME)
+}
+
+impl InstallTransaction {
+    /// Records the intent of an install, replacing any previous record.
+    pub fn begin(
+        cache_dir: &Path,
+        patch_number: usize,
+        slot_index: usize,
+        replacing_patch_number: Option<usize>,
+        started_at: u64,
+    ) -> Self {
+        let transaction = Self {
+            patch_number,
+            slot_index,
+            replacing_patch_number,
+            started_at,
+            steps: Vec::new(),
+            completed: false,
+            error: None,
+        };
+        transaction.save(cache_dir);
+        transaction
+    }
+
+    pub fn record_step(&mut self, cache_dir: &Path, step: InstallStep) {
+        self.steps.push(step);
+        self.save(cache_dir);
+    }
+
+    pub fn complete(&mut self, cache_dir: &Path) {
+        self.completed = true;
+        self.save(cache_dir);
+    }
+
+    pub fn fail(&mut self, cache_dir: &Path, error: &anyhow::Error) {
+        self.error = Some(format!("{:#}", error));
+        self.save(cache_dir);
+    }
+
+    /// The step which was running when the install stopped, None if it
+    /// completed.
+    pub fn interrupted_step(&self) -> Option<&'static str> {
+        if self.completed {
+            return None;
+        }
+        Some(match self.steps.last() {
+            None => "store artifact",
+            Some(InstallStep::ArtifactStored) => "save state",
+            Some(InstallStep::StateSaved) => "remove replaced patch",
+            Some(InstallStep::ReplacedRemoved) => "finish",
+        })
+    }
+
+    /// The latest install, None if there hasn't been one (or the record
+    /// can't be read).
+    pub fn load(cache_dir: &Path) -> Option<Self> {
+        let contents = std::fs::read(transaction_path(cache_dir)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    /// Best effort: the record is for diagnostics, so failing to write it
+    /// must not fail the install.
+    fn save(&self, cache_dir: &Path) {
+        if let Err(e) = self.try_save(cache_dir) {
+            warn!("Failed to record install transaction: {:#}", e);
+        }
+    }
+
+    /// Like UpdaterState::save(), written aside and renamed into place so a
+    /// reader never sees a partial record.
+    fn try_save(&self, cache_dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(cache_dir).with_file_context("create directory", cache_dir)?;
+        let path = transaction_path(cache_dir);
+        let temp_path = cache_dir.join(format!("{}.tmp", TRANSACTION_FILE_NAME));
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(self)?)
+            .with_file_context("write", &temp_path)?;
+        std::fs::rename(&temp_path, &path).with_file_context("move transaction to", &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstallStep, InstallTransaction};
+    use tempdir::TempDir;
+
+    #[test]
+    fn records_each_step() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path();
+        assert_eq!(InstallTransaction::load(cache_dir), None);
+
+        let mut transaction = InstallTransaction::begin(cache_dir, 2, 1, Some(1), 100);
+        assert_eq!(
+            InstallTransaction::load(cache_dir)
+                .unwrap()
+                .interrupted_step(),
+            Some("store artifact")
+        );
+        transaction.record_step(cache_dir, InstallStep::ArtifactStored);
+        let loaded = InstallTransaction::load(cache_dir).unwrap();
+        assert_eq!(loaded.steps, vec![InstallStep::ArtifactStored]);
+        assert_eq!(loaded.interrupted_step(), Some("save state"));
+
+        transaction.record_step(cache_dir, InstallStep::StateSaved);
+        transaction.record_step(cache_dir, InstallStep::ReplacedRemoved);
+        transaction.complete(cache_dir);
+        let loaded = InstallTransaction::load(cache_dir).unwrap();
+        assert_eq!(loaded, transaction);
+        assert_eq!(loaded.interrupted_step(), None);
+
+        // A new install replaces the record.
+        InstallTransaction::begin(cache_dir, 3, 0, None, 200);
+        let loaded = InstallTransaction::load(cache_dir).unwrap();
+        assert_eq!(loaded.patch_number, 3);
+        assert!(loaded.steps.is_empty());
+    }
+
+    #[test]
+    fn steps_serialize_as_snake_case() {
+        assert_eq!(
+            serde_json::to_string(&InstallStep::ReplacedRemoved).unwrap(),
+            "\"replaced_removed\""
+        );
+    }
+}
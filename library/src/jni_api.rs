@@ -0,0 +1,197 @@
+// This file exposes the updater to Java/Kotlin via JNI, for Android apps
+// which want to call the updater directly rather than through the Flutter
+// engine's C shim.  Only built with the "jni" feature.
+
+// The Java side is expected to look like:
+//
+// package dev.shorebird.updater;
+// class ShorebirdUpdater {
+//     static native boolean init(String cacheDir, String releaseVersion,
+//         String[] originalLibappPaths, long buildNumber,
+//         String appIdOverride, String yaml);
+//     static native boolean checkForUpdate();
+//     static native void update();
+//     static native long nextBootPatchNumber();
+//     static native String nextBootPatchPath();
+//     static native void reportLaunchStart();
+//     static native void reportLaunchSuccess();
+//     static native void reportLaunchFailure();
+// }
+//
+// No JNI references are held past the end of a call: Java arguments are
+// copied into Rust values before calling into the updater, so there are no
+// GlobalRefs to manage and these may be called from any attached thread.
+
+use jni::objects::{JClass, JObject, JObjectArray, JString};
+use jni::sys::{jboolean, jlong, jstring, JNI_FALSE, JNI_TRUE};
+use jni::JNIEnv;
+
+use crate::c_api::log_on_error;
+use crate::updater;
+
+// https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
+#[cfg(test)]
+use std::println as info; // Workaround to use println! for logs.
+
+fn to_rust(env: &mut JNIEnv, j_string: &JString) -> anyhow::Result<String> {
+    anyhow::ensure!(!j_string.is_null(), "Null string passed to to_rust");
+    Ok(env.get_string(j_string)?.into())
+}
+
+fn to_rust_option(env: &mut JNIEnv, j_string: &JString) -> anyhow::Result<Option<String>> {
+    if j_string.is_null() {
+        return Ok(None);
+    }
+    to_rust(env, j_string).map(Some)
+}
+
+fn to_rust_vector(env: &mut JNIEnv, j_array: &JObjectArray) -> anyhow::Result<Vec<String>> {
+    anyhow::ensure!(!j_array.is_null(), "Null array passed to to_rust_vector");
+    let mut result = Vec::new();
+    for i in 0..env.get_array_length(j_array)? {
+        let element = JString::from(env.get_object_array_element(j_array, i)?);
+        result.push(to_rust(env, &element)?);
+        // Drop the local ref eagerly so large arrays can't exhaust the table.
+        env.delete_local_ref(element)?;
+    }
+    Ok(result)
+}
+
+fn to_jboolean(value: bool) -> jboolean {
+    if value {
+        JNI_TRUE
+    } else {
+        JNI_FALSE
+    }
+}
+
+/// Configures the updater, see shorebird_init.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_init(
+    mut env: JNIEnv,
+    _class: JClass,
+    cache_dir: JString,
+    release_version: JString,
+    original_libapp_paths: JObjectArray,
+    build_number: jlong,
+    app_id_override: JString,
+    yaml: JString,
+) -> jboolean {
+    to_jboolean(log_on_error(
+        || {
+            let config = updater::AppConfig {
+                cache_dir: to_rust(&mut env, &cache_dir)?,
+                release_version: to_rust(&mut env, &release_version)?,
+                original_libapp_paths: to_rust_vector(&mut env, &original_libapp_paths)?,
+                build_number: match build_number {
+                    0 => None,
+                    build_number => Some(u64::try_from(build_number)?),
+                },
+                app_id_override: to_rust_option(&mut env, &app_id_override)?,
+                download_dir: None,
+                locale: None,
+                debug_build: false,
+            };
+            let yaml_string = to_rust(&mut env, &yaml)?;
+            updater::init(config, &yaml_string)?;
+            Ok(true)
+        },
+        "initializing updater",
+        false,
+    ))
+}
+
+/// Check for an update.  Returns true if an update is available.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_checkForUpdate(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    to_jboolean(log_on_error(
+        updater::check_for_update,
+        "checking for update",
+        false,
+    ))
+}
+
+/// Synchronously download an update if one is available.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_update(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    log_on_error(
+        || updater::update().map(|result| info!("Update result: {}", result)),
+        "downloading update",
+        (),
+    );
+}
+
+/// The patch number that will boot on the next run of the app, or 0 if there
+/// is no next patch.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_nextBootPatchNumber(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    log_on_error(
+        || Ok(updater::next_boot_patch()?.map_or(0, |p| p.number as jlong)),
+        "fetching next_boot_patch_number",
+        0,
+    )
+}
+
+/// The path to the patch that will boot on the next run of the app, or null
+/// if there is no next patch.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_nextBootPatchPath(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    log_on_error(
+        || match updater::next_boot_patch()? {
+            Some(patch) => {
+                let path = patch.path.to_str().unwrap_or_default();
+                Ok(env.new_string(path)?.into_raw())
+            }
+            None => Ok(JObject::null().into_raw()),
+        },
+        "fetching next_boot_patch_path",
+        JObject::null().into_raw(),
+    )
+}
+
+/// See shorebird_report_launch_start.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_reportLaunchStart(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    log_on_error(updater::report_launch_start, "reporting launch start", ());
+}
+
+/// See shorebird_report_launch_success.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_reportLaunchSuccess(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    log_on_error(
+        updater::report_launch_success,
+        "reporting launch success",
+        (),
+    );
+}
+
+/// See shorebird_report_launch_failure.
+#[no_mangle]
+pub extern "system" fn Java_dev_shorebird_updater_ShorebirdUpdater_reportLaunchFailure(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    log_on_error(
+        updater::report_launch_failure,
+        "reporting launch failure",
+        (),
+    );
+}
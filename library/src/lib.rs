@@ -6,20 +6,60 @@
 pub mod c_api;
 
 // Declare other .rs file/module exists, but make them private.
+mod auto_update;
 mod cache;
 mod config;
+mod config_builder;
+mod decode_benchmark;
+mod delegate;
+mod encryption;
+mod environment;
+mod file_errors;
+mod file_provider;
+mod health;
+mod install_transaction;
+mod lifecycle;
 mod logging;
+mod memfd;
+mod names;
 mod network;
+mod request_signing;
+mod storage;
+mod thread_priority;
+mod time;
 mod updater;
 mod updater_lock;
+mod verify;
 mod yaml;
 
 #[cfg(any(target_os = "android", test))]
 mod android;
+#[cfg(any(target_os = "android", test))]
+mod patch_apply;
+#[cfg(any(target_os = "android", test))]
+mod seekable;
+
+// Updating from a separate process, see src/daemon.rs.
+#[cfg(any(feature = "daemon", test))]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+mod daemon;
+
+// Java/Kotlin bindings, for Android apps calling the updater directly.
+#[cfg(feature = "jni")]
+mod jni_api;
+
+#[cfg(all(
+    feature = "network",
+    not(any(feature = "rustls-tls", feature = "native-tls"))
+))]
+compile_error!("The \"network\" feature requires either \"rustls-tls\" or \"native-tls\".");
 
 // Take all public items from the updater namespace and make them public.
 pub use self::updater::*;
 
+#[cfg(feature = "daemon")]
+pub use self::daemon::{run_daemon, DAEMON_USAGE};
+
 #[cfg(not(test))]
 // Exposes error!(), info!(), etc macros.
 #[macro_use]
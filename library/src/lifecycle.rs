@@ -0,0 +1,58 @@
+// This file tracks whether the app is in the foreground, as the host tells us
+// with on_app_foreground() and on_app_background(), so update work can follow
+// the app's lifecycle: check for updates when the app comes back to the
+// foreground, and (unless shorebird.yaml says otherwise) pause downloads
+// while it is in the background, where the OS may kill us at any moment.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by set_in_background.  Hosts which never call the lifecycle hooks are
+/// always in the foreground.
+static IN_BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+/// shorebird.yaml's background_downloads, see set_config.
+static BACKGROUND_DOWNLOADS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_config(background_downloads: bool) {
+    BACKGROUND_DOWNLOADS.store(background_downloads, Ordering::SeqCst);
+}
+
+/// Records whether the app is in the background, returning whether it was
+/// before.
+pub fn set_in_background(in_background: bool) -> bool {
+    IN_BACKGROUND.swap(in_background, Ordering::SeqCst)
+}
+
+pub fn is_in_background() -> bool {
+    IN_BACKGROUND.load(Ordering::SeqCst)
+}
+
+/// Whether downloads should stop where they are, to be resumed by a later
+/// update, because the app is in the background.
+pub fn downloads_paused() -> bool {
+    is_in_background() && !BACKGROUND_DOWNLOADS.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+pub fn testing_reset() {
+    set_in_background(false);
+    set_config(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    #[serial]
+    #[test]
+    fn downloads_pause_in_background() {
+        super::testing_reset();
+        assert!(!super::downloads_paused());
+        assert!(!super::set_in_background(true));
+        assert!(super::downloads_paused());
+        super::set_config(true);
+        assert!(!super::downloads_paused());
+        assert!(super::set_in_background(false));
+        super::testing_reset();
+    }
+}
@@ -1,3 +1,19 @@
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+
+/// The max level set by init_logging, so overrides can be undone.
+fn initial_max_level() -> &'static OnceCell<LevelFilter> {
+    static INSTANCE: OnceCell<LevelFilter> = OnceCell::new();
+    &INSTANCE
+}
+
+/// Overrides the max log level (e.g. from a server directive).  None
+/// restores the level set by init_logging.
+pub fn set_log_level_override(level: Option<LevelFilter>) {
+    let initial = *initial_max_level().get_or_init(log::max_level);
+    log::set_max_level(level.unwrap_or(initial));
+}
+
 #[cfg(target_os = "android")]
 pub fn init_logging() {
     log_panics::init();
@@ -15,7 +31,6 @@ pub fn init_logging() {
 pub fn init_logging() {
     // I could not figure out how to get fancier logging set up on iOS
     // but logging to stderr seems to work.
-    use log::LevelFilter;
     use std::io;
     simple_logging::log_to(io::stderr(), LevelFilter::Info);
     debug!("Logging initialized");
@@ -0,0 +1,99 @@
+// This file copies a patch into a sealed anonymous memory file (a memfd) for
+// the engine to map, see YamlConfig::memfd_max_bytes.  Once sealed, nothing
+// (including us) can change the copy, so the engine runs exactly the bytes
+// whose hash was checked here, however the file on disk changes afterwards.
+
+use std::fs::File;
+use std::path::Path;
+
+/// Copies the file at path into a new sealed memfd, positioned at the start.
+/// If the file is named by its hash (see Slot::hash) the copy must match it.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn sealed_copy(path: &Path) -> anyhow::Result<File> {
+    use crate::file_errors::IoResultExt;
+    use anyhow::Context;
+    use sha2::{Digest, Sha256};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let mut source = File::open(path).with_file_context("open", path)?;
+    // The syscall rather than libc::memfd_create, which older Android API
+    // levels don't have even though their kernels do.
+    // cbindgen can't parse c"" literals.
+    #[allow(clippy::manual_c_str_literals)]
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            b"shorebird_patch\0".as_ptr() as *const libc::c_char,
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("memfd_create failed");
+    }
+    let mut memfd = unsafe { File::from_raw_fd(fd as libc::c_int) };
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let count = source.read(&mut buffer).with_file_context("read", path)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        memfd.write_all(&buffer[..count])?;
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if crate::cache::is_object_name(&name) {
+        let hash = hex::encode(hasher.finalize());
+        anyhow::ensure!(
+            hash == name,
+            "Hash mismatch copying {}, got: {}",
+            path.display(),
+            hash
+        );
+    }
+
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, seals) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Sealing memfd failed");
+    }
+    memfd.seek(SeekFrom::Start(0))?;
+    Ok(memfd)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn sealed_copy(_path: &Path) -> anyhow::Result<File> {
+    anyhow::bail!("memfd is not supported on this platform")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::io::{Read, Write};
+    use tempdir::TempDir;
+
+    #[test]
+    fn sealed_copy() {
+        use sha2::{Digest, Sha256};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir
+            .path()
+            .join(hex::encode(Sha256::digest(b"hello world")));
+        std::fs::write(&path, "hello world").unwrap();
+        let mut copy = super::sealed_copy(&path).unwrap();
+        let mut contents = String::new();
+        copy.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello world");
+        // Sealed against writes.
+        assert!(copy.write_all(b"!").is_err());
+
+        // Objects must match their name.
+        std::fs::write(&path, "hello tests").unwrap();
+        assert!(super::sealed_copy(&path).is_err());
+        // Other files are copied as they are.
+        let other = tmp_dir.path().join("other");
+        std::fs::write(&other, "hello tests").unwrap();
+        assert!(super::sealed_copy(&other).is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+// This file holds the validated names the updater is configured with, so an
+// empty or badly formed channel or release version is rejected by init
+// rather than showing up later as a confusing server error (or an odd
+// directory name).  Both are (de)serialized as plain strings.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::updater::UpdateError;
+
+/// A channel name, e.g. "stable".  Channels are also directory names (see
+/// UpdateConfig::cache_dir), so are limited to ASCII letters, digits, '-',
+/// '_' and '.', and may not be "." or "..".
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Channel(String);
+
+/// The version of the app the patches are for, e.g. "1.0.0+1".  Anything
+/// but whitespace and control characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ReleaseVersion(String);
+
+fn is_valid_channel(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    !name.is_empty() && name != "." && name != ".." && name.chars().all(allowed)
+}
+
+fn is_valid_release_version(version: &str) -> bool {
+    !version.is_empty() && !version.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Implements the conversions shared by Channel and ReleaseVersion.
+/// Surrounding whitespace is trimmed before validating.
+macro_rules! validated_name {
+    ($name:ident, $argument:literal, $is_valid:ident) => {
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = UpdateError;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                let trimmed = value.trim();
+                if !$is_valid(trimmed) {
+                    return Err(UpdateError::InvalidArgument(
+                        $argument.to_string(),
+                        value.to_string(),
+                    ));
+                }
+                Ok(Self(trimmed.to_string()))
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = UpdateError;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                value.parse()
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(name: $name) -> Self {
+                name.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+validated_name!(Channel, "channel", is_valid_channel);
+validated_name!(ReleaseVersion, "release_version", is_valid_release_version);
+
+/// The numbers in a version like "1.2.3+4": ([1, 2, 3], 4).  A missing build
+/// number is 0.
+fn version_numbers(version: &str) -> Option<(Vec<u64>, u64)> {
+    let (version, build) = match version.split_once('+') {
+        Some((version, build)) => (version, build.parse().ok()?),
+        None => (version, 0),
+    };
+    let parts = version
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    Some((parts, build))
+}
+
+impl ReleaseVersion {
+    /// Orders versions like "1.2.3+4" (dot separated numbers, optionally
+    /// followed by '+' and a build number) numerically, e.g. to tell a
+    /// downgrade from an upgrade.  None if either has some other form, as
+    /// their order can't be known.
+    pub fn numeric_cmp(&self, other: &Self) -> Option<Ordering> {
+        let (parts, build) = version_numbers(&self.0)?;
+        let (other_parts, other_build) = version_numbers(&other.0)?;
+        // Missing parts are 0, so "1.2" == "1.2.0".
+        let len = parts.len().max(other_parts.len());
+        let part = |parts: &[u64], i: usize| parts.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| part(&parts, i).cmp(&part(&other_parts, i)))
+            .find(|ordering| ordering.is_ne())
+            .or(Some(build.cmp(&other_build)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, ReleaseVersion};
+    use crate::UpdateError;
+
+    #[test]
+    fn channels() {
+        assert_eq!("stable".parse::<Channel>().unwrap(), "stable");
+        assert_eq!(" beta-2\n".parse::<Channel>().unwrap(), "beta-2");
+        for invalid in ["", "  ", ".", "..", "a/b", "a\\b", "my channel", "bêta"] {
+            assert_eq!(
+                invalid.parse::<Channel>(),
+                Err(UpdateError::InvalidArgument(
+                    "channel".to_string(),
+                    invalid.to_string()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn release_versions() {
+        assert_eq!("1.0.0+1".parse::<ReleaseVersion>().unwrap(), "1.0.0+1");
+        assert_eq!("1.0.0+1\n".parse::<ReleaseVersion>().unwrap(), "1.0.0+1");
+        for invalid in ["", " ", "1.0.0 +1", "1.0\u{0}"] {
+            assert!(invalid.parse::<ReleaseVersion>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn release_versions_compare_numerically() {
+        use std::cmp::Ordering;
+        let cmp = |a: &str, b: &str| {
+            let a: ReleaseVersion = a.parse().unwrap();
+            a.numeric_cmp(&b.parse().unwrap())
+        };
+        assert_eq!(cmp("1.0.0+1", "1.0.1+1"), Some(Ordering::Less));
+        assert_eq!(cmp("1.10.0", "1.9.0+20"), Some(Ordering::Greater));
+        assert_eq!(cmp("1.0.0+2", "1.0.0+10"), Some(Ordering::Less));
+        assert_eq!(cmp("1.2", "1.2.0+0"), Some(Ordering::Equal));
+        assert_eq!(cmp("1.0.0-beta", "1.0.0"), None);
+        assert_eq!(cmp("1.0.0+abc", "1.0.0"), None);
+    }
+
+    #[test]
+    fn serialized_as_strings() {
+        let channel: Channel = "stable".parse().unwrap();
+        assert_eq!(serde_json::to_string(&channel).unwrap(), "\"stable\"");
+        assert_eq!(
+            serde_json::from_str::<Channel>("\"stable\"").unwrap(),
+            channel
+        );
+        assert!(serde_json::from_str::<ReleaseVersion>("\"\"").is_err());
+    }
+}
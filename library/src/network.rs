@@ -2,24 +2,222 @@
 // of the updater library.
 
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::Path;
 use std::string::ToString;
 
-use crate::cache::UpdaterState;
+use crate::cache::{BootTiming, PartialDownload, UpdaterState};
 use crate::config::{current_arch, current_platform, UpdateConfig};
+use crate::decode_benchmark::DecodeBenchmark;
+use crate::environment::{device_environment, DeviceEnvironment};
+use crate::file_errors::IoResultExt;
+use crate::names::{Channel, ReleaseVersion};
+#[cfg(any(test, feature = "network"))]
+use crate::request_signing::signature_headers;
+use crate::updater::UpdateError;
+use crate::verify::{verify_signature, Hash, HashVerifier};
 
 // https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
 #[cfg(test)]
-use std::println as info; // Workaround to use println! for logs.
+use std::{println as info, println as warn}; // Workaround to use println! for logs.
 
-fn patches_check_url(base_url: &str) -> String {
-    return format!("{}/api/v1/patches/check", base_url);
+/// base_url was normalized by parse_base_url, so always ends in a slash.
+fn patches_check_url(base_url: &str) -> anyhow::Result<String> {
+    let base_url = url::Url::parse(base_url)?;
+    Ok(base_url.join("api/v1/patches/check")?.to_string())
 }
 
-pub type PatchCheckRequestFn = fn(&str, PatchCheckRequest) -> anyhow::Result<PatchCheckResponse>;
-pub type DownloadFileFn = fn(&str) -> anyhow::Result<Vec<u8>>;
+/// The HTTP header carrying the base64-encoded ed25519 signature of the
+/// patch check response body.
+/// cbindgen:ignore
+#[cfg(all(not(test), feature = "network"))]
+const SIGNATURE_HEADER: &str = "x-shorebird-signature";
+
+/// Set by set_offline, e.g. to honor a user's data saver setting.
+static OFFLINE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// While offline, all network requests fail with UpdateError::Offline
+/// instead of touching the network.
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, std::sync::atomic::Ordering::SeqCst);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(std::sync::atomic::Ordering::SeqCst)
+}
+
+/// The update server, set by set_config.  Extra headers are only sent to it.
+static HEADERS_BASE_URL: std::sync::RwLock<String> = std::sync::RwLock::new(String::new());
+
+/// Headers from shorebird.yaml's extra_headers, set by set_config.  Keys are
+/// lowercase.
+static CONFIG_HEADERS: std::sync::RwLock<BTreeMap<String, String>> =
+    std::sync::RwLock::new(BTreeMap::new());
+
+/// Headers set by set_extra_header, which take precedence over
+/// CONFIG_HEADERS.  None removes a header from shorebird.yaml.
+static RUNTIME_HEADERS: std::sync::RwLock<BTreeMap<String, Option<String>>> =
+    std::sync::RwLock::new(BTreeMap::new());
+
+/// Whether name is a valid HTTP header name (RFC 9110 token).
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether value is a valid HTTP header value, in particular it can't
+/// contain line breaks which would let it inject other headers.
+fn is_valid_header_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || (b' '..=b'~').contains(&b))
+}
+
+/// Checks and normalizes (lowercases) shorebird.yaml's extra_headers.
+pub fn validate_extra_headers(
+    headers: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, UpdateError> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            if !is_valid_header_name(name) || !is_valid_header_value(value) {
+                return Err(UpdateError::InvalidArgument(
+                    "extra_headers".to_string(),
+                    name.clone(),
+                ));
+            }
+            Ok((name.to_ascii_lowercase(), value.clone()))
+        })
+        .collect()
+}
+
+pub fn set_config_headers(base_url: &str, headers: BTreeMap<String, String>) {
+    *HEADERS_BASE_URL
+        .write()
+        .expect("Failed to acquire headers lock.") = base_url.to_owned();
+    *CONFIG_HEADERS
+        .write()
+        .expect("Failed to acquire headers lock.") = headers;
+}
+
+/// Sets a header to send with every request to base_url, overriding
+/// shorebird.yaml's extra_headers, or removes it if value is None.
+pub fn set_extra_header(name: &str, value: Option<&str>) -> Result<(), UpdateError> {
+    if !is_valid_header_name(name) || !value.is_none_or(is_valid_header_value) {
+        return Err(UpdateError::InvalidArgument(
+            "header".to_string(),
+            name.to_string(),
+        ));
+    }
+    RUNTIME_HEADERS
+        .write()
+        .expect("Failed to acquire headers lock.")
+        .insert(name.to_ascii_lowercase(), value.map(str::to_owned));
+    Ok(())
+}
+
+/// Forgets all headers set by set_extra_header.
+#[cfg(test)]
+pub fn testing_reset_extra_headers() {
+    RUNTIME_HEADERS
+        .write()
+        .expect("Failed to acquire headers lock.")
+        .clear();
+}
+
+/// The headers to send with every request to base_url, see set_extra_header.
+#[cfg(any(test, feature = "network"))]
+pub fn extra_headers() -> BTreeMap<String, String> {
+    let mut headers = CONFIG_HEADERS
+        .read()
+        .expect("Failed to acquire headers lock.")
+        .clone();
+    let runtime = RUNTIME_HEADERS
+        .read()
+        .expect("Failed to acquire headers lock.");
+    for (name, value) in runtime.iter() {
+        match value {
+            Some(value) => headers.insert(name.clone(), value.clone()),
+            None => headers.remove(name),
+        };
+    }
+    headers
+}
+
+/// The headers to add to a request to url.  extra_headers() only go to
+/// base_url: download URLs are usually signed URLs on other hosts (e.g. a
+/// CDN), which mustn't see secrets meant for the update server.  Request
+/// signing's headers replace any extra header of the same name (e.g.
+/// Authorization) rather than being sent alongside it.
+#[cfg(any(test, feature = "network"))]
+fn request_headers(method: &str, url: &str, body: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut headers = BTreeMap::new();
+    let base_url = HEADERS_BASE_URL
+        .read()
+        .expect("Failed to acquire headers lock.")
+        .clone();
+    if !base_url.is_empty() && crate::request_signing::is_under(url, &base_url) {
+        headers = extra_headers();
+    }
+    for (name, value) in signature_headers(method, url, body)? {
+        if headers.insert(name.to_string(), value).is_some() {
+            warn!("Request signing replaces the {} extra header.", name);
+        }
+    }
+    Ok(headers.into_iter().collect())
+}
+
+/// The newest Patch::signature_version this client can check.
+/// cbindgen:ignore
+pub const MAX_SIGNATURE_VERSION: u32 = 2;
+
+pub type PatchCheckRequestFn =
+    fn(&str, PatchCheckRequest) -> anyhow::Result<PatchCheckHttpResponse>;
+
+/// The patch check response as received, so the signature can be checked
+/// against the exact bytes before they are parsed.
+pub struct PatchCheckHttpResponse {
+    pub body: Vec<u8>,
+    /// Base64-encoded signature of body, if the server sent one.
+    pub signature: Option<String>,
+    /// Whether the server responded 404, e.g. for a channel it doesn't know.
+    pub not_found: bool,
+    /// How long the server asked us to wait before checking again, if it
+    /// responded 429 or 503, see retry_after_seconds().
+    pub retry_after_seconds: Option<u64>,
+}
+
+/// Lets tests write hooks in terms of the parsed response.
+#[cfg(test)]
+impl From<PatchCheckResponse> for PatchCheckHttpResponse {
+    fn from(response: PatchCheckResponse) -> Self {
+        Self {
+            body: serde_json::to_vec(&response).unwrap(),
+            signature: None,
+            not_found: false,
+            retry_after_seconds: None,
+        }
+    }
+}
+pub type DownloadFileFn =
+    fn(&str, Option<&PartialDownload>) -> anyhow::Result<DownloadFileResponse>;
+
+/// The result of starting a download, the body is read by the caller.
+pub struct DownloadFileResponse {
+    /// True if the server honored the resume request and `body` continues
+    /// from `PartialDownload::bytes_downloaded` (i.e. HTTP 206).
+    pub is_partial: bool,
+    /// The ETag of the file on the server, if provided.
+    pub etag: Option<String>,
+    /// The size of the whole file (not just what `body` continues with), if
+    /// the server said.
+    pub total_bytes: Option<u64>,
+    pub body: Box<dyn Read>,
+}
 
 /// A container for network clalbacks which can be mocked out for testing.
 #[derive(Clone)]
@@ -44,12 +242,15 @@ impl core::fmt::Debug for NetworkHooks {
 fn patch_check_request_throws(
     _url: &str,
     _request: PatchCheckRequest,
-) -> anyhow::Result<PatchCheckResponse> {
+) -> anyhow::Result<PatchCheckHttpResponse> {
     anyhow::bail!("please set a patch_check_request_fn");
 }
 
 #[cfg(test)]
-fn download_file_throws(_url: &str) -> anyhow::Result<Vec<u8>> {
+fn download_file_throws(
+    _url: &str,
+    _resume_from: Option<&PartialDownload>,
+) -> anyhow::Result<DownloadFileResponse> {
     anyhow::bail!("please set a download_file_fn");
 }
 
@@ -71,23 +272,134 @@ impl Default for NetworkHooks {
     }
 }
 
-#[cfg(not(test))]
+#[cfg(all(not(test), feature = "network"))]
 pub fn patch_check_request_default(
     url: &str,
     request: PatchCheckRequest,
-) -> anyhow::Result<PatchCheckResponse> {
+) -> anyhow::Result<PatchCheckHttpResponse> {
     let client = reqwest::blocking::Client::new();
-    let response = client.post(url).json(&request).send()?.json()?;
-    Ok(response)
+    // Serialized by hand so reqwest doesn't need its "json" feature.
+    let body = serde_json::to_vec(&request)?;
+    let mut http_request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    for (name, value) in request_headers("POST", url, &body)? {
+        http_request = http_request.header(name, value);
+    }
+    let response = http_request.body(body).send()?;
+    let signature = response
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let not_found = response.status() == reqwest::StatusCode::NOT_FOUND;
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok());
+    let retry_after_seconds = retry_after_seconds(response.status().as_u16(), retry_after);
+    Ok(PatchCheckHttpResponse {
+        body: response.bytes()?.to_vec(),
+        signature,
+        not_found,
+        retry_after_seconds,
+    })
+}
+
+/// How long to wait before checking again when the server responds 429 or
+/// 503 without a usable Retry-After header.
+/// cbindgen:ignore
+#[cfg(any(test, feature = "network"))]
+const DEFAULT_RETRY_AFTER_SECONDS: u64 = 60;
+
+/// Upper bound on Retry-After, so a bad header can't stop checks for days.
+/// cbindgen:ignore
+#[cfg(any(test, feature = "network"))]
+const MAX_RETRY_AFTER_SECONDS: u64 = 24 * 60 * 60;
+
+/// The backoff requested by a response with HTTP `status` and Retry-After
+/// header `retry_after`, or None if the server isn't asking us to back off.
+/// Only the delay-seconds form of Retry-After is understood, HTTP dates fall
+/// back to DEFAULT_RETRY_AFTER_SECONDS.
+#[cfg(any(test, feature = "network"))]
+fn retry_after_seconds(status: u16, retry_after: Option<&str>) -> Option<u64> {
+    if status != 429 && status != 503 {
+        return None;
+    }
+    let seconds = retry_after
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER_SECONDS);
+    Some(std::cmp::min(seconds, MAX_RETRY_AFTER_SECONDS))
 }
 
-#[cfg(not(test))]
-pub fn download_file_default(url: &str) -> anyhow::Result<Vec<u8>> {
+/// Without the "network" feature there is no HTTP client, so every request
+/// fails.  Patches can still be installed by other means.
+#[cfg(all(not(test), not(feature = "network")))]
+pub fn patch_check_request_default(
+    _url: &str,
+    _request: PatchCheckRequest,
+) -> anyhow::Result<PatchCheckHttpResponse> {
+    Err(UpdateError::NetworkUnsupported.into())
+}
+
+#[cfg(all(not(test), not(feature = "network")))]
+pub fn download_file_default(
+    _url: &str,
+    _resume_from: Option<&PartialDownload>,
+) -> anyhow::Result<DownloadFileResponse> {
+    Err(UpdateError::NetworkUnsupported.into())
+}
+
+#[cfg(all(not(test), feature = "network"))]
+pub fn download_file_default(
+    url: &str,
+    resume_from: Option<&PartialDownload>,
+) -> anyhow::Result<DownloadFileResponse> {
+    use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+
     let client = reqwest::blocking::Client::new();
-    let response = client.get(url).send()?;
-    let bytes = response.bytes()?;
-    // Patch files are small (e.g. 50kb) so this should be ok to copy into memory.
-    Ok(bytes.to_vec())
+    let mut request = client.get(url);
+    for (name, value) in request_headers("GET", url, &[])? {
+        request = request.header(name, value);
+    }
+    if let Some(partial) = resume_from {
+        request = request.header(RANGE, format!("bytes={}-", partial.bytes_downloaded));
+        // Without If-Range a changed file would be spliced onto the old one.
+        if let Some(etag) = &partial.etag {
+            request = request.header(IF_RANGE, etag);
+        }
+    }
+    let response = request.send()?;
+    // Signed URLs (e.g. from GCS) are refused once they expire.  The caller
+    // can get a fresh one with another patch check.
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err(UpdateError::DownloadUrlExpired.into());
+    }
+    let response = response.error_for_status()?;
+    let is_partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    // A resumed download's Content-Range ends with the whole size, e.g.
+    // "bytes 100-199/200".
+    let total_bytes = if is_partial {
+        response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok())
+    } else {
+        response.content_length()
+    };
+    Ok(DownloadFileResponse {
+        is_partial,
+        etag,
+        total_bytes,
+        body: Box::new(response),
+    })
 }
 
 #[cfg(test)]
@@ -109,7 +421,7 @@ pub fn testing_set_network_hooks(
     });
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Patch {
     /// The patch number.  Starts at 1 for each new release and increases
     /// monotonically.
@@ -119,6 +431,77 @@ pub struct Patch {
     pub hash: String,
     /// The URL to download the patch file from.
     pub download_url: String,
+    /// The hex-encoded sha256 hash of the file at download_url (i.e. still
+    /// compressed), if known.  Lets a corrupt download be rejected before
+    /// spending time inflating it.
+    #[serde(default)]
+    pub download_hash: Option<String>,
+    /// The URL of the whole (zstd compressed) artifact rather than a diff
+    /// against the release, if the server offers one.  Used in place of
+    /// download_url when the diff doesn't apply to this device's base, see
+    /// YamlConfig::allow_full_downloads.
+    #[serde(default)]
+    pub full_download_url: Option<String>,
+    /// The oldest version of this library (e.g. "1.2.0") which can install
+    /// the patch, for patches using formats older updaters don't support.
+    #[serde(default)]
+    pub min_updater_version: Option<String>,
+    /// The lowest app build number this patch may be installed on.
+    #[serde(default)]
+    pub min_build_number: Option<u64>,
+    /// The highest app build number this patch may be installed on.
+    #[serde(default)]
+    pub max_build_number: Option<u64>,
+    /// The native code fingerprint this patch was built against, if any.
+    /// Must match the app's native_fingerprint for the patch to be installed.
+    #[serde(default)]
+    pub native_fingerprint: Option<String>,
+    /// Base64-encoded ed25519 signature of the patch, checked against
+    /// patch_public_key if one is configured (which then requires it).  See
+    /// signature_version.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// What `signature` covers: 1 (the default) signs the hex `hash` string,
+    /// 2 signs the inflated artifact bytes (ed25519ph with a sha512 prehash,
+    /// so it can be checked while streaming the file).
+    #[serde(default)]
+    pub signature_version: Option<u32>,
+    /// Canary patches are only meant for a handful of (e.g. internal)
+    /// devices.  If allowed_client_ids is also set, devices whose client_id
+    /// is not listed will not install the patch.
+    #[serde(default)]
+    pub canary: bool,
+    /// The client_ids which may install this patch if it is a canary.
+    #[serde(default)]
+    pub allowed_client_ids: Option<Vec<String>>,
+    /// Opaque build metadata for the patch (e.g. git sha, Dart version),
+    /// passed through to the app as JSON.  Not interpreted by the updater.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// How urgently the patch should be installed, see PatchPriority.
+    #[serde(default)]
+    pub priority: PatchPriority,
+    /// If set, the downloaded file is encrypted (see encryption.rs) with the
+    /// app's key which has this id.  Servers should also set
+    /// min_updater_version, as older updaters can't decrypt it.
+    #[serde(default)]
+    pub encryption_key_id: Option<String>,
+}
+
+/// How urgently a patch should be installed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchPriority {
+    /// E.g. a security fix: auto updates check for it even when throttled
+    /// or on a metered network, download_update() installs it without
+    /// waiting for install_downloaded_update(), and the app is asked to
+    /// restart (see UpdaterDelegate::on_restart_requested).
+    Critical,
+    /// Installed whenever the app's update policy allows.  Priorities this
+    /// client doesn't know are treated as normal.
+    #[default]
+    #[serde(other)]
+    Normal,
 }
 
 #[derive(Debug, Serialize)]
@@ -126,9 +509,9 @@ pub struct PatchCheckRequest {
     /// The Shorebird app_id built into the shorebird.yaml in the app.
     pub app_id: String,
     /// The Shorebird channel built into the shorebird.yaml in the app.
-    pub channel: String,
+    pub channel: Channel,
     /// The release version from AndroidManifest.xml, Info.plist in the app.
-    pub release_version: String,
+    pub release_version: ReleaseVersion,
     /// The latest patch number that the client has downloaded.
     /// Not necessarily the one it's running (if some have been marked bad).
     /// We could rename this to be more clear.    
@@ -138,63 +521,713 @@ pub struct PatchCheckRequest {
     pub platform: String,
     /// Architecture we're running (e.g. "aarch64", "x86", "x86_64").
     pub arch: String,
+    /// The native code fingerprint from shorebird.yaml, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_fingerprint: Option<String>,
+    /// The newest patch signature_version this client can check, so the
+    /// server can pick which signature to send.
+    pub max_signature_version: u32,
+    /// Random id for this install of the app, see UpdaterState::client_id().
+    /// Lets the server target canary patches at specific devices.
+    pub client_id: String,
+    /// Whether the device looks like an emulator, only if the app opted in
+    /// with report_device_environment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_emulator: Option<bool>,
+    /// Whether the device looks rooted/jailbroken, only if the app opted in
+    /// with report_device_environment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_rooted: Option<bool>,
+    /// How long the most recent successful boot took, so patches which slow
+    /// down startup can be spotted.  Sent once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_boot: Option<BootTiming>,
+    /// Asks for this patch rather than the latest, even if it is older than
+    /// patch_number, see updater::pin_patch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_patch_number: Option<usize>,
+    /// How fast this device decodes each patch format, only if the app
+    /// opted in with decode_benchmark.  Sent once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decode_benchmark: Option<DecodeBenchmark>,
+    /// Every patch number still on disk, in ascending order, only if the
+    /// app opted in with report_installed_patches.  patch_number alone
+    /// can't tell the server about gaps (e.g. patches rolled back or
+    /// dropped), which matter when picking a base to diff against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed_patch_numbers: Option<Vec<usize>>,
+    /// The device's language and country (e.g. "en-US"), only if the app
+    /// opted in with report_locale.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+/// Instructions from the server about how the client should behave.  These
+/// are persisted and followed until the next patch check replaces them, so
+/// problems (e.g. a bad rollout) can be mitigated without an engine update.
+/// Unknown directives are ignored.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ClientDirectives {
+    /// Don't check for updates automatically at launch.  Explicit calls to
+    /// update() are still honored.  Expires after
+    /// disable_auto_update_seconds, as it stops the checks which would
+    /// replace it.
+    #[serde(default)]
+    pub disable_auto_update: bool,
+    /// How long disable_auto_update lasts after the check which sent it,
+    /// one day if unset.
+    #[serde(default)]
+    pub disable_auto_update_seconds: Option<u64>,
+    /// Minimum time between automatic update checks.
+    #[serde(default)]
+    pub check_interval_seconds: Option<u64>,
+    /// Maximum log level (e.g. "warn"), see log::LevelFilter.
+    #[serde(default)]
+    pub log_level: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// A candidate patch offered to a percentage of devices in place of the
+/// response's main patch.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PatchExperiment {
+    pub patch: Patch,
+    /// Percentage (0-100) of devices which should install this patch.
+    pub percentage: u8,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct PatchCheckResponse {
     pub patch_available: bool,
     #[serde(default)]
     pub patch: Option<Patch>,
+    /// An experimental alternative to `patch`, if the server is running one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<PatchExperiment>,
+    /// Absent directives reset any previously received ones.
+    #[serde(default)]
+    pub client_directives: ClientDirectives,
+    /// Kill switch: uninstall all patches for this release and boot the
+    /// release as shipped.
+    #[serde(default)]
+    pub disable_patches: bool,
 }
 
+/// Verifies the signature on a patch check response if we have a key to
+/// verify it with.  Unsigned responses are only rejected when
+/// require_signed_responses is set.
+fn check_response_signature(
+    config: &UpdateConfig,
+    response: &PatchCheckHttpResponse,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use base64::Engine;
+
+    let public_key = match &config.patch_public_key {
+        Some(public_key) => public_key,
+        None => return Ok(()),
+    };
+    let signature = match &response.signature {
+        Some(signature) => signature,
+        None if config.require_signed_responses => {
+            return Err(UpdateError::InvalidSignature).context("Response is not signed.");
+        }
+        None => {
+            info!("Patch check response is not signed, accepting.");
+            return Ok(());
+        }
+    };
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .unwrap_or_default();
+    if !verify_signature(public_key, &response.body, &signature) {
+        return Err(UpdateError::InvalidSignature).context("Response signature is invalid.");
+    }
+    Ok(())
+}
+
+/// Sends a patch check for config.channel.  If the server doesn't know the
+/// channel or has no patch on it, tries each of config.channel_fallbacks in
+/// turn, returning the first response with a patch (or the last response).
+/// `pinned_patch_number` is requested instead of the latest patch, if set.
 pub fn send_patch_check_request(
     config: &UpdateConfig,
     state: &UpdaterState,
+    pinned_patch_number: Option<usize>,
 ) -> anyhow::Result<PatchCheckResponse> {
+    use anyhow::Context;
+
+    let mut channels = std::iter::once(&config.channel)
+        .chain(&config.channel_fallbacks)
+        .peekable();
+    while let Some(channel) = channels.next() {
+        let is_last = channels.peek().is_none();
+        match send_patch_check_request_for_channel(config, state, channel, pinned_patch_number)? {
+            None if is_last => {
+                return Err(UpdateError::BadServerResponse)
+                    .with_context(|| format!("Channel not found: {}", channel));
+            }
+            Some(response) if is_last || response.patch.is_some() || response.disable_patches => {
+                return Ok(response);
+            }
+            _ => info!("No patch on channel {}, trying next channel.", channel),
+        }
+    }
+    unreachable!("config.channel is always checked");
+}
+
+/// Returns None if the server does not know the channel.
+fn send_patch_check_request_for_channel(
+    config: &UpdateConfig,
+    state: &UpdaterState,
+    channel: &Channel,
+    pinned_patch_number: Option<usize>,
+) -> anyhow::Result<Option<PatchCheckResponse>> {
     let latest_patch_number = state.latest_patch_number();
+    let environment = if config.report_device_environment {
+        device_environment()
+    } else {
+        DeviceEnvironment::default()
+    };
 
     // Send the request to the server.
     let request = PatchCheckRequest {
         app_id: config.app_id.clone(),
-        channel: config.channel.clone(),
+        channel: channel.clone(),
         release_version: config.release_version.clone(),
         patch_number: latest_patch_number,
         platform: current_platform().to_string(),
         arch: current_arch().to_string(),
+        native_fingerprint: config.native_fingerprint.clone(),
+        max_signature_version: MAX_SIGNATURE_VERSION,
+        client_id: state.client_id().to_owned(),
+        is_emulator: environment.is_emulator,
+        is_rooted: environment.is_rooted,
+        last_boot: state.last_boot_timing(),
+        pinned_patch_number,
+        decode_benchmark: state.decode_benchmark().cloned(),
+        installed_patch_numbers: config
+            .report_installed_patches
+            .then(|| state.installed_patch_numbers()),
+        locale: config.locale.clone(),
     };
-    info!("Sending patch check request: {:?}", request);
-    let url = &patches_check_url(&config.base_url);
+    if config.log_network_bodies {
+        info!("Sending patch check request: {}", redacted_json(&request));
+    } else {
+        info!(
+            "Sending patch check request: channel {}, patch_number {:?}",
+            request.channel, request.patch_number
+        );
+    }
+    let url = &patches_check_url(&config.base_url)?;
+    anyhow::ensure!(!is_offline(), UpdateError::Offline);
     let patch_check_request_fn = config.network_hooks.patch_check_request_fn;
-    let response = patch_check_request_fn(url, request)?;
+    let http_response = patch_check_request_fn(url, request)?;
+    if http_response.not_found {
+        return Ok(None);
+    }
+    if let Some(seconds) = http_response.retry_after_seconds {
+        return Err(UpdateError::ServerBusy(seconds).into());
+    }
+    check_response_signature(config, &http_response)?;
+    let response: PatchCheckResponse = serde_json::from_slice(&http_response.body)?;
+
+    if config.log_network_bodies {
+        // The body as sent, which may have fields PatchCheckResponse ignores.
+        let body = serde_json::from_slice::<serde_json::Value>(&http_response.body)?;
+        info!("Patch check response: {}", redacted_json(&body));
+    } else {
+        info!(
+            "Patch check response: patch_available {}, patch {:?}",
+            response.patch_available,
+            response.patch.as_ref().map(|patch| patch.number)
+        );
+    }
+    return Ok(Some(response));
+}
+
+/// What redact_for_log() replaces hidden values with.
+/// cbindgen:ignore
+const REDACTED: &str = "<redacted>";
+
+/// Hides values which identify the device or the app's customers, or grant
+/// access, so request and response bodies are safe to log: client_ids, the
+/// values (not the keys) of patch metadata, which is freeform, and the query
+/// strings of download URLs, which are often signed.
+fn redact_for_log(value: &mut serde_json::Value) {
+    fn redact_leaves(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => map.values_mut().for_each(redact_leaves),
+            serde_json::Value::Array(items) => items.iter_mut().for_each(redact_leaves),
+            serde_json::Value::Null => {}
+            leaf => *leaf = REDACTED.into(),
+        }
+    }
+
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match key.as_str() {
+                    "client_id" if !value.is_null() => *value = REDACTED.into(),
+                    "metadata" => redact_leaves(value),
+                    "download_url" | "full_download_url" => {
+                        if let Some((url, _query)) = value.as_str().and_then(|s| s.split_once('?'))
+                        {
+                            *value = format!("{}?{}", url, REDACTED).into();
+                        }
+                    }
+                    _ => redact_for_log(value),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_for_log),
+        _ => {}
+    }
+}
+
+/// value as JSON with redact_for_log() applied, for logging.
+fn redacted_json(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(mut value) => {
+            redact_for_log(&mut value);
+            value.to_string()
+        }
+        Err(e) => format!("<unserializable: {}>", e),
+    }
+}
+
+/// Returns the interrupted download to resume into `path`, if there is one
+/// for this url.  The file on disk is trusted over the recorded byte count,
+/// since the app may have been killed before progress was saved.
+fn resumable_download(state: &UpdaterState, url: &str, path: &Path) -> Option<PartialDownload> {
+    let partial = state.partial_download().filter(|p| p.url == url)?;
+    let bytes_on_disk = std::fs::metadata(path).ok()?.len();
+    if bytes_on_disk == 0 {
+        return None;
+    }
+    Some(PartialDownload {
+        bytes_downloaded: bytes_on_disk,
+        ..partial.clone()
+    })
+}
+
+/// Feeds the first `len` bytes of the file at path to verifier, e.g. the part
+/// of a download which was written before it was interrupted.
+fn hash_file_prefix(verifier: &mut HashVerifier, path: &Path, len: u64) -> anyhow::Result<()> {
+    let mut file = File::open(path).with_file_context("open", path)?.take(len);
+    let mut buffer = [0; 8192];
+    loop {
+        match file.read(&mut buffer).with_file_context("read", path)? {
+            0 => return Ok(()),
+            count => verifier.update(&buffer[..count]),
+        }
+    }
+}
 
-    info!("Patch check response: {:?}", response);
-    return Ok(response);
+/// Copies reader to writer (the file at path), returning the number of bytes
+/// copied so far alongside any error.  Each chunk is also passed to
+/// on_chunk as it is written.
+fn copy_counting(
+    reader: &mut dyn Read,
+    writer: &mut File,
+    path: &Path,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> (u64, anyhow::Result<()>) {
+    let mut buffer = [0; 8192];
+    let mut copied = 0;
+    loop {
+        if crate::lifecycle::downloads_paused() {
+            return (copied, Err(UpdateError::DownloadPaused.into()));
+        }
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => return (copied, Ok(())),
+            Ok(count) => count,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return (copied, Err(e.into())),
+        };
+        if let Err(e) = writer.write_all(&buffer[..count]) {
+            return (copied, Err(e).with_file_context("write", path));
+        }
+        on_chunk(&buffer[..count]);
+        copied += count as u64;
+    }
 }
 
+/// Downloads url to path.  If a previous download of the same url was
+/// interrupted, resumes it where it left off.  Progress is recorded in
+/// `state` so an interrupted download can be resumed after a restart.
+/// If `expected_hash` is given, the download is hashed as it is written and
+/// discarded if it does not match.
 pub fn download_to_path(
     network_hooks: &NetworkHooks,
     url: &str,
     path: &Path,
+    expected_hash: Option<&Hash>,
+    state: &mut UpdaterState,
 ) -> anyhow::Result<()> {
-    info!("Downloading patch from: {}", url);
-    // Download the file at the given url to the given path.
+    let resume_from = resumable_download(state, url, path);
+    match &resume_from {
+        Some(partial) => info!(
+            "Resuming download from: {} at byte {}",
+            url, partial.bytes_downloaded
+        ),
+        None => info!("Downloading patch from: {}", url),
+    }
+    anyhow::ensure!(!is_offline(), UpdateError::Offline);
     let download_file_hook = network_hooks.download_file_fn;
-    let mut bytes = download_file_hook(url)?;
+    let mut response = download_file_hook(url, resume_from.as_ref())?;
     // Ensure the download directory exists.
     if let Some(parent) = path.parent() {
         info!("Creating download directory: {:?}", parent);
-        std::fs::create_dir_all(parent)?;
+        std::fs::create_dir_all(parent).with_file_context("create directory", parent)?;
     }
 
+    // The server may ignore the range (e.g. the file changed), in which case
+    // we start over from the beginning.
+    let already_downloaded = match resume_from {
+        Some(partial) if response.is_partial => partial.bytes_downloaded,
+        _ => 0,
+    };
     info!("Writing download to: {:?}", path);
-    let mut file = File::create(path)?;
-    file.write_all(&mut bytes)?;
-    Ok(())
+    let mut file = if already_downloaded > 0 {
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_file_context("open", path)?
+    } else {
+        File::create(path).with_file_context("create", path)?
+    };
+    let mut partial = PartialDownload {
+        url: url.to_owned(),
+        bytes_downloaded: already_downloaded,
+        etag: response.etag.take(),
+        total_bytes: response.total_bytes,
+    };
+    state.set_partial_download(Some(partial.clone()));
+    // The config lock is also the state write lock, see install_verified_patch().
+    crate::config::with_config(|_| state.save())?;
+
+    let mut verifier = expected_hash.map(|hash| HashVerifier::new(*hash));
+    if let Some(verifier) = verifier.as_mut() {
+        hash_file_prefix(verifier, path, already_downloaded)?;
+    }
+    let (copied, mut result) = copy_counting(&mut response.body, &mut file, path, |chunk| {
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.update(chunk);
+        }
+    });
+    partial.bytes_downloaded += copied;
+    if let (Ok(()), Some(verifier)) = (&result, verifier) {
+        let (hash, hash_matches) = verifier.finalize();
+        if !hash_matches {
+            warn!(
+                "Download hash mismatch: {}, got: {}",
+                url,
+                hex::encode(hash)
+            );
+            // Don't resume from corrupt data.
+            std::fs::remove_file(path).with_file_context("remove", path)?;
+            result =
+                Err(anyhow::Error::from(UpdateError::HashMismatch)
+                    .context("Download hash mismatch."));
+        }
+    }
+    match result {
+        Ok(()) => state.set_partial_download(None),
+        Err(_) if !path.exists() => state.set_partial_download(None),
+        Err(_) => {
+            info!(
+                "Download interrupted after {} bytes, will resume next update.",
+                partial.bytes_downloaded
+            );
+            state.set_partial_download(Some(partial));
+        }
+    }
+    crate::config::with_config(|_| state.save())?;
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::network::PatchCheckResponse;
+    use crate::cache::{PartialDownload, UpdaterState};
+    use crate::network::{DownloadFileResponse, Patch, PatchCheckResponse, PatchPriority};
+    use crate::verify::decode_hash;
+    use serial_test::serial;
+    use tempdir::TempDir;
+
+    #[test]
+    fn patches_check_url_keeps_path_prefix() {
+        let check_url = |base_url| {
+            let base_url = crate::config::parse_base_url(base_url).unwrap();
+            super::patches_check_url(base_url.as_str()).unwrap()
+        };
+        assert_eq!(
+            check_url("https://api.shorebird.dev"),
+            "https://api.shorebird.dev/api/v1/patches/check"
+        );
+        for base_url in ["https://example.com/tenant", "https://example.com/tenant/"] {
+            assert_eq!(
+                check_url(base_url),
+                "https://example.com/tenant/api/v1/patches/check"
+            );
+        }
+    }
+
+    #[test]
+    fn redact_for_log() {
+        let mut body = serde_json::json!({
+            "client_id": "1234",
+            "patch_available": true,
+            "patch": {
+                "number": 2,
+                "download_url": "https://cdn.example.com/patch?X-Goog-Signature=abc",
+                "full_download_url": "https://cdn.example.com/full",
+                "metadata": {"git": {"sha": "abc", "dirty": false}, "tags": ["qa"], "note": null},
+            },
+            "last_boot": null,
+        });
+        super::redact_for_log(&mut body);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "client_id": "<redacted>",
+                "patch_available": true,
+                "patch": {
+                    "number": 2,
+                    "download_url": "https://cdn.example.com/patch?<redacted>",
+                    "full_download_url": "https://cdn.example.com/full",
+                    "metadata": {
+                        "git": {"sha": "<redacted>", "dirty": "<redacted>"},
+                        "tags": ["<redacted>"],
+                        "note": null,
+                    },
+                },
+                "last_boot": null,
+            })
+        );
+    }
+
+    /// sha256 of "hello world".
+    const HELLO_WORLD_HASH: &str =
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    /// Returns its data and then fails, like a dropped connection.
+    struct FlakyReader {
+        data: &'static [u8],
+    }
+
+    impl std::io::Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            let count = std::cmp::min(buf.len(), self.data.len());
+            buf[..count].copy_from_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    fn download_drops_connection(
+        _url: &str,
+        resume_from: Option<&PartialDownload>,
+    ) -> anyhow::Result<DownloadFileResponse> {
+        assert!(resume_from.is_none());
+        Ok(DownloadFileResponse {
+            is_partial: false,
+            etag: Some("\"v1\"".to_owned()),
+            total_bytes: Some(11),
+            body: Box::new(FlakyReader { data: b"hello " }),
+        })
+    }
+
+    fn download_resumes(
+        _url: &str,
+        resume_from: Option<&PartialDownload>,
+    ) -> anyhow::Result<DownloadFileResponse> {
+        let partial = resume_from.unwrap();
+        assert_eq!(partial.bytes_downloaded, 6);
+        assert_eq!(partial.etag.as_deref(), Some("\"v1\""));
+        Ok(DownloadFileResponse {
+            is_partial: true,
+            etag: partial.etag.clone(),
+            total_bytes: None,
+            body: Box::new(&b"world"[..]),
+        })
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn extra_headers() {
+        use std::collections::BTreeMap;
+
+        let yaml = BTreeMap::from([
+            ("X-Org-Token".to_string(), "from yaml".to_string()),
+            ("X-Team".to_string(), "updater".to_string()),
+        ]);
+        super::set_config_headers(
+            "https://example.com/",
+            super::validate_extra_headers(&yaml).unwrap(),
+        );
+        super::set_extra_header("x-org-token", Some("from app")).unwrap();
+        super::set_extra_header("X-Team", None).unwrap();
+        super::set_extra_header("X-New", Some("")).unwrap();
+        assert_eq!(
+            super::extra_headers(),
+            BTreeMap::from([
+                ("x-new".to_string(), "".to_string()),
+                ("x-org-token".to_string(), "from app".to_string()),
+            ])
+        );
+
+        // Nothing which could break out of the header.
+        assert!(super::set_extra_header("X-Bad", Some("a\r\nX-Injected: b")).is_err());
+        assert!(super::set_extra_header("X Bad", Some("a")).is_err());
+        assert!(super::set_extra_header("", None).is_err());
+        let yaml = BTreeMap::from([("X-Bad:".to_string(), "a".to_string())]);
+        assert!(super::validate_extra_headers(&yaml).is_err());
+
+        super::testing_reset_extra_headers();
+        super::set_config_headers("", Default::default());
+        assert!(super::extra_headers().is_empty());
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn extra_headers_only_go_to_base_url() {
+        use crate::request_signing::{RequestSigning, SignatureScheme};
+        use std::collections::BTreeMap;
+
+        let headers = BTreeMap::from([
+            ("x-org-token".to_string(), "secret".to_string()),
+            ("authorization".to_string(), "Bearer mine".to_string()),
+        ]);
+        super::set_config_headers("https://example.com/tenant/", headers);
+        let names = |url: &str| {
+            super::request_headers("GET", url, &[])
+                .unwrap()
+                .into_iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names("https://example.com/tenant/api/v1/patches/check"),
+            ["authorization: Bearer mine", "x-org-token: secret"]
+        );
+        assert!(names("https://storage.googleapis.com/patch?sig=1").is_empty());
+        assert!(names("https://example.com.evil.com/tenant/patch").is_empty());
+
+        // Request signing's Authorization replaces the extra header.
+        crate::request_signing::set_config(Some(RequestSigning {
+            base_url: "https://example.com/tenant/".to_string(),
+            key: Some(b"key".to_vec()),
+            scheme: SignatureScheme::Authorization,
+            clock: std::sync::Arc::new(crate::time::FakeClock::new(1)),
+        }));
+        let sent = names("https://example.com/tenant/api/v1/patches/check");
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].starts_with("authorization: Shorebird-HMAC-SHA256"));
+        crate::request_signing::set_config(None);
+        super::set_config_headers("", Default::default());
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        use super::{retry_after_seconds, DEFAULT_RETRY_AFTER_SECONDS, MAX_RETRY_AFTER_SECONDS};
+        assert_eq!(retry_after_seconds(200, Some("120")), None);
+        assert_eq!(retry_after_seconds(429, Some("120")), Some(120));
+        assert_eq!(retry_after_seconds(503, Some(" 5 ")), Some(5));
+        assert_eq!(
+            retry_after_seconds(503, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(DEFAULT_RETRY_AFTER_SECONDS)
+        );
+        assert_eq!(
+            retry_after_seconds(429, None),
+            Some(DEFAULT_RETRY_AFTER_SECONDS)
+        );
+        assert_eq!(
+            retry_after_seconds(429, Some("999999999")),
+            Some(MAX_RETRY_AFTER_SECONDS)
+        );
+    }
+
+    /// download_to_path() saves progress, which needs init().
+    fn init_for_download(cache_dir: &std::path::Path) {
+        crate::config::testing_reset_config();
+        crate::init(
+            crate::AppConfig {
+                cache_dir: cache_dir.to_str().unwrap().to_string(),
+                release_version: "1.0.0+1".to_string(),
+                original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+                ..Default::default()
+            },
+            "app_id: 1234",
+        )
+        .unwrap();
+    }
+
+    fn load_state(cache_dir: &std::path::Path) -> UpdaterState {
+        let clock: std::sync::Arc<dyn crate::time::Clock> =
+            std::sync::Arc::new(crate::time::SystemClock);
+        UpdaterState::load_or_new_on_error(cache_dir, &"1.0.0+1".parse().unwrap(), &clock, false)
+    }
+
+    // Serial since offline mode (see updater tests) is process-wide.
+    #[serial]
+    #[test]
+    fn download_resumes_after_interruption() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_download(tmp_dir.path());
+        let path = tmp_dir.path().join("downloads").join("1");
+        let url = "https://example.com/patch";
+        let mut hooks = super::NetworkHooks {
+            download_file_fn: download_drops_connection,
+            ..Default::default()
+        };
+        let mut state = load_state(tmp_dir.path());
+        assert!(super::download_to_path(&hooks, url, &path, None, &mut state).is_err());
+
+        // Progress survives a restart.
+        let mut state = load_state(tmp_dir.path());
+        assert_eq!(state.partial_download().unwrap().bytes_downloaded, 6);
+        assert_eq!(state.partial_download().unwrap().total_bytes, Some(11));
+
+        // The part downloaded before the interruption is hashed too.
+        hooks.download_file_fn = download_resumes;
+        let hash = decode_hash(HELLO_WORLD_HASH).unwrap();
+        super::download_to_path(&hooks, url, &path, Some(&hash), &mut state).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        assert!(state.partial_download().is_none());
+    }
+
+    fn download_hello_tests(
+        _url: &str,
+        _resume_from: Option<&PartialDownload>,
+    ) -> anyhow::Result<DownloadFileResponse> {
+        Ok(DownloadFileResponse {
+            is_partial: false,
+            etag: None,
+            total_bytes: None,
+            body: Box::new(&b"hello tests"[..]),
+        })
+    }
+
+    #[serial]
+    #[test]
+    fn download_hash_mismatch_discards_download() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_download(tmp_dir.path());
+        let path = tmp_dir.path().join("downloads").join("1");
+        let hooks = super::NetworkHooks {
+            download_file_fn: download_hello_tests,
+            ..Default::default()
+        };
+        let mut state = load_state(tmp_dir.path());
+        let hash = decode_hash(HELLO_WORLD_HASH).unwrap();
+        let url = "https://example.com/patch";
+        assert!(super::download_to_path(&hooks, url, &path, Some(&hash), &mut state).is_err());
+        assert!(!path.exists());
+        assert!(state.partial_download().is_none());
+    }
 
     #[test]
     fn check_patch_request_response_deserialization() {
@@ -217,6 +1250,58 @@ mod tests {
         assert_eq!(patch.number, 1);
         assert_eq!(patch.download_url, "https://storage.googleapis.com/patch_artifacts/17a28ec1-00cf-452d-bdf9-dbb9acb78600/dlc.vmcode");
         assert_eq!(patch.hash, "#");
+        assert_eq!(patch.min_build_number, None);
+        assert_eq!(patch.max_build_number, None);
+        assert_eq!(patch.native_fingerprint, None);
+        assert_eq!(response.client_directives, Default::default());
+        assert!(!response.disable_patches);
+        assert!(response.experiment.is_none());
+        assert_eq!(patch.priority, PatchPriority::Normal);
+    }
+
+    #[test]
+    fn patch_priority_deserialization() {
+        let priority = |json: &str| {
+            let data = format!(
+                r##"{{"number": 1, "download_url": "url", "hash": "#", "priority": {}}}"##,
+                json
+            );
+            serde_json::from_str::<Patch>(&data).unwrap().priority
+        };
+        assert_eq!(priority(r#""critical""#), PatchPriority::Critical);
+        assert_eq!(priority(r#""normal""#), PatchPriority::Normal);
+        // Priorities from newer servers don't break parsing.
+        assert_eq!(priority(r#""urgent""#), PatchPriority::Normal);
+    }
+
+    #[test]
+    fn client_directives_deserialization() {
+        let data = r###"
+    {
+        "patch_available": false,
+        "client_directives": {
+            "disable_auto_update": true,
+            "disable_auto_update_seconds": 600,
+            "check_interval_seconds": 3600,
+            "log_level": "warn",
+            "some_future_directive": 1
+        }
+    }"###;
+
+        let response: PatchCheckResponse = serde_json::from_str(data).unwrap();
+        assert!(response.client_directives.disable_auto_update);
+        assert_eq!(
+            response.client_directives.disable_auto_update_seconds,
+            Some(600)
+        );
+        assert_eq!(
+            response.client_directives.check_interval_seconds,
+            Some(3600)
+        );
+        assert_eq!(
+            response.client_directives.log_level.as_deref(),
+            Some("warn")
+        );
     }
 
     // This confirms that the default network hooks throw an error in cfg(test).
@@ -229,15 +1314,25 @@ mod tests {
             "",
             super::PatchCheckRequest {
                 app_id: "".to_string(),
-                channel: "".to_string(),
-                release_version: "".to_string(),
+                channel: "stable".parse().unwrap(),
+                release_version: "1.0.0+1".parse().unwrap(),
                 patch_number: None,
                 platform: "".to_string(),
                 arch: "".to_string(),
+                native_fingerprint: None,
+                max_signature_version: super::MAX_SIGNATURE_VERSION,
+                client_id: "".to_string(),
+                is_emulator: None,
+                is_rooted: None,
+                last_boot: None,
+                pinned_patch_number: None,
+                decode_benchmark: None,
+                installed_patch_numbers: None,
+                locale: None,
             },
         );
         assert!(result.is_err());
-        let result = (network_hooks.download_file_fn)("");
+        let result = (network_hooks.download_file_fn)("", None);
         assert!(result.is_err());
     }
 
@@ -0,0 +1,408 @@
+// This file deals with applying patches in a way which can be resumed.  For
+// large patches inflating can take long enough that the app is killed part
+// way through, so we periodically record how far we got in a checkpoint file
+// next to the output.  The next attempt picks up from there, provided it is
+// applying the same download.
+//
+// bipatch::Reader keeps its position private, so this is our own reader for
+// the same format: a header, then repeated (add length, add bytes, copy
+// length, copy bytes, base seek) commands, all lengths as varints.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::hash_file;
+use crate::file_errors::IoResultExt;
+
+#[cfg(test)]
+use std::{println as info, println as warn}; // Workaround to use println! for logs.
+
+/// How many bytes of output to write between checkpoints.
+/// cbindgen:ignore
+pub const CHECKPOINT_INTERVAL: u64 = 8 * 1024 * 1024;
+
+const BIPATCH_MAGIC: u32 = 0xB1DF;
+const BIPATCH_VERSION: u32 = 0x1000;
+/// Magic and version, both u32.
+const BIPATCH_HEADER_SIZE: u64 = 8;
+
+/// Which command the reader is part way through.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ApplyState {
+    /// About to read the next add length.
+    Initial,
+    /// This many bytes left to add to the base.
+    Add(u64),
+    /// This many bytes left to copy from the patch.
+    Copy(u64),
+    /// Finished.
+    Final,
+}
+
+/// How far a patch application got.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// sha256 of the (compressed) download being applied.
+    pub download_hash: String,
+    /// Bytes of the inflated patch consumed.
+    pub patch_offset: u64,
+    /// Position in the base.
+    pub base_offset: u64,
+    /// Bytes of output written.
+    pub output_offset: u64,
+    pub state: ApplyState,
+}
+
+fn checkpoint_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".checkpoint");
+    PathBuf::from(path)
+}
+
+/// The checkpoint left by an interrupted application of the download at
+/// download_path to output_path, if it can be resumed.
+pub fn load_checkpoint(output_path: &Path, download_path: &Path) -> Option<Checkpoint> {
+    let path = checkpoint_path(output_path);
+    let contents = std::fs::read(&path).ok()?;
+    let checkpoint: Checkpoint = match serde_json::from_slice(&contents) {
+        Ok(checkpoint) => checkpoint,
+        Err(err) => {
+            warn!("Ignoring unreadable checkpoint {:?}: {}", path, err);
+            return None;
+        }
+    };
+    let download_hash = hash_file(download_path).ok()?;
+    if checkpoint.download_hash != download_hash {
+        info!("Checkpoint {:?} is for a different download.", path);
+        return None;
+    }
+    let output_len = std::fs::metadata(output_path).ok()?.len();
+    if output_len < checkpoint.output_offset {
+        warn!(
+            "Output {:?} is shorter ({} bytes) than its checkpoint ({} bytes).",
+            output_path, output_len, checkpoint.output_offset
+        );
+        return None;
+    }
+    Some(checkpoint)
+}
+
+/// Records checkpoint for output_path.  The output must already be flushed
+/// up to checkpoint.output_offset.
+pub fn save_checkpoint(output_path: &Path, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    // Write to a temporary file and rename so the checkpoint is never partial.
+    let path = checkpoint_path(output_path);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    std::fs::write(&tmp_path, serde_json::to_vec(checkpoint)?)
+        .with_file_context("write", &tmp_path)?;
+    std::fs::rename(&tmp_path, &path).with_file_context("create", &path)?;
+    Ok(())
+}
+
+/// Removes any checkpoint for output_path, e.g. once it is complete.
+pub fn remove_checkpoint(output_path: &Path) -> anyhow::Result<()> {
+    let path = checkpoint_path(output_path);
+    if path.exists() {
+        std::fs::remove_file(&path).with_file_context("remove", &path)?;
+    }
+    Ok(())
+}
+
+/// Reads the result of applying an (inflated) bipatch stream to a base,
+/// keeping track of where it is so it can be checkpointed.
+pub struct PatchApplier<R, RS>
+where
+    R: Read,
+    RS: Read + Seek,
+{
+    patch: R,
+    base: RS,
+    state: ApplyState,
+    buf: Vec<u8>,
+    patch_offset: u64,
+    base_offset: u64,
+    output_offset: u64,
+}
+
+impl<R, RS> PatchApplier<R, RS>
+where
+    R: Read,
+    RS: Read + Seek,
+{
+    /// Starts applying patch, which must be at the start of the stream.
+    pub fn new(patch: R, mut base: RS) -> std::io::Result<Self> {
+        base.rewind()?;
+        let mut applier = Self::with_state(patch, base, ApplyState::Initial);
+        let mut header = [0u8; BIPATCH_HEADER_SIZE as usize];
+        applier.read_patch(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if magic != BIPATCH_MAGIC || version != BIPATCH_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Not a bipatch: magic {:#x}, version {:#x}", magic, version),
+            ));
+        }
+        Ok(applier)
+    }
+
+    /// Continues from checkpoint.  patch must be positioned at
+    /// checkpoint.patch_offset.
+    pub fn resume(patch: R, mut base: RS, checkpoint: &Checkpoint) -> std::io::Result<Self> {
+        base.seek(SeekFrom::Start(checkpoint.base_offset))?;
+        let mut applier = Self::with_state(patch, base, checkpoint.state);
+        applier.patch_offset = checkpoint.patch_offset;
+        applier.base_offset = checkpoint.base_offset;
+        applier.output_offset = checkpoint.output_offset;
+        Ok(applier)
+    }
+
+    fn with_state(patch: R, base: RS, state: ApplyState) -> Self {
+        Self {
+            patch,
+            base,
+            state,
+            buf: vec![0u8; 4096],
+            patch_offset: 0,
+            base_offset: 0,
+            output_offset: 0,
+        }
+    }
+
+    /// Where we are, valid between calls to read().
+    pub fn checkpoint(&self, download_hash: &str) -> Checkpoint {
+        Checkpoint {
+            download_hash: download_hash.to_owned(),
+            patch_offset: self.patch_offset,
+            base_offset: self.base_offset,
+            output_offset: self.output_offset,
+            state: self.state,
+        }
+    }
+
+    fn read_patch(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.patch.read_exact(buf)?;
+        self.patch_offset += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reads an unsigned LEB128 varint, or None if the patch ended before it.
+    fn read_varint(&mut self) -> std::io::Result<Option<u64>> {
+        let mut value = 0u64;
+        let mut byte = [0u8; 1];
+        for shift in (0..64).step_by(7) {
+            if let Err(err) = self.patch.read_exact(&mut byte) {
+                if shift == 0 && err.kind() == std::io::ErrorKind::UnexpectedEof {
+                    return Ok(None);
+                }
+                return Err(err);
+            }
+            self.patch_offset += 1;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "varint too long",
+        ))
+    }
+
+    fn read_required_varint(&mut self) -> std::io::Result<u64> {
+        self.read_varint()?
+            .ok_or_else(|| std::io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl<R, RS> Read for PatchApplier<R, RS>
+where
+    R: Read,
+    RS: Read + Seek,
+{
+    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while !buf.is_empty() {
+            let processed = match self.state {
+                ApplyState::Initial => {
+                    self.state = match self.read_varint()? {
+                        Some(add_len) => ApplyState::Add(add_len),
+                        None => ApplyState::Final,
+                    };
+                    0
+                }
+                ApplyState::Add(add_len) => {
+                    let n = (add_len.min(self.buf.len() as u64) as usize).min(buf.len());
+                    let out = &mut buf[..n];
+                    self.base.read_exact(out)?;
+                    let mut diff = std::mem::take(&mut self.buf);
+                    let result = self.read_patch(&mut diff[..n]);
+                    for (out, diff) in out.iter_mut().zip(&diff[..n]) {
+                        *out = out.wrapping_add(*diff);
+                    }
+                    self.buf = diff;
+                    result?;
+                    self.base_offset += n as u64;
+                    self.state = if add_len == n as u64 {
+                        ApplyState::Copy(self.read_required_varint()?)
+                    } else {
+                        ApplyState::Add(add_len - n as u64)
+                    };
+                    n
+                }
+                ApplyState::Copy(copy_len) => {
+                    let n = copy_len.min(buf.len() as u64) as usize;
+                    self.read_patch(&mut buf[..n])?;
+                    if copy_len == n as u64 {
+                        // Seeks are zigzag encoded.
+                        let seek = self.read_required_varint()?;
+                        let seek = (seek >> 1) as i64 ^ -((seek & 1) as i64);
+                        self.base_offset = self.base.seek(SeekFrom::Current(seek))?;
+                        self.state = ApplyState::Initial;
+                    } else {
+                        self.state = ApplyState::Copy(copy_len - n as u64);
+                    }
+                    n
+                }
+                ApplyState::Final => break,
+            };
+            read += processed;
+            self.output_offset += processed as u64;
+            buf = &mut buf[processed..];
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    /// Appends value as an unsigned LEB128 varint.
+    pub fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+    }
+
+    /// A bipatch stream which adds `diff` to the start of the base (zero
+    /// diffs copy it unchanged) then appends `tail`.
+    pub fn bipatch(diff: &[u8], tail: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(super::BIPATCH_MAGIC.to_le_bytes());
+        bytes.extend(super::BIPATCH_VERSION.to_le_bytes());
+        push_varint(&mut bytes, diff.len() as u64);
+        bytes.extend(diff);
+        push_varint(&mut bytes, tail.len() as u64);
+        bytes.extend(tail);
+        // Seek of 0.
+        push_varint(&mut bytes, 0);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::bipatch;
+    use super::{ApplyState, PatchApplier, BIPATCH_HEADER_SIZE};
+    use std::io::{Cursor, Read};
+    use tempdir::TempDir;
+
+    #[test]
+    fn matches_bipatch_crate() {
+        // Generated by `string_patch "hello world" "hello tests"`
+        let patch_bytes: Vec<u8> = vec![
+            40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0, 0, 0, 0,
+            5, 116, 101, 115, 116, 115, 0,
+        ];
+        let mut inflated = Vec::new();
+        comde::de::Decompressor::copy(
+            &comde::zstd::ZstdDecompressor,
+            &patch_bytes[..],
+            &mut inflated,
+        )
+        .unwrap();
+        let base = Cursor::new(b"hello world".to_vec());
+
+        let mut expected = String::new();
+        bipatch::Reader::new(&inflated[..], base.clone())
+            .unwrap()
+            .read_to_string(&mut expected)
+            .unwrap();
+        let mut actual = String::new();
+        let mut applier = PatchApplier::new(&inflated[..], base).unwrap();
+        applier.read_to_string(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(actual, "hello tests");
+        let checkpoint = applier.checkpoint("hash");
+        assert_eq!(checkpoint.state, ApplyState::Final);
+        assert_eq!(checkpoint.patch_offset, inflated.len() as u64);
+        assert_eq!(checkpoint.output_offset, 11);
+
+        assert!(PatchApplier::new(&b"not a patch"[..], Cursor::new(vec![])).is_err());
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let base: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let diff = vec![1u8; base.len()];
+        let patch = bipatch(&diff, b"tail");
+        let expected: Vec<u8> = base
+            .iter()
+            .map(|b| b.wrapping_add(1))
+            .chain(*b"tail")
+            .collect();
+
+        // Stop part way through the add.
+        let mut applier = PatchApplier::new(&patch[..], Cursor::new(base.clone())).unwrap();
+        let mut output = vec![0u8; 5000];
+        applier.read_exact(&mut output).unwrap();
+        let checkpoint = applier.checkpoint("hash");
+        assert_eq!(checkpoint.state, ApplyState::Add(5000));
+        assert_eq!(checkpoint.base_offset, 5000);
+
+        let remaining = &patch[checkpoint.patch_offset as usize..];
+        let mut resumed = PatchApplier::resume(remaining, Cursor::new(base), &checkpoint).unwrap();
+        resumed.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected);
+        assert!(checkpoint.patch_offset > BIPATCH_HEADER_SIZE);
+    }
+
+    #[test]
+    fn checkpoint_files() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let download_path = tmp_dir.path().join("1");
+        let output_path = tmp_dir.path().join("1.full");
+        std::fs::write(&download_path, "download").unwrap();
+        std::fs::write(&output_path, "output").unwrap();
+        assert!(super::load_checkpoint(&output_path, &download_path).is_none());
+
+        let checkpoint = super::Checkpoint {
+            download_hash: crate::cache::hash_file(&download_path).unwrap(),
+            patch_offset: 10,
+            base_offset: 4,
+            output_offset: 6,
+            state: ApplyState::Copy(2),
+        };
+        super::save_checkpoint(&output_path, &checkpoint).unwrap();
+        assert_eq!(
+            super::load_checkpoint(&output_path, &download_path),
+            Some(checkpoint.clone())
+        );
+
+        // Not for a different download, or if the output was truncated.
+        std::fs::write(&download_path, "other download").unwrap();
+        assert!(super::load_checkpoint(&output_path, &download_path).is_none());
+        std::fs::write(&download_path, "download").unwrap();
+        std::fs::write(&output_path, "out").unwrap();
+        assert!(super::load_checkpoint(&output_path, &download_path).is_none());
+
+        super::remove_checkpoint(&output_path).unwrap();
+        std::fs::write(&output_path, "output").unwrap();
+        assert!(super::load_checkpoint(&output_path, &download_path).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+// This file signs requests to the update server so that private patch
+// servers can authenticate devices.  The key comes from shorebird.yaml or,
+// so it can be rotated without a new release, from the host at runtime.
+
+// Without the "network" feature there are no requests to sign.
+#![cfg_attr(not(feature = "network"), allow(dead_code))]
+
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::time::Clock;
+
+/// How the signature is attached to requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureScheme {
+    /// `Authorization: Shorebird-HMAC-SHA256 timestamp=<t>,signature=<hex>`
+    #[default]
+    Authorization,
+    /// `X-Shorebird-Timestamp: <t>` and `X-Shorebird-Request-Signature: <hex>`,
+    /// for servers behind proxies which consume Authorization themselves.
+    Headers,
+}
+
+/// Request signing as configured by shorebird.yaml.
+#[derive(Clone)]
+pub struct RequestSigning {
+    /// Requests to other hosts (e.g. signed download URLs) are not signed.
+    pub base_url: String,
+    /// The HMAC key, unless the host provides one, see set_key_provider.
+    pub key: Option<Vec<u8>>,
+    pub scheme: SignatureScheme,
+    /// For the timestamp, see UpdateConfig::clock.
+    pub clock: Arc<dyn Clock>,
+}
+
+// Written by hand so the key doesn't end up in logs.
+impl core::fmt::Debug for RequestSigning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestSigning")
+            .field("base_url", &self.base_url)
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("scheme", &self.scheme)
+            .finish()
+    }
+}
+
+/// Returns the current HMAC key, or None if the host has none.
+pub type KeyProvider = Arc<dyn Fn() -> Option<Vec<u8>> + Send + Sync>;
+
+/// Set by set_config, None if shorebird.yaml doesn't ask for signing.
+static SIGNING: RwLock<Option<RequestSigning>> = RwLock::new(None);
+
+/// Set by set_key_provider, takes precedence over RequestSigning::key.
+static KEY_PROVIDER: RwLock<Option<KeyProvider>> = RwLock::new(None);
+
+pub fn set_config(signing: Option<RequestSigning>) {
+    *SIGNING.write().expect("Failed to acquire signing lock.") = signing;
+}
+
+/// Replaces the host's key provider, or removes it if None.
+pub fn set_key_provider(provider: Option<KeyProvider>) {
+    *KEY_PROVIDER
+        .write()
+        .expect("Failed to acquire signing lock.") = provider;
+}
+
+/// HMAC-SHA256 (RFC 2104) of the concatenated message parts.
+fn hmac_sha256(key: &[u8], message: &[&[u8]]) -> [u8; 32] {
+    use hmac::{Hmac, Mac};
+
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in message {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Whether url is on base_url, so e.g. https://a.com.evil.com/ is not on
+/// https://a.com.
+pub fn is_under(url: &str, base_url: &str) -> bool {
+    url.strip_prefix(base_url.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The path (and query) of url, e.g. "/api/v1/patches/check".
+fn url_path(url: &str) -> &str {
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    after_scheme
+        .find('/')
+        .map_or("/", |start| &after_scheme[start..])
+}
+
+/// The signature of a request: hex HMAC-SHA256 over the method, path and
+/// timestamp, each followed by a newline, and then the body.
+fn sign(key: &[u8], method: &str, path: &str, body: &[u8], timestamp: u64) -> String {
+    let timestamp = timestamp.to_string();
+    hex::encode(hmac_sha256(
+        key,
+        &[
+            method.as_bytes(),
+            b"\n",
+            path.as_bytes(),
+            b"\n",
+            timestamp.as_bytes(),
+            b"\n",
+            body,
+        ],
+    ))
+}
+
+/// The headers to add to a request to url, empty if it shouldn't be
+/// signed.  Fails if signing is configured but there is no key.
+pub fn signature_headers(
+    method: &str,
+    url: &str,
+    body: &[u8],
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let signing = SIGNING.read().expect("Failed to acquire signing lock.");
+    let signing = match signing.as_ref() {
+        Some(signing) if is_under(url, &signing.base_url) => signing,
+        _ => return Ok(Vec::new()),
+    };
+    let provider = KEY_PROVIDER
+        .read()
+        .expect("Failed to acquire signing lock.")
+        .clone();
+    let key = match provider {
+        Some(provider) => provider(),
+        None => signing.key.clone(),
+    };
+    let key = key.ok_or_else(|| anyhow::anyhow!("Request signing has no key."))?;
+    let timestamp = signing.clock.unix_timestamp();
+    let signature = sign(&key, method, url_path(url), body, timestamp);
+    Ok(match signing.scheme {
+        SignatureScheme::Authorization => vec![(
+            "authorization",
+            format!(
+                "Shorebird-HMAC-SHA256 timestamp={},signature={}",
+                timestamp, signature
+            ),
+        )],
+        SignatureScheme::Headers => vec![
+            ("x-shorebird-timestamp", timestamp.to_string()),
+            ("x-shorebird-request-signature", signature),
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    use super::{RequestSigning, SignatureScheme};
+    use crate::time::FakeClock;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // Test case 2.
+        let mac = super::hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Test case 6, a key longer than the block size.
+        let mac = super::hmac_sha256(
+            &[0xaa; 131],
+            &[b"Test Using Larger Than Block-Size Key - Hash Key First"],
+        );
+        assert_eq!(
+            hex::encode(mac),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn url_path() {
+        assert_eq!(
+            super::url_path("https://example.com/api/v1/patches/check"),
+            "/api/v1/patches/check"
+        );
+        assert_eq!(super::url_path("https://example.com/a?b=c"), "/a?b=c");
+        assert_eq!(super::url_path("https://example.com"), "/");
+    }
+
+    #[serial]
+    #[test]
+    fn signature_headers() {
+        let url = "https://example.com/api/v1/patches/check";
+        let expected = super::sign(b"key", "POST", "/api/v1/patches/check", b"{}", 42);
+
+        super::set_config(None);
+        assert!(super::signature_headers("POST", url, b"{}")
+            .unwrap()
+            .is_empty());
+
+        super::set_config(Some(RequestSigning {
+            base_url: "https://example.com".to_string(),
+            key: Some(b"key".to_vec()),
+            scheme: SignatureScheme::Authorization,
+            clock: Arc::new(FakeClock::new(42)),
+        }));
+        assert_eq!(
+            super::signature_headers("POST", url, b"{}").unwrap(),
+            vec![(
+                "authorization",
+                format!("Shorebird-HMAC-SHA256 timestamp=42,signature={}", expected)
+            )]
+        );
+        // Other hosts (e.g. signed download URLs) are left alone.
+        for other_host in ["https://storage.example/patch", "https://example.com.evil/"] {
+            assert!(super::signature_headers("GET", other_host, b"")
+                .unwrap()
+                .is_empty());
+        }
+
+        // The host's key wins, and a missing key is an error.
+        super::set_key_provider(Some(Arc::new(|| None)));
+        assert!(super::signature_headers("POST", url, b"{}").is_err());
+        super::set_key_provider(Some(Arc::new(|| Some(b"other".to_vec()))));
+        super::set_config(Some(RequestSigning {
+            base_url: "https://example.com".to_string(),
+            key: None,
+            scheme: SignatureScheme::Headers,
+            clock: Arc::new(FakeClock::new(42)),
+        }));
+        let other = super::sign(b"other", "POST", "/api/v1/patches/check", b"{}", 42);
+        assert_eq!(
+            super::signature_headers("POST", url, b"{}").unwrap(),
+            vec![
+                ("x-shorebird-timestamp", "42".to_string()),
+                ("x-shorebird-request-signature", other)
+            ]
+        );
+
+        super::set_key_provider(None);
+        super::set_config(None);
+    }
+
+    #[test]
+    fn debug_redacts_key() {
+        let signing = RequestSigning {
+            base_url: "https://example.com".to_string(),
+            key: Some(b"secret".to_vec()),
+            scheme: SignatureScheme::Authorization,
+            clock: Arc::new(FakeClock::new(0)),
+        };
+        let debug = format!("{:?}", signing);
+        assert!(debug.contains("key: Some(\"<redacted>\")"));
+    }
+}
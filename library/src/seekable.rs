@@ -0,0 +1,302 @@
+// This file deals with decompressing patches in parallel.  The patch tool
+// splits large patches into independent zstd frames followed by a seek table
+// in zstd's "seekable format":
+// https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+// The seek table lives in a skippable frame, so such patches are still
+// ordinary zstd streams for updaters which don't know about it.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+
+use comde::de::Decompressor;
+use comde::zstd::ZstdDecompressor;
+
+use crate::thread_priority::{set_current_thread_priority, ThreadPriority};
+
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// Number_Of_Frames (4), Seek_Table_Descriptor (1), Seekable_Magic_Number (4).
+const FOOTER_SIZE: u64 = 9;
+/// Skippable_Magic_Number (4), Frame_Size (4).
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+/// The patch tool uses 4MiB frames, anything much larger than that is more
+/// likely a corrupt table than a real patch.
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+/// More threads than this don't help on phones and cost memory (each thread
+/// holds a whole frame).
+const MAX_INFLATE_THREADS: usize = 4;
+
+/// Sizes of one zstd frame, from the seek table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameEntry {
+    pub compressed_size: u32,
+    pub decompressed_size: u32,
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Reads the seek table from the end of a patch, or None if the patch does
+/// not have one (e.g. it is small, or was made by an older patch tool).
+/// Leaves the reader positioned at the start.
+pub fn read_seek_table<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Vec<FrameEntry>>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    if length < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+        reader.rewind()?;
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+    let frame_count = read_u32(reader)? as u64;
+    let mut descriptor = [0u8; 1];
+    reader.read_exact(&mut descriptor)?;
+    let magic = read_u32(reader)?;
+    reader.rewind()?;
+    if magic != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    // Bit 7 is Checksum_Flag, bits 2-6 are reserved and must be zero.
+    if descriptor[0] & 0x7c != 0 {
+        anyhow::bail!("Unsupported seek table descriptor: {:#x}", descriptor[0]);
+    }
+    let entry_size = if descriptor[0] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = SKIPPABLE_HEADER_SIZE + frame_count * entry_size + FOOTER_SIZE;
+    if table_size > length {
+        anyhow::bail!("Seek table ({} bytes) is larger than patch", table_size);
+    }
+    reader.seek(SeekFrom::Start(length - table_size))?;
+    if read_u32(reader)? != SKIPPABLE_FRAME_MAGIC
+        || read_u32(reader)? as u64 != table_size - SKIPPABLE_HEADER_SIZE
+    {
+        anyhow::bail!("Seek table is not in a skippable frame");
+    }
+    let mut frames = Vec::with_capacity(frame_count as usize);
+    let mut compressed_total = 0u64;
+    for _ in 0..frame_count {
+        let entry = FrameEntry {
+            compressed_size: read_u32(reader)?,
+            decompressed_size: read_u32(reader)?,
+        };
+        if entry_size == 12 {
+            // We check the hash of the whole inflated patch instead.
+            read_u32(reader)?;
+        }
+        if entry.decompressed_size > MAX_FRAME_SIZE {
+            anyhow::bail!("Frame too large: {} bytes", entry.decompressed_size);
+        }
+        compressed_total += entry.compressed_size as u64;
+        frames.push(entry);
+    }
+    reader.rewind()?;
+    if compressed_total != length - table_size {
+        anyhow::bail!(
+            "Seek table covers {} bytes, but patch has {}",
+            compressed_total,
+            length - table_size
+        );
+    }
+    Ok(Some(frames))
+}
+
+fn decompress_frame(compressed: &[u8], decompressed_size: u32) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(decompressed_size as usize);
+    ZstdDecompressor::new().copy(compressed, &mut output)?;
+    if output.len() != decompressed_size as usize {
+        anyhow::bail!(
+            "Frame inflated to {} bytes, expected {}",
+            output.len(),
+            decompressed_size
+        );
+    }
+    Ok(output)
+}
+
+/// Where decompression has to start from to reach a given offset.
+#[derive(Debug, PartialEq)]
+pub struct FrameStart {
+    /// Index into the frames.
+    pub index: usize,
+    /// Offset of the frame in the compressed patch.
+    pub compressed_offset: u64,
+    /// Offset of the frame's first byte in the inflated patch.
+    pub decompressed_offset: u64,
+}
+
+/// The frame containing the inflated byte at offset, or just past the last
+/// frame if offset is beyond the end.
+pub fn frame_containing(frames: &[FrameEntry], offset: u64) -> FrameStart {
+    let mut start = FrameStart {
+        index: 0,
+        compressed_offset: 0,
+        decompressed_offset: 0,
+    };
+    for frame in frames {
+        let decompressed_end = start.decompressed_offset + frame.decompressed_size as u64;
+        if offset < decompressed_end {
+            break;
+        }
+        start.index += 1;
+        start.compressed_offset += frame.compressed_size as u64;
+        start.decompressed_offset = decompressed_end;
+    }
+    start
+}
+
+/// How many threads to decompress with, or 1 if it isn't worth it.
+pub fn inflate_thread_count() -> usize {
+    std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(MAX_INFLATE_THREADS)
+}
+
+/// Decompresses the frames described by `frames` from `reader` into
+/// `writer`, `threads` frames at a time.  Frames are written in order, so
+/// `writer` sees the same bytes as from a sequential decompression.
+pub fn decompress_frames<R, W>(
+    mut reader: R,
+    frames: &[FrameEntry],
+    threads: usize,
+    priority: ThreadPriority,
+    mut writer: W,
+) -> anyhow::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    for batch in frames.chunks(threads.max(1)) {
+        let mut compressed = Vec::with_capacity(batch.len());
+        for entry in batch {
+            let mut bytes = vec![0u8; entry.compressed_size as usize];
+            reader.read_exact(&mut bytes)?;
+            compressed.push(bytes);
+        }
+        let decompressed = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .zip(&compressed)
+                .map(|(entry, bytes)| {
+                    scope.spawn(move || {
+                        set_current_thread_priority(priority);
+                        decompress_frame(bytes, entry.decompressed_size)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Decompression panicked")))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
+        for frame in decompressed {
+            writer.write_all(&frame)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod testing {
+    use comde::com::Compressor;
+    use comde::zstd::ZstdCompressor;
+    use std::io::Cursor;
+
+    /// Compresses each chunk as its own frame and appends a seek table, like
+    /// the patch tool does for large patches.
+    pub fn seekable_compress(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        let mut entries = Vec::new();
+        for chunk in chunks {
+            let count = ZstdCompressor::new()
+                .compress(&mut output, &mut &chunk[..])
+                .unwrap();
+            entries.push((count.write as u32, count.read as u32));
+        }
+        let mut output = output.into_inner();
+        output.extend(super::SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        output.extend((entries.len() as u32 * 8 + 9).to_le_bytes());
+        for (compressed, decompressed) in &entries {
+            output.extend(compressed.to_le_bytes());
+            output.extend(decompressed.to_le_bytes());
+        }
+        output.extend((entries.len() as u32).to_le_bytes());
+        output.push(0);
+        output.extend(super::SEEKABLE_MAGIC.to_le_bytes());
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::seekable_compress;
+    use super::{decompress_frames, read_seek_table, FrameEntry};
+    use crate::thread_priority::ThreadPriority;
+    use comde::de::Decompressor;
+    use comde::zstd::ZstdDecompressor;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_seek_table() {
+        let patch = seekable_compress(&[b"hello ", b"world", b"!"]);
+        let mut reader = Cursor::new(&patch);
+        let frames = read_seek_table(&mut reader).unwrap().unwrap();
+        assert_eq!(reader.position(), 0);
+        let sizes: Vec<_> = frames.iter().map(|frame| frame.decompressed_size).collect();
+        assert_eq!(sizes, vec![6, 5, 1]);
+
+        // Ordinary zstd streams don't have one.
+        let mut plain = Cursor::new(vec![40, 181, 47, 253, 0, 128, 1, 0, 0]);
+        assert!(read_seek_table(&mut plain).unwrap().is_none());
+        assert!(read_seek_table(&mut Cursor::new(vec![])).unwrap().is_none());
+
+        // A table which doesn't match the data is an error.
+        let mut truncated = patch.clone();
+        truncated.remove(0);
+        assert!(read_seek_table(&mut Cursor::new(truncated)).is_err());
+    }
+
+    #[test]
+    fn decompresses_in_parallel() {
+        let patch = seekable_compress(&[b"hello ", b"world", b"!"]);
+        let mut reader = Cursor::new(&patch);
+        let frames = read_seek_table(&mut reader).unwrap().unwrap();
+        for threads in 1..=4 {
+            let mut output = Vec::new();
+            decompress_frames(
+                Cursor::new(&patch),
+                &frames,
+                threads,
+                ThreadPriority::Normal,
+                &mut output,
+            )
+            .unwrap();
+            assert_eq!(output, b"hello world!");
+        }
+
+        // Older updaters just see a zstd stream.
+        let mut output = Vec::new();
+        ZstdDecompressor::new()
+            .copy(&patch[..], &mut output)
+            .unwrap();
+        assert_eq!(output, b"hello world!");
+
+        // Sizes are checked against the table.
+        let wrong_size = [FrameEntry {
+            decompressed_size: 5,
+            ..frames[0]
+        }];
+        let result = decompress_frames(
+            Cursor::new(&patch),
+            &wrong_size,
+            2,
+            ThreadPriority::Normal,
+            Vec::new(),
+        );
+        assert!(result.is_err());
+    }
+}
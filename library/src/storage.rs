@@ -0,0 +1,118 @@
+// This file answers questions about the storage patches are installed on,
+// recorded at install time so that later corruption can be traced back to
+// the device's flash rather than to the updater.
+
+use std::path::Path;
+
+use crate::file_errors::IoResultExt;
+
+/// Checks that files can be created in dir (creating dir if needed) by
+/// writing, reading back and removing a small file.
+pub fn probe_writable(dir: &Path) -> anyhow::Result<()> {
+    const PROBE: &[u8] = b"shorebird write probe";
+    std::fs::create_dir_all(dir).with_file_context("create", dir)?;
+    let probe = dir.join(".write_probe");
+    std::fs::write(&probe, PROBE).with_file_context("write", &probe)?;
+    let read = std::fs::read(&probe).with_file_context("read", &probe)?;
+    anyhow::ensure!(read == PROBE, "{} read back differently", probe.display());
+    std::fs::remove_file(&probe).with_file_context("remove", &probe)?;
+    Ok(())
+}
+
+/// Bytes available to this app on the filesystem containing path, or None
+/// if they can't be determined on this platform.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // The field types vary by platform.
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Name of the type of filesystem (e.g. "ext4" or "f2fs") containing path,
+/// or None if it can't be determined on this platform.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn filesystem_type(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // f_type is signed on some targets, magic numbers are 32 bits.
+    let magic = stat.f_type as u64 & 0xffff_ffff;
+    let name = match magic {
+        0xEF53 => "ext4",
+        0xF2F52010 => "f2fs",
+        0x01021994 => "tmpfs",
+        0x65735546 => "fuse",
+        0x5DCA2DF5 => "sdcardfs",
+        0x9123683E => "btrfs",
+        0x58465342 => "xfs",
+        0x794C7630 => "overlayfs",
+        0x2FC12FC1 => "zfs",
+        _ => return Some(format!("{:#x}", magic)),
+    };
+    Some(name.to_string())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn filesystem_type(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub fn filesystem_type(_path: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn probes_writability() {
+        let tmp_dir = tempdir::TempDir::new("example").unwrap();
+        let dir = tmp_dir.path().join("a").join("b");
+        super::probe_writable(&dir).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // A file is in the way.
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let error = super::probe_writable(&file.join("downloads")).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to create"));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn describes_storage() {
+        let tmp_dir = tempdir::TempDir::new("example").unwrap();
+        assert!(super::free_space(tmp_dir.path()).is_some_and(|free| free > 0));
+        assert!(super::filesystem_type(tmp_dir.path()).is_some_and(|name| !name.is_empty()));
+
+        let missing = tmp_dir.path().join("missing");
+        assert_eq!(super::free_space(&missing), None);
+        assert_eq!(super::filesystem_type(&missing), None);
+    }
+}
@@ -0,0 +1,113 @@
+// This file deals with running the updater's background work at a lower
+// priority, so inflating a large patch doesn't jank the UI on slow devices.
+
+use serde::Deserialize;
+
+#[cfg(test)]
+use std::{println as debug, println as warn}; // Workaround to use println! for logs.
+
+/// How much to lower the priority of the threads doing update work, see
+/// YamlConfig::update_thread_priority.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadPriority {
+    /// Leave the priority alone.
+    #[default]
+    Normal,
+    /// Below UI work (nice 10, or the "utility" QoS class on Apple platforms).
+    Low,
+    /// Only when nothing else wants the CPU (nice 19, or the "background" QoS
+    /// class).
+    Background,
+}
+
+/// Lowers the priority of the calling thread.  Failing to do so is only
+/// logged, the work will still get done.
+pub fn set_current_thread_priority(priority: ThreadPriority) {
+    if priority == ThreadPriority::Normal {
+        return;
+    }
+    match set_priority(priority) {
+        Ok(()) => debug!("Set update thread priority to {:?}", priority),
+        Err(err) => warn!("Failed to set thread priority {:?}: {}", priority, err),
+    }
+}
+
+/// Thread priorities are nice values for the thread's id on Linux (which
+/// Android's Process.setThreadPriority uses too).
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_priority(priority: ThreadPriority) -> std::io::Result<()> {
+    let nice = match priority {
+        ThreadPriority::Normal => 0,
+        ThreadPriority::Low => 10,
+        ThreadPriority::Background => 19,
+    };
+    let result = unsafe {
+        let tid = libc::gettid();
+        libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+fn set_priority(priority: ThreadPriority) -> std::io::Result<()> {
+    // qos_class_t values from <sys/qos.h>, libc doesn't expose them.
+    const QOS_CLASS_DEFAULT: u32 = 0x15;
+    const QOS_CLASS_UTILITY: u32 = 0x11;
+    const QOS_CLASS_BACKGROUND: u32 = 0x09;
+    extern "C" {
+        fn pthread_set_qos_class_self_np(
+            qos_class: u32,
+            relative_priority: libc::c_int,
+        ) -> libc::c_int;
+    }
+    let qos_class = match priority {
+        ThreadPriority::Normal => QOS_CLASS_DEFAULT,
+        ThreadPriority::Low => QOS_CLASS_UTILITY,
+        ThreadPriority::Background => QOS_CLASS_BACKGROUND,
+    };
+    let result = unsafe { pthread_set_qos_class_self_np(qos_class, 0) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "linux",
+    target_os = "ios",
+    target_os = "macos"
+)))]
+fn set_priority(_priority: ThreadPriority) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadPriority;
+
+    #[test]
+    fn parses_from_yaml() {
+        let priority: ThreadPriority = serde_yaml::from_str("background").unwrap();
+        assert_eq!(priority, ThreadPriority::Background);
+        assert!(serde_yaml::from_str::<ThreadPriority>("realtime").is_err());
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn lowers_priority_of_current_thread() {
+        std::thread::spawn(|| {
+            // Raising it again would need privileges, but lowering is fine.
+            super::set_priority(ThreadPriority::Low).unwrap();
+            let nice =
+                unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t) };
+            assert!(nice >= 10, "{}", nice);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+// This file's job is to provide the current time to the rest of the updater.
+// Anything which schedules or expires things should ask the Clock on the
+// UpdateConfig rather than calling SystemTime::now() directly, so tests can
+// control time and embedders can supply a trusted time source.
+
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// Seconds since the unix epoch.
+    fn unix_timestamp(&self) -> u64;
+}
+
+/// The device's wall clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_timestamp(&self) -> u64 {
+        // A clock set before 1970 is broken, treat it as the epoch rather
+        // than failing whatever asked for the time.
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock which only moves when told to, for tests.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Default)]
+pub struct FakeClock {
+    now: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl FakeClock {
+    pub fn new(unix_timestamp: u64) -> Self {
+        Self {
+            now: unix_timestamp.into(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, seconds: u64) {
+        self.now
+            .fetch_add(seconds, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for FakeClock {
+    fn unix_timestamp(&self) -> u64 {
+        self.now.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, FakeClock, SystemClock};
+
+    #[test]
+    fn system_clock_is_after_2023() {
+        // 2023-01-01T00:00:00Z
+        assert!(SystemClock.unix_timestamp() > 1_672_531_200);
+    }
+
+    #[test]
+    fn fake_clock_advances() {
+        let clock = FakeClock::new(100);
+        assert_eq!(clock.unix_timestamp(), 100);
+        clock.advance(50);
+        assert_eq!(clock.unix_timestamp(), 150);
+    }
+}
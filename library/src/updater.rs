@@ -2,37 +2,141 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs;
+use std::io::Read;
 #[cfg(any(target_os = "android", test))]
-use std::io::{Read, Seek};
+use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::Context;
+use serde::{Deserialize, Serialize};
 
-use crate::cache::{PatchInfo, UpdaterState};
+use crate::auto_update::AutoUpdatePolicy;
+use crate::cache::{
+    artifact_name, clear_boot_pending_marker, handle_release_change, take_boot_pending_marker,
+    write_boot_pending_marker, BootArtifactSnapshot, PartialDownload, PatchInfo, ReleaseChange,
+    UpdaterState,
+};
+pub use crate::cache::{BootTiming, InstalledPatch, PatchRejection, RejectedPatch, UpdateAttempt};
 use crate::config::{set_config, with_config, UpdateConfig};
-use crate::logging::init_logging;
+pub use crate::config_builder::{UpdaterConfig, UpdaterConfigBuilder};
+use crate::delegate::notify_delegate;
+pub use crate::delegate::{
+    PatchCheckResponseCallback, StoreUpdatePendingCallback, UpdaterDelegate,
+};
+#[cfg(feature = "encryption")]
+use crate::encryption::DecryptingReader;
+use crate::encryption::PatchKey;
+pub use crate::encryption::{PatchKeyProvider, PATCH_KEY_LEN};
+use crate::file_errors::IoResultExt;
+use crate::file_provider::read_shorebird_yaml;
+pub use crate::file_provider::ExternalFileProvider;
+pub use crate::health::{DownloadProgress, HealthCheck, HealthReport};
+pub use crate::install_transaction::{InstallStep, InstallTransaction};
+use crate::logging::{init_logging, set_log_level_override};
+pub use crate::names::{Channel, ReleaseVersion};
 use crate::network::{
-    download_to_path, send_patch_check_request, NetworkHooks, PatchCheckResponse,
+    download_to_path, is_offline, send_patch_check_request, ClientDirectives, NetworkHooks,
+    PatchCheckResponse,
 };
+pub use crate::request_signing::KeyProvider;
+use crate::thread_priority::set_current_thread_priority;
+use crate::time::SystemClock;
 use crate::updater_lock::{with_updater_thread_lock, UpdaterLockState};
+use crate::verify::{decode_hash, verify_signature, HashVerifier, SignatureVerifier};
 use crate::yaml::YamlConfig;
 
 // https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests
 #[cfg(test)]
 use std::{println as info, println as warn, println as error, println as debug}; // Workaround to use println! for logs.
 
+#[cfg(any(test, feature = "testing"))]
+pub use crate::config::testing_advance_clock;
 #[cfg(test)]
 // Expose testing_reset_config for integration tests.
 pub use crate::config::testing_reset_config;
+pub use crate::network::PatchPriority;
 #[cfg(test)]
 pub use crate::network::{
     testing_set_network_hooks, DownloadFileFn, Patch, PatchCheckRequest, PatchCheckRequestFn,
 };
 
+/// Why the updater chose not to install an available (or possibly
+/// available) update.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum DeferReason {
+    /// The server's directives turned off automatic updates.
+    AutoUpdateDisabled,
+    /// The server's directives say it is too soon to check again.
+    CheckThrottled,
+    /// The server's kill switch disabled all patches for this release.
+    PatchesDisabled,
+    /// The patch targets other build numbers.
+    IncompatibleBuildNumber,
+    /// The patch was built against different native code.
+    IncompatibleNativeFingerprint,
+    /// The app put the updater in offline mode, see set_offline.
+    Offline,
+    /// The server asked us to back off (e.g. 429 with Retry-After) and that
+    /// time has not yet passed.
+    ServerBackoff,
+    /// The app turned off automatic updates, see set_auto_update.
+    AutoUpdateDisabledByApp,
+    /// shorebird.yaml turned off automatic updates.
+    AutoUpdateDisabledByConfig,
+    /// The device is on a metered network, see set_metered_network.
+    MeteredNetwork,
+    /// The app is in the background and shorebird.yaml doesn't allow
+    /// background_downloads, see on_app_background.
+    AppInBackground,
+    /// The patch needs a newer version of the updater library, see
+    /// Patch::min_updater_version.
+    UpdaterUpgradeRequired,
+    /// A separate process updates for the app, see
+    /// YamlConfig::update_in_daemon.
+    UpdateInDaemon,
+    /// The app's patch check response callback vetoed the offered patch, see
+    /// set_patch_check_response_callback.
+    VetoedByApp,
+    /// An app store update is waiting to install and would replace this
+    /// release (and its patches), see set_store_update_pending_callback.
+    StoreUpdatePending,
+}
+
+impl Display for DeferReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeferReason::AutoUpdateDisabled => write!(f, "auto update disabled"),
+            DeferReason::CheckThrottled => write!(f, "check throttled"),
+            DeferReason::PatchesDisabled => write!(f, "patches disabled"),
+            DeferReason::IncompatibleBuildNumber => write!(f, "incompatible build number"),
+            DeferReason::IncompatibleNativeFingerprint => {
+                write!(f, "incompatible native fingerprint")
+            }
+            DeferReason::Offline => write!(f, "offline"),
+            DeferReason::ServerBackoff => write!(f, "server backoff"),
+            DeferReason::AutoUpdateDisabledByApp => write!(f, "auto update disabled by app"),
+            DeferReason::AutoUpdateDisabledByConfig => {
+                write!(f, "auto update disabled by shorebird.yaml")
+            }
+            DeferReason::MeteredNetwork => write!(f, "metered network"),
+            DeferReason::AppInBackground => write!(f, "app in background"),
+            DeferReason::UpdaterUpgradeRequired => write!(f, "updater upgrade required"),
+            DeferReason::UpdateInDaemon => write!(f, "updates run in daemon"),
+            DeferReason::VetoedByApp => write!(f, "vetoed by app"),
+            DeferReason::StoreUpdatePending => write!(f, "store update pending"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 pub enum UpdateStatus {
     NoUpdate,
     UpdateAvailable,
     UpdateInstalled,
+    UpdateDownloaded,
+    /// The updater declined to act, see DeferReason.
+    UpdateDeferred(DeferReason),
     UpdateHadError,
 }
 
@@ -42,6 +146,8 @@ impl Display for UpdateStatus {
             UpdateStatus::NoUpdate => write!(f, "No update"),
             UpdateStatus::UpdateAvailable => write!(f, "Update available"),
             UpdateStatus::UpdateInstalled => write!(f, "Update installed"),
+            UpdateStatus::UpdateDownloaded => write!(f, "Update downloaded"),
+            UpdateStatus::UpdateDeferred(reason) => write!(f, "Update deferred: {}", reason),
             UpdateStatus::UpdateHadError => write!(f, "Update had error"),
         }
     }
@@ -55,6 +161,34 @@ pub enum UpdateError {
     FailedToSaveState,
     ConfigNotInitialized,
     UpdateAlreadyInProgress,
+    /// The release's own copy of the code patches apply to (e.g. libapp.so)
+    /// is unusable, usually a build or configuration problem.
+    BadBaseArtifact(String),
+    /// Downloaded or inflated patch contents did not match the expected hash.
+    HashMismatch,
+    /// A server response was unsigned or its signature did not verify.
+    InvalidSignature,
+    /// A network request was skipped because the updater is offline.
+    Offline,
+    /// The server asked us (e.g. with 429 and Retry-After) not to check
+    /// again for this many seconds.
+    ServerBusy(u64),
+    /// The updater was built without the "network" feature.
+    NetworkUnsupported,
+    /// The server refused a download (HTTP 403), e.g. because its signed URL
+    /// expired.
+    DownloadUrlExpired,
+    /// The patch's hash is not in the patch_allowlist bundled with the app.
+    PatchNotAllowed,
+    /// A download stopped because the app went into the background, it
+    /// resumes with the next update.
+    DownloadPaused,
+    /// The patch is encrypted (see Patch::encryption_key_id) and the app
+    /// provided no key with this id.
+    PatchKeyUnavailable(String),
+    /// The patch did not apply to this device's base, usually because it was
+    /// built against a different build of the release.
+    PatchDidNotApply(String),
 }
 
 impl std::error::Error for UpdateError {}
@@ -72,17 +206,121 @@ impl Display for UpdateError {
             UpdateError::UpdateAlreadyInProgress => {
                 write!(f, "Update already in progress")
             }
+            UpdateError::BadBaseArtifact(msg) => write!(f, "Bad base artifact: {}", msg),
+            UpdateError::HashMismatch => write!(f, "Hash mismatch"),
+            UpdateError::InvalidSignature => write!(f, "Invalid signature"),
+            UpdateError::Offline => write!(f, "Offline"),
+            UpdateError::ServerBusy(seconds) => {
+                write!(f, "Server busy, retry after {}s", seconds)
+            }
+            UpdateError::NetworkUnsupported => {
+                write!(f, "Network support was not compiled into the updater")
+            }
+            UpdateError::DownloadUrlExpired => write!(f, "Download URL expired"),
+            UpdateError::PatchNotAllowed => write!(f, "Patch is not in patch_allowlist"),
+            UpdateError::DownloadPaused => write!(f, "Download paused in background"),
+            UpdateError::PatchKeyUnavailable(key_id) => {
+                write!(f, "No key for encrypted patch (key id {})", key_id)
+            }
+            UpdateError::PatchDidNotApply(msg) => {
+                write!(f, "Failed to apply patch to base: {}", msg)
+            }
         }
     }
 }
 
+/// What sort of error an update failed with, e.g. for
+/// UpdateAttempt::error.  The C API reports these as ShorebirdErrorCode.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum UpdateErrorKind {
+    /// An error not covered by a more specific kind.
+    Unknown,
+    InvalidArgument,
+    InvalidState,
+    BadServerResponse,
+    FailedToSaveState,
+    ConfigNotInitialized,
+    UpdateAlreadyInProgress,
+    BadBaseArtifact,
+    HashMismatch,
+    InvalidSignature,
+    /// The server could not be reached or the connection failed.
+    Network,
+    /// Reading or writing a file failed.
+    Io,
+    Offline,
+    ServerBusy,
+    NetworkUnsupported,
+    DownloadUrlExpired,
+    PatchNotAllowed,
+    DownloadPaused,
+    PatchKeyUnavailable,
+    PatchDidNotApply,
+}
+
+impl From<&anyhow::Error> for UpdateErrorKind {
+    fn from(error: &anyhow::Error) -> Self {
+        // The most specific cause is the first one we recognize.
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<UpdateError>() {
+                return match error {
+                    UpdateError::InvalidArgument(_, _) => UpdateErrorKind::InvalidArgument,
+                    UpdateError::InvalidState(_) => UpdateErrorKind::InvalidState,
+                    UpdateError::BadServerResponse => UpdateErrorKind::BadServerResponse,
+                    UpdateError::FailedToSaveState => UpdateErrorKind::FailedToSaveState,
+                    UpdateError::ConfigNotInitialized => UpdateErrorKind::ConfigNotInitialized,
+                    UpdateError::UpdateAlreadyInProgress => {
+                        UpdateErrorKind::UpdateAlreadyInProgress
+                    }
+                    UpdateError::BadBaseArtifact(_) => UpdateErrorKind::BadBaseArtifact,
+                    UpdateError::HashMismatch => UpdateErrorKind::HashMismatch,
+                    UpdateError::InvalidSignature => UpdateErrorKind::InvalidSignature,
+                    UpdateError::Offline => UpdateErrorKind::Offline,
+                    UpdateError::ServerBusy(_) => UpdateErrorKind::ServerBusy,
+                    UpdateError::NetworkUnsupported => UpdateErrorKind::NetworkUnsupported,
+                    UpdateError::DownloadUrlExpired => UpdateErrorKind::DownloadUrlExpired,
+                    UpdateError::PatchNotAllowed => UpdateErrorKind::PatchNotAllowed,
+                    UpdateError::DownloadPaused => UpdateErrorKind::DownloadPaused,
+                    UpdateError::PatchKeyUnavailable(_) => UpdateErrorKind::PatchKeyUnavailable,
+                    UpdateError::PatchDidNotApply(_) => UpdateErrorKind::PatchDidNotApply,
+                };
+            }
+            #[cfg(feature = "network")]
+            if cause.is::<reqwest::Error>() {
+                return UpdateErrorKind::Network;
+            }
+            if cause.is::<std::io::Error>() {
+                return UpdateErrorKind::Io;
+            }
+        }
+        UpdateErrorKind::Unknown
+    }
+}
+
 // AppConfig is the rust API.  ResolvedConfig is the internal storage.
 // However rusty api would probably used &str instead of String,
 // but making &str from CStr* is a bit of a pain.
+#[derive(Default)]
 pub struct AppConfig {
     pub cache_dir: String,
     pub release_version: String,
     pub original_libapp_paths: Vec<String>,
+    /// Build number of the running app (e.g. versionCode on Android), if known.
+    pub build_number: Option<u64>,
+    /// app_id to use instead of the one in shorebird.yaml.  Only honored if
+    /// shorebird.yaml allows it.
+    pub app_id_override: Option<String>,
+    /// Where to download patches to instead of under cache_dir, e.g. app
+    /// storage on devices which purge cache_dir aggressively.  cache_dir is
+    /// still used if this is not writable.
+    pub download_dir: Option<String>,
+    /// The device's locale (e.g. "en_US" or "zh-Hant-TW"), if known.  Only
+    /// sent to the server, reduced to language and country, if
+    /// shorebird.yaml sets report_locale.
+    pub locale: Option<String>,
+    /// Whether the app is a debug build.  Only debug builds honor
+    /// shorebird.yaml's pinned_patch_number.
+    pub debug_build: bool,
 }
 
 // On Android we don't use a direct path to libapp.so, but rather a data dir
@@ -99,6 +337,24 @@ fn libapp_path_from_settings(original_libapp_paths: &Vec<String>) -> Result<Path
     first.map(|path| PathBuf::from(path))
 }
 
+/// Like init(), but configured with typed setters rather than an AppConfig
+/// and a yaml string, for Rust hosts.
+pub fn init_with(builder: UpdaterConfigBuilder) -> Result<(), UpdateError> {
+    let config = builder.build()?;
+    init(config.app_config, &config.yaml)
+}
+
+/// Like init(), but reads shorebird.yaml through `file_provider`, for hosts
+/// which can't easily read it themselves this early in boot.
+pub fn init_with_file_provider(
+    app_config: AppConfig,
+    file_provider: &dyn ExternalFileProvider,
+) -> Result<(), UpdateError> {
+    let yaml = read_shorebird_yaml(file_provider)
+        .map_err(|err| UpdateError::InvalidArgument("yaml".to_string(), format!("{:#}", err)))?;
+    init(app_config, &yaml)
+}
+
 /// Initialize the updater library.
 /// Takes a AppConfig struct and a yaml string.
 /// The yaml string is the contents of the shorebird.yaml file.
@@ -114,16 +370,314 @@ pub fn init(app_config: AppConfig, yaml: &str) -> Result<(), UpdateError> {
 
     let libapp_path = libapp_path_from_settings(&app_config.original_libapp_paths)?;
     info!("libapp_path: {:?}", libapp_path);
-    set_config(app_config, libapp_path, config, NetworkHooks::default())
-        .map_err(|err| UpdateError::InvalidState(err.to_string()))
+    set_config(
+        app_config,
+        libapp_path,
+        config,
+        NetworkHooks::default(),
+        Arc::new(SystemClock),
+    )
+    .map_err(|err| UpdateError::InvalidState(err.to_string()))?;
+    // Before anything loads (and so clears) the state of a newer release.
+    match with_config(|config| {
+        let change = handle_release_change(&config.cache_dir, &config.release_version)?;
+        Ok((change, config.release_version.clone()))
+    }) {
+        Ok((ReleaseChange::Downgraded { from }, to)) => {
+            notify_delegate(|delegate| delegate.on_release_downgraded(&from, &to));
+        }
+        Ok(_) => {}
+        Err(err) => error!("Failed to handle release change: {:?}", err),
+    }
+    // Failing to recover should not prevent the updater from being used.
+    if let Err(err) = recover_unfinished_boot() {
+        error!("Failed to recover from unfinished boot: {:?}", err);
+    }
+    if let Err(err) = remove_orphaned_slot_dirs() {
+        error!("Failed to remove orphaned slots: {:?}", err);
+    }
+    // Keep following the directives from the last check across launches.
+    if let Err(err) = with_config(|config| {
+        let state = load_state(config);
+        apply_client_directives(state.client_directives());
+        Ok(())
+    }) {
+        error!("Failed to apply client directives: {:?}", err);
+    }
+    Ok(())
+}
+
+/// Applies the directives which take effect immediately (rather than being
+/// consulted later, like disable_auto_update).
+fn apply_client_directives(directives: &ClientDirectives) {
+    let log_level = match &directives.log_level {
+        Some(log_level) => match log_level.parse::<log::LevelFilter>() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                warn!("Ignoring unknown log_level directive: {}", log_level);
+                None
+            }
+        },
+        None => None,
+    };
+    set_log_level_override(log_level);
+}
+
+/// Sends a patch check request and persists the directives in the response,
+/// replacing those from the previous check.
+/// Fails with UpdateError::ServerBusy rather than sending anything while the
+/// server has asked us to back off, and records any new backoff request.
+fn send_patch_check_and_record(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+) -> anyhow::Result<PatchCheckResponse> {
+    if let Some(remaining) = server_backoff_remaining(config, state) {
+        return Err(UpdateError::ServerBusy(remaining).into());
+    }
+    let pinned = pinned_patch_number(config, state);
+    let response = match send_patch_check_request(config, state, pinned) {
+        Ok(response) => response,
+        Err(e) => {
+            let retry_after = e.chain().find_map(|cause| match cause.downcast_ref() {
+                Some(UpdateError::ServerBusy(seconds)) => Some(*seconds),
+                _ => None,
+            });
+            if let Some(seconds) = retry_after {
+                warn!(
+                    "Server asked us to wait {}s before checking again.",
+                    seconds
+                );
+                state.set_check_not_before(config.clock.unix_timestamp() + seconds);
+                state.save()?;
+            }
+            return Err(e);
+        }
+    };
+    if response.disable_patches {
+        warn!("Server disabled patches for this release, uninstalling all patches.");
+        state.uninstall_all_patches()?;
+        info!("All patches uninstalled, next boot will use the release as shipped.");
+    }
+    state.record_patch_check(
+        response.client_directives.clone(),
+        config.clock.unix_timestamp(),
+    );
+    // Whichever patch this device ends up with, a critical one on offer is
+    // reason enough to skip the usual auto update gating until installed.
+    let offered = response.patch.iter().chain(
+        response
+            .experiment
+            .iter()
+            .map(|experiment| &experiment.patch),
+    );
+    let critical = offered
+        .filter(|patch| patch.priority == PatchPriority::Critical && !response.disable_patches)
+        .filter(|patch| !state.is_known_bad_patch(patch.number))
+        .map(|patch| patch.number)
+        .max();
+    if let Some(number) = critical {
+        info!("Server offered critical patch {}.", number);
+    }
+    state.set_critical_patch_number(critical);
+    state.save()?;
+    apply_client_directives(&response.client_directives);
+    Ok(response)
+}
+
+/// The patch this device should request and boot instead of the latest, if
+/// QA pinned one, see pin_patch().  A pin at runtime wins over shorebird.yaml.
+fn pinned_patch_number(config: &UpdateConfig, state: &UpdaterState) -> Option<usize> {
+    state.pinned_patch_number().or(config.pinned_patch_number)
+}
+
+/// Seconds until the server said we may check for patches again, or None if
+/// it hasn't asked us to back off (or that time has passed).
+fn server_backoff_remaining(config: &UpdateConfig, state: &UpdaterState) -> Option<u64> {
+    let not_before = state.check_not_before()?;
+    let now = config.clock.unix_timestamp();
+    (now < not_before).then(|| not_before - now)
+}
+
+/// How long a disable_auto_update directive lasts if the server doesn't say,
+/// see ClientDirectives::disable_auto_update_seconds.
+/// cbindgen:ignore
+const DEFAULT_DISABLE_AUTO_UPDATE_SECONDS: u64 = 24 * 60 * 60;
+
+/// Why an automatic update check should not run now, or None if it should.
+/// Combines offline mode, the server's directives, shorebird.yaml and the
+/// app's runtime settings, see AutoUpdatePolicy for which takes precedence.
+pub fn auto_update_deferral() -> anyhow::Result<Option<DeferReason>> {
+    with_config(|config| {
+        let state = load_state(config);
+        let directives = state.client_directives();
+        let now = config.clock.unix_timestamp();
+        // A clock which went backwards should not block checks forever.
+        let checked_within = |seconds: u64| {
+            state
+                .last_patch_check_timestamp()
+                .is_some_and(|last_check| now >= last_check && now - last_check < seconds)
+        };
+        let throttled = directives
+            .check_interval_seconds
+            .is_some_and(checked_within);
+        let server_disabled = directives.disable_auto_update
+            && checked_within(
+                directives
+                    .disable_auto_update_seconds
+                    .unwrap_or(DEFAULT_DISABLE_AUTO_UPDATE_SECONDS),
+            );
+        let policy = AutoUpdatePolicy {
+            update_in_daemon: config.update_in_daemon,
+            offline: is_offline(),
+            server_backoff: server_backoff_remaining(config, &state).is_some(),
+            server_disabled,
+            critical_patch_pending: state.critical_patch_number().is_some(),
+            app_override: crate::auto_update::auto_update_override(),
+            config_enabled: config.auto_update,
+            metered_network: crate::auto_update::is_metered_network(),
+            throttled,
+        };
+        let reason = policy.defer_reason();
+        if let Some(reason) = &reason {
+            info!("Skipping automatic update check: {}.", reason);
+        }
+        Ok(reason)
+    })
+}
+
+/// Reconciles the state with what is actually on disk.  Loading the state
+/// drops slots whose artifacts are missing, this removes directories and
+/// objects which no slot refers to.
+fn remove_orphaned_slot_dirs() -> anyhow::Result<()> {
+    with_config(|config| {
+        let state = load_state(config);
+        let removed = state.remove_orphaned_slot_dirs()?;
+        if removed > 0 {
+            info!("Removed {} orphaned slot directories.", removed);
+        }
+        let removed = state.remove_unreferenced_objects()?;
+        if removed > 0 {
+            info!("Removed {} unreferenced objects.", removed);
+        }
+        Ok(())
+    })
+}
+
+/// A boot started by begin_boot() which never reported success or failure
+/// must have crashed before it could, so treat it as a failed launch.
+fn recover_unfinished_boot() -> anyhow::Result<()> {
+    let invalidated = with_config(|config| {
+        let patch_number = match take_boot_pending_marker(&config.cache_dir)? {
+            Some(patch_number) => patch_number,
+            None => return Ok(None),
+        };
+        warn!(
+            "Boot of patch {} never finished, treating as a failed launch.",
+            patch_number
+        );
+        let (_state_lock, mut state) = lock_state(config)?;
+        state.mark_patch_as_bad(patch_number);
+        state.activate_latest_bootable_patch()?;
+        Ok(Some(patch_number))
+    })?;
+    if let Some(patch_number) = invalidated {
+        notify_delegate(|delegate| delegate.on_patch_invalidated(patch_number));
+    }
+    Ok(())
 }
 
 fn check_for_update_internal() -> anyhow::Result<PatchCheckResponse> {
     with_config(|config| {
         // Load UpdaterState from disk
         // If there is no state, make an empty state.
-        let state = UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
-        send_patch_check_request(&config, &state)
+        let mut state = load_state(config);
+        send_patch_check_and_record(config, &mut state)
+    })
+}
+
+/// Returns the reason the patch must not be installed on the running build,
+/// or None if the patch's build number constraints (if any) are satisfied.
+/// Servers may share a release version string across builds, so this guards
+/// against patches mis-targeted at a different build.
+fn build_number_mismatch(config: &UpdateConfig, patch: &crate::network::Patch) -> Option<String> {
+    if patch.min_build_number.is_none() && patch.max_build_number.is_none() {
+        return None;
+    }
+    let build_number = match config.build_number {
+        Some(build_number) => build_number,
+        None => return Some("build number constrained but app build number unknown".to_owned()),
+    };
+    if let Some(min) = patch.min_build_number {
+        if build_number < min {
+            return Some(format!("build number {} < min {}", build_number, min));
+        }
+    }
+    if let Some(max) = patch.max_build_number {
+        if build_number > max {
+            return Some(format!("build number {} > max {}", build_number, max));
+        }
+    }
+    None
+}
+
+/// Returns the reason the patch must not be installed on the running app, or
+/// None if the patch does not require a native fingerprint or requires the one
+/// compiled into this app.  Installing a patch built against different native
+/// code (e.g. changed plugins) would likely crash.
+fn native_fingerprint_mismatch(
+    config: &UpdateConfig,
+    patch: &crate::network::Patch,
+) -> Option<String> {
+    let required = patch.native_fingerprint.as_ref()?;
+    match &config.native_fingerprint {
+        Some(local) if local == required => None,
+        Some(local) => Some(format!(
+            "native fingerprint {} does not match required {}",
+            local, required
+        )),
+        None => Some(format!(
+            "patch requires native fingerprint {} but app has none",
+            required
+        )),
+    }
+}
+
+/// The version of this library, see Patch::min_updater_version.
+/// cbindgen:ignore
+const UPDATER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The numeric parts of a version like "1.2.3", ignoring any pre-release or
+/// build suffix (e.g. "-beta.1").  None if it isn't a version.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let release = version.trim().split(['-', '+']).next()?;
+    release.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Returns the reason the patch must not be installed by this version of
+/// the updater (`updater_version`), or None if it has no minimum or we meet
+/// it.  Patches with a minimum we can't parse are refused, the server may be
+/// using a scheme newer than us.
+fn updater_version_mismatch(
+    patch: &crate::network::Patch,
+    updater_version: &str,
+) -> Option<String> {
+    let required = patch.min_updater_version.as_ref()?;
+    let too_old = match (parse_version(updater_version), parse_version(required)) {
+        (Some(ours), Some(min)) => {
+            let len = ours.len().max(min.len());
+            let part = |version: &Vec<u64>, i| version.get(i).copied().unwrap_or(0);
+            (0..len)
+                .map(|i| part(&ours, i).cmp(&part(&min, i)))
+                .find(|ordering| ordering.is_ne())
+                .is_some_and(|ordering| ordering.is_lt())
+        }
+        _ => true,
+    };
+    too_old.then(|| {
+        format!(
+            "updater version {} is older than required {}",
+            updater_version, required
+        )
     })
 }
 
@@ -133,19 +687,20 @@ pub fn check_for_update() -> anyhow::Result<bool> {
 }
 
 fn check_hash(path: &Path, expected_string: &str) -> anyhow::Result<bool> {
-    let expected = hex::decode(expected_string).context("Invalid hash string from server.")?;
-
-    use sha2::{Digest, Sha256}; // Digest is needed for Sha256::new();
-
-    // Based on guidance from:
-    // https://github.com/RustCrypto/hashes#hashing-readable-objects
+    let expected = decode_hash(expected_string).context("Invalid hash string from server.")?;
 
+    // The verifier itself does no IO, so feed it the file in chunks.
     let mut file = fs::File::open(&path)?;
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut file, &mut hasher)?;
-    // Check that the length from copy is the same as the file size?
-    let hash = hasher.finalize();
-    let hash_matches = hash.as_slice() == expected;
+    let mut verifier = HashVerifier::new(expected);
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = std::io::Read::read(&mut file, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        verifier.update(&buffer[..read]);
+    }
+    let (hash, hash_matches) = verifier.finalize();
     if !hash_matches {
         warn!(
             "Hash mismatch: {:?}, expected: {}, got: {:?}",
@@ -166,14 +721,26 @@ fn prepare_for_install(
     config: &UpdateConfig,
     download_path: &Path,
     output_path: &Path,
+    key: Option<&PatchKey>,
 ) -> anyhow::Result<()> {
     // We abuse libapp_path to actually be the path to the data dir for now.
     // This is an abuse because the variable name is libapp_path, but
     // we're making it point to a the app_data directory instead.
     let app_dir = &config.libapp_path;
     debug!("app_dir: {:?}", app_dir);
-    let base_r = crate::android::open_base_lib(&app_dir, "libapp.so")?;
-    inflate(&download_path, base_r, &output_path)
+    let base_r = crate::android::open_base_lib(
+        &app_dir,
+        "libapp.so",
+        config.expected_apk_cert_sha256.as_ref(),
+    )?;
+    inflate(
+        &download_path,
+        base_r,
+        &output_path,
+        config.update_thread_priority,
+        crate::patch_apply::CHECKPOINT_INTERVAL,
+        key,
+    )
 }
 
 #[cfg(not(any(target_os = "android", test)))]
@@ -181,19 +748,622 @@ fn prepare_for_install(
     _config: &UpdateConfig,
     download_path: &Path,
     output_path: &Path,
+    key: Option<&PatchKey>,
 ) -> anyhow::Result<()> {
     // On iOS we don't yet support compressed patches, just copy the file.
-    fs::copy(download_path, output_path)?;
+    let mut input = open_download(download_path, key)?;
+    let mut output = fs::File::create(output_path).with_file_context("create", output_path)?;
+    std::io::copy(&mut input, &mut output).context("Failed to copy patch.")?;
     Ok(())
 }
 
+/// Opens a downloaded file, decrypting it as it is read if `key` is set (see
+/// Patch::encryption_key_id).
+fn open_download(path: &Path, key: Option<&PatchKey>) -> anyhow::Result<Box<dyn Read + Send>> {
+    let file = std::io::BufReader::new(fs::File::open(path).with_file_context("open", path)?);
+    decrypting(file, key)
+}
+
+/// Decrypts `file` as it is read if `key` is set, see open_download().
+fn decrypting(
+    file: impl Read + Send + 'static,
+    key: Option<&PatchKey>,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    Ok(match key {
+        #[cfg(feature = "encryption")]
+        Some(key) => Box::new(DecryptingReader::new(file, key)),
+        // key_for() fails first, but just in case.
+        #[cfg(not(feature = "encryption"))]
+        Some(_) => anyhow::bail!("Encrypted patches need the \"encryption\" feature."),
+        None => Box::new(file),
+    })
+}
+
+/// Loads the state for config's release, see UpdaterState::load_or_new_on_error.
+fn load_state(config: &UpdateConfig) -> UpdaterState {
+    UpdaterState::load_or_new_on_error(
+        &config.cache_dir,
+        &config.release_version,
+        &config.clock,
+        config.durable_writes,
+    )
+}
+
+#[cfg(feature = "daemon")]
+type StateLock = crate::daemon::StateLock;
+#[cfg(not(feature = "daemon"))]
+type StateLock = ();
+
+/// Loads the state to modify and save it.  In daemon mode the state is
+/// locked against the other process until the returned lock is dropped, so
+/// the save can't fail because that process saved in between.
+fn lock_state(config: &UpdateConfig) -> anyhow::Result<(StateLock, UpdaterState)> {
+    Ok((
+        #[cfg(feature = "daemon")]
+        crate::daemon::StateLock::acquire(&config.cache_dir)?,
+        #[cfg(not(feature = "daemon"))]
+        (),
+        load_state(config),
+    ))
+}
+
 fn copy_update_config() -> anyhow::Result<UpdateConfig> {
     with_config(|config: &UpdateConfig| Ok(config.clone()))
 }
 
+/// Where a downloaded and verified patch waits to be installed.
+fn staged_patch_path(config: &UpdateConfig, patch_number: usize) -> PathBuf {
+    let name = artifact_name(&config.release_version, patch_number);
+    config.download_dir.join(format!("{}.full", name))
+}
+
+/// Where the patch (or with `suffix`, e.g. ".whole", another file for it)
+/// is downloaded to.
+fn download_file_path(config: &UpdateConfig, patch_number: usize, suffix: &str) -> PathBuf {
+    let name = artifact_name(&config.release_version, patch_number);
+    config.download_dir.join(format!("{}{}", name, suffix))
+}
+
+/// Older versions named download directory files by the patch number alone
+/// (e.g. "3.full").  Renames `legacy_name` to `path` if only the former
+/// exists, so a staged patch or partial download survives the upgrade.
+fn migrate_legacy_download(
+    config: &UpdateConfig,
+    legacy_name: &str,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let legacy_path = config.download_dir.join(legacy_name);
+    if legacy_path.exists() && !path.exists() {
+        info!("Moving {:?} to {:?}", legacy_path, path);
+        std::fs::rename(&legacy_path, path).with_file_context("move download to", path)?;
+    }
+    Ok(())
+}
+
+/// Checks patch.signature against patch_public_key (if any).  Once a key is
+/// configured unsigned patches are rejected, otherwise a compromised server
+/// could install anything by leaving the signature out.  Version 1 signatures
+/// cover the hash string, version 2 the inflated artifact at path.
+fn check_patch_signature(
+    config: &UpdateConfig,
+    patch: &crate::network::Patch,
+    path: &Path,
+) -> anyhow::Result<()> {
+    use base64::Engine;
+
+    let (public_key, signature) = match (&config.patch_public_key, &patch.signature) {
+        (Some(public_key), Some(signature)) => (public_key, signature),
+        (Some(_), None) => {
+            return Err(UpdateError::InvalidSignature)
+                .with_context(|| format!("Patch {} is not signed.", patch.number));
+        }
+        (None, _) => return Ok(()),
+    };
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .unwrap_or_default();
+    let valid = match patch.signature_version.unwrap_or(1) {
+        1 => verify_signature(public_key, patch.hash.as_bytes(), &signature),
+        2 => match SignatureVerifier::new(public_key, &signature) {
+            Some(mut verifier) => {
+                let mut file = fs::File::open(path).with_file_context("open", path)?;
+                let mut buffer = [0u8; 8192];
+                loop {
+                    match std::io::Read::read(&mut file, &mut buffer)
+                        .with_file_context("read", path)?
+                    {
+                        0 => break,
+                        count => verifier.update(&buffer[..count]),
+                    }
+                }
+                verifier.finalize()
+            }
+            None => false,
+        },
+        version => {
+            return Err(UpdateError::InvalidSignature)
+                .with_context(|| format!("Unknown patch signature_version: {}", version));
+        }
+    };
+    if !valid {
+        return Err(UpdateError::InvalidSignature).context("Patch signature is invalid.");
+    }
+    info!("Patch {} signature verified.", patch.number);
+    Ok(())
+}
+
+/// Whether the device with `seed` falls within the `percentage` of devices
+/// which should get experimental patch `patch_number`.  Hashing in the patch
+/// number means each experiment picks an independent set of devices.
+fn in_experiment(seed: u64, patch_number: usize, percentage: u8) -> bool {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update((patch_number as u64).to_le_bytes());
+    let hash = hasher.finalize();
+    let bucket = u64::from_le_bytes(hash[..8].try_into().unwrap()) % 100;
+    bucket < u64::from(percentage)
+}
+
+/// How many times a download whose URL expired is restarted with a fresh URL
+/// from another patch check.
+/// cbindgen:ignore
+const MAX_DOWNLOAD_URL_REFRESHES: usize = 2;
+
+/// The URL to download patch from, its full_download_url if `full`.
+fn patch_download_url(patch: &crate::network::Patch, full: bool) -> Option<String> {
+    if full {
+        patch.full_download_url.clone()
+    } else {
+        Some(patch.download_url.clone())
+    }
+}
+
+/// Downloads patch (or its full artifact, if `full`) to download_path.  If
+/// the download URL has expired (e.g. a signed URL and a slow download),
+/// re-checks for patches to get a fresh URL for the same patch and resumes
+/// from where the download stopped.
+fn download_patch(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+    patch: &crate::network::Patch,
+    full: bool,
+    download_path: &Path,
+    download_hash: Option<&crate::verify::Hash>,
+) -> anyhow::Result<()> {
+    let mut url = patch_download_url(patch, full).ok_or(UpdateError::BadServerResponse)?;
+    let mut refreshes = 0;
+    loop {
+        let result = download_to_path(
+            &config.network_hooks,
+            &url,
+            download_path,
+            download_hash,
+            state,
+        );
+        let expired = result.as_ref().err().is_some_and(|e| {
+            e.chain()
+                .any(|cause| cause.downcast_ref() == Some(&UpdateError::DownloadUrlExpired))
+        });
+        if !expired || refreshes == MAX_DOWNLOAD_URL_REFRESHES {
+            return result;
+        }
+        refreshes += 1;
+        info!("Download URL expired, checking for a fresh one.");
+        let response = send_patch_check_and_record(config, state)?;
+        let fresh_url = response
+            .patch
+            .into_iter()
+            .chain(response.experiment.map(|experiment| experiment.patch))
+            .find(|offered| offered.number == patch.number && offered.hash == patch.hash)
+            .and_then(|offered| patch_download_url(&offered, full));
+        url = match fresh_url {
+            Some(fresh_url) => fresh_url,
+            None => {
+                return result.context(format!(
+                    "Patch {} is no longer offered, can't refresh its download URL.",
+                    patch.number
+                ))
+            }
+        };
+        // Keep what we have downloaded, it's the same file at a new URL.
+        if let Some(partial) = state.partial_download().cloned() {
+            state.set_partial_download(Some(PartialDownload {
+                url: url.clone(),
+                ..partial
+            }));
+        }
+    }
+}
+
+/// Checks for an update and, if there is one, downloads and verifies it.
+/// Returns the verified patch, ready to install, or the status to report if
+/// there is nothing (usable) to install.
+fn download_and_verify(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+) -> anyhow::Result<Result<PatchInfo, UpdateStatus>> {
+    if is_offline() {
+        return Ok(Err(UpdateStatus::UpdateDeferred(DeferReason::Offline)));
+    }
+    if server_backoff_remaining(config, state).is_some() {
+        return Ok(Err(UpdateStatus::UpdateDeferred(
+            DeferReason::ServerBackoff,
+        )));
+    }
+    let pinned = pinned_patch_number(config, state);
+    if let Some(pinned) = pinned {
+        if state.next_boot_patch().is_some_and(|p| p.number == pinned) {
+            info!("Pinned patch {} is already the next boot patch.", pinned);
+            return Ok(Err(UpdateStatus::NoUpdate));
+        }
+        if state.set_next_boot_patch_number(pinned) {
+            info!("Pinned patch {} is installed, booting it next.", pinned);
+            with_config(|_| state.save())?;
+            return Ok(Err(UpdateStatus::NoUpdate));
+        }
+    }
+    if config.decode_benchmark && !state.decode_benchmark_ran() {
+        run_decode_benchmark(state)?;
+    }
+    // Check for update.
+    let response = send_patch_check_and_record(config, state)?;
+    if response.disable_patches {
+        return Ok(Err(UpdateStatus::UpdateDeferred(
+            DeferReason::PatchesDisabled,
+        )));
+    }
+    if !response.patch_available {
+        return Ok(Err(UpdateStatus::NoUpdate));
+    }
+    if !crate::delegate::response_allowed(&response)? {
+        info!("App vetoed installing the offered patch.");
+        return Ok(Err(UpdateStatus::UpdateDeferred(DeferReason::VetoedByApp)));
+    }
+    // Asked last so hosts can skip their store query when there is nothing
+    // to download.
+    if crate::delegate::is_store_update_pending() {
+        info!("An app store update is pending, not downloading the offered patch.");
+        return Ok(Err(UpdateStatus::UpdateDeferred(
+            DeferReason::StoreUpdatePending,
+        )));
+    }
+
+    let mut patch = response.patch.ok_or(UpdateError::BadServerResponse)?;
+    if let Some(pinned) = pinned {
+        if patch.number != pinned {
+            warn!(
+                "Server offered patch {}, but this device is pinned to {}.",
+                patch.number, pinned
+            );
+            return Ok(Err(UpdateStatus::NoUpdate));
+        }
+    } else if let Some(experiment) = response.experiment {
+        let seed = state.experiment_seed();
+        let assigned = in_experiment(seed, experiment.patch.number, experiment.percentage);
+        info!(
+            "Patch experiment {} vs {}: assigned to {}.",
+            patch.number,
+            experiment.patch.number,
+            if assigned { "experiment" } else { "control" }
+        );
+        if assigned {
+            patch = experiment.patch;
+        }
+        // Persist the seed so the assignment is stable across launches.
+        state.save()?;
+    }
+    if patch.canary {
+        match &patch.allowed_client_ids {
+            Some(ids) if !ids.iter().any(|id| id == state.client_id()) => {
+                warn!(
+                    "Canary patch {} is not for this device ({}), ignoring.",
+                    patch.number,
+                    state.client_id()
+                );
+                return Ok(Err(UpdateStatus::NoUpdate));
+            }
+            _ => info!("Patch {} is a canary patch.", patch.number),
+        }
+    }
+
+    let incompatibility = updater_version_mismatch(&patch, UPDATER_VERSION)
+        .map(|message| (DeferReason::UpdaterUpgradeRequired, message))
+        .or_else(|| {
+            build_number_mismatch(config, &patch)
+                .map(|message| (DeferReason::IncompatibleBuildNumber, message))
+        })
+        .or_else(|| {
+            native_fingerprint_mismatch(config, &patch)
+                .map(|message| (DeferReason::IncompatibleNativeFingerprint, message))
+        });
+    if let Some((reason, message)) = incompatibility {
+        warn!("Refusing to install patch {}: {}", patch.number, message);
+        return Ok(Err(UpdateStatus::UpdateDeferred(reason)));
+    }
+    if !is_allowlisted(config, Some(&patch.hash)) {
+        return Err(UpdateError::PatchNotAllowed)
+            .with_context(|| format!("Refusing to download patch {}.", patch.number));
+    }
+
+    // Saved along with the download progress below.
+    state.set_patch_metadata(
+        patch.number,
+        patch.metadata.as_ref().map(|metadata| metadata.to_string()),
+    );
+    // Checked against the inflated patch before it is installed.
+    state.set_patch_hash(patch.number, patch.hash.clone());
+
+    let download_path = download_file_path(config, patch.number, "");
+    migrate_legacy_download(config, &patch.number.to_string(), &download_path)?;
+    let download_hash = match &patch.download_hash {
+        Some(hash) => Some(decode_hash(hash).context("Invalid hash string from server.")?),
+        None => None,
+    };
+    // Fetched before downloading, as without it the download is useless.
+    let key = match &patch.encryption_key_id {
+        Some(key_id) => Some(crate::encryption::key_for(key_id)?),
+        None => None,
+    };
+    // Consider supporting allowing the system to download for us (e.g. iOS).
+    if !download_unless_paused(
+        config,
+        state,
+        &patch,
+        false,
+        &download_path,
+        download_hash.as_ref(),
+    )? {
+        return Ok(Err(UpdateStatus::UpdateDeferred(
+            DeferReason::AppInBackground,
+        )));
+    }
+
+    let output_path = staged_patch_path(config, patch.number);
+    // Should not pass config, rather should read necessary information earlier.
+    let prepared = prepare_for_install(config, &download_path, &output_path, key.as_ref())
+        .and_then(|()| check_hash(&output_path, &patch.hash));
+    // A patch which doesn't apply (or applies to the wrong result) means this
+    // device's base isn't the one the patch was built against, so try the
+    // whole artifact instead.  Other errors (e.g. a full disk) would likely
+    // fail the full download too.
+    let did_not_apply = |result: &anyhow::Result<bool>| match result {
+        Ok(hash_ok) => !hash_ok,
+        Err(error) => error
+            .chain()
+            .any(|cause| matches!(cause.downcast_ref(), Some(UpdateError::PatchDidNotApply(_)))),
+    };
+    let hash_ok = match prepared {
+        result
+            if did_not_apply(&result)
+                && config.allow_full_downloads
+                && patch.full_download_url.is_some() =>
+        {
+            match result {
+                Err(error) => warn!(
+                    "Patch {} failed to apply ({:#}), downloading the full artifact.",
+                    patch.number, error
+                ),
+                Ok(_) => warn!(
+                    "Patch {} doesn't match this release, downloading the full artifact.",
+                    patch.number
+                ),
+            }
+            let full_download_path = download_file_path(config, patch.number, ".whole");
+            if !download_unless_paused(config, state, &patch, true, &full_download_path, None)? {
+                return Ok(Err(UpdateStatus::UpdateDeferred(
+                    DeferReason::AppInBackground,
+                )));
+            }
+            decompress_full_artifact(&full_download_path, &output_path, key.as_ref())?;
+            check_hash(&output_path, &patch.hash)?
+        }
+        result => result?,
+    };
+    if !hash_ok {
+        return Err(UpdateError::HashMismatch).context("This is most often caused by using the same version number with a different app binary.");
+    }
+    check_patch_signature(config, &patch, &output_path)?;
+    if let Some(alignment) = config.patch_alignment {
+        pad_to_alignment(&output_path, alignment)?;
+    }
+    if config.durable_writes {
+        sync_file_and_parent(&output_path)?;
+    }
+    Ok(Ok(PatchInfo {
+        path: output_path,
+        number: patch.number,
+    }))
+}
+
+/// Downloads `patch` (its full artifact if `full`) unless downloads are paused
+/// in the background.  Returns false if the download was paused.
+fn download_unless_paused(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+    patch: &crate::network::Patch,
+    full: bool,
+    download_path: &Path,
+    download_hash: Option<&crate::verify::Hash>,
+) -> anyhow::Result<bool> {
+    let downloaded = if crate::lifecycle::downloads_paused() {
+        Err(UpdateError::DownloadPaused.into())
+    } else {
+        download_patch(config, state, patch, full, download_path, download_hash)
+    };
+    match downloaded {
+        Ok(()) => Ok(true),
+        Err(e)
+            if e.chain()
+                .any(|cause| cause.downcast_ref() == Some(&UpdateError::DownloadPaused)) =>
+        {
+            info!(
+                "App is in the background, pausing download of patch {}.",
+                patch.number
+            );
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs the decode benchmark, to be sent with the next patch check.  It is
+/// not retried if it fails, as it would likely fail again.
+fn run_decode_benchmark(state: &mut UpdaterState) -> anyhow::Result<()> {
+    let benchmark = crate::decode_benchmark::run()
+        .inspect_err(|e| warn!("Decode benchmark failed: {:#}", e))
+        .ok();
+    state.set_decode_benchmark(benchmark);
+    with_config(|_| state.save())
+}
+
+/// Whether patch_allowlist (if any) allows a patch with the given hash.
+/// Patches with an unknown hash are only allowed without an allowlist.
+fn is_allowlisted(config: &UpdateConfig, hash: Option<&str>) -> bool {
+    match &config.patch_allowlist {
+        Some(allowlist) => hash
+            .and_then(decode_hash)
+            .is_some_and(|hash| allowlist.contains(&hash)),
+        None => true,
+    }
+}
+
+/// The next boot patch, unless patch_allowlist doesn't allow it, in which
+/// case the patch is marked bad and the newest remaining allowed patch (or
+/// the base release) boots instead, as after a launch failure.  Also returns
+/// the number of the patch marked bad, if any, for the caller to pass to
+/// notify_invalidated() once it has released the config lock.
+fn allowlisted_next_boot_patch(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+) -> anyhow::Result<(Option<PatchInfo>, Option<usize>)> {
+    let patch = match state.next_boot_patch() {
+        Some(patch) => patch,
+        None => return Ok((None, None)),
+    };
+    if is_allowlisted(config, state.patch_hash(patch.number)) {
+        return Ok((Some(patch), None));
+    }
+    error!(
+        "Patch {} is not in patch_allowlist, refusing to boot it.",
+        patch.number
+    );
+    state.mark_patch_as_bad(patch.number);
+    state.reject_patch(patch.number, PatchRejection::NotAllowlisted);
+    let allowed: Vec<usize> = state
+        .installed_patch_numbers()
+        .into_iter()
+        .filter(|number| is_allowlisted(config, state.patch_hash(*number)))
+        .collect();
+    state.activate_latest_bootable_patch_where(|number| allowed.contains(&number));
+    state.save().map_err(|_| UpdateError::FailedToSaveState)?;
+    if let Some(fallback) = state.next_boot_patch() {
+        info!("Falling back to patch {}.", fallback.number);
+    }
+    Ok((state.next_boot_patch(), Some(patch.number)))
+}
+
+/// Tells the delegate about a patch allowlisted_next_boot_patch() marked bad.
+fn notify_invalidated(patch_number: Option<usize>) {
+    if let Some(patch_number) = patch_number {
+        notify_delegate(|delegate| delegate.on_patch_invalidated(patch_number));
+    }
+}
+
+/// A patch install_verified_patch() installed, for notify_installed() to tell
+/// the delegate about.
+struct NewlyInstalled {
+    number: usize,
+    critical: bool,
+}
+
+/// Tells the delegate about the patch (if any) an update installed.  Called
+/// once the update lock is released, so the delegate may update from the
+/// callbacks.
+fn notify_installed(
+    result: anyhow::Result<(UpdateStatus, Option<NewlyInstalled>)>,
+) -> anyhow::Result<UpdateStatus> {
+    let (status, installed) = result?;
+    if let Some(patch) = installed {
+        notify_delegate(|delegate| delegate.on_patch_installed(patch.number));
+        if patch.critical {
+            notify_delegate(|delegate| delegate.on_restart_requested(patch.number));
+        }
+    }
+    Ok(status)
+}
+
+/// Moves a verified patch into a slot and makes it the next boot patch.
+/// The caller tells the delegate, see notify_installed().
+fn install_verified_patch(
+    config: &UpdateConfig,
+    state: &mut UpdaterState,
+    patch: PatchInfo,
+) -> anyhow::Result<(UpdateStatus, Option<NewlyInstalled>)> {
+    let patch_number = patch.number;
+    let critical = state.critical_patch_number() == Some(patch_number);
+    // We're abusing the config lock as a UpdateState lock for now.
+    // This makes it so we never try to write to the UpdateState file from
+    // two threads at once. We could give UpdateState its own lock instead.
+    let status = with_config(|_| {
+        // Held until the install is saved, so the check below still holds.
+        #[cfg(feature = "daemon")]
+        let _state_lock = crate::daemon::StateLock::acquire(&config.cache_dir)?;
+        #[cfg(feature = "daemon")]
+        anyhow::ensure!(
+            !crate::cache::state_changed_elsewhere(&config.cache_dir),
+            "State was saved by another process during the update, not installing patch {}",
+            patch_number
+        );
+        // Whatever was staged is superseded by (or is) this patch.
+        state.set_staged_patch_number(None);
+        if critical {
+            state.set_critical_patch_number(None);
+        }
+        // Move/state update should be "atomic" (it isn't today).
+        state.install_patch(patch, config.retain_patch_count)?;
+        info!("Patch {} successfully installed.", patch_number);
+        #[cfg(target_os = "android")]
+        if let Some(installed) = state.next_boot_patch() {
+            if let Err(err) = crate::android::fix_installed_file_attributes(&installed.path) {
+                warn!(
+                    "Failed to fix attributes of patch {}: {:?}",
+                    patch_number, err
+                );
+            }
+        }
+        if config.durable_writes {
+            if let Some(installed) = state.next_boot_patch() {
+                // The slot directory may be new too, so sync its parent.
+                let result =
+                    sync_file_and_parent(&installed.path).and_then(|_| sync_dir(&config.cache_dir));
+                if let Err(err) = result {
+                    warn!("Failed to sync patch {}: {:?}", patch_number, err);
+                }
+            }
+        }
+        if config.prefault_patches {
+            if let Some(installed) = state.next_boot_patch() {
+                if let Err(err) = prefault_pages(&installed.path) {
+                    warn!("Failed to prefault patch {}: {:?}", patch_number, err);
+                }
+            }
+        }
+        // Should set some state to say the status is "update required" and that
+        // we now have a different "next" version of the app from the current
+        // booted version (patched or not).
+        Ok(UpdateStatus::UpdateInstalled)
+    })?;
+    let installed = NewlyInstalled {
+        number: patch_number,
+        critical,
+    };
+    Ok((status, Some(installed)))
+}
+
 // Callers must possess the Updater lock, but we don't care about the contents
 // since they're empty.
-fn update_internal(_: &UpdaterLockState) -> anyhow::Result<UpdateStatus> {
+fn update_internal(_: &UpdaterLockState) -> anyhow::Result<(UpdateStatus, Option<NewlyInstalled>)> {
     // Only one copy of Update can be running at a time.
     // Update will take the global Updater lock.
     // Update will need to take the Config lock at times, but will only
@@ -212,75 +1382,415 @@ fn update_internal(_: &UpdaterLockState) -> anyhow::Result<UpdateStatus> {
     // Saves state to disk (holds Config lock while writing).
 
     let config = copy_update_config()?;
+    #[cfg(feature = "daemon")]
+    let _cache_lock = crate::daemon::CacheLock::acquire(&config.cache_dir)?;
 
     // Load the state from disk.
-    let mut state = UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
-    // Check for update.
-    let response = send_patch_check_request(&config, &state)?;
-    if !response.patch_available {
-        return Ok(UpdateStatus::NoUpdate);
+    let mut state = load_state(&config);
+    match download_and_verify(&config, &mut state)? {
+        Ok(patch) => install_verified_patch(&config, &mut state, patch),
+        Err(status) => Ok((status, None)),
     }
+}
 
-    let patch = response.patch.ok_or(UpdateError::BadServerResponse)?;
+/// Turns offline mode on or off.  While offline, update() reports
+/// UpdateDeferred(Offline) and other calls which need the network fail with
+/// UpdateError::Offline.  Takes effect immediately, and does not require
+/// init.
+pub fn set_offline(offline: bool) {
+    info!("Updater offline mode: {}", offline);
+    crate::network::set_offline(offline);
+}
 
-    let download_dir = PathBuf::from(&config.download_dir);
-    let download_path = download_dir.join(patch.number.to_string());
-    // Consider supporting allowing the system to download for us (e.g. iOS).
-    download_to_path(&config.network_hooks, &patch.download_url, &download_path)?;
+/// Turns automatic updates (see start_update_thread) on or off at runtime,
+/// overriding shorebird.yaml's auto_update, or clears the override if None.
+/// Does not require init.
+pub fn set_auto_update(enabled: Option<bool>) {
+    info!("Updater auto update override: {:?}", enabled);
+    crate::auto_update::set_auto_update_override(enabled);
+}
 
-    let output_path = download_dir.join(format!("{}.full", patch.number.to_string()));
-    // Should not pass config, rather should read necessary information earlier.
-    prepare_for_install(&config, &download_path, &output_path)?;
+/// Tells the updater whether the device is on a metered network, in which
+/// case automatic updates are skipped unless set_auto_update(Some(true)).
+/// Does not require init.
+pub fn set_metered_network(metered: bool) {
+    info!("Updater metered network: {}", metered);
+    crate::auto_update::set_metered_network(metered);
+}
 
-    // Check the hash before moving into place.
-    let hash_ok = check_hash(&output_path, &patch.hash)?;
-    if !hash_ok {
-        return Err(UpdateError::InvalidState("Hash mismatch.  This is most often caused by using the same version number with a different app binary.".to_string()).into());
+/// Tells the updater the app came to the foreground.  If it was in the
+/// background, an automatic update check runs on a new thread, subject to
+/// auto_update_deferral() like start_update_thread (so the server's check
+/// interval still applies), and picks up any download which was paused.
+/// Does nothing before init.
+pub fn on_app_foreground() {
+    let was_in_background = crate::lifecycle::set_in_background(false);
+    info!("App in foreground.");
+    if was_in_background {
+        let priority = with_config(|config| Ok(config.update_thread_priority));
+        if let Ok(priority) = priority {
+            std::thread::spawn(move || {
+                set_current_thread_priority(priority);
+                let status = auto_update_check();
+                info!("Foreground update check finished with status: {}", status);
+            });
+        }
     }
+}
 
-    // We're abusing the config lock as a UpdateState lock for now.
-    // This makes it so we never try to write to the UpdateState file from
-    // two threads at once. We could give UpdateState its own lock instead.
-    with_config(|_| {
-        let patch_info = PatchInfo {
-            path: output_path,
-            number: patch.number,
-        };
-        // Move/state update should be "atomic" (it isn't today).
-        state.install_patch(patch_info)?;
-        info!("Patch {} successfully installed.", patch.number);
-        // Should set some state to say the status is "update required" and that
-        // we now have a different "next" version of the app from the current
-        // booted version (patched or not).
-        return Ok(UpdateStatus::UpdateInstalled);
+/// Tells the updater the app went into the background.  Downloads in
+/// progress stop (keeping what they have, to resume in the foreground)
+/// unless shorebird.yaml allows background_downloads.  Does not require
+/// init.
+pub fn on_app_background() {
+    crate::lifecycle::set_in_background(true);
+    info!("App in background.");
+}
+
+/// Pins this device to patch_number for QA: updates request that patch (even
+/// if newer ones exist, or it is older than the installed patch) and nothing
+/// else, and it becomes the next boot patch once installed.  If it is
+/// already installed it becomes the next boot patch now.  None removes the
+/// pin (the pinned patch stays installed until a newer one is).  Overrides
+/// shorebird.yaml's pinned_patch_number, and is kept across launches.
+/// Unlike shorebird.yaml's pin this works in release builds too, as QA
+/// usually tests release builds, so apps should only expose it to testers
+/// (e.g. from a debug menu).
+pub fn pin_patch(patch_number: Option<usize>) -> anyhow::Result<()> {
+    info!("Pinning patch: {:?}", patch_number);
+    with_config(|config| {
+        let (_state_lock, mut state) = lock_state(config)?;
+        state.set_pinned_patch_number(patch_number);
+        if let Some(number) = patch_number {
+            if state.set_next_boot_patch_number(number) {
+                info!("Pinned patch {} is installed, booting it next.", number);
+            }
+        }
+        state.save()
     })
 }
 
+/// Sets a header to send with every request to base_url, overriding
+/// shorebird.yaml's extra_headers, or removes it (including one from
+/// shorebird.yaml) if value is None.  Header names are case-insensitive.
+/// Does not require init.
+pub fn set_extra_header(name: &str, value: Option<&str>) -> anyhow::Result<()> {
+    info!("Updater extra header set: {}", name);
+    crate::network::set_extra_header(name, value)?;
+    Ok(())
+}
+
+/// Provides the key used to sign requests to the update server, taking
+/// precedence over shorebird.yaml's request_signing key, or removes the
+/// provider if None.  Called before every signed request, so the key can be
+/// rotated.  Requests are only signed if shorebird.yaml has request_signing.
+/// Does not require init.
+pub fn set_request_signing_key_provider(provider: Option<KeyProvider>) {
+    crate::request_signing::set_key_provider(provider);
+}
+
+/// Provides the keys for patches the server encrypts in transit (see
+/// Patch::encryption_key_id), or removes the provider if None.  Called with
+/// the patch's key id before each encrypted patch is downloaded.  Does not
+/// require init.
+pub fn set_patch_key_provider(provider: Option<PatchKeyProvider>) {
+    crate::encryption::set_key_provider(provider);
+}
+
 /// Synchronously checks for an update and downloads and installs it if available.
 pub fn update() -> anyhow::Result<UpdateStatus> {
-    with_updater_thread_lock(update_internal)
+    cancel_update_thread_delay();
+    let result = notify_installed(with_updater_thread_lock(update_internal));
+    // A concurrent update() will record its own outcome.
+    let in_progress = result.as_ref().is_err_and(|e| {
+        matches!(
+            e.downcast_ref::<UpdateError>(),
+            Some(UpdateError::UpdateAlreadyInProgress)
+        )
+    });
+    if !in_progress {
+        if let Err(err) = record_update_attempt(&result) {
+            warn!("Failed to record update result: {:?}", err);
+        }
+        if let Err(err) = &result {
+            notify_delegate(|delegate| delegate.on_update_error(err));
+        }
+    }
+    result
+}
+
+/// Registers a callback to see each patch check response which offers a
+/// patch (as JSON, see PatchCheckResponse) before update() downloads it, and
+/// veto installing it by returning false, e.g. while the user is checking
+/// out.  A vetoed update reports UpdateDeferred(VetoedByApp).  The kill
+/// switch (disable_patches) is applied regardless.  Called on the updating
+/// thread, which holds the update lock, so the callback must not itself
+/// update.  Pass None to remove it.  Does not require init.
+pub fn set_patch_check_response_callback(callback: Option<PatchCheckResponseCallback>) {
+    crate::delegate::set_response_callback(callback);
+}
+
+/// Registers a callback which update() asks, once a patch is offered, whether
+/// an app store update is pending install (e.g. a downloaded Play in-app
+/// update).  If it is, the patch is not downloaded, as the store update
+/// would replace it, and the update reports UpdateDeferred(StoreUpdatePending).
+/// The same threading rules as set_patch_check_response_callback apply.
+/// Pass None to remove it.  Does not require init.
+pub fn set_store_update_pending_callback(callback: Option<StoreUpdatePendingCallback>) {
+    crate::delegate::set_store_update_pending_callback(callback);
+}
+
+/// Registers the delegate to notify of installed and invalidated patches and
+/// update errors (e.g. from the update thread), replacing any previous one.
+/// Pass None to remove it.  Does not require init.
+pub fn set_delegate(delegate: Option<Arc<dyn UpdaterDelegate>>) {
+    crate::delegate::set_delegate(delegate);
+}
+
+/// Saves the outcome of update() for last_update_attempt().
+fn record_update_attempt(result: &anyhow::Result<UpdateStatus>) -> anyhow::Result<()> {
+    with_config(|config| {
+        let (_state_lock, mut state) = lock_state(config)?;
+        let (status, error) = match result {
+            Ok(status) => (*status, None),
+            Err(e) => (UpdateStatus::UpdateHadError, Some(UpdateErrorKind::from(e))),
+        };
+        state.set_last_update_attempt(UpdateAttempt {
+            timestamp: config.clock.unix_timestamp(),
+            status,
+            error,
+        });
+        state.save()
+    })
+}
+
+/// The outcome of the most recent update(), or None if it has never been
+/// called for this release.
+pub fn last_update_attempt() -> anyhow::Result<Option<UpdateAttempt>> {
+    with_config(|config| {
+        let state = load_state(config);
+        Ok(state.last_update_attempt().cloned())
+    })
+}
+
+fn download_update_internal(
+    _: &UpdaterLockState,
+) -> anyhow::Result<(UpdateStatus, Option<NewlyInstalled>)> {
+    let config = copy_update_config()?;
+    #[cfg(feature = "daemon")]
+    let _cache_lock = crate::daemon::CacheLock::acquire(&config.cache_dir)?;
+    let mut state = load_state(&config);
+    let patch = match download_and_verify(&config, &mut state)? {
+        Ok(patch) => patch,
+        Err(status) => return Ok((status, None)),
+    };
+    if state.critical_patch_number() == Some(patch.number) {
+        info!("Patch {} is critical, installing now.", patch.number);
+        return install_verified_patch(&config, &mut state, patch);
+    }
+    with_config(|_| {
+        state.set_staged_patch_number(Some(patch.number));
+        state.save()
+    })?;
+    info!(
+        "Patch {} downloaded, waiting to be installed.",
+        patch.number
+    );
+    Ok((UpdateStatus::UpdateDownloaded, None))
+}
+
+/// Synchronously checks for an update and downloads and verifies it if
+/// available, but does not install it.  The next boot patch is unchanged
+/// until install_downloaded_update() is called.
+pub fn download_update() -> anyhow::Result<UpdateStatus> {
+    notify_installed(with_updater_thread_lock(download_update_internal))
+}
+
+fn install_downloaded_update_internal(
+    _: &UpdaterLockState,
+) -> anyhow::Result<(UpdateStatus, Option<NewlyInstalled>)> {
+    let config = copy_update_config()?;
+    #[cfg(feature = "daemon")]
+    let _cache_lock = crate::daemon::CacheLock::acquire(&config.cache_dir)?;
+    let mut state = load_state(&config);
+    let patch_number = match state.staged_patch_number() {
+        Some(patch_number) => patch_number,
+        None => return Ok((UpdateStatus::NoUpdate, None)),
+    };
+    let path = staged_patch_path(&config, patch_number);
+    migrate_legacy_download(&config, &format!("{}.full", patch_number), &path)?;
+    if !path.exists() || state.is_known_bad_patch(patch_number) {
+        warn!("Staged patch {} is no longer installable.", patch_number);
+        state.set_staged_patch_number(None);
+        with_config(|_| state.save())?;
+        return Ok((UpdateStatus::NoUpdate, None));
+    }
+    let patch = PatchInfo {
+        path,
+        number: patch_number,
+    };
+    install_verified_patch(&config, &mut state, patch)
+}
+
+/// Installs the patch previously downloaded by download_update(), making it
+/// the next boot patch.  Returns NoUpdate if there is nothing to install.
+pub fn install_downloaded_update() -> anyhow::Result<UpdateStatus> {
+    notify_installed(with_updater_thread_lock(install_downloaded_update_internal))
+}
+
+/// Flushes the file at path, and its directory entry, to disk so a power cut
+/// can't leave behind a zero-filled file which still has the right size.
+fn sync_file_and_parent(path: &Path) -> anyhow::Result<()> {
+    fs::File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_file_context("sync", path)?;
+    match path.parent() {
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Flushes a directory's entries (e.g. a rename into it) to disk.
+pub(crate) fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    // Only unix lets us open a directory to sync it.
+    #[cfg(unix)]
+    fs::File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .with_file_context("sync", path)?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Pads the file at path with zeros so its length is a multiple of
+/// alignment.  Done after the hash check, since it changes the contents.
+fn pad_to_alignment(path: &Path, alignment: u64) -> anyhow::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    let length = file.metadata()?.len();
+    let padded_length = length.div_ceil(alignment) * alignment;
+    if padded_length != length {
+        debug!(
+            "Padding {:?} from {} to {} bytes",
+            path, length, padded_length
+        );
+        file.set_len(padded_length)?;
+    }
+    Ok(())
+}
+
+/// Asks the OS to read the file into the page cache, so that mmapping it at
+/// the next boot does not have to wait on disk.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn prefault_pages(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::File::open(path)?;
+    // Unlike madvise, fadvise works on the file without needing to map it.
+    let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+    anyhow::ensure!(result == 0, "posix_fadvise failed: {}", result);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn prefault_pages(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Decompresses a full artifact (see Patch::full_download_url), which needs
+/// no base, to output_path.
+fn decompress_full_artifact(
+    download_path: &Path,
+    output_path: &Path,
+    key: Option<&PatchKey>,
+) -> anyhow::Result<()> {
+    use comde::de::Decompressor;
+    use std::io::{BufWriter, Write};
+
+    let input = open_download(download_path, key)?;
+    let output = fs::File::create(output_path).with_file_context("create", output_path)?;
+    let mut output_w = BufWriter::new(output);
+    comde::zstd::ZstdDecompressor::new()
+        .copy(input, &mut output_w)
+        .context("Failed to decompress full artifact.")?;
+    output_w.flush()?;
+    Ok(())
 }
 
 /// Given a path to a patch file, and a base file, apply the patch to the base
 /// and write the result to the output path.
 #[cfg(any(target_os = "android", test))]
-fn inflate<RS>(patch_path: &Path, base_r: RS, output_path: &Path) -> anyhow::Result<()>
+fn inflate<RS>(
+    patch_path: &Path,
+    mut base_r: RS,
+    output_path: &Path,
+    priority: crate::thread_priority::ThreadPriority,
+    checkpoint_interval: u64,
+    key: Option<&PatchKey>,
+) -> anyhow::Result<()>
 where
     RS: Read + Seek,
 {
+    use crate::patch_apply::{self, PatchApplier};
     use comde::de::Decompressor;
     use comde::zstd::ZstdDecompressor;
     info!("Patch is compressed, inflating...");
-    use std::io::{BufReader, BufWriter};
+    use std::io::{BufReader, BufWriter, SeekFrom, Write};
+
+    // bipatch fails confusingly when the base is missing, so check up front.
+    let base_len = base_r.seek(SeekFrom::End(0))?;
+    base_r.seek(SeekFrom::Start(0))?;
+    if base_len == 0 {
+        error!("Base artifact is empty, cannot apply patch.");
+        anyhow::bail!(UpdateError::BadBaseArtifact(
+            "base is empty, check that original_libapp_paths points to the app's libapp"
+                .to_string()
+        ));
+    }
 
     // Open all our files first for error clarity.  Otherwise we might see
     // PipeReader/Writer errors instead of file open errors.
     info!("Reading patch file: {:?}", patch_path);
-    let compressed_patch_r = BufReader::new(
-        fs::File::open(patch_path)
-            .context(format!("Failed to open patch file: {:?}", patch_path))?,
-    );
-    let output_file_w = fs::File::create(&output_path)?;
+    let mut compressed_patch_file = fs::File::open(patch_path)
+        .context(format!("Failed to open patch file: {:?}", patch_path))?;
+    // Large patches are split into frames we can decompress in parallel.
+    // An encrypted patch's seek table is encrypted too, so those are
+    // decompressed sequentially.
+    let mut frames = match key {
+        Some(_) => None,
+        None => crate::seekable::read_seek_table(&mut compressed_patch_file)?,
+    };
+    let threads = crate::seekable::inflate_thread_count();
+
+    // Pick up where a previous attempt at applying this download left off.
+    let checkpoint = patch_apply::load_checkpoint(output_path, patch_path);
+    // Bytes of inflated patch to discard before resuming.
+    let mut skip = 0;
+    let output_file_w = match &checkpoint {
+        Some(checkpoint) => {
+            info!(
+                "Resuming patch application at {} bytes of output.",
+                checkpoint.output_offset
+            );
+            skip = checkpoint.patch_offset;
+            // With a seek table we can start decompressing from the frame
+            // containing the checkpoint, rather than from the beginning.
+            if let Some(frame_list) = frames.as_mut() {
+                let start = crate::seekable::frame_containing(frame_list, skip);
+                compressed_patch_file.seek(SeekFrom::Start(start.compressed_offset))?;
+                skip -= start.decompressed_offset;
+                frame_list.drain(..start.index);
+            }
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(output_path)
+                .with_file_context("open", output_path)?;
+            file.set_len(checkpoint.output_offset)
+                .with_file_context("truncate", output_path)?;
+            file.seek(SeekFrom::End(0))?;
+            file
+        }
+        None => fs::File::create(&output_path)?,
+    };
+    let compressed_patch_r = decrypting(BufReader::new(compressed_patch_file), key)?;
 
     // Set up a pipe to connect the writing from the decompression thread
     // to the reading of the decompressed patch data on this thread.
@@ -290,80 +1800,510 @@ where
     // Spawn a thread to run the decompression in parallel to the patching.
     // decompress.copy will block on the pipe being full (I think) and then
     // when it returns the thread will exit.
-    std::thread::spawn(move || {
-        // If this thread fails, undoubtedly the main thread will fail too.
-        // Most important is to not crash.
-        let result = decompress.copy(compressed_patch_r, patch_w);
-        if let Err(err) = result {
+    let decompress_thread = std::thread::spawn(move || -> anyhow::Result<()> {
+        set_current_thread_priority(priority);
+        let result = match frames {
+            Some(frames) if frames.len() > 1 && threads > 1 => {
+                info!("Inflating {} frames on {} threads.", frames.len(), threads);
+                crate::seekable::decompress_frames(
+                    compressed_patch_r,
+                    &frames,
+                    threads,
+                    priority,
+                    patch_w,
+                )
+            }
+            _ => decompress
+                .copy(compressed_patch_r, patch_w)
+                .map(|_| ())
+                .map_err(Into::into),
+        };
+        if let Err(err) = &result {
             error!("Decompression thread failed: {err}");
         }
+        result
     });
 
     // Do the patch, using the uncompressed patch data from the pipe.
-    let mut fresh_r = bipatch::Reader::new(patch_r, base_r)?;
+    let apply_error = |error: std::io::Error| {
+        UpdateError::PatchDidNotApply(format!(
+            "{} ({} byte base), was the patch built for this release?",
+            error, base_len
+        ))
+    };
+    let apply_result = (|| -> anyhow::Result<()> {
+        let mut fresh_r = match &checkpoint {
+            Some(checkpoint) => {
+                let mut patch_r = patch_r;
+                let skipped = std::io::copy(&mut (&mut patch_r).take(skip), &mut std::io::sink())?;
+                anyhow::ensure!(skipped == skip, "Patch ended before its checkpoint.");
+                PatchApplier::resume(patch_r, base_r, checkpoint)
+            }
+            None => PatchApplier::new(patch_r, base_r),
+        }
+        .map_err(apply_error)?;
+        // Only hashed once there is a checkpoint to save.
+        let mut download_hash = checkpoint.map(|checkpoint| checkpoint.download_hash);
+
+        // Write out the resulting patched file to the new location, saving a
+        // checkpoint every so often.
+        let mut output_w = BufWriter::new(output_file_w);
+        let mut buffer = vec![0u8; checkpoint_interval.clamp(1, 64 * 1024) as usize];
+        let mut since_checkpoint = 0;
+        loop {
+            let read = fresh_r.read(&mut buffer).map_err(apply_error)?;
+            if read == 0 {
+                break;
+            }
+            output_w.write_all(&buffer[..read])?;
+            since_checkpoint += read as u64;
+            if since_checkpoint >= checkpoint_interval {
+                // Flushed (but not synced) output survives the app being killed,
+                // and the hash check catches anything lost to a power cut.
+                output_w.flush()?;
+                if download_hash.is_none() {
+                    download_hash = Some(crate::cache::hash_file(patch_path)?);
+                }
+                let hash = download_hash.as_deref().unwrap_or_default();
+                patch_apply::save_checkpoint(output_path, &fresh_r.checkpoint(hash))?;
+                since_checkpoint = 0;
+            }
+        }
+        output_w.flush()?;
+        Ok(())
+    })();
 
-    // Write out the resulting patched file to the new location.
-    let mut output_w = BufWriter::new(output_file_w);
-    std::io::copy(&mut fresh_r, &mut output_w)?;
+    // A failed decompression just looks like the end of the patch from here,
+    // so its error (rather than e.g. a truncated output) is the real one.
+    // The reader is gone by now, so the thread can't be stuck on the pipe.
+    let decompress_result = decompress_thread
+        .join()
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Decompression thread panicked.")));
+    match decompress_result {
+        // We stopped reading (after failing ourselves), breaking the pipe.
+        Err(e) if !is_broken_pipe(&e) => return Err(e.context("Failed to decompress patch")),
+        _ => apply_result?,
+    }
+    patch_apply::remove_checkpoint(output_path)?;
     Ok(())
 }
 
+/// Whether error was caused by writing to a pipe whose reader had gone away.
+#[cfg(any(target_os = "android", test))]
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// The result of prevalidate_next_boot_patch(), waiting to be used by
+/// next_boot_patch().
+struct PrevalidatedPatch {
+    cache_dir: PathBuf,
+    /// cache::state_generation() from before the state was loaded.
+    generation: u64,
+    patch: Option<PatchInfo>,
+}
+
+type Prevalidation = std::thread::JoinHandle<anyhow::Result<PrevalidatedPatch>>;
+
+static PREVALIDATION: std::sync::Mutex<Option<Prevalidation>> = std::sync::Mutex::new(None);
+
+/// Starts loading and validating (existence, size and hash) the next boot
+/// patch on a background thread, so the engine can do it ahead of its
+/// critical path.  The next call to next_boot_patch() uses the result
+/// (waiting for it if necessary) unless the state has changed since.
+pub fn prevalidate_next_boot_patch() {
+    let handle = std::thread::spawn(|| {
+        let (prevalidated, invalidated) = with_config(|config| {
+            let generation = crate::cache::state_generation();
+            let (_state_lock, mut state) = lock_state(config)?;
+            let (patch, invalidated) = allowlisted_next_boot_patch(config, &mut state)?;
+            let prevalidated = PrevalidatedPatch {
+                cache_dir: config.cache_dir.clone(),
+                generation,
+                patch,
+            };
+            Ok((prevalidated, invalidated))
+        })?;
+        notify_invalidated(invalidated);
+        Ok(prevalidated)
+    });
+    *PREVALIDATION
+        .lock()
+        .expect("Failed to acquire prevalidation lock.") = Some(handle);
+}
+
+/// Takes the result of prevalidate_next_boot_patch(), if there is one.
+/// Must not be called with the config lock held, as the prevalidation
+/// thread needs it.
+fn take_prevalidation() -> Option<PrevalidatedPatch> {
+    let handle = PREVALIDATION
+        .lock()
+        .expect("Failed to acquire prevalidation lock.")
+        .take()?;
+    match handle.join() {
+        Ok(Ok(prevalidated)) => Some(prevalidated),
+        Ok(Err(err)) => {
+            warn!("Prevalidating next boot patch failed: {:#}", err);
+            None
+        }
+        Err(_) => {
+            error!("Prevalidating next boot patch panicked.");
+            None
+        }
+    }
+}
+
 /// The patch which will be run on next boot (which may still be the same
 /// as the current boot).
 /// This may be changed any time update() or start_update_thread() are called.
 pub fn next_boot_patch() -> anyhow::Result<Option<PatchInfo>> {
+    let prevalidated = take_prevalidation();
+    let (patch, invalidated) = with_config(|config| {
+        let (patch, invalidated) = select_next_boot_patch(config, prevalidated)?;
+        if config.verify_boot_artifact {
+            snapshot_boot_artifact(patch.as_ref());
+        }
+        Ok((patch, invalidated))
+    })?;
+    notify_invalidated(invalidated);
+    Ok(patch)
+}
+
+/// See allowlisted_next_boot_patch().
+fn select_next_boot_patch(
+    config: &UpdateConfig,
+    prevalidated: Option<PrevalidatedPatch>,
+) -> anyhow::Result<(Option<PatchInfo>, Option<usize>)> {
+    if let Some(prevalidated) = prevalidated {
+        if prevalidated.cache_dir == config.cache_dir
+            && prevalidated.generation == crate::cache::state_generation()
+        {
+            info!("Using prevalidated next boot patch.");
+            return Ok((prevalidated.patch, None));
+        }
+        info!("State changed since prevalidation, validating again.");
+    }
+    let (_state_lock, mut state) = lock_state(config)?;
+    allowlisted_next_boot_patch(config, &mut state)
+}
+
+/// The artifact next_boot_patch() last handed out, see verify_boot_artifact.
+static BOOT_ARTIFACT_SNAPSHOT: std::sync::Mutex<Option<BootArtifactSnapshot>> =
+    std::sync::Mutex::new(None);
+
+fn snapshot_boot_artifact(patch: Option<&PatchInfo>) {
+    let snapshot = patch.and_then(|patch| match BootArtifactSnapshot::take(patch) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            warn!("Failed to snapshot boot artifact: {:#}", e);
+            None
+        }
+    });
+    *BOOT_ARTIFACT_SNAPSHOT
+        .lock()
+        .expect("Failed to acquire boot snapshot lock.") = snapshot;
+}
+
+/// The patch being booted, if shorebird.yaml's verify_boot_artifact is set
+/// and its artifact changed since next_boot_patch() handed it out.  Must be
+/// checked before loading the state, which quietly drops artifacts which no
+/// longer match their hash.
+fn changed_boot_artifact(config: &UpdateConfig) -> Option<usize> {
+    if !config.verify_boot_artifact {
+        return None;
+    }
+    let snapshot = BOOT_ARTIFACT_SNAPSHOT
+        .lock()
+        .expect("Failed to acquire boot snapshot lock.")
+        .take()?;
+    snapshot.changed().then_some(snapshot.patch_number)
+}
+
+/// The next boot patch as a sealed, read-only copy in anonymous memory, for
+/// engines which would rather map memory than re-open a file which could
+/// change underneath them.  None if there is no next boot patch, or it is
+/// larger than shorebird.yaml's memfd_max_bytes (or that isn't set), in which
+/// case the engine should use next_boot_patch()'s path.  Fails where memfd is
+/// unsupported (e.g. old kernels, or not Android or Linux).
+pub fn next_boot_patch_memfd() -> anyhow::Result<Option<fs::File>> {
+    let max_bytes = match with_config(|config| Ok(config.memfd_max_bytes))? {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(None),
+    };
+    let patch = match next_boot_patch()? {
+        Some(patch) => patch,
+        None => return Ok(None),
+    };
+    let size = fs::metadata(&patch.path)
+        .with_file_context("read metadata of", &patch.path)?
+        .len();
+    if size > max_bytes {
+        info!(
+            "Patch {} is {} bytes, over memfd_max_bytes ({}).",
+            patch.number, size, max_bytes
+        );
+        return Ok(None);
+    }
+    crate::memfd::sealed_copy(&patch.path).map(Some)
+}
+
+/// What the next boot will run, see boot_decision().
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BootDecision {
+    /// The next boot uses this patch.
+    Patch(usize),
+    /// No patch is installed for this release.
+    NoPatch,
+    /// The next boot uses the release because this patch was refused.
+    Rejected(RejectedPatch),
+}
+
+/// What the next boot will run and, if it isn't a patch, why not.  Meant for
+/// debugging "my patch isn't applying": next_boot_patch() only says there is
+/// no patch.
+pub fn boot_decision() -> anyhow::Result<BootDecision> {
+    let (decision, invalidated) = with_config(|config| {
+        let (_state_lock, mut state) = lock_state(config)?;
+        let (patch, invalidated) = allowlisted_next_boot_patch(config, &mut state)?;
+        let decision = match patch {
+            Some(patch) => BootDecision::Patch(patch.number),
+            None => match state.last_rejected_patch() {
+                Some(rejected) => BootDecision::Rejected(rejected),
+                None => BootDecision::NoPatch,
+            },
+        };
+        Ok((decision, invalidated))
+    })?;
+    notify_invalidated(invalidated);
+    Ok(decision)
+}
+
+/// The metadata (JSON) the server sent with the next boot patch, or None if
+/// there is no next patch or it has no metadata.
+pub fn next_boot_patch_metadata() -> anyhow::Result<Option<String>> {
+    with_config(|config| {
+        let state = load_state(config);
+        Ok(state
+            .next_boot_patch()
+            .and_then(|patch| state.patch_metadata(patch.number))
+            .map(str::to_owned))
+    })
+}
+
+/// The patch that will boot on the next run of the app, opened for reading,
+/// or None if there is no next patch.  Lets the engine map the patch without
+/// looking it up by path again.
+pub fn next_boot_patch_file() -> anyhow::Result<Option<fs::File>> {
+    match next_boot_patch()? {
+        Some(patch) => Ok(Some(fs::File::open(&patch.path)?)),
+        None => Ok(None),
+    }
+}
+
+/// Random id for this install of the app, sent with patch checks so canary
+/// patches can be targeted at this device.  Kept when the release version
+/// changes.
+pub fn client_id() -> anyhow::Result<String> {
     with_config(|config| {
-        let state = UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
-        return Ok(state.next_boot_patch());
+        let state = load_state(config);
+        Ok(state.client_id().to_owned())
     })
 }
 
+/// Every patch currently on disk for this release, whether or not it will
+/// boot next.  Used for diagnostics rather than guessing from the directory
+/// layout.
+pub fn installed_patches() -> anyhow::Result<Vec<InstalledPatch>> {
+    with_config(|config| {
+        let state = load_state(config);
+        Ok(state.installed_patches())
+    })
+}
+
+/// Probes the cache and download directories, checks the state file parses
+/// and re-hashes the booted patch.  Also reports the progress of any
+/// interrupted download.  Slow (it hashes the whole patch), meant
+/// for diagnostics rather than routine use.
+pub fn health_check() -> anyhow::Result<HealthReport> {
+    // Copied so the checks don't hold the config lock (e.g. blocking
+    // next_boot_patch() at boot) while they hash the patch.
+    let config = copy_update_config()?;
+    Ok(crate::health::run_health_check(&config))
+}
+
+/// The record of the latest patch install, showing which step it stopped at
+/// if it didn't finish.  None if no patch has been installed.
+pub fn last_install_transaction() -> anyhow::Result<Option<InstallTransaction>> {
+    with_config(|config| Ok(InstallTransaction::load(&config.cache_dir)))
+}
+
+/// Non-fatal problems found in shorebird.yaml by init, e.g. unknown keys.
+pub fn config_warnings() -> anyhow::Result<Vec<String>> {
+    with_config(|config| Ok(config.config_warnings.clone()))
+}
+
 /// The patch which is currently booted.  This is None until
 /// report_launch_start() is called at which point it is copied from
 /// next_boot_patch.
 pub fn current_boot_patch() -> anyhow::Result<Option<PatchInfo>> {
     with_config(|config| {
-        let state = UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
+        let state = load_state(config);
         return Ok(state.current_boot_patch());
     })
 }
 
+/// Reports that the engine is launching the next boot patch.  Repeated
+/// reports for the same patch from one process (e.g. hot restarts in debug
+/// builds) are ignored, see is_repeated_launch_start().
 pub fn report_launch_start() -> anyhow::Result<()> {
-    with_config(|config| {
-        let mut state =
-            UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
+    let changed = with_config(|config| {
+        let changed = changed_boot_artifact(config);
+        let (_state_lock, mut state) = lock_state(config)?;
+        if let Some(patch_number) = changed {
+            // Whatever the engine is booting, it isn't this patch, so don't
+            // record it as booted.
+            error!(
+                "Patch {} changed after it was selected for boot, marking it bad.",
+                patch_number
+            );
+            state.mark_patch_as_bad(patch_number);
+            state.reject_patch(patch_number, PatchRejection::ChangedBeforeBoot);
+            state.activate_latest_bootable_patch()?;
+            return Ok(changed);
+        }
+        if is_repeated_launch_start(state.next_boot_patch().map(|p| p.number)) {
+            return Ok(None);
+        }
+        record_launch_start();
         // Validate that we have an installed patch.
         // Make that patch the "booted" patch.
         state.activate_current_patch()?;
-        state.save()
+        state.save()?;
+        Ok(None)
+    })?;
+    if let Some(patch_number) = changed {
+        notify_delegate(|delegate| delegate.on_patch_invalidated(patch_number));
+        notify_delegate(|delegate| delegate.on_boot_artifact_changed(patch_number));
+    }
+    Ok(())
+}
+
+/// Single-call alternative to report_launch_start() which also records that
+/// a boot of `patch_number` is pending.  If the app crashes before launch
+/// success or failure is reported, the next init() treats the boot as a
+/// failure.  `patch_number` must be the next boot patch number, or 0 when
+/// booting without a patch.
+pub fn begin_boot(patch_number: usize) -> anyhow::Result<()> {
+    with_config(|config| {
+        let (_state_lock, mut state) = lock_state(config)?;
+        let next_boot_number = state.next_boot_patch().map_or(0, |p| p.number);
+        if patch_number != next_boot_number {
+            anyhow::bail!(UpdateError::InvalidArgument(
+                "patch_number".to_string(),
+                format!("{} is not the next boot patch", patch_number),
+            ));
+        }
+        if patch_number != 0 && is_repeated_launch_start(Some(patch_number)) {
+            return Ok(());
+        }
+        record_launch_start();
+        if patch_number == 0 {
+            // Nothing can be rolled back if the base release fails to boot.
+            return Ok(());
+        }
+        state.activate_current_patch()?;
+        state.save()?;
+        write_boot_pending_marker(&config.cache_dir, patch_number)
     })
 }
 
+/// The patch this process last reported a launch start for, and how many
+/// times the start was reported again since.
+static LAUNCH_STARTED: std::sync::Mutex<Option<(usize, usize)>> = std::sync::Mutex::new(None);
+
+/// Whether this process already reported a launch start for patch_number.
+/// The engine re-runs its boot sequence on hot restart, which shouldn't
+/// rewrite the state or restart the boot timing each time.  Only patches
+/// are tracked, booting without one writes nothing anyway.
+fn is_repeated_launch_start(patch_number: Option<usize>) -> bool {
+    let mut started = LAUNCH_STARTED
+        .lock()
+        .expect("Failed to acquire launch start lock.");
+    match (patch_number, started.as_mut()) {
+        (Some(number), Some((started_number, repeats))) if number == *started_number => {
+            *repeats += 1;
+            info!(
+                "Ignoring repeated launch start for patch {} ({} so far).",
+                number, repeats
+            );
+            true
+        }
+        (Some(number), _) => {
+            *started = Some((number, 0));
+            false
+        }
+        (None, _) => false,
+    }
+}
+
+/// Forgets which patch this process reported a launch start for, since
+/// tests simulate several launches in one process.
+#[cfg(test)]
+pub fn testing_reset_launch_start() {
+    *LAUNCH_STARTED
+        .lock()
+        .expect("Failed to acquire launch start lock.") = None;
+}
+
+/// When this process reported the start of its launch, for timing the boot.
+static LAUNCH_START: std::sync::Mutex<Option<std::time::Instant>> = std::sync::Mutex::new(None);
+
+fn record_launch_start() {
+    *LAUNCH_START
+        .lock()
+        .expect("Failed to acquire launch start lock.") = Some(std::time::Instant::now());
+}
+
+/// Time since record_launch_start(), if it was called (and this hasn't been
+/// since).
+fn take_launch_duration() -> Option<std::time::Duration> {
+    LAUNCH_START
+        .lock()
+        .expect("Failed to acquire launch start lock.")
+        .take()
+        .map(|start| start.elapsed())
+}
+
 /// Report that the current active path failed to launch.
 /// This will mark the patch as bad and activate the next best patch.
 pub fn report_launch_failure() -> anyhow::Result<()> {
     info!("Reporting failed launch.");
-    with_config(|config| {
-        let mut state =
-            UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
+    // Failed boots aren't timed.
+    take_launch_duration();
+    let patch_number =
+        with_config(|config| {
+            clear_boot_pending_marker(&config.cache_dir)?;
+            let (_state_lock, mut state) = lock_state(config)?;
 
-        let patch =
-            state
-                .current_boot_patch()
-                .ok_or(anyhow::Error::from(UpdateError::InvalidState(
-                    "No current patch".to_string(),
-                )))?;
-        state.mark_patch_as_bad(patch.number);
-        state
-            .activate_latest_bootable_patch()
-            .map_err(|err| anyhow::Error::from(err))
-    })
+            let patch = state.current_boot_patch().ok_or(anyhow::Error::from(
+                UpdateError::InvalidState("No current patch".to_string()),
+            ))?;
+            state.mark_patch_as_bad(patch.number);
+            state.activate_latest_bootable_patch()?;
+            Ok(patch.number)
+        })?;
+    notify_delegate(|delegate| delegate.on_patch_invalidated(patch_number));
+    Ok(())
 }
 
 pub fn report_launch_success() -> anyhow::Result<()> {
     with_config(|config| {
-        let mut state =
-            UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
+        clear_boot_pending_marker(&config.cache_dir)?;
+        let (_state_lock, mut state) = lock_state(config)?;
 
         let patch =
             state
@@ -372,22 +2312,215 @@ pub fn report_launch_success() -> anyhow::Result<()> {
                     "No current patch".to_string(),
                 )))?;
         state.mark_patch_as_good(patch.number);
+        if let Some(duration) = take_launch_duration() {
+            info!("Patch {} booted in {:?}.", patch.number, duration);
+            state.set_last_boot_timing(BootTiming {
+                patch_number: patch.number,
+                duration_ms: duration.as_millis() as u64,
+            });
+        }
         state
             .save()
             .map_err(|_| anyhow::Error::from(UpdateError::FailedToSaveState))
     })
 }
 
+/// Uninstalls the most recently installed patch and makes the previous good
+/// patch (if any) the patch used on next boot.  The uninstalled patch is
+/// marked as bad so it will not be downloaded again.
+/// Returns the patch number which was rolled back.
+pub fn rollback_last_patch() -> anyhow::Result<usize> {
+    info!("Rolling back last patch.");
+    let patch_number = with_config(|config| {
+        let (_state_lock, mut state) = lock_state(config)?;
+        state
+            .rollback_next_boot_patch()
+            .map_err(anyhow::Error::from)
+    })?;
+    notify_delegate(|delegate| delegate.on_patch_invalidated(patch_number));
+    Ok(patch_number)
+}
+
+/// Set when an explicit update() makes a delayed update thread unnecessary.
+fn update_thread_delay_cancelled() -> &'static (std::sync::Mutex<bool>, std::sync::Condvar) {
+    use once_cell::sync::OnceCell;
+    static INSTANCE: OnceCell<(std::sync::Mutex<bool>, std::sync::Condvar)> = OnceCell::new();
+    INSTANCE.get_or_init(Default::default)
+}
+
+fn cancel_update_thread_delay() {
+    let (cancelled, condvar) = update_thread_delay_cancelled();
+    *cancelled.lock().expect("Failed to acquire delay lock.") = true;
+    condvar.notify_all();
+}
+
+/// Forgets any earlier cancel_update_thread_delay(), before a new update
+/// thread starts.
+fn reset_update_thread_delay() {
+    let (cancelled, _) = update_thread_delay_cancelled();
+    *cancelled.lock().expect("Failed to acquire delay lock.") = false;
+}
+
+/// Sleeps for `delay`, returning early (and true) if
+/// cancel_update_thread_delay() is called, including if it was called after
+/// reset_update_thread_delay() but before the wait began.
+fn wait_unless_cancelled(delay: std::time::Duration) -> bool {
+    let (cancelled, condvar) = update_thread_delay_cancelled();
+    let cancelled = cancelled.lock().expect("Failed to acquire delay lock.");
+    let (mut cancelled, _) = condvar
+        .wait_timeout_while(cancelled, delay, |cancelled| !*cancelled)
+        .expect("Failed to acquire delay lock.");
+    std::mem::replace(&mut *cancelled, false)
+}
+
+/// A random delay of up to `max_seconds`.
+fn random_delay(max_seconds: u64) -> std::time::Duration {
+    use std::hash::{BuildHasher, Hasher};
+    if max_seconds == 0 {
+        return std::time::Duration::ZERO;
+    }
+    // RandomState is randomly keyed per instance, which is plenty here.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    std::time::Duration::from_secs(random % (max_seconds + 1))
+}
+
+/// Whether an update is running and whether another is queued, see
+/// update_queue_state().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateQueueState {
+    /// No update is running.
+    Idle,
+    /// An update is running, from update() or the update thread.
+    Running,
+    /// The update thread is running and will run once more when it is done,
+    /// see YamlConfig::queue_follow_up_update.
+    FollowUpQueued,
+}
+
+/// The update thread's state.  Only ever Idle, Running or FollowUpQueued
+/// while the thread is alive, so there is at most one update thread.
+static UPDATE_THREAD_STATE: std::sync::Mutex<UpdateQueueState> =
+    std::sync::Mutex::new(UpdateQueueState::Idle);
+
+/// Held by the update thread.  Puts UPDATE_THREAD_STATE back to Idle if the
+/// thread panics, which would otherwise leave it Running and make every
+/// later start_update_thread() a no-op.
+struct UpdateThreadGuard;
+
+impl Drop for UpdateThreadGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            *UPDATE_THREAD_STATE
+                .lock()
+                .expect("Failed to acquire update thread lock.") = UpdateQueueState::Idle;
+        }
+    }
+}
+
+/// Whether an update is running or queued.  Does not require init.
+pub fn update_queue_state() -> UpdateQueueState {
+    let thread_state = *UPDATE_THREAD_STATE
+        .lock()
+        .expect("Failed to acquire update thread lock.");
+    match thread_state {
+        UpdateQueueState::Idle if crate::updater_lock::is_updating() => UpdateQueueState::Running,
+        state => state,
+    }
+}
+
 /// This does not return status.  The only output is the change to the saved
 /// cache. The Engine calls this during boot and it will check for an update
 /// and install it if available.
+///
+/// Calls while the update thread is already running (e.g. from several
+/// isolates) don't start another, they return immediately, at most queueing
+/// one follow-up run (see YamlConfig::queue_follow_up_update).
 pub fn start_update_thread() {
+    let (max_jitter, priority, queue_follow_up) = with_config(|config| {
+        Ok((
+            config.auto_update_jitter_seconds,
+            config.update_thread_priority,
+            config.queue_follow_up_update,
+        ))
+    })
+    .unwrap_or_default();
+    {
+        let mut state = UPDATE_THREAD_STATE
+            .lock()
+            .expect("Failed to acquire update thread lock.");
+        match *state {
+            UpdateQueueState::Idle => *state = UpdateQueueState::Running,
+            UpdateQueueState::Running if queue_follow_up => {
+                info!("Update thread is running, queueing a follow-up run.");
+                *state = UpdateQueueState::FollowUpQueued;
+                return;
+            }
+            _ => {
+                info!("Update thread is already running.");
+                return;
+            }
+        }
+    }
+    // Done here rather than in the thread, so an update() which cancels the
+    // delay before the thread starts waiting isn't forgotten.
+    reset_update_thread_delay();
     std::thread::spawn(move || {
-        let status = update().unwrap_or(UpdateStatus::UpdateHadError);
-        info!("Update thread finished with status: {}", status);
+        let _guard = UpdateThreadGuard;
+        set_current_thread_priority(priority);
+        let mut check = true;
+        let delay = random_delay(max_jitter);
+        if !delay.is_zero() {
+            info!("Delaying update check by {}s.", delay.as_secs());
+            if wait_unless_cancelled(delay) {
+                info!("Update check delay cancelled by explicit update.");
+                check = false;
+            }
+        }
+        loop {
+            if check {
+                let status = auto_update_check();
+                info!("Update thread finished with status: {}", status);
+            }
+            // The jitter delay is for launches, not needed for follow-ups.
+            if !finish_update_thread_run() {
+                break;
+            }
+            info!("Running queued follow-up update.");
+            check = true;
+        }
     });
 }
 
+/// Marks the update thread's run as done, returning whether a follow-up run
+/// was queued (and is now running).
+fn finish_update_thread_run() -> bool {
+    let mut state = UPDATE_THREAD_STATE
+        .lock()
+        .expect("Failed to acquire update thread lock.");
+    match *state {
+        UpdateQueueState::FollowUpQueued => {
+            *state = UpdateQueueState::Running;
+            true
+        }
+        _ => {
+            *state = UpdateQueueState::Idle;
+            false
+        }
+    }
+}
+
+/// Updates, unless auto_update_deferral() says an automatic check should
+/// not run now.
+fn auto_update_check() -> UpdateStatus {
+    // Fail open: if we can't read the directives, check anyway.
+    match auto_update_deferral() {
+        Ok(Some(reason)) => UpdateStatus::UpdateDeferred(reason),
+        _ => update().unwrap_or(UpdateStatus::UpdateHadError),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
@@ -397,6 +2530,10 @@ mod tests {
     use crate::config::testing_reset_config;
 
     fn init_for_testing(tmp_dir: &TempDir) {
+        init_for_testing_with_yaml(tmp_dir, "app_id: 1234");
+    }
+
+    fn init_for_testing_with_yaml(tmp_dir: &TempDir, yaml: &str) {
         testing_reset_config();
         let cache_dir = tmp_dir.path().to_str().unwrap().to_string();
         crate::init(
@@ -404,42 +2541,94 @@ mod tests {
                 cache_dir: cache_dir.clone(),
                 release_version: "1.0.0+1".to_string(),
                 original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+                ..Default::default()
             },
-            "app_id: 1234",
+            yaml,
         )
         .unwrap();
     }
 
-    #[serial]
-    #[test]
-    fn ignore_version_after_marked_bad() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        init_for_testing(&tmp_dir);
+    fn install_fake_patch(number: usize) {
+        install_fake_patch_with_contents(number, "hello");
+    }
 
-        use crate::cache::{PatchInfo, UpdaterState};
+    fn install_fake_patch_with_contents(number: usize, contents: &str) {
+        use crate::cache::PatchInfo;
         use crate::config::with_config;
 
-        // Install a fake patch.
         with_config(|config| {
             let download_dir = std::path::PathBuf::from(&config.download_dir);
-            let artifact_path = download_dir.join("1");
+            let artifact_path = download_dir.join(number.to_string());
             fs::create_dir_all(&download_dir).unwrap();
-            fs::write(&artifact_path, "hello").unwrap();
+            fs::write(&artifact_path, contents).unwrap();
 
-            let mut state =
-                UpdaterState::load_or_new_on_error(&config.cache_dir, &config.release_version);
-            state
-                .install_patch(PatchInfo {
+            let mut state = super::load_state(config);
+            state.install_patch(
+                PatchInfo {
                     path: artifact_path,
-                    number: 1,
-                })
-                .expect("move failed");
-            state.save().expect("save failed");
-            Ok(())
+                    number,
+                },
+                config.retain_patch_count,
+            )?;
+            state.save()
         })
         .unwrap();
-        assert!(crate::next_boot_patch().unwrap().is_some());
-        // pretend we booted from it
+    }
+
+    #[serial]
+    #[test]
+    fn init_with_builder() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        testing_reset_config();
+        crate::init_with(
+            crate::UpdaterConfigBuilder::new("1234")
+                .cache_dir(tmp_dir.path())
+                .release_version("1.0.0+1")
+                .libapp_path("/dir/lib/arch/libapp.so")
+                .channel("beta".parse().unwrap()),
+        )
+        .unwrap();
+        crate::config::with_config(|config| {
+            assert_eq!(config.app_id, "1234");
+            assert_eq!(config.channel.as_str(), "beta");
+            assert!(config.cache_dir.starts_with(tmp_dir.path()));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[serial]
+    #[test]
+    fn ignore_version_after_marked_bad() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+
+        use crate::cache::PatchInfo;
+        use crate::config::with_config;
+
+        // Install a fake patch.
+        with_config(|config| {
+            let download_dir = std::path::PathBuf::from(&config.download_dir);
+            let artifact_path = download_dir.join("1");
+            fs::create_dir_all(&download_dir).unwrap();
+            fs::write(&artifact_path, "hello").unwrap();
+
+            let mut state = super::load_state(config);
+            state
+                .install_patch(
+                    PatchInfo {
+                        path: artifact_path,
+                        number: 1,
+                    },
+                    config.retain_patch_count,
+                )
+                .expect("move failed");
+            state.save().expect("save failed");
+            Ok(())
+        })
+        .unwrap();
+        assert!(crate::next_boot_patch().unwrap().is_some());
+        // pretend we booted from it
         crate::report_launch_start().unwrap();
         crate::report_launch_success().unwrap();
         assert!(crate::next_boot_patch().unwrap().is_some());
@@ -450,6 +2639,561 @@ mod tests {
         assert!(crate::next_boot_patch().unwrap().is_none());
     }
 
+    #[serial]
+    #[test]
+    fn boot_decision_explains_missing_patch() {
+        use crate::{BootDecision, PatchRejection, RejectedPatch};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        assert_eq!(crate::boot_decision().unwrap(), BootDecision::NoPatch);
+        install_fake_patch(1);
+        assert_eq!(crate::boot_decision().unwrap(), BootDecision::Patch(1));
+
+        crate::report_launch_start().unwrap();
+        crate::report_launch_failure().unwrap();
+        assert_eq!(
+            crate::boot_decision().unwrap(),
+            BootDecision::Rejected(RejectedPatch {
+                patch_number: 1,
+                reason: PatchRejection::MarkedBad
+            })
+        );
+
+        install_fake_patch(2);
+        assert_eq!(crate::boot_decision().unwrap(), BootDecision::Patch(2));
+        let path = crate::next_boot_patch().unwrap().unwrap().path;
+        fs::remove_file(path).unwrap();
+        assert!(crate::next_boot_patch().unwrap().is_none());
+        assert_eq!(
+            crate::boot_decision().unwrap(),
+            BootDecision::Rejected(RejectedPatch {
+                patch_number: 2,
+                reason: PatchRejection::ArtifactMissing
+            })
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn repeated_launch_starts_are_ignored() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        crate::report_launch_start().unwrap();
+        crate::report_launch_success().unwrap();
+
+        // Hot restart re-runs the boot sequence, which shouldn't touch disk.
+        let state_path =
+            crate::config::with_config(|config| Ok(config.cache_dir.join("state.json"))).unwrap();
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&state_path)
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+        crate::report_launch_start().unwrap();
+        crate::begin_boot(1).unwrap();
+        assert_eq!(
+            fs::metadata(&state_path).unwrap().modified().unwrap(),
+            long_ago
+        );
+        // Nor restart the boot timing.
+        assert!(super::take_launch_duration().is_none());
+
+        // A newly installed patch is a real launch.
+        install_fake_patch(2);
+        crate::report_launch_start().unwrap();
+        assert_eq!(crate::current_boot_patch().unwrap().unwrap().number, 2);
+        assert!(super::take_launch_duration().is_some());
+    }
+
+    #[serial]
+    #[test]
+    fn changed_boot_artifact_is_marked_bad() {
+        use crate::{BootDecision, PatchRejection, RejectedPatch};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nverify_boot_artifact: true");
+        install_fake_patch_with_contents(1, "one");
+        crate::next_boot_patch().unwrap();
+        crate::report_launch_start().unwrap();
+        crate::report_launch_success().unwrap();
+
+        // Touched but unchanged is fine.
+        install_fake_patch_with_contents(2, "two");
+        let path = crate::next_boot_patch().unwrap().unwrap().path;
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+        crate::report_launch_start().unwrap();
+        assert_eq!(crate::boot_decision().unwrap(), BootDecision::Patch(2));
+
+        // Swapped between selection and boot.
+        install_fake_patch_with_contents(3, "three");
+        let path = crate::next_boot_patch().unwrap().unwrap().path;
+        fs::write(&path, "swapped").unwrap();
+        crate::report_launch_start().unwrap();
+        assert_eq!(crate::current_boot_patch().unwrap().unwrap().number, 2);
+        assert_ne!(crate::next_boot_patch().unwrap().map(|p| p.number), Some(3));
+        assert_eq!(
+            crate::boot_decision().unwrap(),
+            BootDecision::Rejected(RejectedPatch {
+                patch_number: 3,
+                reason: PatchRejection::ChangedBeforeBoot
+            })
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn prevalidate_next_boot_patch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+
+        crate::prevalidate_next_boot_patch();
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+        // The result is only used once.
+        assert!(super::take_prevalidation().is_none());
+
+        // A state change after prevalidating is not missed.
+        crate::prevalidate_next_boot_patch();
+        crate::report_launch_start().unwrap();
+        crate::report_launch_failure().unwrap();
+        assert!(crate::next_boot_patch().unwrap().is_none());
+
+        // Nor are results from another cache_dir.
+        crate::prevalidate_next_boot_patch();
+        let other_dir = TempDir::new("other").unwrap();
+        init_for_testing(&other_dir);
+        install_fake_patch(2);
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 2);
+    }
+
+    #[serial]
+    #[test]
+    fn health_check_finds_problems() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        crate::report_launch_start().unwrap();
+        let failing = |report: &crate::HealthReport| -> Vec<String> {
+            let failing = report.checks.iter().filter(|check| !check.ok);
+            failing.map(|check| check.name.clone()).collect()
+        };
+        let report = crate::health_check().unwrap();
+        assert!(report.healthy, "{:?}", report);
+        assert_eq!(report.checks[3].detail.as_deref(), Some("patch 1"));
+
+        let path = crate::current_boot_patch().unwrap().unwrap().path;
+        fs::write(&path, "corrupt").unwrap();
+        let report = crate::health_check().unwrap();
+        assert!(!report.healthy);
+        assert_eq!(failing(&report), vec!["current_patch_valid"]);
+
+        let config = super::copy_update_config().unwrap();
+        fs::write(config.cache_dir.join("state.json"), "{").unwrap();
+        let report = crate::health_check().unwrap();
+        assert_eq!(failing(&report), vec!["state_file_parses"]);
+    }
+
+    #[serial]
+    #[test]
+    fn last_install_transaction() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        assert_eq!(crate::last_install_transaction().unwrap(), None);
+        install_fake_patch(1);
+        let transaction = crate::last_install_transaction().unwrap().unwrap();
+        assert_eq!(transaction.patch_number, 1);
+        assert!(transaction.completed);
+        assert_eq!(
+            transaction.steps,
+            vec![
+                crate::InstallStep::ArtifactStored,
+                crate::InstallStep::StateSaved,
+                crate::InstallStep::ReplacedRemoved
+            ]
+        );
+
+        // As if the process died while saving the state for patch 2.
+        let config = super::copy_update_config().unwrap();
+        let interrupted = crate::InstallTransaction {
+            patch_number: 2,
+            steps: vec![crate::InstallStep::ArtifactStored],
+            completed: false,
+            ..transaction
+        };
+        fs::write(
+            config.cache_dir.join("install_transaction.json"),
+            serde_json::to_string(&interrupted).unwrap(),
+        )
+        .unwrap();
+        let report = crate::health_check().unwrap();
+        let check = report.checks.last().unwrap();
+        assert_eq!(check.name, "last_install_completed");
+        assert!(!check.ok);
+        assert_eq!(
+            check.detail.as_deref(),
+            Some("install of patch 2 stopped at: save state")
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn boot_timing_is_reported() {
+        fn no_patch() -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            Ok(crate::network::PatchCheckResponse::default().into())
+        }
+        fn check_boot_timing(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            let timing = request.last_boot.expect("boot timing");
+            assert_eq!(timing.patch_number, 1);
+            assert!(timing.duration_ms >= 10);
+            no_patch()
+        }
+        fn check_no_boot_timing(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            assert!(request.last_boot.is_none());
+            no_patch()
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        crate::report_launch_start().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        crate::report_launch_success().unwrap();
+
+        crate::testing_set_network_hooks(check_boot_timing, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert_eq!(crate::update().unwrap(), crate::UpdateStatus::NoUpdate);
+        // Only sent once.
+        crate::testing_set_network_hooks(check_no_boot_timing, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert_eq!(crate::update().unwrap(), crate::UpdateStatus::NoUpdate);
+    }
+
+    #[serial]
+    #[test]
+    fn installed_patch_numbers_are_reported() {
+        fn check_installed(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            anyhow::bail!("installed: {:?}", request.installed_patch_numbers)
+        }
+        fn check() -> String {
+            crate::testing_set_network_hooks(check_installed, |_url, _resume_from| {
+                anyhow::bail!("unexpected download")
+            });
+            format!("{:#}", crate::check_for_update().err().unwrap())
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        let error = check();
+        assert!(error.contains("installed: None"), "{}", error);
+
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nreport_installed_patches: true");
+        // Booted successfully, so kept as a fallback.
+        crate::report_launch_start().unwrap();
+        crate::report_launch_success().unwrap();
+        install_fake_patch_with_contents(3, "three");
+        let error = check();
+        assert!(error.contains("installed: Some([1, 3])"), "{}", error);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[serial]
+    #[test]
+    fn next_boot_patch_memfd() {
+        use std::io::Read;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        // Off by default.
+        assert!(crate::next_boot_patch_memfd().unwrap().is_none());
+
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nmemfd_max_bytes: 5");
+        let mut contents = String::new();
+        crate::next_boot_patch_memfd()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hello");
+
+        // Larger patches are left on disk.
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nmemfd_max_bytes: 4");
+        assert!(crate::next_boot_patch_memfd().unwrap().is_none());
+    }
+
+    #[serial]
+    #[test]
+    fn decode_benchmark_is_reported_once() {
+        fn check(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            anyhow::bail!("benchmark sent: {}", request.decode_benchmark.is_some())
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(check, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        // Off unless the app opts in.
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("benchmark sent: false"));
+
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\ndecode_benchmark: true");
+        crate::testing_set_network_hooks(check, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("benchmark sent: true"));
+        // Kept until a check succeeds.
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("benchmark sent: true"));
+
+        let config = super::copy_update_config().unwrap();
+        let mut state = super::load_state(&config);
+        state.record_patch_check(Default::default(), 0);
+        state.save().unwrap();
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("benchmark sent: false"));
+    }
+
+    #[serial]
+    #[test]
+    fn download_refreshes_expired_url() {
+        use crate::cache::PartialDownload;
+        use crate::network::DownloadFileResponse;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        static FIRST_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+        fn patch(download_url: String) -> crate::Patch {
+            crate::Patch {
+                number: 1,
+                hash: "#".to_string(),
+                download_url,
+                ..Default::default()
+            }
+        }
+        // Each check hands out a new URL: url-1, url-2, ...
+        fn check_fresh_url(
+            _url: &str,
+            _request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            let checks = CHECKS.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(patch(format!("url-{}", checks))),
+                ..Default::default()
+            }
+            .into())
+        }
+        /// Drops the connection, like a connection reset.
+        struct Dropped;
+        impl std::io::Read for Dropped {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::ConnectionReset.into())
+            }
+        }
+        // url-0 is interrupted and then expires, url-1 resumes it.  Every
+        // other URL has expired.
+        fn download(
+            url: &str,
+            resume_from: Option<&PartialDownload>,
+        ) -> anyhow::Result<DownloadFileResponse> {
+            match url {
+                "url-0" if FIRST_DOWNLOADS.fetch_add(1, Ordering::SeqCst) == 0 => {
+                    Ok(DownloadFileResponse {
+                        is_partial: false,
+                        etag: Some("etag".to_string()),
+                        total_bytes: None,
+                        body: Box::new(std::io::Read::chain(&b"hello "[..], Dropped)),
+                    })
+                }
+                "url-1" => {
+                    let partial = resume_from.expect("resumed download");
+                    assert_eq!(partial.bytes_downloaded, 6);
+                    assert_eq!(partial.etag.as_deref(), Some("etag"));
+                    Ok(DownloadFileResponse {
+                        is_partial: true,
+                        etag: Some("etag".to_string()),
+                        total_bytes: None,
+                        body: Box::new(&b"world"[..]),
+                    })
+                }
+                _ => Err(crate::UpdateError::DownloadUrlExpired.into()),
+            }
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(check_fresh_url, download);
+        let config = super::copy_update_config().unwrap();
+        let mut state = super::load_state(&config);
+        let download_path = tmp_dir.path().join("download");
+
+        let first = patch("url-0".to_string());
+        super::download_patch(&config, &mut state, &first, false, &download_path, None)
+            .unwrap_err();
+        assert_eq!(CHECKS.load(Ordering::SeqCst), 0);
+        super::download_patch(&config, &mut state, &first, false, &download_path, None).unwrap();
+        assert_eq!(CHECKS.load(Ordering::SeqCst), 1);
+        assert_eq!(fs::read_to_string(&download_path).unwrap(), "hello world");
+
+        // We give up if fresh URLs keep expiring.
+        let error = super::download_patch(
+            &config,
+            &mut state,
+            &patch("url-expired".to_string()),
+            false,
+            &download_path,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            CHECKS.load(Ordering::SeqCst),
+            1 + super::MAX_DOWNLOAD_URL_REFRESHES
+        );
+        assert_eq!(
+            crate::UpdateErrorKind::from(&error),
+            crate::UpdateErrorKind::DownloadUrlExpired
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn build_number_mismatch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let mut config = super::copy_update_config().unwrap();
+        let mut patch = crate::Patch {
+            number: 1,
+            hash: "ignored".to_owned(),
+            download_url: "ignored".to_owned(),
+            ..Default::default()
+        };
+        // Unconstrained patches install on any build.
+        assert_eq!(super::build_number_mismatch(&config, &patch), None);
+
+        // Constrained patches are refused if we don't know our build number.
+        patch.min_build_number = Some(2);
+        patch.max_build_number = Some(4);
+        assert!(super::build_number_mismatch(&config, &patch).is_some());
+
+        config.build_number = Some(1);
+        assert!(super::build_number_mismatch(&config, &patch).is_some());
+        config.build_number = Some(2);
+        assert_eq!(super::build_number_mismatch(&config, &patch), None);
+        config.build_number = Some(4);
+        assert_eq!(super::build_number_mismatch(&config, &patch), None);
+        config.build_number = Some(5);
+        assert!(super::build_number_mismatch(&config, &patch).is_some());
+    }
+
+    #[serial]
+    #[test]
+    fn native_fingerprint_mismatch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let mut config = super::copy_update_config().unwrap();
+        let mut patch = crate::Patch {
+            number: 1,
+            hash: "ignored".to_owned(),
+            download_url: "ignored".to_owned(),
+            ..Default::default()
+        };
+        // Patches without a required fingerprint install anywhere.
+        assert_eq!(super::native_fingerprint_mismatch(&config, &patch), None);
+        config.native_fingerprint = Some("abc".to_owned());
+        assert_eq!(super::native_fingerprint_mismatch(&config, &patch), None);
+
+        patch.native_fingerprint = Some("abc".to_owned());
+        assert_eq!(super::native_fingerprint_mismatch(&config, &patch), None);
+        patch.native_fingerprint = Some("def".to_owned());
+        assert!(super::native_fingerprint_mismatch(&config, &patch).is_some());
+        config.native_fingerprint = None;
+        assert!(super::native_fingerprint_mismatch(&config, &patch).is_some());
+    }
+
+    #[test]
+    fn updater_version_mismatch() {
+        let mut patch = crate::Patch {
+            number: 1,
+            hash: "ignored".to_owned(),
+            download_url: "ignored".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(super::updater_version_mismatch(&patch, "0.1.0"), None);
+
+        let mut mismatch = |required: &str, ours: &str| {
+            patch.min_updater_version = Some(required.to_owned());
+            super::updater_version_mismatch(&patch, ours).is_some()
+        };
+        assert!(!mismatch("1.2.0", "1.2.0"));
+        assert!(!mismatch("1.2", "1.2.0"));
+        assert!(!mismatch("1.2.0", "1.10.0"));
+        assert!(!mismatch("1.2.0-beta", "1.2.0"));
+        assert!(mismatch("1.2.1", "1.2.0"));
+        assert!(mismatch("2.0.0", "1.99.99"));
+        assert!(mismatch("1.2.0.1", "1.2"));
+        // Unknown schemes are assumed to be newer than us.
+        assert!(mismatch("v2", "1.2.0"));
+        assert!(!mismatch(super::UPDATER_VERSION, super::UPDATER_VERSION));
+    }
+
+    #[serial]
+    #[test]
+    fn unfinished_boot_is_a_failure() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+
+        // Only the next boot patch may be booted.
+        assert!(crate::begin_boot(2).is_err());
+        crate::begin_boot(1).unwrap();
+        assert_eq!(crate::current_boot_patch().unwrap().unwrap().number, 1);
+
+        // Crash (never report success or failure) and start again.
+        init_for_testing(&tmp_dir);
+        assert!(crate::next_boot_patch().unwrap().is_none());
+    }
+
+    #[serial]
+    #[test]
+    fn finished_boot_is_not_a_failure() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+
+        crate::begin_boot(1).unwrap();
+        crate::report_launch_success().unwrap();
+        init_for_testing(&tmp_dir);
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+    }
+
     #[test]
     fn hash_matches() {
         let tmp_dir = TempDir::new("example").unwrap();
@@ -494,6 +3238,7 @@ mod tests {
                     cache_dir: cache_dir.clone(),
                     release_version: "1.0.0+1".to_string(),
                     original_libapp_paths: vec!["original_libapp_path".to_string()],
+                    ..Default::default()
                 },
                 "",
             ),
@@ -504,6 +3249,1660 @@ mod tests {
         );
     }
 
+    #[serial]
+    #[test]
+    fn init_with_app_id_override() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path().to_str().unwrap().to_string();
+        let app_config = || crate::AppConfig {
+            cache_dir: cache_dir.clone(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            app_id_override: Some("override".to_string()),
+            ..Default::default()
+        };
+
+        // Overrides are ignored unless shorebird.yaml allows them.
+        testing_reset_config();
+        crate::init(app_config(), "app_id: 1234").unwrap();
+        let config = super::copy_update_config().unwrap();
+        assert_eq!(config.app_id, "1234");
+        assert_eq!(config.cache_dir, tmp_dir.path().join("channels/stable"));
+
+        // State for overridden app_ids is kept separately on disk.
+        testing_reset_config();
+        let yaml = "app_id: 1234\nallow_app_id_override: true";
+        crate::init(app_config(), yaml).unwrap();
+        let config = super::copy_update_config().unwrap();
+        assert_eq!(config.app_id, "override");
+        assert_eq!(
+            config.cache_dir,
+            tmp_dir.path().join("apps/override/channels/stable")
+        );
+        assert!(config.download_dir.starts_with(&config.cache_dir));
+
+        // app_ids are used as path components, so must not contain separators.
+        testing_reset_config();
+        let mut bad_config = app_config();
+        bad_config.app_id_override = Some("../escape".to_string());
+        assert!(crate::init(bad_config, yaml).is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn locale_is_only_reported_if_enabled() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let app_config = |locale: &str| crate::AppConfig {
+            cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            locale: Some(locale.to_string()),
+            ..Default::default()
+        };
+
+        testing_reset_config();
+        crate::init(app_config("zh_Hant_TW"), "app_id: 1234").unwrap();
+        assert_eq!(super::copy_update_config().unwrap().locale, None);
+
+        let yaml = "app_id: 1234\nreport_locale: true";
+        testing_reset_config();
+        crate::init(app_config("zh_Hant_TW"), yaml).unwrap();
+        assert_eq!(
+            super::copy_update_config().unwrap().locale.as_deref(),
+            Some("zh-TW")
+        );
+
+        // Unrecognized locales are dropped rather than failing init.
+        testing_reset_config();
+        crate::init(app_config("C"), yaml).unwrap();
+        assert_eq!(super::copy_update_config().unwrap().locale, None);
+    }
+
+    #[serial]
+    #[test]
+    fn init_validates_channel_and_release_version() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let app_config = |release_version: &str| crate::AppConfig {
+            cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+            release_version: release_version.to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            ..Default::default()
+        };
+
+        // Surrounding whitespace is trimmed.
+        testing_reset_config();
+        crate::init(app_config(" 1.0.0+1\n"), "app_id: 1234\nchannel: ' beta'").unwrap();
+        let config = super::copy_update_config().unwrap();
+        assert_eq!(config.release_version, "1.0.0+1");
+        assert_eq!(config.channel, "beta");
+        assert_eq!(config.cache_dir, tmp_dir.path().join("channels/beta"));
+
+        testing_reset_config();
+        let error = crate::init(app_config(""), "app_id: 1234").unwrap_err();
+        assert!(error.to_string().contains("release_version"), "{}", error);
+        testing_reset_config();
+        let yaml = "app_id: 1234\nchannel: 'my channel'";
+        let error = crate::init(app_config("1.0.0+1"), yaml).unwrap_err();
+        assert!(error.to_string().contains("my channel"), "{}", error);
+        testing_reset_config();
+        assert!(crate::init(
+            app_config("1.0.0+1"),
+            "app_id: 1234\nchannel_fallbacks: ['']"
+        )
+        .is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn init_with_download_dir() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let storage_dir = TempDir::new("storage").unwrap();
+        let app_config = |download_dir: &std::path::Path| crate::AppConfig {
+            cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            download_dir: Some(download_dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        testing_reset_config();
+        crate::init(app_config(storage_dir.path()), "app_id: 1234").unwrap();
+        let config = super::copy_update_config().unwrap();
+        assert_eq!(
+            config.download_dir,
+            storage_dir.path().join("channels/stable/downloads")
+        );
+
+        // Unwritable directories fall back to the default.
+        let file = storage_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        testing_reset_config();
+        crate::init(app_config(&file), "app_id: 1234").unwrap();
+        let config = super::copy_update_config().unwrap();
+        assert_eq!(config.download_dir, config.cache_dir.join("downloads"));
+    }
+
+    #[serial]
+    #[test]
+    fn init_validates_yaml() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let cache_dir = tmp_dir.path().to_str().unwrap().to_string();
+        let app_config = || crate::AppConfig {
+            cache_dir: cache_dir.clone(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            ..Default::default()
+        };
+
+        // Unknown keys are reported but do not prevent init.
+        testing_reset_config();
+        crate::init(app_config(), "app_id: 1234\nauto_updates: false").unwrap();
+        assert_eq!(
+            crate::config_warnings().unwrap(),
+            vec!["Unknown key in shorebird.yaml: auto_updates".to_string()]
+        );
+
+        // Unless strict_config is set.
+        testing_reset_config();
+        let yaml = "app_id: 1234\nauto_updates: false\nstrict_config: true";
+        assert!(crate::init(app_config(), yaml).is_err());
+
+        testing_reset_config();
+        crate::init(app_config(), "app_id: 1234\nstrict_config: true").unwrap();
+        assert!(crate::config_warnings().unwrap().is_empty());
+
+        // base_url must be an absolute http(s) URL.
+        for base_url in ["api.shorebird.dev", "ftp://example.com", "not a url"] {
+            testing_reset_config();
+            let yaml = format!("app_id: 1234\nbase_url: {}", base_url);
+            assert!(crate::init(app_config(), &yaml).is_err(), "{}", base_url);
+        }
+        testing_reset_config();
+        let yaml = "app_id: 1234\nbase_url: http://localhost:8080";
+        crate::init(app_config(), yaml).unwrap();
+
+        // expected_apk_cert_sha256 must be a sha256, colons are allowed.
+        testing_reset_config();
+        let yaml = "app_id: 1234\nexpected_apk_cert_sha256: AB:CD";
+        assert!(crate::init(app_config(), yaml).is_err());
+        testing_reset_config();
+        let yaml = format!(
+            "app_id: 1234\nexpected_apk_cert_sha256: \"{}\"",
+            ["AB"; 32].join(":")
+        );
+        crate::init(app_config(), &yaml).unwrap();
+    }
+
+    #[serial]
+    #[test]
+    fn auto_update_runtime_overrides() {
+        use crate::DeferReason;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nauto_update: false");
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(DeferReason::AutoUpdateDisabledByConfig)
+        );
+        crate::set_auto_update(Some(true));
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+        assert!(crate::c_api::shorebird_should_auto_update());
+
+        init_for_testing(&tmp_dir);
+        crate::set_auto_update(None);
+        crate::set_metered_network(true);
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(DeferReason::MeteredNetwork)
+        );
+        crate::set_auto_update(Some(false));
+        assert_eq!(
+            crate::c_api::shorebird_auto_update_defer_reason(),
+            crate::c_api::ShorebirdDeferReason::AutoUpdateDisabledByApp
+        );
+        assert!(!crate::c_api::shorebird_should_auto_update());
+
+        crate::c_api::shorebird_clear_auto_update();
+        crate::set_metered_network(false);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+
+        // Runtime settings don't outlive a test's config.
+        crate::set_auto_update(Some(false));
+        crate::set_metered_network(true);
+        testing_reset_config();
+        assert_eq!(
+            crate::c_api::shorebird_auto_update_defer_reason(),
+            crate::c_api::ShorebirdDeferReason::NotInitialized
+        );
+        assert!(!crate::c_api::shorebird_should_auto_update());
+        init_for_testing(&tmp_dir);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+    }
+
+    #[serial]
+    #[test]
+    fn advancing_the_clock_ends_server_backoff() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                Ok(crate::network::PatchCheckResponse {
+                    client_directives: crate::network::ClientDirectives {
+                        check_interval_seconds: Some(3600),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }
+                .into())
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        // Stop the clock, so the check below sees exactly 3599 seconds pass.
+        crate::testing_advance_clock(0);
+        assert!(matches!(crate::update(), Ok(crate::UpdateStatus::NoUpdate)));
+        crate::testing_advance_clock(3599);
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(crate::DeferReason::CheckThrottled)
+        );
+        crate::testing_advance_clock(1);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+    }
+
+    #[serial]
+    #[test]
+    fn client_directives_throttle_auto_update() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let clock = std::sync::Arc::new(crate::time::FakeClock::new(1000));
+        crate::config::testing_set_clock(clock.clone());
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                Ok(crate::network::PatchCheckResponse {
+                    client_directives: crate::network::ClientDirectives {
+                        check_interval_seconds: Some(60),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }
+                .into())
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+        assert!(matches!(crate::update(), Ok(crate::UpdateStatus::NoUpdate)));
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(crate::DeferReason::CheckThrottled)
+        );
+        clock.advance(60);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                Ok(crate::network::PatchCheckResponse {
+                    client_directives: crate::network::ClientDirectives {
+                        disable_auto_update: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }
+                .into())
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert!(crate::check_for_update().is_ok());
+        // Persisted across launches.
+        init_for_testing(&tmp_dir);
+        crate::config::testing_set_clock(clock.clone());
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(crate::DeferReason::AutoUpdateDisabled)
+        );
+        // Until it expires, as nothing checks to replace it.
+        clock.advance(super::DEFAULT_DISABLE_AUTO_UPDATE_SECONDS - 1);
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(crate::DeferReason::AutoUpdateDisabled)
+        );
+        clock.advance(1);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+    }
+
+    #[serial]
+    #[test]
+    fn known_bad_critical_patch_is_not_pending() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                Ok(crate::network::PatchCheckResponse {
+                    patch_available: true,
+                    patch: Some(crate::Patch {
+                        number: 1,
+                        hash: "#".to_string(),
+                        download_url: "ignored".to_string(),
+                        priority: crate::PatchPriority::Critical,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+                .into())
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        let config = super::copy_update_config().unwrap();
+        assert!(crate::check_for_update().unwrap());
+        assert_eq!(super::load_state(&config).critical_patch_number(), Some(1));
+
+        let mut state = super::load_state(&config);
+        state.mark_patch_as_bad(1);
+        state.save().unwrap();
+        assert_eq!(super::load_state(&config).critical_patch_number(), None);
+        // The server offering it again doesn't make it pending again.
+        crate::check_for_update().unwrap();
+        assert_eq!(super::load_state(&config).critical_patch_number(), None);
+    }
+
+    #[serial]
+    #[test]
+    fn health_check_leaves_missing_dirs_alone() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let config = super::copy_update_config().unwrap();
+        let _ = fs::remove_dir_all(&config.download_dir);
+
+        let report = crate::health_check().unwrap();
+        assert!(report.healthy, "{:?}", report);
+        assert!(!config.download_dir.exists());
+        let download_check = report
+            .checks
+            .iter()
+            .find(|check| check.name == "download_dir_writable")
+            .unwrap();
+        assert_eq!(download_check.detail.as_deref(), Some("not created yet"));
+    }
+
+    #[serial]
+    #[test]
+    fn delegate_is_told_about_rollbacks() {
+        #[derive(Default)]
+        struct Recorder {
+            invalidated: std::sync::Mutex<Vec<usize>>,
+        }
+        impl crate::UpdaterDelegate for Recorder {
+            fn on_patch_invalidated(&self, patch_number: usize) {
+                self.invalidated.lock().unwrap().push(patch_number);
+            }
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        install_fake_patch(2);
+        let recorder = std::sync::Arc::new(Recorder::default());
+        crate::set_delegate(Some(recorder.clone()));
+        assert_eq!(super::rollback_last_patch().unwrap(), 2);
+        crate::set_delegate(None);
+        assert_eq!(*recorder.invalidated.lock().unwrap(), [2]);
+    }
+
+    #[serial]
+    #[test]
+    fn delegate_is_told_about_update_errors() {
+        #[derive(Default)]
+        struct Recorder {
+            errors: std::sync::Mutex<Vec<String>>,
+        }
+        impl crate::UpdaterDelegate for Recorder {
+            fn on_update_error(&self, error: &anyhow::Error) {
+                self.errors.lock().unwrap().push(format!("{:#}", error));
+            }
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let recorder = std::sync::Arc::new(Recorder::default());
+        crate::set_delegate(Some(recorder.clone()));
+        crate::testing_set_network_hooks(
+            |_url, _request| anyhow::bail!("server unreachable"),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert!(crate::update().is_err());
+        crate::set_delegate(None);
+        assert!(crate::update().is_err());
+
+        let errors = recorder.errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("server unreachable"), "{}", errors[0]);
+    }
+
+    #[serial]
+    #[test]
+    fn server_backoff_is_respected() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let clock = std::sync::Arc::new(crate::time::FakeClock::new(1000));
+        crate::config::testing_set_clock(clock.clone());
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                let mut response: crate::network::PatchCheckHttpResponse =
+                    crate::network::PatchCheckResponse::default().into();
+                response.retry_after_seconds = Some(120);
+                Ok(response)
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        let error = crate::update().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(crate::UpdateError::ServerBusy(120))
+        ));
+
+        // No requests are sent until the backoff has passed.
+        crate::testing_set_network_hooks(
+            |_url, _request| anyhow::bail!("unexpected check"),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert_eq!(
+            super::auto_update_deferral().unwrap(),
+            Some(crate::DeferReason::ServerBackoff)
+        );
+        assert_eq!(
+            crate::update().unwrap(),
+            crate::UpdateStatus::UpdateDeferred(crate::DeferReason::ServerBackoff)
+        );
+        clock.advance(100);
+        let error = super::check_for_update_internal().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(crate::UpdateError::ServerBusy(20))
+        ));
+
+        clock.advance(20);
+        assert_eq!(super::auto_update_deferral().unwrap(), None);
+    }
+
+    #[serial]
+    #[test]
+    fn disable_patches_uninstalls_patches() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        install_fake_patch(1);
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                Ok(crate::network::PatchCheckResponse {
+                    disable_patches: true,
+                    ..Default::default()
+                }
+                .into())
+            },
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert_eq!(
+            crate::update().unwrap(),
+            crate::UpdateStatus::UpdateDeferred(crate::DeferReason::PatchesDisabled)
+        );
+        assert!(crate::next_boot_patch().unwrap().is_none());
+    }
+
+    #[serial]
+    #[test]
+    fn last_update_attempt_is_recorded() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let clock = std::sync::Arc::new(crate::time::FakeClock::new(1000));
+        crate::config::testing_set_clock(clock.clone());
+        assert_eq!(crate::last_update_attempt().unwrap(), None);
+
+        crate::testing_set_network_hooks(
+            |_url, _request| Ok(crate::network::PatchCheckResponse::default().into()),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        crate::update().unwrap();
+        assert_eq!(
+            crate::last_update_attempt().unwrap(),
+            Some(crate::UpdateAttempt {
+                timestamp: 1000,
+                status: crate::UpdateStatus::NoUpdate,
+                error: None,
+            })
+        );
+
+        clock.advance(60);
+        crate::testing_set_network_hooks(
+            |_url, _request| Err(crate::UpdateError::BadServerResponse.into()),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert!(crate::update().is_err());
+        // Persisted across launches.
+        init_for_testing(&tmp_dir);
+        assert_eq!(
+            crate::last_update_attempt().unwrap(),
+            Some(crate::UpdateAttempt {
+                timestamp: 1060,
+                status: crate::UpdateStatus::UpdateHadError,
+                error: Some(crate::UpdateErrorKind::BadServerResponse),
+            })
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn offline_mode_skips_network() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(
+            |_url, _request| panic!("unexpected patch check"),
+            |_url, _resume_from| panic!("unexpected download"),
+        );
+        crate::set_offline(true);
+        let status = crate::update();
+        let check = crate::check_for_update();
+        let deferral = super::auto_update_deferral();
+        // Reset before asserting so other tests aren't left offline.
+        crate::set_offline(false);
+
+        assert_eq!(
+            status.unwrap(),
+            crate::UpdateStatus::UpdateDeferred(crate::DeferReason::Offline)
+        );
+        assert_eq!(
+            check.unwrap_err().downcast::<crate::UpdateError>().unwrap(),
+            crate::UpdateError::Offline
+        );
+        assert_eq!(deferral.unwrap(), Some(crate::DeferReason::Offline));
+    }
+
+    #[test]
+    fn sync_file_and_parent() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("patch");
+        assert!(super::sync_file_and_parent(&path).is_err());
+        fs::write(&path, "hello").unwrap();
+        super::sync_file_and_parent(&path).unwrap();
+    }
+
+    #[test]
+    fn random_delay() {
+        assert!(super::random_delay(0).is_zero());
+        for _ in 0..100 {
+            assert!(super::random_delay(10) <= std::time::Duration::from_secs(10));
+        }
+    }
+
+    #[serial]
+    #[test]
+    fn update_thread_coalesces_starts() {
+        use crate::UpdateQueueState;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+        fn blocking_check(
+            _url: &str,
+            _request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            CHECKS.fetch_add(1, Ordering::SeqCst);
+            while !RELEASED.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            Ok(crate::network::PatchCheckResponse::default().into())
+        }
+        let wait_until_idle = || {
+            for _ in 0..1000 {
+                if super::update_queue_state() == UpdateQueueState::Idle {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+            panic!("Update thread did not finish.");
+        };
+
+        for (yaml, queued_state, expected_checks) in [
+            ("app_id: 1234", UpdateQueueState::Running, 1),
+            (
+                "app_id: 1234\nqueue_follow_up_update: true",
+                UpdateQueueState::FollowUpQueued,
+                2,
+            ),
+        ] {
+            // Other tests may have left an update thread finishing.
+            wait_until_idle();
+            let tmp_dir = TempDir::new("example").unwrap();
+            init_for_testing_with_yaml(&tmp_dir, yaml);
+            crate::testing_set_network_hooks(blocking_check, |_url, _resume_from| {
+                anyhow::bail!("unexpected download")
+            });
+            CHECKS.store(0, Ordering::SeqCst);
+            RELEASED.store(false, Ordering::SeqCst);
+            for _ in 0..5 {
+                crate::start_update_thread();
+            }
+            assert_eq!(super::update_queue_state(), queued_state);
+            RELEASED.store(true, Ordering::SeqCst);
+            wait_until_idle();
+            assert_eq!(CHECKS.load(Ordering::SeqCst), expected_checks);
+        }
+    }
+
+    /// A check hook offering patch 1, whose download fails.
+    fn offer_patch_1(
+        _url: &str,
+        _request: crate::PatchCheckRequest,
+    ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+        Ok(crate::network::PatchCheckResponse {
+            patch_available: true,
+            patch: Some(crate::Patch {
+                number: 1,
+                hash: "#".to_string(),
+                download_url: "https://example.com/patch".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .into())
+    }
+
+    #[serial]
+    #[test]
+    fn patch_check_response_callback_can_veto() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static ALLOW: AtomicBool = AtomicBool::new(false);
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(offer_patch_1, |_url, _resume_from| {
+            anyhow::bail!("download attempted")
+        });
+        super::set_patch_check_response_callback(Some(std::sync::Arc::new(|response| {
+            let response: serde_json::Value = serde_json::from_str(response).unwrap();
+            assert_eq!(response["patch"]["number"], 1);
+            ALLOW.load(Ordering::SeqCst)
+        })));
+
+        ALLOW.store(false, Ordering::SeqCst);
+        assert_eq!(
+            crate::update().unwrap(),
+            super::UpdateStatus::UpdateDeferred(super::DeferReason::VetoedByApp)
+        );
+        ALLOW.store(true, Ordering::SeqCst);
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("download attempted"));
+        super::set_patch_check_response_callback(None);
+    }
+
+    #[serial]
+    #[test]
+    fn staged_patch_with_legacy_name_installs() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let config = super::copy_update_config().unwrap();
+        fs::create_dir_all(&config.download_dir).unwrap();
+        fs::write(config.download_dir.join("1.full"), "patch").unwrap();
+        let mut state = super::load_state(&config);
+        state.set_staged_patch_number(Some(1));
+        state.save().unwrap();
+
+        assert_eq!(
+            crate::install_downloaded_update().unwrap(),
+            super::UpdateStatus::UpdateInstalled
+        );
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+        assert!(!config.download_dir.join("1.full").exists());
+    }
+
+    #[serial]
+    #[test]
+    fn pending_store_update_defers_download() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        static PENDING: AtomicBool = AtomicBool::new(true);
+        static ASKED: AtomicUsize = AtomicUsize::new(0);
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(offer_patch_1, |_url, _resume_from| {
+            anyhow::bail!("download attempted")
+        });
+        super::set_store_update_pending_callback(Some(std::sync::Arc::new(|| {
+            ASKED.fetch_add(1, Ordering::SeqCst);
+            PENDING.load(Ordering::SeqCst)
+        })));
+
+        PENDING.store(true, Ordering::SeqCst);
+        assert_eq!(
+            crate::update().unwrap(),
+            super::UpdateStatus::UpdateDeferred(super::DeferReason::StoreUpdatePending)
+        );
+        assert_eq!(ASKED.load(Ordering::SeqCst), 1);
+        PENDING.store(false, Ordering::SeqCst);
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("download attempted"));
+        super::set_store_update_pending_callback(None);
+    }
+
+    #[serial]
+    #[test]
+    fn panicking_updates_dont_wedge_update_state() {
+        use crate::UpdateQueueState;
+
+        // A panic during an update must not leave it looking like it runs.
+        let result = std::panic::catch_unwind(|| {
+            crate::updater_lock::with_updater_thread_lock(|_| -> anyhow::Result<()> {
+                assert!(crate::updater_lock::is_updating());
+                panic!("update panicked")
+            })
+        });
+        assert!(result.is_err());
+        assert!(!crate::updater_lock::is_updating());
+        assert!(crate::updater_lock::with_updater_thread_lock(|_| Ok(())).is_ok());
+
+        // Nor the update thread.
+        *super::UPDATE_THREAD_STATE.lock().unwrap() = UpdateQueueState::Running;
+        let thread = std::thread::spawn(|| {
+            let _guard = super::UpdateThreadGuard;
+            panic!("update thread panicked");
+        });
+        assert!(thread.join().is_err());
+        assert_eq!(super::update_queue_state(), UpdateQueueState::Idle);
+    }
+
+    #[serial]
+    #[test]
+    fn explicit_update_cancels_update_thread_delay() {
+        let hour = std::time::Duration::from_secs(3600);
+        // Cancelling before the wait begins still cancels it.
+        super::reset_update_thread_delay();
+        super::cancel_update_thread_delay();
+        assert!(super::wait_unless_cancelled(hour));
+
+        // As does cancelling during the wait (or before, the thread may not
+        // have started waiting yet, either way it must return).
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || sender.send(super::wait_unless_cancelled(hour)).unwrap());
+        super::cancel_update_thread_delay();
+        assert!(receiver.recv().unwrap());
+
+        // A cancellation is used up by the wait it cancels, so an uncancelled
+        // wait runs to completion.
+        assert!(!super::wait_unless_cancelled(
+            std::time::Duration::from_millis(1)
+        ));
+    }
+
+    #[serial]
+    #[test]
+    fn channel_fallbacks() {
+        // beta is unknown to the server, alpha has no patch, stable does.
+        fn check_by_channel(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            let mut response: crate::network::PatchCheckHttpResponse =
+                crate::network::PatchCheckResponse {
+                    patch_available: request.channel == "stable",
+                    patch: (request.channel == "stable").then(|| crate::Patch {
+                        number: 1,
+                        hash: "#".to_string(),
+                        download_url: "bogus_url".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+                .into();
+            response.not_found = request.channel == "beta";
+            Ok(response)
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(
+            &tmp_dir,
+            "app_id: 1234\nchannel: beta\nchannel_fallbacks: [alpha, stable]",
+        );
+        crate::testing_set_network_hooks(check_by_channel, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert!(crate::check_for_update().unwrap());
+
+        // No fallback has a patch.
+        init_for_testing_with_yaml(
+            &tmp_dir,
+            "app_id: 1234\nchannel: beta\nchannel_fallbacks: [alpha]",
+        );
+        crate::testing_set_network_hooks(check_by_channel, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert!(!crate::check_for_update().unwrap());
+
+        // No fallbacks, and the server doesn't know the channel.
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nchannel: beta");
+        crate::testing_set_network_hooks(check_by_channel, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert!(crate::check_for_update().is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn canary_patches() {
+        // Channel "internal" lists this device, any other lists someone else.
+        fn check_canary(
+            _url: &str,
+            request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            assert_eq!(request.client_id.len(), 32);
+            let allowed = match request.channel.as_str() {
+                "internal" => request.client_id,
+                _ => "someone-else".to_string(),
+            };
+            Ok(crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(crate::Patch {
+                    number: 1,
+                    hash: "#".to_string(),
+                    download_url: "bogus_url".to_string(),
+                    canary: true,
+                    allowed_client_ids: Some(vec![allowed]),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+            .into())
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let client_id = crate::client_id().unwrap();
+        assert_eq!(crate::client_id().unwrap(), client_id);
+        crate::testing_set_network_hooks(check_canary, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        assert_eq!(crate::update().unwrap(), crate::UpdateStatus::NoUpdate);
+
+        // This device is allowed, so the download is attempted.
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nchannel: internal");
+        crate::testing_set_network_hooks(check_canary, |_url, _resume_from| {
+            anyhow::bail!("expected download")
+        });
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("expected download"));
+    }
+
+    #[serial]
+    #[test]
+    fn pinned_patches() {
+        fn offer(number: usize) -> crate::network::PatchCheckHttpResponse {
+            crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(crate::Patch {
+                    number,
+                    hash: "#".to_string(),
+                    download_url: format!("patch_{}", number),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+            .into()
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let yaml = "app_id: 1234\npinned_patch_number: 3";
+        let app_config = |debug_build| crate::AppConfig {
+            cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            debug_build,
+            ..Default::default()
+        };
+        // Release builds of the app ignore shorebird.yaml's pin.
+        testing_reset_config();
+        crate::init(app_config(false), yaml).unwrap();
+        assert_eq!(
+            super::copy_update_config().unwrap().pinned_patch_number,
+            None
+        );
+        testing_reset_config();
+        crate::init(app_config(true), yaml).unwrap();
+        install_fake_patch(1);
+        // Booted successfully, so kept as a fallback.
+        crate::report_launch_start().unwrap();
+        crate::report_launch_success().unwrap();
+        install_fake_patch(4);
+        // The pinned patch is requested (and downloaded) even though it is
+        // older than the installed patch.
+        crate::testing_set_network_hooks(
+            |_url, request| Ok(offer(request.pinned_patch_number.unwrap_or(4))),
+            |url, _resume_from| anyhow::bail!("download {}", url),
+        );
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("download patch_3"));
+
+        // A pin at runtime wins, and an installed patch is booted right away
+        // without checking again.
+        crate::pin_patch(Some(1)).unwrap();
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+        crate::testing_set_network_hooks(
+            |_url, _request| anyhow::bail!("unexpected check"),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert_eq!(crate::update().unwrap(), crate::UpdateStatus::NoUpdate);
+
+        // Other patches the server offers are ignored.
+        crate::pin_patch(Some(2)).unwrap();
+        crate::testing_set_network_hooks(
+            |_url, _request| Ok(offer(4)),
+            |_url, _resume_from| anyhow::bail!("unexpected download"),
+        );
+        assert_eq!(crate::update().unwrap(), crate::UpdateStatus::NoUpdate);
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+
+        // Without the runtime pin, shorebird.yaml's applies again.
+        crate::pin_patch(None).unwrap();
+        crate::testing_set_network_hooks(
+            |_url, request| Ok(offer(request.pinned_patch_number.unwrap_or(4))),
+            |url, _resume_from| anyhow::bail!("download {}", url),
+        );
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("download patch_3"));
+    }
+
+    #[serial]
+    #[test]
+    fn downloads_pause_in_background() {
+        use crate::cache::PartialDownload;
+        use crate::network::DownloadFileResponse;
+
+        fn check(
+            _url: &str,
+            _request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            Ok(crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(crate::Patch {
+                    number: 1,
+                    hash: "#".to_string(),
+                    download_url: "patch_1".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+            .into())
+        }
+        /// Sends "hello " and then the app goes into the background.
+        struct Backgrounded {
+            sent: bool,
+        }
+        impl std::io::Read for Backgrounded {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                assert!(!self.sent, "read after the app went into the background");
+                self.sent = true;
+                crate::on_app_background();
+                buf[..6].copy_from_slice(b"hello ");
+                Ok(6)
+            }
+        }
+        fn download(
+            _url: &str,
+            resume_from: Option<&PartialDownload>,
+        ) -> anyhow::Result<DownloadFileResponse> {
+            Ok(match resume_from {
+                None => DownloadFileResponse {
+                    is_partial: false,
+                    etag: None,
+                    total_bytes: None,
+                    body: Box::new(Backgrounded { sent: false }),
+                },
+                Some(partial) => {
+                    assert_eq!(partial.bytes_downloaded, 6);
+                    anyhow::bail!("resumed")
+                }
+            })
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        crate::testing_set_network_hooks(check, download);
+        let deferred = crate::UpdateStatus::UpdateDeferred(crate::DeferReason::AppInBackground);
+        assert_eq!(crate::update().unwrap(), deferred);
+        // Nothing is downloaded while in the background.
+        assert_eq!(crate::update().unwrap(), deferred);
+
+        // Back in the foreground the download picks up where it stopped.
+        crate::lifecycle::set_in_background(false);
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("resumed"));
+
+        // Unless shorebird.yaml allows background downloads.
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, "app_id: 1234\nbackground_downloads: true");
+        crate::testing_set_network_hooks(check, |_url, _resume_from| anyhow::bail!("downloaded"));
+        crate::on_app_background();
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("downloaded"));
+        crate::lifecycle::testing_reset();
+    }
+
+    #[serial]
+    #[test]
+    fn full_download_fallback() {
+        use crate::network::DownloadFileResponse;
+
+        fn check(
+            _url: &str,
+            _request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            use sha2::{Digest, Sha256};
+            Ok(crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(crate::Patch {
+                    number: 1,
+                    hash: hex::encode(Sha256::digest(b"hello world")),
+                    download_url: "patch_1".to_string(),
+                    full_download_url: Some("full_1".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+            .into())
+        }
+        fn compress(data: &[u8]) -> Vec<u8> {
+            use comde::com::Compressor;
+            let mut compressed = std::io::Cursor::new(Vec::new());
+            comde::zstd::ZstdCompressor::new()
+                .compress(&mut compressed, &mut &data[..])
+                .unwrap();
+            compressed.into_inner()
+        }
+        fn respond(url: &str, patch: Vec<u8>) -> anyhow::Result<DownloadFileResponse> {
+            let body = match url {
+                "patch_1" => patch,
+                "full_1" => compress(b"hello world"),
+                _ => anyhow::bail!("unexpected download {}", url),
+            };
+            Ok(DownloadFileResponse {
+                is_partial: false,
+                etag: None,
+                total_bytes: None,
+                body: Box::new(std::io::Cursor::new(body)),
+            })
+        }
+        // Decompresses fine, but isn't a patch of the base.
+        fn download(
+            url: &str,
+            _resume_from: Option<&crate::cache::PartialDownload>,
+        ) -> anyhow::Result<DownloadFileResponse> {
+            respond(url, compress(b"not applicable"))
+        }
+        // e.g. a truncated download.
+        fn corrupt_download(
+            url: &str,
+            _resume_from: Option<&crate::cache::PartialDownload>,
+        ) -> anyhow::Result<DownloadFileResponse> {
+            respond(url, b"not compressed".to_vec())
+        }
+        fn init_with_base(tmp_dir: &TempDir, yaml: &str) {
+            testing_reset_config();
+            let libapp_path = tmp_dir.path().join("lib/arch/libapp.so");
+            crate::init(
+                crate::AppConfig {
+                    cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+                    release_version: "1.0.0+1".to_string(),
+                    original_libapp_paths: vec![libapp_path.to_str().unwrap().to_string()],
+                    ..Default::default()
+                },
+                yaml,
+            )
+            .unwrap();
+            crate::android::tests::write_apk(tmp_dir.path(), None);
+        }
+
+        // Off by default.
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_base(&tmp_dir, "app_id: 1234");
+        crate::testing_set_network_hooks(check, download);
+        assert!(crate::update().is_err());
+        assert!(crate::next_boot_patch().unwrap().is_none());
+
+        // Only a patch which doesn't apply falls back.
+        let yaml = "app_id: 1234\nallow_full_downloads: true";
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_base(&tmp_dir, yaml);
+        crate::testing_set_network_hooks(check, corrupt_download);
+        let error = crate::update().unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to decompress patch"));
+        assert!(crate::next_boot_patch().unwrap().is_none());
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_with_base(&tmp_dir, yaml);
+        crate::testing_set_network_hooks(check, download);
+        assert_eq!(
+            crate::update().unwrap(),
+            crate::UpdateStatus::UpdateInstalled
+        );
+        let patch = crate::next_boot_patch().unwrap().unwrap();
+        assert_eq!(patch.number, 1);
+        assert_eq!(fs::read_to_string(&patch.path).unwrap(), "hello world");
+    }
+
+    /// Responses signed with a key derived from `seed`.
+    fn signed_response(seed: u8) -> crate::network::PatchCheckHttpResponse {
+        use base64::Engine;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let mut response: crate::network::PatchCheckHttpResponse =
+            crate::network::PatchCheckResponse::default().into();
+        let signature = SigningKey::from_bytes(&[seed; 32]).sign(&response.body);
+        response.signature =
+            Some(base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()));
+        response
+    }
+
+    #[serial]
+    #[test]
+    fn patch_check_response_signatures() {
+        use base64::Engine;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let yaml = format!(
+            "app_id: 1234\npatch_public_key: {}\nrequire_signed_responses: true",
+            base64::engine::general_purpose::STANDARD.encode(public_key.to_bytes())
+        );
+        init_for_testing_with_yaml(&tmp_dir, &yaml);
+        let no_download = |_url: &str, _resume_from: Option<&_>| anyhow::bail!("no download");
+
+        crate::testing_set_network_hooks(|_url, _request| Ok(signed_response(7)), no_download);
+        assert!(crate::check_for_update().is_ok());
+
+        crate::testing_set_network_hooks(|_url, _request| Ok(signed_response(8)), no_download);
+        assert!(crate::check_for_update().is_err());
+
+        crate::testing_set_network_hooks(
+            |_url, _request| {
+                let mut response = signed_response(7);
+                response.signature = None;
+                Ok(response)
+            },
+            no_download,
+        );
+        assert!(crate::check_for_update().is_err());
+
+        // Requiring signatures without a key to check them is a config error.
+        testing_reset_config();
+        assert!(crate::init(
+            crate::AppConfig {
+                cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+                release_version: "1.0.0+1".to_string(),
+                original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+                ..Default::default()
+            },
+            "app_id: 1234\nrequire_signed_responses: true",
+        )
+        .is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn patch_allowlist_falls_back_to_allowed_patch() {
+        use base64::Engine;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let allowed = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let other = "0000000000000000000000000000000000000000000000000000000000000000";
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let yaml = format!(
+            "app_id: 1234\npatch_public_key: {}\npatch_allowlist:\n  hashes: [{}]\n  signature: {}",
+            encode(&signing_key.verifying_key().to_bytes()),
+            allowed,
+            encode(&signing_key.sign(allowed.as_bytes()).to_bytes())
+        );
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, &yaml);
+        let set_hash = |number: usize, hash: &str| {
+            crate::config::with_config(|config| {
+                let mut state = super::load_state(config);
+                state.set_patch_hash(number, hash.to_string());
+                state.save()
+            })
+            .unwrap();
+        };
+        install_fake_patch(1);
+        set_hash(1, allowed);
+        // Booted successfully, so kept as a fallback.
+        crate::report_launch_start().unwrap();
+        crate::report_launch_success().unwrap();
+        install_fake_patch(2);
+        set_hash(2, other);
+        let installed: Vec<usize> = crate::installed_patches()
+            .unwrap()
+            .iter()
+            .map(|patch| patch.number)
+            .collect();
+        assert_eq!(installed, [1, 2]);
+
+        // Patch 2 isn't allowed, but patch 1 still is, so it boots rather
+        // than the base release.
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+    }
+
+    #[serial]
+    #[test]
+    fn patch_allowlist_is_enforced() {
+        use base64::Engine;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        // sha256("hello"), the contents of install_fake_patch's patches.
+        let allowed = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let other = "0000000000000000000000000000000000000000000000000000000000000000";
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let yaml_with_signature = |signature: &[u8]| {
+            format!(
+                "app_id: 1234\npatch_public_key: {}\npatch_allowlist:\n  hashes: [{}]\n  signature: {}",
+                encode(&signing_key.verifying_key().to_bytes()),
+                allowed,
+                encode(signature)
+            )
+        };
+        let signature = signing_key.sign(allowed.as_bytes()).to_bytes();
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, &yaml_with_signature(&signature));
+        let set_hash = |number: usize, hash: &str| {
+            crate::config::with_config(|config| {
+                let mut state = super::load_state(config);
+                state.set_patch_hash(number, hash.to_string());
+                state.save()
+            })
+            .unwrap();
+        };
+        install_fake_patch(1);
+        set_hash(1, allowed);
+        assert_eq!(crate::next_boot_patch().unwrap().unwrap().number, 1);
+
+        // Patches which aren't listed (or whose hash is unknown) don't boot,
+        // and the delegate hears they were invalidated.
+        #[derive(Default)]
+        struct Recorder {
+            invalidated: std::sync::Mutex<Vec<usize>>,
+        }
+        impl crate::UpdaterDelegate for Recorder {
+            fn on_patch_invalidated(&self, patch_number: usize) {
+                self.invalidated.lock().unwrap().push(patch_number);
+            }
+        }
+        let recorder = std::sync::Arc::new(Recorder::default());
+        crate::set_delegate(Some(recorder.clone()));
+        install_fake_patch(2);
+        set_hash(2, other);
+        assert!(crate::next_boot_patch().unwrap().is_none());
+        install_fake_patch(3);
+        assert!(crate::next_boot_patch().unwrap().is_none());
+        crate::set_delegate(None);
+        assert_eq!(*recorder.invalidated.lock().unwrap(), [2, 3]);
+
+        // Nor is a patch which isn't listed downloaded.
+        fn check_other(
+            _url: &str,
+            _request: crate::PatchCheckRequest,
+        ) -> anyhow::Result<crate::network::PatchCheckHttpResponse> {
+            Ok(crate::network::PatchCheckResponse {
+                patch_available: true,
+                patch: Some(crate::Patch {
+                    number: 4,
+                    hash: "0000000000000000000000000000000000000000000000000000000000000000"
+                        .to_string(),
+                    download_url: "bogus_url".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+            .into())
+        }
+        crate::testing_set_network_hooks(check_other, |_url, _resume_from| {
+            anyhow::bail!("unexpected download")
+        });
+        let error = crate::update().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<crate::UpdateError>(),
+            Some(&crate::UpdateError::PatchNotAllowed)
+        );
+
+        // The allowlist must be signed with patch_public_key.
+        testing_reset_config();
+        let bad_signature = signing_key.sign(other.as_bytes()).to_bytes();
+        let app_config = || crate::AppConfig {
+            cache_dir: tmp_dir.path().to_str().unwrap().to_string(),
+            release_version: "1.0.0+1".to_string(),
+            original_libapp_paths: vec!["/dir/lib/arch/libapp.so".to_string()],
+            ..Default::default()
+        };
+        assert!(crate::init(app_config(), &yaml_with_signature(&bad_signature)).is_err());
+        let without_key = format!(
+            "app_id: 1234\npatch_allowlist:\n  hashes: [{}]\n  signature: {}",
+            allowed,
+            encode(&signature)
+        );
+        assert!(crate::init(app_config(), &without_key).is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn patch_signatures() {
+        use base64::Engine;
+        use ed25519_dalek::{Signer, SigningKey};
+        use sha2::{Digest, Sha512};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let yaml = format!(
+            "app_id: 1234\npatch_public_key: {}",
+            base64::engine::general_purpose::STANDARD
+                .encode(signing_key.verifying_key().to_bytes())
+        );
+        init_for_testing_with_yaml(&tmp_dir, &yaml);
+        let config = super::copy_update_config().unwrap();
+        let path = tmp_dir.path().join("artifact");
+        fs::write(&path, "hello world").unwrap();
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let mut patch = crate::Patch {
+            number: 1,
+            hash: "#".to_string(),
+            download_url: "ignored".to_string(),
+            ..Default::default()
+        };
+        // Unsigned patches are rejected once a key is configured.
+        let error = super::check_patch_signature(&config, &patch, &path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<crate::UpdateError>(),
+            Some(&crate::UpdateError::InvalidSignature)
+        );
+
+        // Version 1 signs the hash string.
+        patch.signature = Some(encode(&signing_key.sign(b"#").to_bytes()));
+        super::check_patch_signature(&config, &patch, &path).unwrap();
+        patch.hash = "other".to_string();
+        assert!(super::check_patch_signature(&config, &patch, &path).is_err());
+
+        // Version 2 signs the artifact itself.
+        let prehashed = Sha512::new().chain_update(b"hello world");
+        let signature = signing_key.sign_prehashed(prehashed, None).unwrap();
+        patch.signature = Some(encode(&signature.to_bytes()));
+        patch.signature_version = Some(2);
+        super::check_patch_signature(&config, &patch, &path).unwrap();
+        fs::write(&path, "hello tests").unwrap();
+        let error = super::check_patch_signature(&config, &patch, &path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<crate::UpdateError>(),
+            Some(&crate::UpdateError::InvalidSignature)
+        );
+
+        // Versions we don't know are rejected rather than ignored.
+        patch.signature_version = Some(3);
+        assert!(super::check_patch_signature(&config, &patch, &path).is_err());
+    }
+
+    #[test]
+    fn inflate_rejects_empty_base() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let patch_path = tmp_dir.path().join("patch");
+        fs::write(&patch_path, "not a patch").unwrap();
+        let output_path = tmp_dir.path().join("output");
+        let error = super::inflate(
+            &patch_path,
+            std::io::Cursor::new(vec![]),
+            &output_path,
+            crate::thread_priority::ThreadPriority::Normal,
+            crate::patch_apply::CHECKPOINT_INTERVAL,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<crate::UpdateError>(),
+            Some(crate::UpdateError::BadBaseArtifact(_))
+        ));
+    }
+
+    #[test]
+    fn inflate_reports_decompression_errors() {
+        // Generated by `string_patch "hello world" "hello tests"`, then cut
+        // short, which the patch applier alone would see as a short patch.
+        let patch_bytes: Vec<u8> = vec![
+            40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0, 0, 0, 0,
+            5, 116, 101, 115, 116, 115, 0,
+        ];
+        let tmp_dir = TempDir::new("example").unwrap();
+        let patch_path = tmp_dir.path().join("patch");
+        fs::write(&patch_path, &patch_bytes[..patch_bytes.len() - 6]).unwrap();
+        let output_path = tmp_dir.path().join("output");
+        let error = super::inflate(
+            &patch_path,
+            std::io::Cursor::new(b"hello world".to_vec()),
+            &output_path,
+            crate::thread_priority::ThreadPriority::Normal,
+            crate::patch_apply::CHECKPOINT_INTERVAL,
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error).starts_with("Failed to decompress patch"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn inflate_seekable_patch() {
+        use comde::de::Decompressor;
+        // Generated by `string_patch "hello world" "hello tests"`
+        let patch_bytes: Vec<u8> = vec![
+            40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0, 0, 0, 0,
+            5, 116, 101, 115, 116, 115, 0,
+        ];
+        let mut bipatch = Vec::new();
+        comde::zstd::ZstdDecompressor::new()
+            .copy(&patch_bytes[..], &mut bipatch)
+            .unwrap();
+        let (first, rest) = bipatch.split_at(bipatch.len() / 2);
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let patch_path = tmp_dir.path().join("patch");
+        fs::write(
+            &patch_path,
+            crate::seekable::testing::seekable_compress(&[first, rest]),
+        )
+        .unwrap();
+        let output_path = tmp_dir.path().join("output");
+        super::inflate(
+            &patch_path,
+            std::io::Cursor::new(b"hello world".to_vec()),
+            &output_path,
+            crate::thread_priority::ThreadPriority::Normal,
+            crate::patch_apply::CHECKPOINT_INTERVAL,
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), "hello tests");
+    }
+
+    /// A base which fails reads past a limit, like the app being killed.
+    struct FailingBase {
+        base: std::io::Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl std::io::Read for FailingBase {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.base.position() >= self.limit {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.base.read(buf)
+        }
+    }
+
+    impl std::io::Seek for FailingBase {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.base.seek(pos)
+        }
+    }
+
+    #[test]
+    fn inflate_resumes_from_checkpoint() {
+        use crate::patch_apply::testing::bipatch;
+        use comde::com::Compressor;
+
+        let base: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let bipatch = bipatch(&vec![1u8; base.len()], b"tail");
+        let expected: Vec<u8> = base
+            .iter()
+            .map(|b| b.wrapping_add(1))
+            .chain(*b"tail")
+            .collect();
+        let mut plain = std::io::Cursor::new(Vec::new());
+        comde::zstd::ZstdCompressor::new()
+            .compress(&mut plain, &mut &bipatch[..])
+            .unwrap();
+        let chunks: Vec<&[u8]> = bipatch.chunks(9000).collect();
+        let seekable = crate::seekable::testing::seekable_compress(&chunks);
+        #[cfg_attr(not(feature = "encryption"), allow(unused_mut))]
+        let mut cases = vec![(plain.into_inner(), None), (seekable.clone(), None)];
+        #[cfg(feature = "encryption")]
+        {
+            let key = [42u8; crate::PATCH_KEY_LEN];
+            let encrypted = crate::encryption::testing::encrypt(&key, &seekable);
+            cases.push((encrypted, crate::encryption::PatchKey::new(&key)));
+        }
+
+        for (compressed, key) in cases {
+            let tmp_dir = TempDir::new("example").unwrap();
+            let patch_path = tmp_dir.path().join("patch");
+            fs::write(&patch_path, compressed).unwrap();
+            let output_path = tmp_dir.path().join("output");
+            let checkpoint_path = tmp_dir.path().join("output.checkpoint");
+            let inflate = |base| {
+                super::inflate(
+                    &patch_path,
+                    base,
+                    &output_path,
+                    crate::thread_priority::ThreadPriority::Normal,
+                    4096,
+                    key.as_ref(),
+                )
+            };
+
+            // Interrupted part way through, leaving a checkpoint.
+            inflate(FailingBase {
+                base: std::io::Cursor::new(base.clone()),
+                limit: 25000,
+            })
+            .unwrap_err();
+            assert!(checkpoint_path.exists());
+
+            // Mark the output so we can tell it was resumed, not restarted.
+            let mut output = fs::read(&output_path).unwrap();
+            assert!(output.len() >= 20000);
+            output[0] = b'X';
+            fs::write(&output_path, output).unwrap();
+
+            inflate(FailingBase {
+                base: std::io::Cursor::new(base.clone()),
+                limit: u64::MAX,
+            })
+            .unwrap();
+            let output = fs::read(&output_path).unwrap();
+            assert_eq!(output[0], b'X');
+            assert_eq!(output[1..], expected[1..]);
+            assert!(!checkpoint_path.exists());
+
+            // A different download starts over.
+            fs::write(&output_path, b"X").unwrap();
+            crate::patch_apply::save_checkpoint(
+                &output_path,
+                &crate::patch_apply::Checkpoint {
+                    download_hash: "other".to_string(),
+                    patch_offset: 0,
+                    base_offset: 0,
+                    output_offset: 1,
+                    state: crate::patch_apply::ApplyState::Initial,
+                },
+            )
+            .unwrap();
+            inflate(FailingBase {
+                base: std::io::Cursor::new(base.clone()),
+                limit: u64::MAX,
+            })
+            .unwrap();
+            assert_eq!(fs::read(&output_path).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn in_experiment() {
+        for seed in 0..100 {
+            assert!(!super::in_experiment(seed, 2, 0));
+            assert!(super::in_experiment(seed, 2, 100));
+            // Stable for a given device and experiment.
+            assert_eq!(
+                super::in_experiment(seed, 2, 50),
+                super::in_experiment(seed, 2, 50)
+            );
+        }
+        // Roughly the requested share of devices are assigned.
+        let assigned = (0..1000)
+            .filter(|seed| super::in_experiment(*seed, 2, 10))
+            .count();
+        assert!((50..150).contains(&assigned), "{}", assigned);
+    }
+
+    #[test]
+    fn pad_to_alignment() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("patch");
+        fs::write(&path, b"hello").unwrap();
+        super::pad_to_alignment(&path, 16).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 16);
+        assert!(contents.starts_with(b"hello"));
+        assert!(contents[5..].iter().all(|b| *b == 0));
+        // Already aligned files are left alone.
+        super::pad_to_alignment(&path, 16).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 16);
+        assert!(super::prefault_pages(&path).is_ok());
+    }
+
+    #[serial]
+    #[test]
+    fn next_boot_patch_file() {
+        use std::io::Read;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        assert!(crate::next_boot_patch_file().unwrap().is_none());
+        install_fake_patch(1);
+        let mut contents = String::new();
+        crate::next_boot_patch_file()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        let path = crate::next_boot_patch().unwrap().unwrap().path;
+        assert_eq!(contents, fs::read_to_string(path).unwrap());
+    }
+
+    #[serial]
+    #[test]
+    fn clock_is_injectable() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir);
+        let clock = std::sync::Arc::new(crate::time::FakeClock::new(1000));
+        crate::config::testing_set_clock(clock.clone());
+        clock.advance(5);
+        let now = super::with_config(|config| Ok(config.clock.unix_timestamp())).unwrap();
+        assert_eq!(now, 1005);
+    }
+
     #[serial]
     #[test]
     fn report_launch_result_with_no_current_patch() {
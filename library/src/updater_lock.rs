@@ -1,4 +1,5 @@
 use crate::updater::UpdateError;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 // This file's job is to handle the boilerplate around locking for the
 // updater thread.
@@ -15,6 +16,15 @@ fn updater_lock() -> &'static std::sync::Mutex<UpdaterLockState> {
     INSTANCE.get_or_init(|| Mutex::new(UpdaterLockState::empty()))
 }
 
+/// Whether a thread holds the updater lock.  Tracked separately so asking
+/// doesn't mean briefly taking the lock (failing a concurrent update).
+static UPDATING: AtomicBool = AtomicBool::new(false);
+
+/// Whether an update (from any thread) is running.
+pub fn is_updating() -> bool {
+    UPDATING.load(Ordering::SeqCst)
+}
+
 // Note: it is not OK to ever ask for the Updater lock *while* holding the
 // UpdateConfig lock because the updater thread *will* block on getting the
 // UpdateConfig lock while holding the Updater lock.  Allowing the inverse
@@ -30,16 +40,33 @@ where
     // Unlike our UpdateConfig lock, our UpdaterThread lock does not wait
     // if an updater thread is already running. We use try_lock instead
     // of lock to error out immediately.
-    let lock = updater_lock().try_lock();
-    match lock {
-        Ok(lock) => f(&lock),
+    let lock = match updater_lock().try_lock() {
+        Ok(lock) => lock,
         Err(std::sync::TryLockError::WouldBlock) => {
             anyhow::bail!(UpdateError::UpdateAlreadyInProgress)
         }
-        // This should never happen. Poisoning only happens if a thread panics
-        // while holding the lock, and we never allow the updater thread to
-        // panic.
-        Err(std::sync::TryLockError::Poisoned(e)) => panic!("Updater lock poisoned: {:?}", e),
+        // Poisoning only happens if an update panicked.  The lock guards no
+        // data, so nothing was left half changed and later updates may run.
+        Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+    };
+    let _updating = UpdatingGuard::new();
+    f(&lock)
+}
+
+/// Sets UPDATING for as long as it lives, so it is cleared even if the
+/// update panics.
+struct UpdatingGuard;
+
+impl UpdatingGuard {
+    fn new() -> Self {
+        UPDATING.store(true, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for UpdatingGuard {
+    fn drop(&mut self) {
+        UPDATING.store(false, Ordering::SeqCst);
     }
 }
 
@@ -0,0 +1,179 @@
+// This file's job is to verify patch contents (e.g. hashes) without depending
+// on std, so the same code can be used in constrained environments.
+// Only use `core` here.  File IO belongs in the callers (e.g. updater.rs).
+// sha2, hex and ed25519-dalek are all built with default-features = false,
+// and tools/verify_no_std builds this file in a no_std crate to check it.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256, Sha512}; // Digest is needed for Sha256::new();
+
+/// A sha256 hash.
+pub type Hash = [u8; 32];
+
+/// An ed25519 public key.
+pub type PublicKey = [u8; 32];
+
+/// Returns true if the bytes are a usable ed25519 public key.
+pub fn is_valid_public_key(public_key: &PublicKey) -> bool {
+    VerifyingKey::from_bytes(public_key).is_ok()
+}
+
+/// Returns true if `signature` is a valid ed25519 signature of `message`
+/// made with the private half of `public_key`.
+pub fn verify_signature(public_key: &PublicKey, message: &[u8], signature: &[u8]) -> bool {
+    let key = match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    key.verify_strict(message, &signature).is_ok()
+}
+
+/// Decodes a hex-encoded sha256 hash, returns None if the string is not a
+/// valid hex encoding of exactly 32 bytes.
+pub fn decode_hash(hex_string: &str) -> Option<Hash> {
+    let mut hash = Hash::default();
+    hex::decode_to_slice(hex_string, &mut hash).ok()?;
+    Some(hash)
+}
+
+/// Incrementally hashes data and compares the result to an expected hash.
+pub struct HashVerifier {
+    hasher: Sha256,
+    expected: Hash,
+}
+
+impl HashVerifier {
+    pub fn new(expected: Hash) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            expected,
+        }
+    }
+
+    /// Adds more data to the hash.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Returns the hash of all data passed to update() and whether it
+    /// matches the expected hash.
+    pub fn finalize(self) -> (Hash, bool) {
+        let hash: Hash = self.hasher.finalize().into();
+        (hash, hash == self.expected)
+    }
+}
+
+/// Incrementally checks an ed25519ph (sha512-prehashed) signature, so large
+/// messages (e.g. patch artifacts) need not be held in memory.
+pub struct SignatureVerifier {
+    hasher: Sha512,
+    public_key: PublicKey,
+    signature: Signature,
+}
+
+impl SignatureVerifier {
+    /// Returns None if `signature` is not a well-formed signature.
+    pub fn new(public_key: &PublicKey, signature: &[u8]) -> Option<Self> {
+        Some(Self {
+            hasher: Sha512::new(),
+            public_key: *public_key,
+            signature: Signature::from_slice(signature).ok()?,
+        })
+    }
+
+    /// Adds more of the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Returns true if the signature is valid for all data passed to update().
+    pub fn finalize(self) -> bool {
+        match VerifyingKey::from_bytes(&self.public_key) {
+            Ok(key) => key
+                .verify_prehashed_strict(self.hasher, None, &self.signature)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_hash, is_valid_public_key, verify_signature, HashVerifier, SignatureVerifier,
+    };
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn decode_hash_rejects_bad_input() {
+        assert!(decode_hash("#").is_none());
+        assert!(decode_hash("foo").is_none());
+        // Valid hex, but too short to be a sha256 hash.
+        assert!(decode_hash("abcd").is_none());
+        assert!(
+            decode_hash("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn verifier_hashes_in_chunks() {
+        let expected =
+            decode_hash("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+                .unwrap();
+        let mut verifier = HashVerifier::new(expected);
+        verifier.update(b"hello ");
+        verifier.update(b"world");
+        let (hash, matches) = verifier.finalize();
+        assert!(matches);
+        assert_eq!(hash, expected);
+
+        let mut verifier = HashVerifier::new(expected);
+        verifier.update(b"hello tests");
+        assert!(!verifier.finalize().1);
+    }
+
+    #[test]
+    fn signature_verifier_checks_streamed_message() {
+        use sha2::{Digest, Sha512};
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = signing_key.verifying_key().to_bytes();
+        let prehashed = Sha512::new().chain_update(b"hello world");
+        let signature = signing_key
+            .sign_prehashed(prehashed, None)
+            .unwrap()
+            .to_bytes();
+
+        let mut verifier = SignatureVerifier::new(&public_key, &signature).unwrap();
+        verifier.update(b"hello ");
+        verifier.update(b"world");
+        assert!(verifier.finalize());
+
+        let mut verifier = SignatureVerifier::new(&public_key, &signature).unwrap();
+        verifier.update(b"hello tests");
+        assert!(!verifier.finalize());
+
+        assert!(SignatureVerifier::new(&public_key, &signature[1..]).is_none());
+    }
+
+    #[test]
+    fn verify_signature_checks_message_and_key() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = signing_key.verifying_key().to_bytes();
+        assert!(is_valid_public_key(&public_key));
+        let signature = signing_key.sign(b"hello world").to_bytes();
+        assert!(verify_signature(&public_key, b"hello world", &signature));
+        assert!(!verify_signature(&public_key, b"hello tests", &signature));
+        assert!(!verify_signature(
+            &public_key,
+            b"hello world",
+            &signature[1..]
+        ));
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(!verify_signature(&other_key, b"hello world", &signature));
+    }
+}
@@ -1,5 +1,10 @@
+use std::collections::BTreeMap;
+
 use serde::Deserialize;
 
+use crate::request_signing::SignatureScheme;
+use crate::thread_priority::ThreadPriority;
+
 /// Struct for parsing shorebird.yaml.
 #[derive(Deserialize)]
 pub struct YamlConfig {
@@ -8,8 +13,151 @@ pub struct YamlConfig {
     pub app_id: String,
     /// Update channel name.  Defaults to "stable" if not set.
     pub channel: Option<String>,
-    /// Update URL.  Defaults to the default update URL if not set.
+    /// Channels to check, in order, when the server has no patch for
+    /// `channel` (or doesn't know it).  Optional, e.g. `[stable]` lets beta
+    /// testers get stable patches while beta lags behind.
+    pub channel_fallbacks: Option<Vec<String>>,
+    /// Update URL.  Defaults to the default update URL if not set.  May have
+    /// a path prefix (e.g. https://example.com/tenant), request paths are
+    /// joined onto it.
     pub base_url: Option<String>,
+    /// Fingerprint of the native code (engine, plugins) compiled into this
+    /// app.  Optional.  Patches requiring a different fingerprint will not be
+    /// installed.
+    pub native_fingerprint: Option<String>,
+    /// Hex-encoded sha256 of the certificate the app's APK is signed with
+    /// (colons allowed, as shown by e.g. the Play Console).  Android only,
+    /// optional.  If set, patches are not applied to a base libapp.so from
+    /// an APK signed with any other certificate (e.g. a modified repack).
+    pub expected_apk_cert_sha256: Option<String>,
+    /// Whether the app may replace app_id at init time (e.g. white-label
+    /// apps which decide their app_id at runtime).  Defaults to false.
+    pub allow_app_id_override: Option<bool>,
+    /// Number of patches to keep on disk, including the booted patch, so
+    /// there are older good patches to fall back to.  Defaults to 2.
+    pub retain_patch_count: Option<usize>,
+    /// Base64-encoded ed25519 public key used to verify signatures on patch
+    /// check responses, patches (which must then be signed) and
+    /// patch_allowlist.  Optional.
+    pub patch_public_key: Option<String>,
+    /// Whether to reject patch check responses which are not signed with
+    /// patch_public_key.  Defaults to false.
+    pub require_signed_responses: Option<bool>,
+    /// Hashes of the only patches this release may install or boot, signed
+    /// with patch_public_key, so a compromised server can't push arbitrary
+    /// patches.  Optional, requires patch_public_key.
+    pub patch_allowlist: Option<PatchAllowlist>,
+    /// Sign requests to base_url so a private patch server can authenticate
+    /// them.  Optional.
+    pub request_signing: Option<RequestSigningConfig>,
+    /// Headers to send with every request to base_url (e.g. a token required
+    /// by an enterprise gateway), but not to download URLs on other hosts.
+    /// Optional.  Secrets are better provided at runtime, see
+    /// shorebird_set_extra_header.  Request signing's headers replace any of
+    /// the same name.
+    pub extra_headers: Option<BTreeMap<String, String>>,
+    /// Pad installed patch artifacts with zeros to a multiple of this many
+    /// bytes (e.g. the page size), so they can be mmapped cleanly.  Optional.
+    pub patch_alignment: Option<u64>,
+    /// Whether to ask the OS to read installed patches into the page cache
+    /// ahead of the next boot.  Defaults to false.
+    pub prefault_patches: Option<bool>,
+    /// Whether to fsync patch artifacts (and their directories) as they are
+    /// written, so a power cut can't leave a corrupt patch installed.
+    /// Defaults to true; embedders sensitive to write latency may opt out.
+    pub durable_writes: Option<bool>,
+    /// Whether to download the whole patched artifact when a patch doesn't
+    /// apply to the installed release (e.g. a base binary modified after
+    /// it was built), if the server offers one.  Full artifacts are much
+    /// larger than patches, so this defaults to false.
+    pub allow_full_downloads: Option<bool>,
+    /// Whether start_update_thread checks for updates.  Defaults to true.
+    /// The app may override this at runtime, see set_auto_update.
+    pub auto_update: Option<bool>,
+    /// Delay background update checks at launch by a random amount up to
+    /// this many seconds, so a new patch doesn't have every device checking
+    /// at once.  Defaults to 0 (no delay).
+    pub auto_update_jitter_seconds: Option<u64>,
+    /// Whether calling start_update_thread while the update thread is busy
+    /// queues one more run once it finishes (e.g. to pick up a patch
+    /// published meanwhile), rather than doing nothing.  However many calls
+    /// arrive, at most one run is queued.  Defaults to false.
+    pub queue_follow_up_update: Option<bool>,
+    /// Whether a separate process (shorebird_updater_daemon, built with the
+    /// "daemon" feature) does updates for the app, in which case
+    /// start_update_thread does not check.  Desktop only.  Defaults to false.
+    pub update_in_daemon: Option<bool>,
+    /// Whether report_launch_start checks the patch being booted is still
+    /// the artifact next_boot_patch returned (same size and mtime, or else
+    /// the same hash), and marks it bad if not, e.g. if a storage cleaner
+    /// swapped it in between.  Defaults to false.
+    pub verify_boot_artifact: Option<bool>,
+    /// Whether downloads continue while the host reports the app is in the
+    /// background (see shorebird_on_app_background).  Defaults to false:
+    /// downloads pause and resume once the app is back in the foreground.
+    pub background_downloads: Option<bool>,
+    /// Whether to measure, once, how fast the device decodes each patch
+    /// format and send the result with the next patch check.  Takes about a
+    /// tenth of a second on the update thread.  Defaults to false.
+    pub decode_benchmark: Option<bool>,
+    /// Patches up to this many bytes may be handed to the engine as a sealed
+    /// in-memory copy (see shorebird_next_boot_patch_memfd) instead of a path,
+    /// on Android and Linux.  Set it with the device's RAM in mind, the copy
+    /// lives in memory for as long as the engine keeps it.  Defaults to off.
+    pub memfd_max_bytes: Option<u64>,
+    /// Whether to tell the server if the device looks like an emulator or is
+    /// rooted/jailbroken, so QA devices can be filtered out of reliability
+    /// metrics.  Defaults to false.
+    pub report_device_environment: Option<bool>,
+    /// Whether patch checks list every installed patch number, not just the
+    /// latest, so the server can account for gaps when choosing what to
+    /// offer.  Defaults to false.
+    pub report_installed_patches: Option<bool>,
+    /// Whether patch checks include the device's language and country (e.g.
+    /// "en-US", from AppConfig::locale), for looking at rollouts by region.
+    /// Nothing finer (script, variant or encoding) is sent.  Defaults to
+    /// false.
+    pub report_locale: Option<bool>,
+    /// Priority for the threads doing background update work (checking,
+    /// downloading and inflating patches): normal, low or background.
+    /// Lowering it keeps inflating large patches from janking the UI on
+    /// low-end devices at the cost of slower updates.  Defaults to normal.
+    pub update_thread_priority: Option<ThreadPriority>,
+    /// Patch number to request and boot instead of the latest, for QA.
+    /// Optional.  Only honored by debug builds of the app (see
+    /// AppParameters::debug_build), see also shorebird_pin_patch.
+    pub pinned_patch_number: Option<usize>,
+    /// Whether to log the full JSON of each patch check request and
+    /// response, for debugging targeting.  The client_id and patch metadata
+    /// values are redacted.  Defaults to false, which logs a summary.
+    pub log_network_bodies: Option<bool>,
+    /// Whether unrecognized keys are an error rather than a warning.
+    /// Defaults to false, so that shorebird.yaml files written for newer
+    /// versions of the updater still load.
+    pub strict_config: Option<bool>,
+    /// Any keys not recognized above, e.g. typos like `auto_updates`.
+    #[serde(flatten)]
+    unknown_keys: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// The patch_allowlist section of shorebird.yaml.
+#[derive(Deserialize)]
+pub struct PatchAllowlist {
+    /// Hex-encoded sha256 hashes of the inflated patches.
+    pub hashes: Vec<String>,
+    /// Base64-encoded ed25519 signature of `hashes` joined with newlines.
+    pub signature: String,
+}
+
+/// The request_signing section of shorebird.yaml.
+#[derive(Deserialize)]
+pub struct RequestSigningConfig {
+    /// Base64-encoded HMAC-SHA256 key.  Optional if the app provides the key
+    /// at runtime, see shorebird_set_request_signing_key_callback.
+    pub key: Option<String>,
+    /// How the signature is sent: authorization or headers.  Defaults to
+    /// authorization.
+    pub scheme: Option<SignatureScheme>,
 }
 
 impl YamlConfig {
@@ -17,4 +165,38 @@ impl YamlConfig {
     pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
         serde_yaml::from_str(yaml)
     }
+
+    /// Problems with this config which do not prevent the updater from
+    /// running, suitable for showing to the developer.
+    pub fn warnings(&self) -> Vec<String> {
+        self.unknown_keys
+            .keys()
+            .map(|key| format!("Unknown key in shorebird.yaml: {}", key))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::YamlConfig;
+
+    #[test]
+    fn unknown_keys_are_warnings() {
+        let config = YamlConfig::from_yaml("app_id: 1234\nauto_updates: false").unwrap();
+        assert_eq!(config.app_id, "1234");
+        assert_eq!(
+            config.warnings(),
+            vec!["Unknown key in shorebird.yaml: auto_updates".to_string()]
+        );
+    }
+
+    #[test]
+    fn known_keys_are_not_warnings() {
+        let yaml = "app_id: 1234\nchannel: beta\nretain_patch_count: 3\nprefault_patches: true";
+        let config = YamlConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.channel.as_deref(), Some("beta"));
+        assert_eq!(config.retain_patch_count, Some(3));
+        assert_eq!(config.prefault_patches, Some(true));
+        assert!(config.warnings().is_empty());
+    }
 }
@@ -1,10 +1,34 @@
 use bidiff::DiffParams;
-use std::io::{BufWriter, Seek, Write};
+use std::io::{BufWriter, Read, Seek, Write};
 
 use comde::com::Compressor;
 use comde::zstd::ZstdCompressor;
 
+/// Patches larger than this are split into independently compressed frames
+/// of this size, so the updater can decompress them in parallel.
+pub const FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+// See zstd's contrib/seekable_format/zstd_seekable_compression_format.md.
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
 pub fn make_patch<WS>(older: Vec<u8>, newer: Vec<u8>, patch: &mut WS)
+where
+    WS: Write + Seek,
+{
+    make_patch_with_frame_size(older, newer, patch, FRAME_SIZE)
+}
+
+/// Reads until `buffer` is full or the reader is exhausted.
+fn read_frame<R: Read>(reader: &mut R, buffer: &mut Vec<u8>, frame_size: usize) {
+    buffer.clear();
+    reader
+        .take(frame_size as u64)
+        .read_to_end(buffer)
+        .expect("read patch");
+}
+
+fn make_patch_with_frame_size<WS>(older: Vec<u8>, newer: Vec<u8>, patch: &mut WS, frame_size: usize)
 where
     WS: Write + Seek,
 {
@@ -18,9 +42,42 @@ where
     let compressor = ZstdCompressor::new();
 
     let mut compatch_w = BufWriter::new(patch);
-    compressor
-        .compress(&mut compatch_w, &mut patch_r)
-        .expect("compress patch");
+    let mut frame = Vec::with_capacity(frame_size);
+    let mut next = Vec::with_capacity(frame_size);
+    read_frame(&mut patch_r, &mut frame, frame_size);
+    read_frame(&mut patch_r, &mut next, frame_size);
+    if next.is_empty() {
+        // Small patches stay a single plain zstd frame.
+        compressor
+            .compress(&mut compatch_w, &mut &frame[..])
+            .expect("compress patch");
+        compatch_w.flush().expect("flush patch");
+        return;
+    }
+
+    // Otherwise write a frame per chunk, followed by a seek table (in a
+    // skippable frame, which decoders unaware of it ignore).
+    let mut entries = Vec::new();
+    while !frame.is_empty() {
+        let count = compressor
+            .compress(&mut compatch_w, &mut &frame[..])
+            .expect("compress patch");
+        entries.push((count.write as u32, count.read as u32));
+        std::mem::swap(&mut frame, &mut next);
+        read_frame(&mut patch_r, &mut next, frame_size);
+    }
+    let mut table = Vec::new();
+    table.extend(SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+    table.extend((entries.len() as u32 * 8 + 9).to_le_bytes());
+    for (compressed_size, decompressed_size) in &entries {
+        table.extend(compressed_size.to_le_bytes());
+        table.extend(decompressed_size.to_le_bytes());
+    }
+    table.extend((entries.len() as u32).to_le_bytes());
+    // Seek_Table_Descriptor: no per-frame checksums.
+    table.push(0);
+    table.extend(SEEKABLE_MAGIC.to_le_bytes());
+    compatch_w.write_all(&table).expect("write seek table");
     compatch_w.flush().expect("flush patch");
 }
 
@@ -44,4 +101,35 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_make_patch_with_frames() {
+        use comde::de::Decompressor;
+        use comde::zstd::ZstdDecompressor;
+
+        let older = b"hello world".repeat(10);
+        let newer = b"hello tests".repeat(10);
+        let mut single = Cursor::new(Vec::new());
+        make_patch(older.clone(), newer.clone(), &mut single);
+        let mut framed = Cursor::new(Vec::new());
+        make_patch_with_frame_size(older, newer, &mut framed, 8);
+        let framed = framed.into_inner();
+
+        // The seek table footer.
+        let footer = &framed[framed.len() - 9..];
+        let frame_count = u32::from_le_bytes(footer[..4].try_into().unwrap());
+        assert!(frame_count > 1);
+        assert_eq!(footer[5..], SEEKABLE_MAGIC.to_le_bytes());
+
+        // Both decompress to the same thing with a plain zstd decoder.
+        let mut expected = Vec::new();
+        ZstdDecompressor::new()
+            .copy(&single.into_inner()[..], &mut expected)
+            .unwrap();
+        let mut actual = Vec::new();
+        ZstdDecompressor::new()
+            .copy(&framed[..], &mut actual)
+            .unwrap();
+        assert_eq!(actual, expected);
+    }
 }
@@ -2069,6 +2069,8 @@ class UpdaterBindings {
   /// from the running app.  Second parameter is a YAML string containing
   /// configuration compiled into the app.  Returns true on success and false on
   /// failure. If false is returned, the updater library will not be usable.
+  /// Passing NULL for either parameter (or a required AppParameters field)
+  /// returns false rather than crashing.
   bool shorebird_init(
     ffi.Pointer<AppParameters> c_params,
     ffi.Pointer<ffi.Char> c_yaml,
@@ -2086,6 +2088,48 @@ class UpdaterBindings {
   late final _shorebird_init = _shorebird_initPtr.asFunction<
       bool Function(ffi.Pointer<AppParameters>, ffi.Pointer<ffi.Char>)>();
 
+  /// Like shorebird_init, but returns why initialization failed.  Returns
+  /// ShorebirdErrorCode_None on success.
+  int shorebird_init_with_result(
+    ffi.Pointer<AppParameters> c_params,
+    ffi.Pointer<ffi.Char> c_yaml,
+  ) {
+    return _shorebird_init_with_result(
+      c_params,
+      c_yaml,
+    );
+  }
+
+  late final _shorebird_init_with_resultPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<AppParameters>,
+              ffi.Pointer<ffi.Char>)>>('shorebird_init_with_result');
+  late final _shorebird_init_with_result =
+      _shorebird_init_with_resultPtr.asFunction<
+          int Function(ffi.Pointer<AppParameters>, ffi.Pointer<ffi.Char>)>();
+
+  /// Like shorebird_init_with_result, but reads shorebird.yaml through
+  /// `file_callbacks` (by the name "shorebird.yaml") instead of taking it as a
+  /// string.  The callbacks are only used during this call.
+  int shorebird_init_with_file_callbacks(
+    ffi.Pointer<AppParameters> c_params,
+    ffi.Pointer<FileCallbacks> file_callbacks,
+  ) {
+    return _shorebird_init_with_file_callbacks(
+      c_params,
+      file_callbacks,
+    );
+  }
+
+  late final _shorebird_init_with_file_callbacksPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<AppParameters>,
+              ffi.Pointer<FileCallbacks>)>>('shorebird_init_with_file_callbacks');
+  late final _shorebird_init_with_file_callbacks =
+      _shorebird_init_with_file_callbacksPtr.asFunction<
+          int Function(ffi.Pointer<AppParameters>,
+              ffi.Pointer<FileCallbacks>)>();
+
   /// The currently running patch number, or 0 if the release has not been
   /// patched.
   int shorebird_current_boot_patch_number() {
@@ -2110,6 +2154,20 @@ class UpdaterBindings {
   late final _shorebird_next_boot_patch_number =
       _shorebird_next_boot_patch_numberPtr.asFunction<int Function()>();
 
+  /// Start validating the next boot patch (that it exists and matches its hash)
+  /// on a background thread, so the next call to
+  /// shorebird_next_boot_patch_number or shorebird_next_boot_patch_path does
+  /// not have to.  Call as early as possible during engine startup.
+  void shorebird_prevalidate_next_boot_patch() {
+    return _shorebird_prevalidate_next_boot_patch();
+  }
+
+  late final _shorebird_prevalidate_next_boot_patchPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function()>>(
+          'shorebird_prevalidate_next_boot_patch');
+  late final _shorebird_prevalidate_next_boot_patch =
+      _shorebird_prevalidate_next_boot_patchPtr.asFunction<void Function()>();
+
   /// The path to the patch that will boot on the next run of the app, or NULL if
   /// there is no next patch.
   ffi.Pointer<ffi.Char> shorebird_next_boot_patch_path() {
@@ -2123,7 +2181,94 @@ class UpdaterBindings {
       _shorebird_next_boot_patch_pathPtr
           .asFunction<ffi.Pointer<ffi.Char> Function()>();
 
-  /// Free a string returned by the updater library.
+  /// The metadata the server sent with the patch that will boot on the next run
+  /// of the app (e.g. git sha, Dart version), as JSON.  NULL if there is no next
+  /// patch or it has no metadata.  The caller must free the result with
+  /// shorebird_free_string.
+  ffi.Pointer<ffi.Char> shorebird_next_boot_patch_metadata() {
+    return _shorebird_next_boot_patch_metadata();
+  }
+
+  late final _shorebird_next_boot_patch_metadataPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_next_boot_patch_metadata');
+  late final _shorebird_next_boot_patch_metadata =
+      _shorebird_next_boot_patch_metadataPtr
+          .asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// This device's client_id, which canary patches can be restricted to.
+  /// The caller must free the result with shorebird_free_string.  NULL on error.
+  ffi.Pointer<ffi.Char> shorebird_client_id() {
+    return _shorebird_client_id();
+  }
+
+  late final _shorebird_client_idPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_client_id');
+  late final _shorebird_client_id =
+      _shorebird_client_idPtr.asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// The patches currently on disk as a JSON array of objects with number, path,
+  /// size, hash, metadata, is_current_boot, is_next_boot and is_known_good.
+  /// The caller must free the result with shorebird_free_string.  NULL on error.
+  ffi.Pointer<ffi.Char> shorebird_installed_patches_json() {
+    return _shorebird_installed_patches_json();
+  }
+
+  late final _shorebird_installed_patches_jsonPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_installed_patches_json');
+  late final _shorebird_installed_patches_json =
+      _shorebird_installed_patches_jsonPtr
+          .asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// Checks the updater's storage and state, as a JSON object with `healthy`,
+  /// `checks`, an array of objects with name, ok and detail, and
+  /// `download_progress`, null or an object with bytes_downloaded and
+  /// total_bytes (null if unknown) for an interrupted download.  Slow, it
+  /// re-hashes the booted patch, so only call it from e.g. a debug screen.
+  /// The caller must free the result with shorebird_free_string.  NULL on error
+  /// (e.g. before shorebird_init).
+  ffi.Pointer<ffi.Char> shorebird_run_health_check() {
+    return _shorebird_run_health_check();
+  }
+
+  late final _shorebird_run_health_checkPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_run_health_check');
+  late final _shorebird_run_health_check = _shorebird_run_health_checkPtr
+      .asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// The latest patch install as a JSON object with patch_number, slot_index,
+  /// replacing_patch_number, started_at, steps (the steps which finished, e.g.
+  /// "artifact_stored"), completed and error, or "null" if no patch has been
+  /// installed.  The caller must free the result with shorebird_free_string.
+  /// NULL on error.
+  ffi.Pointer<ffi.Char> shorebird_last_install_transaction_json() {
+    return _shorebird_last_install_transaction_json();
+  }
+
+  late final _shorebird_last_install_transaction_jsonPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_last_install_transaction_json');
+  late final _shorebird_last_install_transaction_json =
+      _shorebird_last_install_transaction_jsonPtr
+          .asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// Non-fatal problems found in shorebird.yaml by shorebird_init (e.g. unknown
+  /// keys), one per line, or NULL if there are none.  The caller must free the
+  /// result with shorebird_free_string.
+  ffi.Pointer<ffi.Char> shorebird_get_config_warnings() {
+    return _shorebird_get_config_warnings();
+  }
+
+  late final _shorebird_get_config_warningsPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'shorebird_get_config_warnings');
+  late final _shorebird_get_config_warnings = _shorebird_get_config_warningsPtr
+      .asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  /// Free a string returned by the updater library.  Passing NULL is a no-op.
   void shorebird_free_string(
     ffi.Pointer<ffi.Char> c_string,
   ) {
@@ -2138,6 +2283,44 @@ class UpdaterBindings {
   late final _shorebird_free_string = _shorebird_free_stringPtr
       .asFunction<void Function(ffi.Pointer<ffi.Char>)>();
 
+  /// For QA: make updates fetch and boot patch `patch_number` instead of the
+  /// latest, even if newer patches exist.  If it is already installed it
+  /// becomes the next boot patch now.  Pass 0 to remove the pin.  Kept across
+  /// launches.  Works in release builds, so only expose it to testers.
+  /// Returns false on error (e.g. before shorebird_init).
+  bool shorebird_pin_patch(
+    int patch_number,
+  ) {
+    return _shorebird_pin_patch(
+      patch_number,
+    );
+  }
+
+  late final _shorebird_pin_patchPtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function(ffi.UintPtr)>>(
+          'shorebird_pin_patch');
+  late final _shorebird_pin_patch =
+      _shorebird_pin_patchPtr.asFunction<bool Function(int)>();
+
+  /// What the next boot will run and, if not a patch, why not (e.g. the patch
+  /// was corrupted on disk), for debugging a patch which isn't applying.
+  /// `out_patch_number` (which may be NULL) is set to the patch selected or
+  /// refused, or 0 if there is none.  Returns NoPatchInstalled on error.
+  int shorebird_boot_decision_reason(
+    ffi.Pointer<ffi.UintPtr> out_patch_number,
+  ) {
+    return _shorebird_boot_decision_reason(
+      out_patch_number,
+    );
+  }
+
+  late final _shorebird_boot_decision_reasonPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ffi.UintPtr>)>>(
+          'shorebird_boot_decision_reason');
+  late final _shorebird_boot_decision_reason =
+      _shorebird_boot_decision_reasonPtr
+          .asFunction<int Function(ffi.Pointer<ffi.UintPtr>)>();
+
   /// Check for an update.  Returns true if an update is available.
   bool shorebird_check_for_update() {
     return _shorebird_check_for_update();
@@ -2149,6 +2332,284 @@ class UpdaterBindings {
   late final _shorebird_check_for_update =
       _shorebird_check_for_updatePtr.asFunction<bool Function()>();
 
+  /// Like shorebird_check_for_update, but returns why the check failed.
+  /// On success, `out_update_available` (which may be NULL) is set to whether
+  /// an update is available.
+  int shorebird_check_for_update_with_result(
+    ffi.Pointer<ffi.Bool> out_update_available,
+  ) {
+    return _shorebird_check_for_update_with_result(
+      out_update_available,
+    );
+  }
+
+  late final _shorebird_check_for_update_with_resultPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ffi.Bool>)>>(
+          'shorebird_check_for_update_with_result');
+  late final _shorebird_check_for_update_with_result =
+      _shorebird_check_for_update_with_resultPtr
+          .asFunction<int Function(ffi.Pointer<ffi.Bool>)>();
+
+  /// Turn offline mode on or off, e.g. to honor a user's data saver setting.
+  /// While offline the updater makes no network requests: updates report
+  /// ShorebirdDeferReason_Offline and checks fail with
+  /// ShorebirdErrorCode_Offline.  May be called at any time, including before
+  /// shorebird_init.
+  void shorebird_set_offline(
+    bool offline,
+  ) {
+    return _shorebird_set_offline(
+      offline,
+    );
+  }
+
+  late final _shorebird_set_offlinePtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Bool)>>(
+          'shorebird_set_offline');
+  late final _shorebird_set_offline =
+      _shorebird_set_offlinePtr.asFunction<void Function(bool)>();
+
+  /// Turn automatic updates (shorebird_start_update_thread) on or off,
+  /// overriding shorebird.yaml's auto_update.  Offline mode and the server's
+  /// directives still take precedence.  May be called at any time, including
+  /// before shorebird_init.
+  void shorebird_set_auto_update(
+    bool enabled,
+  ) {
+    return _shorebird_set_auto_update(
+      enabled,
+    );
+  }
+
+  late final _shorebird_set_auto_updatePtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Bool)>>(
+          'shorebird_set_auto_update');
+  late final _shorebird_set_auto_update =
+      _shorebird_set_auto_updatePtr.asFunction<void Function(bool)>();
+
+  /// Undo shorebird_set_auto_update, so shorebird.yaml's auto_update (and the
+  /// network type) decide again.  May be called at any time, including before
+  /// shorebird_init.
+  void shorebird_clear_auto_update() {
+    return _shorebird_clear_auto_update();
+  }
+
+  late final _shorebird_clear_auto_updatePtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function()>>(
+          'shorebird_clear_auto_update');
+  late final _shorebird_clear_auto_update =
+      _shorebird_clear_auto_updatePtr.asFunction<void Function()>();
+
+  /// Tell the updater whether the device is on a metered network, in which
+  /// case automatic updates are skipped unless turned on with
+  /// shorebird_set_auto_update.  May be called at any time, including before
+  /// shorebird_init.
+  void shorebird_set_metered_network(
+    bool metered,
+  ) {
+    return _shorebird_set_metered_network(
+      metered,
+    );
+  }
+
+  late final _shorebird_set_metered_networkPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Bool)>>(
+          'shorebird_set_metered_network');
+  late final _shorebird_set_metered_network =
+      _shorebird_set_metered_networkPtr.asFunction<void Function(bool)>();
+
+  /// Tell the updater the app came to the foreground (e.g. from
+  /// onResume/applicationDidBecomeActive).  If it was in the background, an
+  /// automatic update check starts on a background thread, as long as
+  /// shorebird_auto_update_defer_reason allows, which also resumes any
+  /// download paused by shorebird_on_app_background.  Does nothing before
+  /// shorebird_init.
+  void shorebird_on_app_foreground() {
+    return _shorebird_on_app_foreground();
+  }
+
+  late final _shorebird_on_app_foregroundPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function()>>(
+          'shorebird_on_app_foreground');
+  late final _shorebird_on_app_foreground =
+      _shorebird_on_app_foregroundPtr.asFunction<void Function()>();
+
+  /// Tell the updater the app went into the background (e.g. from
+  /// onPause/applicationDidEnterBackground).  Downloads pause, keeping their
+  /// progress, unless shorebird.yaml sets background_downloads.  May be called
+  /// at any time, including before shorebird_init.
+  void shorebird_on_app_background() {
+    return _shorebird_on_app_background();
+  }
+
+  late final _shorebird_on_app_backgroundPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function()>>(
+          'shorebird_on_app_background');
+  late final _shorebird_on_app_background =
+      _shorebird_on_app_backgroundPtr.asFunction<void Function()>();
+
+  /// Set a header to send with every request the updater makes to base_url,
+  /// overriding extra_headers in shorebird.yaml, so secrets (e.g. a gateway
+  /// token) need not be built into the app.  Pass NULL for `value` to remove
+  /// the header.  Returns false if the name or value is not a valid header.
+  /// May be called at any time, including before shorebird_init.
+  bool shorebird_set_extra_header(
+    ffi.Pointer<ffi.Char> name,
+    ffi.Pointer<ffi.Char> value,
+  ) {
+    return _shorebird_set_extra_header(
+      name,
+      value,
+    );
+  }
+
+  late final _shorebird_set_extra_headerPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Bool Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('shorebird_set_extra_header');
+  late final _shorebird_set_extra_header =
+      _shorebird_set_extra_headerPtr.asFunction<
+          bool Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>();
+
+  /// Provide the key used to sign requests to the update server (see
+  /// request_signing in shorebird.yaml), taking precedence over any key in
+  /// shorebird.yaml so it can be rotated.  `context` is passed back to the
+  /// callback and must stay valid until the callback is replaced.  Pass NULL
+  /// to remove the callback.  May be called at any time, including before
+  /// shorebird_init.
+  void shorebird_set_request_signing_key_callback(
+    ShorebirdSigningKeyCallback callback,
+    ffi.Pointer<ffi.Void> context,
+  ) {
+    return _shorebird_set_request_signing_key_callback(
+      callback,
+      context,
+    );
+  }
+
+  late final _shorebird_set_request_signing_key_callbackPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(ShorebirdSigningKeyCallback,
+              ffi.Pointer<ffi.Void>)>>('shorebird_set_request_signing_key_callback');
+  late final _shorebird_set_request_signing_key_callback =
+      _shorebird_set_request_signing_key_callbackPtr.asFunction<
+          void Function(ShorebirdSigningKeyCallback, ffi.Pointer<ffi.Void>)>();
+
+  /// Provide the keys for patches the server encrypts in transit.  Without a
+  /// key, encrypted patches fail to install with
+  /// ShorebirdErrorCode_PatchKeyUnavailable.  `context` is passed back to the
+  /// callback and must stay valid until the callback is replaced.  Pass NULL
+  /// to remove the callback.  May be called at any time, including before
+  /// shorebird_init.
+  void shorebird_set_patch_key_callback(
+    ShorebirdPatchKeyCallback callback,
+    ffi.Pointer<ffi.Void> context,
+  ) {
+    return _shorebird_set_patch_key_callback(
+      callback,
+      context,
+    );
+  }
+
+  late final _shorebird_set_patch_key_callbackPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(ShorebirdPatchKeyCallback,
+              ffi.Pointer<ffi.Void>)>>('shorebird_set_patch_key_callback');
+  late final _shorebird_set_patch_key_callback =
+      _shorebird_set_patch_key_callbackPtr.asFunction<
+          void Function(ShorebirdPatchKeyCallback, ffi.Pointer<ffi.Void>)>();
+
+  /// Why shorebird_start_update_thread would not check for an update right
+  /// now, or ShorebirdDeferReason_None if it would.
+  int shorebird_auto_update_defer_reason() {
+    return _shorebird_auto_update_defer_reason();
+  }
+
+  late final _shorebird_auto_update_defer_reasonPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function()>>(
+          'shorebird_auto_update_defer_reason');
+  late final _shorebird_auto_update_defer_reason =
+      _shorebird_auto_update_defer_reasonPtr.asFunction<int Function()>();
+
+  /// Whether shorebird_start_update_thread would check for an update right
+  /// now, see shorebird_auto_update_defer_reason for why not.
+  bool shorebird_should_auto_update() {
+    return _shorebird_should_auto_update();
+  }
+
+  late final _shorebird_should_auto_updatePtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function()>>(
+          'shorebird_should_auto_update');
+  late final _shorebird_should_auto_update =
+      _shorebird_should_auto_updatePtr.asFunction<bool Function()>();
+
+  /// Lets the app apply its own policy (e.g. no updates during checkout) to
+  /// each patch offered, see updater::set_patch_check_response_callback.  A
+  /// vetoed update reports ShorebirdDeferReason_VetoedByApp.  The callback
+  /// must not itself update.  `context` is passed back to the callback and
+  /// must stay valid until the callback is replaced.  Pass NULL to remove the
+  /// callback.  Does not require shorebird_init.
+  void shorebird_set_patch_check_response_callback(
+    ShorebirdPatchCheckResponseCallback callback,
+    ffi.Pointer<ffi.Void> context,
+  ) {
+    return _shorebird_set_patch_check_response_callback(
+      callback,
+      context,
+    );
+  }
+
+  late final _shorebird_set_patch_check_response_callbackPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(ShorebirdPatchCheckResponseCallback,
+              ffi.Pointer<ffi.Void>)>>('shorebird_set_patch_check_response_callback');
+  late final _shorebird_set_patch_check_response_callback =
+      _shorebird_set_patch_check_response_callbackPtr.asFunction<
+          void Function(ShorebirdPatchCheckResponseCallback,
+              ffi.Pointer<ffi.Void>)>();
+
+  /// Registers a callback asked before downloading an offered patch, see
+  /// updater::set_store_update_pending_callback.  While it returns true updates
+  /// report ShorebirdDeferReason_StoreUpdatePending.  `context` is passed back
+  /// to the callback and must stay valid until the callback is replaced.  Pass
+  /// NULL to remove the callback.  Does not require shorebird_init.
+  void shorebird_set_store_update_pending_callback(
+    ShorebirdStoreUpdatePendingCallback callback,
+    ffi.Pointer<ffi.Void> context,
+  ) {
+    return _shorebird_set_store_update_pending_callback(
+      callback,
+      context,
+    );
+  }
+
+  late final _shorebird_set_store_update_pending_callbackPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(ShorebirdStoreUpdatePendingCallback,
+              ffi.Pointer<ffi.Void>)>>('shorebird_set_store_update_pending_callback');
+  late final _shorebird_set_store_update_pending_callback =
+      _shorebird_set_store_update_pending_callbackPtr.asFunction<
+          void Function(ShorebirdStoreUpdatePendingCallback,
+              ffi.Pointer<ffi.Void>)>();
+
+  /// Registers callbacks so the app doesn't need to poll after background
+  /// updates, replacing any previously registered.  The struct is copied.
+  /// Passing NULL removes the callbacks.  Does not require shorebird_init.
+  void shorebird_set_delegate(
+    ffi.Pointer<ShorebirdDelegate> delegate,
+  ) {
+    return _shorebird_set_delegate(
+      delegate,
+    );
+  }
+
+  late final _shorebird_set_delegatePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(
+              ffi.Pointer<ShorebirdDelegate>)>>('shorebird_set_delegate');
+  late final _shorebird_set_delegate = _shorebird_set_delegatePtr
+      .asFunction<void Function(ffi.Pointer<ShorebirdDelegate>)>();
+
   /// Synchronously download an update if one is available.
   void shorebird_update() {
     return _shorebird_update();
@@ -2159,7 +2620,60 @@ class UpdaterBindings {
   late final _shorebird_update =
       _shorebird_updatePtr.asFunction<void Function()>();
 
-  /// Start a thread to download an update if one is available.
+  /// Synchronously download an update if one is available, and report what
+  /// happened (including why, if the updater declined to install it).
+  UpdateResult shorebird_update_with_result() {
+    return _shorebird_update_with_result();
+  }
+
+  late final _shorebird_update_with_resultPtr =
+      _lookup<ffi.NativeFunction<UpdateResult Function()>>(
+          'shorebird_update_with_result');
+  late final _shorebird_update_with_result =
+      _shorebird_update_with_resultPtr.asFunction<UpdateResult Function()>();
+
+  /// The outcome of the most recent shorebird_update (or
+  /// shorebird_update_with_result or update thread run), without checking for
+  /// a new update.  Lets apps show e.g. "last checked 2h ago, up to date".
+  LastUpdateResult shorebird_last_update_result() {
+    return _shorebird_last_update_result();
+  }
+
+  late final _shorebird_last_update_resultPtr =
+      _lookup<ffi.NativeFunction<LastUpdateResult Function()>>(
+          'shorebird_last_update_result');
+  late final _shorebird_last_update_result = _shorebird_last_update_resultPtr
+      .asFunction<LastUpdateResult Function()>();
+
+  /// Synchronously download and verify an update if one is available, without
+  /// installing it.  Returns true if an update is ready for
+  /// shorebird_install_downloaded_update.  Critical patches are installed
+  /// straight away instead, so this returns false for them.
+  bool shorebird_download_update() {
+    return _shorebird_download_update();
+  }
+
+  late final _shorebird_download_updatePtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function()>>(
+          'shorebird_download_update');
+  late final _shorebird_download_update =
+      _shorebird_download_updatePtr.asFunction<bool Function()>();
+
+  /// Install the update downloaded by shorebird_download_update so it is used
+  /// on the next run of the app.  Returns true if an update was installed.
+  bool shorebird_install_downloaded_update() {
+    return _shorebird_install_downloaded_update();
+  }
+
+  late final _shorebird_install_downloaded_updatePtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function()>>(
+          'shorebird_install_downloaded_update');
+  late final _shorebird_install_downloaded_update =
+      _shorebird_install_downloaded_updatePtr.asFunction<bool Function()>();
+
+  /// Start a thread to download an update if one is available.  Returns
+  /// immediately without starting another thread if one is already running,
+  /// see shorebird_update_queue_state.
   void shorebird_start_update_thread() {
     return _shorebird_start_update_thread();
   }
@@ -2170,12 +2684,26 @@ class UpdaterBindings {
   late final _shorebird_start_update_thread =
       _shorebird_start_update_threadPtr.asFunction<void Function()>();
 
+  /// Whether an update is running or queued, e.g. so an app can show that it
+  /// is checking.  Does not require shorebird_init.
+  int shorebird_update_queue_state() {
+    return _shorebird_update_queue_state();
+  }
+
+  late final _shorebird_update_queue_statePtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function()>>(
+          'shorebird_update_queue_state');
+  late final _shorebird_update_queue_state =
+      _shorebird_update_queue_statePtr.asFunction<int Function()>();
+
   /// Tell the updater that we're launching from what it told us was the
   /// next patch to boot from. This will copy the next_boot patch to be the
   /// current_boot patch.
   ///
   /// It is required to call this function before calling
   /// shorebird_report_launch_success or shorebird_report_launch_failure.
+  /// Calling it again for the same patch from the same process (e.g. on hot
+  /// restart) does nothing.
   void shorebird_report_launch_start() {
     return _shorebird_report_launch_start();
   }
@@ -2186,6 +2714,25 @@ class UpdaterBindings {
   late final _shorebird_report_launch_start =
       _shorebird_report_launch_startPtr.asFunction<void Function()>();
 
+  /// Single-call alternative to shorebird_report_launch_start which also records
+  /// that a boot of `patch_number` is pending.  If the app crashes (e.g. abort())
+  /// before shorebird_report_launch_success or shorebird_report_launch_failure
+  /// is called, the next shorebird_init treats the boot as a failure.
+  /// `patch_number` must be shorebird_next_boot_patch_number().
+  void shorebird_begin_boot(
+    int patch_number,
+  ) {
+    return _shorebird_begin_boot(
+      patch_number,
+    );
+  }
+
+  late final _shorebird_begin_bootPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.UintPtr)>>(
+          'shorebird_begin_boot');
+  late final _shorebird_begin_boot =
+      _shorebird_begin_bootPtr.asFunction<void Function(int)>();
+
   /// Report that the app failed to launch.  This will cause the updater to
   /// attempt to roll back to the previous version if this version has not
   /// been launched successfully before.
@@ -2216,6 +2763,21 @@ class UpdaterBindings {
           'shorebird_report_launch_success');
   late final _shorebird_report_launch_success =
       _shorebird_report_launch_successPtr.asFunction<void Function()>();
+
+  /// Uninstall the most recently installed patch and make the previous good
+  /// patch (if any) the one used on next boot.  The uninstalled patch is
+  /// marked as bad and will not be downloaded again.  Intended to be triggered
+  /// manually (e.g. from a debug menu) to undo a problematic patch.
+  /// Returns true if a patch was rolled back.
+  bool shorebird_rollback_last_patch() {
+    return _shorebird_rollback_last_patch();
+  }
+
+  late final _shorebird_rollback_last_patchPtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function()>>(
+          'shorebird_rollback_last_patch');
+  late final _shorebird_rollback_last_patch =
+      _shorebird_rollback_last_patchPtr.asFunction<bool Function()>();
 }
 
 final class __mbstate_t extends ffi.Union {
@@ -3331,10 +3893,134 @@ typedef mode_t = __darwin_mode_t;
 typedef __darwin_mode_t = __uint16_t;
 typedef __uint16_t = ffi.UnsignedShort;
 
+/// What the next boot will run, see updater::BootDecision.
+abstract class ShorebirdBootDecisionReason {
+  /// The next boot uses a patch.
+  static const int ShorebirdBootDecisionReason_PatchSelected = 0;
+  /// No patch is installed for this release.
+  static const int ShorebirdBootDecisionReason_NoPatchInstalled = 1;
+  /// The patch failed to launch or was rolled back.
+  static const int ShorebirdBootDecisionReason_MarkedBad = 2;
+  /// The patch's artifact is no longer on disk.
+  static const int ShorebirdBootDecisionReason_ArtifactMissing = 3;
+  /// The patch's artifact could not be read.
+  static const int ShorebirdBootDecisionReason_ArtifactUnreadable = 4;
+  /// The patch's artifact no longer matches its hash.
+  static const int ShorebirdBootDecisionReason_HashMismatch = 5;
+  /// The patch is not in shorebird.yaml's patch_allowlist.
+  static const int ShorebirdBootDecisionReason_NotAllowlisted = 6;
+  /// The app was updated to a new release.
+  static const int ShorebirdBootDecisionReason_ReleaseVersionChanged = 7;
+  /// The patch's artifact changed between selection and boot.
+  static const int ShorebirdBootDecisionReason_ChangedBeforeBoot = 8;
+  /// The app was replaced by an older release, the patch is kept for if
+  /// the newer release returns.
+  static const int ShorebirdBootDecisionReason_ReleaseVersionDowngraded = 9;
+}
+
+/// Why an update was deferred, see updater::DeferReason.
+abstract class ShorebirdDeferReason {
+  /// The update was not deferred.
+  static const int ShorebirdDeferReason_None = 0;
+  static const int ShorebirdDeferReason_AutoUpdateDisabled = 1;
+  static const int ShorebirdDeferReason_CheckThrottled = 2;
+  static const int ShorebirdDeferReason_PatchesDisabled = 3;
+  static const int ShorebirdDeferReason_IncompatibleBuildNumber = 4;
+  static const int ShorebirdDeferReason_IncompatibleNativeFingerprint = 5;
+  static const int ShorebirdDeferReason_Offline = 6;
+  static const int ShorebirdDeferReason_ServerBackoff = 7;
+  static const int ShorebirdDeferReason_AutoUpdateDisabledByApp = 8;
+  static const int ShorebirdDeferReason_AutoUpdateDisabledByConfig = 9;
+  static const int ShorebirdDeferReason_MeteredNetwork = 10;
+  static const int ShorebirdDeferReason_AppInBackground = 11;
+  static const int ShorebirdDeferReason_UpdaterUpgradeRequired = 12;
+  static const int ShorebirdDeferReason_UpdateInDaemon = 13;
+  /// The app's patch check response callback vetoed the offered patch,
+  /// see shorebird_set_patch_check_response_callback.
+  static const int ShorebirdDeferReason_VetoedByApp = 14;
+  /// An app store update is waiting to install, see
+  /// shorebird_set_store_update_pending_callback.
+  static const int ShorebirdDeferReason_StoreUpdatePending = 15;
+  /// shorebird_init has not been called (or failed), so the updater can't
+  /// check for updates.
+  static const int ShorebirdDeferReason_NotInitialized = 16;
+}
+
+/// Stable error codes for callers which need to handle failures
+/// programmatically rather than by reading logs.  Values must never be
+/// reused; add new codes at the end.
+abstract class ShorebirdErrorCode {
+  /// No error.
+  static const int ShorebirdErrorCode_None = 0;
+  /// An error not covered by a more specific code.
+  static const int ShorebirdErrorCode_Unknown = 1;
+  static const int ShorebirdErrorCode_InvalidArgument = 2;
+  static const int ShorebirdErrorCode_InvalidState = 3;
+  static const int ShorebirdErrorCode_BadServerResponse = 4;
+  static const int ShorebirdErrorCode_FailedToSaveState = 5;
+  static const int ShorebirdErrorCode_ConfigNotInitialized = 6;
+  static const int ShorebirdErrorCode_UpdateAlreadyInProgress = 7;
+  static const int ShorebirdErrorCode_BadBaseArtifact = 8;
+  static const int ShorebirdErrorCode_HashMismatch = 9;
+  static const int ShorebirdErrorCode_InvalidSignature = 10;
+  /// The server could not be reached or the connection failed.
+  static const int ShorebirdErrorCode_Network = 11;
+  /// Reading or writing a file failed.
+  static const int ShorebirdErrorCode_Io = 12;
+  /// The updater is in offline mode, see shorebird_set_offline.
+  static const int ShorebirdErrorCode_Offline = 13;
+  /// The server asked the updater to back off (e.g. HTTP 429).
+  static const int ShorebirdErrorCode_ServerBusy = 14;
+  /// The updater was built without network support.
+  static const int ShorebirdErrorCode_NetworkUnsupported = 15;
+  /// A download was refused (e.g. its signed URL expired) and a fresh URL
+  /// could not be obtained.
+  static const int ShorebirdErrorCode_DownloadUrlExpired = 16;
+  /// The patch is not in the patch_allowlist bundled with the app.
+  static const int ShorebirdErrorCode_PatchNotAllowed = 17;
+  /// A download stopped because the app went into the background, see
+  /// shorebird_on_app_background.
+  static const int ShorebirdErrorCode_DownloadPaused = 18;
+  /// The patch is encrypted and the app provided no key for it, see
+  /// shorebird_set_patch_key_callback.
+  static const int ShorebirdErrorCode_PatchKeyUnavailable = 19;
+  /// The patch did not apply to this device's copy of the release, e.g.
+  /// because it was built against a different build.
+  static const int ShorebirdErrorCode_PatchDidNotApply = 20;
+}
+
+/// Whether an update is running or queued, see updater::UpdateQueueState.
+abstract class ShorebirdUpdateQueueState {
+  /// No update is running.
+  static const int ShorebirdUpdateQueueState_Idle = 0;
+  /// An update is running (shorebird_update or the update thread).
+  static const int ShorebirdUpdateQueueState_Running = 1;
+  /// The update thread is running and will run once more when it is done,
+  /// see queue_follow_up_update in shorebird.yaml.
+  static const int ShorebirdUpdateQueueState_FollowUpQueued = 2;
+}
+
+/// Outcome of shorebird_update_with_result, see updater::UpdateStatus.
+abstract class ShorebirdUpdateStatus {
+  static const int ShorebirdUpdateStatus_NoUpdate = 0;
+  static const int ShorebirdUpdateStatus_UpdateAvailable = 1;
+  static const int ShorebirdUpdateStatus_UpdateInstalled = 2;
+  static const int ShorebirdUpdateStatus_UpdateDownloaded = 3;
+  static const int ShorebirdUpdateStatus_UpdateDeferred = 4;
+  static const int ShorebirdUpdateStatus_UpdateHadError = 5;
+}
+
 /// Struct containing configuration parameters for the updater.
 /// Passed to all updater functions.
 /// NOTE: If this struct is changed all language bindings must be updated.
 final class AppParameters extends ffi.Struct {
+  /// sizeof(AppParameters) as compiled by the caller, required.  New fields
+  /// are only ever appended, and fields which don't fit in `size` are
+  /// treated as unset, so callers built against an older updater.h keep
+  /// working.
+  @ffi.UintPtr()
+  external int size;
+
   /// release_version, required.  Named version of the app, off of which
   /// updates are based.  Can be either a version number or a hash.
   external ffi.Pointer<ffi.Char> release_version;
@@ -3348,8 +4034,150 @@ final class AppParameters extends ffi.Struct {
   @ffi.Int()
   external int original_libapp_paths_size;
 
-  /// Path to cache_dir where the updater will store downloaded artifacts.
+  /// Path to cache_dir where the updater will store downloaded artifacts,
+  /// required.
   external ffi.Pointer<ffi.Char> cache_dir;
+
+  /// Build number of the running app (e.g. versionCode on Android), optional.
+  /// 0 if unknown.  Used to refuse patches targeted at other builds.
+  @ffi.Uint64()
+  external int build_number;
+
+  /// app_id to use instead of the one in shorebird.yaml, optional (NULL).
+  /// Only honored when shorebird.yaml sets `allow_app_id_override: true`.
+  external ffi.Pointer<ffi.Char> app_id_override;
+
+  /// Directory to download patches to, optional (NULL).  Defaults to a
+  /// directory under cache_dir, which is also used if this one turns out
+  /// not to be writable.
+  external ffi.Pointer<ffi.Char> download_dir;
+
+  /// The device's locale (e.g. "en_US"), optional (NULL).  Only sent to
+  /// the server, as language and country, when shorebird.yaml sets
+  /// `report_locale: true`.
+  external ffi.Pointer<ffi.Char> locale;
+
+  /// Whether the app is a debug build, optional (false).  The updater
+  /// itself is built in release mode for every flavor, so it can't tell.
+  /// Only debug builds honor shorebird.yaml's `pinned_patch_number`.
+  @ffi.Bool()
+  external bool debug_build;
+}
+
+/// Callbacks for reading files the host can't give the updater a path to,
+/// see shorebird_init_with_file_callbacks.  Files are read from start to end
+/// and every file opened is closed.  `context` is passed back to open.
+/// NOTE: If this struct is changed all language bindings must be updated.
+final class FileCallbacks extends ffi.Struct {
+  /// sizeof(FileCallbacks) as compiled by the caller, required.  As with
+  /// AppParameters, new fields are only ever appended.
+  @ffi.UintPtr()
+  external int size;
+
+  external ffi.Pointer<ffi.Void> context;
+
+  /// Opens the file called `name` (e.g. "shorebird.yaml"), returning a
+  /// handle for read and close, or NULL if it can't be opened.
+  external ffi.Pointer<
+      ffi.NativeFunction<
+          ffi.Pointer<ffi.Void> Function(ffi.Pointer<ffi.Void>,
+              ffi.Pointer<ffi.Char>)>>
+      open;
+
+  /// Reads up to `count` bytes into `buffer`, returning how many were read
+  /// (0 at the end of the file), or -1 on error.
+  external ffi.Pointer<
+      ffi.NativeFunction<
+          ffi.IntPtr Function(ffi.Pointer<ffi.Void>,
+              ffi.Pointer<ffi.Uint8>, ffi.UintPtr)>>
+      read;
+
+  /// Closes a handle returned by open.
+  external ffi.Pointer<
+      ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Void>)>>
+      close;
+}
+
+/// Called before each signed request to the update server to get the HMAC
+/// key.  Writes the key to `buffer` (which holds `buffer_len` bytes) and
+/// returns its length, or returns 0 if there is no key.
+typedef ShorebirdSigningKeyCallback = ffi.Pointer<
+    ffi.NativeFunction<
+        ffi.UintPtr Function(ffi.Pointer<ffi.Void>,
+            ffi.Pointer<ffi.Uint8>, ffi.UintPtr)>>;
+
+/// Called with a patch's encryption key id to get the key which decrypts it.
+/// Writes the 32 byte AES-256 key to `buffer` (which holds `buffer_len`
+/// bytes) and returns its length, or returns 0 if the app has no such key.
+typedef ShorebirdPatchKeyCallback = ffi.Pointer<
+    ffi.NativeFunction<
+        ffi.UintPtr Function(ffi.Pointer<ffi.Void>,
+            ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Uint8>, ffi.UintPtr)>>;
+
+/// Called with the JSON of a patch check response offering a patch (valid
+/// only during the call), before the update downloads it.  Return false to
+/// veto installing it.
+typedef ShorebirdPatchCheckResponseCallback = ffi.Pointer<
+    ffi.NativeFunction<
+        ffi.Bool Function(ffi.Pointer<ffi.Void>, ffi.Pointer<ffi.Char>)>>;
+
+/// Returns whether an app store update (e.g. a Play in-app update) is
+/// downloaded or downloading and waiting to install.
+typedef ShorebirdStoreUpdatePendingCallback = ffi.Pointer<
+    ffi.NativeFunction<
+        ffi.Bool Function(ffi.Pointer<ffi.Void>)>>;
+
+/// Callbacks for shorebird_set_delegate.  Any callback may be NULL.  They may
+/// be called on any thread (e.g. the update thread), with `context` passed
+/// back as the first argument.
+/// NOTE: If this struct is changed all language bindings must be updated.
+final class ShorebirdDelegate extends ffi.Struct {
+  external ffi.Pointer<ffi.Void> context;
+
+  /// Called with the patch number after a patch is installed.
+  external ffi.Pointer<
+      ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Void>, ffi.UintPtr)>>
+      on_patch_installed;
+
+  /// Called with the patch number after a patch is marked bad.
+  external ffi.Pointer<
+      ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Void>, ffi.UintPtr)>>
+      on_patch_invalidated;
+
+  /// Called when an update fails.
+  external ffi.Pointer<
+      ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Void>, ffi.Int32)>>
+      on_update_error;
+
+  /// Called with the patch number after a critical patch is installed, the
+  /// app should prompt the user to restart.
+  external ffi.Pointer<
+      ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Void>, ffi.UintPtr)>>
+      on_restart_requested;
+}
+
+/// Returned by shorebird_update_with_result.  `defer_reason` is only set
+/// when `status` is UpdateDeferred, and `error` only when it is
+/// UpdateHadError.
+final class UpdateResult extends ffi.Struct {
+  @ffi.Int32()
+  external int status;
+
+  @ffi.Int32()
+  external int defer_reason;
+
+  @ffi.Int32()
+  external int error;
+}
+
+/// Returned by shorebird_last_update_result.
+final class LastUpdateResult extends ffi.Struct {
+  /// When the most recent update finished (seconds since epoch), or 0 if
+  /// there has been no update for this release.
+  @ffi.Uint64()
+  external int timestamp;
+
+  external UpdateResult result;
 }
 
 const int __GNUC_VA_LIST = 1;
@@ -0,0 +1,30 @@
+// A mock update server for engine integration tests, serving scripted
+// responses from a scenario file so full-device tests are deterministic.
+// See README.md for usage and the scenario format.
+
+mod scenario;
+mod server;
+
+use std::path::Path;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args();
+    args.next(); // skip program name
+    let scenario_path = args.next().expect("path to scenario file");
+    let port = match args.next() {
+        Some(port) => port.parse()?,
+        None => 0,
+    };
+
+    let scenario = scenario::Scenario::load(Path::new(&scenario_path))?;
+    let server = server::Server::bind(scenario, port)?;
+    // Tests read the URL from this first line, the request log follows.
+    println!("Listening on {}", server.base_url());
+    server.serve(|request| {
+        println!(
+            "{}",
+            serde_json::to_string(request).expect("Request serializes")
+        )
+    });
+    Ok(())
+}
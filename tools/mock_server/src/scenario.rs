@@ -0,0 +1,156 @@
+// This file defines the scenario files the mock server serves from, see
+// README.md for the format.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Replaced in response bodies with the server's own URL, so e.g. a patch
+/// check can point download_url back at this server.
+pub const BASE_URL_PLACEHOLDER: &str = "{{base_url}}";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// e.g. "POST".  Any method matches if unset.
+    pub method: Option<String>,
+    /// Matched against the request path, ignoring any query string.
+    pub path: String,
+    /// Served in order, one per request.  The last is repeated once the
+    /// others are used up.
+    pub responses: Vec<Response>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Response {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Wait this long before responding, e.g. to test timeouts.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// At most one of these, an empty body if none is set.
+    pub json: Option<serde_json::Value>,
+    pub body: Option<String>,
+    /// Relative to the scenario file.
+    pub file: Option<PathBuf>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Scenario {
+    pub fn from_yaml(yaml: &str, dir: &Path) -> anyhow::Result<Self> {
+        let mut scenario: Scenario = serde_yaml::from_str(yaml)?;
+        for route in &mut scenario.routes {
+            anyhow::ensure!(
+                !route.responses.is_empty(),
+                "Route {} has no responses",
+                route.path
+            );
+            for response in &mut route.responses {
+                let bodies = [
+                    response.json.is_some(),
+                    response.body.is_some(),
+                    response.file.is_some(),
+                ];
+                anyhow::ensure!(
+                    bodies.iter().filter(|set| **set).count() <= 1,
+                    "Route {} has a response with more than one of json, body and file",
+                    route.path
+                );
+                if let Some(file) = &mut response.file {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+        Ok(scenario)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let yaml = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Self::from_yaml(&yaml, dir).with_context(|| format!("Invalid scenario {}", path.display()))
+    }
+}
+
+impl Route {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        self.path == path
+            && self
+                .method
+                .as_deref()
+                .is_none_or(|expected| expected.eq_ignore_ascii_case(method))
+    }
+}
+
+impl Response {
+    /// The body to send, with BASE_URL_PLACEHOLDER replaced.  Files are sent
+    /// as they are.
+    pub fn body(&self, base_url: &str) -> anyhow::Result<Vec<u8>> {
+        if let Some(file) = &self.file {
+            return std::fs::read(file)
+                .with_context(|| format!("Failed to read {}", file.display()));
+        }
+        let text = match (&self.json, &self.body) {
+            (Some(json), _) => serde_json::to_string(json)?,
+            (None, Some(body)) => body.clone(),
+            (None, None) => String::new(),
+        };
+        Ok(text.replace(BASE_URL_PLACEHOLDER, base_url).into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scenario;
+    use std::path::Path;
+
+    #[test]
+    fn parses_scenarios() {
+        let yaml = r#"
+routes:
+  - method: POST
+    path: /api/v1/patches/check
+    responses:
+      - json: { patch_available: true, download_url: "{{base_url}}/patch" }
+      - status: 500
+  - path: /patch
+    responses:
+      - file: patch.bin
+"#;
+        let scenario = Scenario::from_yaml(yaml, Path::new("/scenarios")).unwrap();
+        let check = &scenario.routes[0];
+        assert!(check.matches("post", "/api/v1/patches/check?x=1"));
+        assert!(!check.matches("GET", "/api/v1/patches/check"));
+        assert_eq!(check.responses[1].status, 500);
+        assert_eq!(
+            String::from_utf8(check.responses[0].body("http://host").unwrap()).unwrap(),
+            r#"{"download_url":"http://host/patch","patch_available":true}"#
+        );
+        let download = &scenario.routes[1];
+        assert!(download.matches("GET", "/patch"));
+        assert_eq!(
+            download.responses[0].file.as_deref(),
+            Some(Path::new("/scenarios/patch.bin"))
+        );
+
+        let two_bodies = "routes: [{path: /, responses: [{body: a, json: b}]}]";
+        assert!(Scenario::from_yaml(two_bodies, Path::new(".")).is_err());
+        let no_responses = "routes: [{path: /, responses: []}]";
+        assert!(Scenario::from_yaml(no_responses, Path::new(".")).is_err());
+    }
+}
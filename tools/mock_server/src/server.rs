@@ -0,0 +1,282 @@
+// This file is a deliberately tiny HTTP/1.1 server: one request per
+// connection, bodies only by Content-Length.  That is all the updater needs,
+// and keeps the harness free of an async runtime.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::scenario::{Response, Scenario};
+
+/// Requests with larger bodies are refused, nothing the updater sends comes
+/// close.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// One line of the request log (JSON), printed for every request so tests
+/// can check what the updater sent.
+#[derive(Debug, Serialize)]
+pub struct LoggedRequest {
+    pub method: String,
+    pub path: String,
+    /// The body as JSON if it parses, otherwise as a (lossy) string.
+    pub body: serde_json::Value,
+    /// The status served, 404 if no route matched.
+    pub status: u16,
+}
+
+struct State {
+    scenario: Scenario,
+    /// How many requests each route has served, by index.
+    served: Vec<usize>,
+}
+
+impl State {
+    fn next_response(&mut self, method: &str, path: &str) -> Option<Response> {
+        let index = self
+            .scenario
+            .routes
+            .iter()
+            .position(|route| route.matches(method, path))?;
+        let responses = &self.scenario.routes[index].responses;
+        let response = &responses[self.served[index].min(responses.len() - 1)];
+        self.served[index] += 1;
+        Some(response.clone())
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    base_url: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &TcpStream) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Malformed request line: {:?}", line);
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        anyhow::ensure!(reader.read_line(&mut line)? > 0, "Connection closed");
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    anyhow::ensure!(content_length <= MAX_BODY_BYTES, "Request body too large");
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, body })
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response, body: &[u8]) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason_phrase(response.status),
+        body.len()
+    );
+    let has_content_type = response
+        .headers
+        .keys()
+        .any(|name| name.eq_ignore_ascii_case("content-type"));
+    if response.json.is_some() && !has_content_type {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn body_for_log(body: &[u8]) -> serde_json::Value {
+    if body.is_empty() {
+        return serde_json::Value::Null;
+    }
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned().into())
+}
+
+impl Server {
+    /// Listens on 127.0.0.1:port, port 0 picks a free port (see base_url).
+    pub fn bind(scenario: Scenario, port: u16) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+        let served = vec![0; scenario.routes.len()];
+        Ok(Self {
+            listener,
+            base_url,
+            state: Arc::new(Mutex::new(State { scenario, served })),
+        })
+    }
+
+    /// e.g. "http://127.0.0.1:1234", for base_url in shorebird.yaml.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Serves requests until the process exits, calling `log` with each.
+    pub fn serve(self, log: impl Fn(&LoggedRequest) + Send + Sync + 'static) {
+        let log = Arc::new(log);
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let state = self.state.clone();
+            let base_url = self.base_url.clone();
+            let log = log.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handle(&stream, &state, &base_url, &*log) {
+                    eprintln!("Failed to handle request: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+fn handle(
+    stream: &TcpStream,
+    state: &Mutex<State>,
+    base_url: &str,
+    log: &dyn Fn(&LoggedRequest),
+) -> anyhow::Result<()> {
+    let request = read_request(stream)?;
+    let response = state
+        .lock()
+        .expect("State lock poisoned")
+        .next_response(&request.method, &request.path)
+        .unwrap_or(Response {
+            status: 404,
+            headers: Default::default(),
+            delay_ms: 0,
+            json: None,
+            body: None,
+            file: None,
+        });
+    log(&LoggedRequest {
+        method: request.method,
+        path: request.path,
+        body: body_for_log(&request.body),
+        status: response.status,
+    });
+    if response.delay_ms > 0 {
+        std::thread::sleep(Duration::from_millis(response.delay_ms));
+    }
+    let body = response.body(base_url)?;
+    write_response(stream, &response, &body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::scenario::Scenario;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    fn request(base_url: &str, request: &str) -> String {
+        let address = base_url.strip_prefix("http://").unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_scenario() {
+        let yaml = r#"
+routes:
+  - method: POST
+    path: /api/v1/patches/check
+    responses:
+      - json: { download_url: "{{base_url}}/patch" }
+      - status: 503
+        headers: { Retry-After: "10" }
+"#;
+        let scenario = Scenario::from_yaml(yaml, Path::new(".")).unwrap();
+        let server = Server::bind(scenario, 0).unwrap();
+        let base_url = server.base_url().to_string();
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        std::thread::spawn(move || {
+            server.serve(move |request| {
+                log.lock()
+                    .unwrap()
+                    .push(serde_json::to_string(request).unwrap())
+            })
+        });
+
+        let check =
+            "POST /api/v1/patches/check HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"app_id\":1}\n";
+        let first = request(&base_url, check);
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{}", first);
+        assert!(first.contains("Content-Type: application/json\r\n"));
+        assert!(first.ends_with(&format!("{{\"download_url\":\"{}/patch\"}}", base_url)));
+        // The last response repeats.
+        for _ in 0..2 {
+            let busy = request(&base_url, check);
+            assert!(busy.starts_with("HTTP/1.1 503 "), "{}", busy);
+            assert!(busy.contains("Retry-After: 10\r\n"));
+        }
+        let missing = request(&base_url, "GET /patch HTTP/1.1\r\n\r\n");
+        assert!(missing.starts_with("HTTP/1.1 404 "), "{}", missing);
+
+        let logged = logged.lock().unwrap();
+        assert_eq!(logged.len(), 4);
+        assert_eq!(
+            logged[0],
+            r#"{"method":"POST","path":"/api/v1/patches/check","body":{"app_id":1},"status":200}"#
+        );
+        assert_eq!(
+            logged[3],
+            r#"{"method":"GET","path":"/patch","body":null,"status":404}"#
+        );
+    }
+}